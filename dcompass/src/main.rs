@@ -26,12 +26,13 @@ mod tests;
 mod worker;
 
 use self::{parser::Parsed, worker::worker};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::BytesMut;
 use droute::{
     builders::{RouterBuilder, RuneScript},
     errors::ScriptError,
-    AsyncTryInto, Router,
+    testing::Fixture,
+    utils, AsyncTryInto, Label, Router,
 };
 use log::*;
 use simple_logger::SimpleLogger;
@@ -39,8 +40,8 @@ use std::{net::SocketAddr, path::PathBuf, result::Result as StdResult, sync::Arc
 use structopt::StructOpt;
 use tokio::{
     fs::File,
-    io::AsyncReadExt,
-    net::UdpSocket,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
     signal,
     sync::broadcast::{self, Sender},
     time::sleep,
@@ -59,11 +60,25 @@ struct DcompassOpts {
     /// Set this flag to validate the configuration file only.
     #[structopt(short, long, parse(from_flag))]
     validate: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Route the queries in the fixture file with the upstreams mocked, and check the responses against the expectations.
+    Test {
+        /// Path to the fixture file.
+        #[structopt(parse(from_os_str))]
+        fixture: PathBuf,
+    },
 }
 
 async fn init(p: Parsed) -> StdResult<(Router<RuneScript>, SocketAddr, LevelFilter), ScriptError> {
     Ok((
         RouterBuilder::new(p.script, p.upstreams)
+            .on_error(p.on_error)
             .async_try_into()
             .await?,
         p.address,
@@ -71,7 +86,165 @@ async fn init(p: Parsed) -> StdResult<(Router<RuneScript>, SocketAddr, LevelFilt
     ))
 }
 
-async fn serve(socket: Arc<UdpSocket>, router: Arc<Router<RuneScript>>, tx: &Sender<()>) {
+// Rebuild the upstreams and the script from the configuration file on SIGHUP, and swap them into the running router.
+// The cached responses of the upstreams kept are carried over, while health checks and prefetching start over with the new upstreams.
+#[cfg(unix)]
+async fn reload(router: Arc<Router<RuneScript>>, config_path: Option<PathBuf>) -> Result<()> {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        let config_path = match &config_path {
+            Some(p) => p,
+            None => {
+                warn!("SIGHUP received, but the built-in config is in use, nothing to reload");
+                continue;
+            }
+        };
+        info!(
+            "SIGHUP received, reloading upstreams and script from {}",
+            config_path.display()
+        );
+        let parsed: StdResult<Parsed, _> = match tokio::fs::read_to_string(config_path).await {
+            Ok(config) => serde_yaml::from_str(&config).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match parsed {
+            // Only upstreams and script are reloaded, the changes to other fields take effect on restart.
+            Ok(p) => {
+                let script = p.script.path(config_path);
+                match router.reload_upstreams(p.upstreams).await {
+                    Ok(()) => info!("upstreams reloaded"),
+                    Err(e) => warn!(
+                        "failed to reload upstreams, keeping the current ones: {}",
+                        e
+                    ),
+                }
+                // The script is built with the upstreams in use, so it goes after them.
+                match router.reload_script(script).await {
+                    Ok(()) => info!("script reloaded"),
+                    Err(e) => warn!("failed to reload script, keeping the current one: {}", e),
+                }
+            }
+            Err(e) => warn!(
+                "failed to read the configuration, keeping the current upstreams and script: {}",
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn reload(_: Arc<Router<RuneScript>>, _: Option<PathBuf>) -> Result<()> {
+    std::future::pending().await
+}
+
+// Flush the response cache on SIGUSR1, e.g. after the records of internal zones are changed.
+#[cfg(unix)]
+async fn flush(router: Arc<Router<RuneScript>>) -> Result<()> {
+    let mut user1 = signal::unix::signal(signal::unix::SignalKind::user_defined1())?;
+    while user1.recv().await.is_some() {
+        info!(
+            "SIGUSR1 received, {} cached responses flushed",
+            router.flush_cache(None)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn flush(_: Arc<Router<RuneScript>>) -> Result<()> {
+    std::future::pending().await
+}
+
+// Wait for Ctrl-C, or SIGTERM sent by service managers and container runtimes to stop dcompass, returning the name of the signal.
+#[cfg(unix)]
+async fn terminated() -> Result<&'static str> {
+    let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        r = signal::ctrl_c() => r.map(|()| "Ctrl-C").map_err(Into::into),
+        _ = term.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() -> Result<&'static str> {
+    signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
+
+// Wait for the workers to exit, and save the cache snapshot so that it is loaded on the next start.
+async fn shutdown(router: &Router<RuneScript>, tx: &Sender<()>) {
+    sleep(Duration::from_millis(500)).await;
+    // Error implies that there is no receiver/active worker, we are done
+    if tx.send(()).is_ok() {
+        while tx.receiver_count() != 0 {
+            log::warn!("waiting 5 seconds for workers to exit...");
+            sleep(Duration::from_secs(5)).await
+        }
+    }
+    match router.save_cache().await {
+        Ok(0) => (),
+        Ok(n) => log::warn!("{} cached responses saved to the snapshot", n),
+        Err(e) => log::warn!("failed to save the cache snapshot: {}", e),
+    }
+    log::warn!("gracefully shut down!");
+}
+
+// Respond to the raw HTTP request with the metrics recorded by the script in the Prometheus text format if it is `GET /metrics`, or with 404 otherwise.
+fn metrics_response(request: &[u8]) -> String {
+    let mut parts = request.split(|b| b.is_ascii_whitespace());
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(path)) if path.split(|b| *b == b'?').next() == Some(b"/metrics") => {
+            ("200 OK", utils::metrics::render())
+        }
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// Serve the metrics recorded by the script over plain HTTP.
+async fn metrics(addr: Option<SocketAddr>) -> Result<()> {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return std::future::pending().await,
+    };
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind to {}", addr))?;
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("failed to accept metrics request: {}", e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            // Only the request line is looked at.
+            let mut buf = [0; 1024];
+            let len = match stream.read(&mut buf).await {
+                Ok(len) => len,
+                Err(_) => return,
+            };
+            let resp = metrics_response(&buf[..len]);
+            if let Err(e) = stream.write_all(resp.as_bytes()).await {
+                warn!("failed to send back metrics: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve(
+    socket: Arc<UdpSocket>,
+    listener: Label,
+    router: Arc<Router<RuneScript>>,
+    tx: &Sender<()>,
+) {
     loop {
         // Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
         let mut buf = BytesMut::with_capacity(1024);
@@ -89,11 +262,12 @@ async fn serve(socket: Arc<UdpSocket>, router: Arc<Router<RuneScript>>, tx: &Sen
 
         let router = router.clone();
         let socket = socket.clone();
+        let listener = listener.clone();
         let mut shutdown = tx.subscribe();
         #[rustfmt::skip]
         tokio::spawn(async move {
             tokio::select! {
-                biased; res = worker(router, socket, buf.freeze(), src) => {
+                biased; res = worker(router, socket, listener, buf.freeze(), src) => {
                     match res {
                         Ok(_) => (),
                         Err(e) => warn!("handling query failed: {}", e),
@@ -109,12 +283,45 @@ async fn serve(socket: Arc<UdpSocket>, router: Arc<Router<RuneScript>>, tx: &Sen
     }
 }
 
+// Run the test cases in the fixture file, failing if any of them fails.
+async fn test(router: Router<RuneScript>, fixture_path: PathBuf) -> Result<()> {
+    let display_path = fixture_path.as_path().display();
+    let fixture: Fixture = serde_yaml::from_str(
+        &tokio::fs::read_to_string(&fixture_path)
+            .await
+            .with_context(|| format!("Failed to read the fixture file: {}", display_path))?,
+    )
+    .with_context(|| format!("Failed to parse the fixture file: {}", display_path))?;
+
+    let outcomes = router.test(&fixture).await;
+    let mut failed = 0;
+    for outcome in &outcomes {
+        if outcome.passed() {
+            println!("ok: {}", outcome.name);
+        } else {
+            failed += 1;
+            println!("FAILED: {}", outcome.name);
+            for failure in &outcome.failures {
+                println!("    {}", failure);
+            }
+        }
+    }
+    println!("{} passed, {} failed", outcomes.len() - failed, failed);
+    if failed > 0 {
+        bail!("{} of the test cases failed", failed);
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // console_subscriber::init();
 
     let args: DcompassOpts = DcompassOpts::from_args();
 
+    // The path of the config file in use, from which upstreams are reloaded.
+    let mut reload_path = None;
+
     // If the config path is manually specified with `-c` flag, we use it and any error should fail early.
     // If there is no specified config but there is `config.yaml` under the path where user is invoking `dcompass` (not the absolute path of the binary), then we shall try that config. If the file exists but we failed to read, this should fail. Otherwise, we shall use the default anyway.
     let config = if let Some(config_path) = args.config {
@@ -127,6 +334,7 @@ async fn main() -> Result<()> {
             .await
             .with_context(|| format!("Failed to read from the file specified: {}", display_path))?;
         println!("Using the config file specified: {}", display_path);
+        reload_path = Some(config_path);
         config
     } else {
         let mut config_path = std::env::current_dir()?;
@@ -140,6 +348,7 @@ async fn main() -> Result<()> {
                     format!("Failed to read from the file found: {}", display_path)
                 })?;
                 println!("Using the config under current path: {}", display_path);
+                reload_path = Some(config_path.clone());
                 config
             }
             // No config found, using built-in.
@@ -156,12 +365,16 @@ async fn main() -> Result<()> {
         }
     };
 
+    let mut parsed: Parsed = serde_yaml::from_str(&config)
+        .with_context(|| "Failed to parse the configuration file".to_string())?;
+    // Script modules are resolved relative to the config file. The built-in config has none.
+    if let Some(path) = &reload_path {
+        parsed.script = parsed.script.path(path);
+    }
+    let metrics_addr = parsed.metrics;
+
     // Create whatever we need for get dcompass up and running.
-    let (router, addr, verbosity) = init(
-        serde_yaml::from_str(&config)
-            .with_context(|| "Failed to parse the configuration file".to_string())?,
-    )
-    .await?;
+    let (router, addr, verbosity) = init(parsed).await?;
 
     // If we are only required to validate the config, we shall be safe to exit now.
     if args.validate {
@@ -169,12 +382,22 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(Command::Test { fixture }) = args.command {
+        return test(router, fixture).await;
+    }
+
     // Start logging
     SimpleLogger::new()
         // These modules are quite chatty, we want to disable it.
         .with_level(verbosity)
         .init()?;
 
+    match router.load_cache().await {
+        Ok(0) => (),
+        Ok(n) => info!("{} cached responses loaded from the snapshot", n),
+        Err(e) => warn!("failed to load the cache snapshot: {}", e),
+    }
+
     info!("dcompass ready!");
 
     let router = Arc::new(router);
@@ -191,18 +414,21 @@ async fn main() -> Result<()> {
     // We don't have to worry about incoming requests when shutting down, because when we initiate shutdown, the loop was already terminated
     #[rustfmt::skip]
     tokio::select! {
-        _ = serve(socket, router, &tx) => (),
-        _ = signal::ctrl_c() => {
-            log::warn!("Ctrl-C received, shutting down");
-	    sleep(Duration::from_millis(500)).await;
-            // Error implies that there is no receiver/active worker, we are done
-            if tx.send(()).is_ok() {
-                while tx.receiver_count() != 0 {
-                    log::warn!("waiting 5 seconds for workers to exit...");
-                    sleep(Duration::from_secs(5)).await
-                }
-            }
-            log::warn!("gracefully shut down!");
+        // There is a single listener for now, tagged with the address it is bound to.
+        _ = serve(socket, addr.to_string().into(), router.clone(), &tx) => (),
+        Err(e) = flush(router.clone()) => {
+            return Err(e).context("failed to listen for SIGUSR1");
+        }
+        Err(e) = reload(router.clone(), reload_path) => {
+            return Err(e).context("failed to listen for SIGHUP");
+        }
+        Err(e) = metrics(metrics_addr) => {
+            return Err(e).context("failed to serve metrics");
+        }
+        r = terminated() => {
+            let signal = r.context("failed to listen for termination signals")?;
+            log::warn!("{} received, shutting down", signal);
+            shutdown(&router, &tx).await;
         }
     };
     Ok(())
@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use droute::builders::*;
+use droute::{builders::*, ErrorPolicy};
 use log::LevelFilter;
 use serde::Deserialize;
 use std::net::SocketAddr;
@@ -38,6 +38,12 @@ pub struct Parsed {
     #[serde(flatten)]
     pub upstreams: UpstreamsBuilder<UpstreamBuilder>,
     pub address: SocketAddr,
+    // Address to serve the metrics recorded by the script on, if any.
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
     #[serde(with = "LevelFilterDef")]
     pub verbosity: LevelFilter,
+    // What to do with the queries the script fails to route.
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
 }
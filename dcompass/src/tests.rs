@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::init;
+use super::{init, metrics_response, Parsed};
 use droute::errors::*;
 
 #[tokio::test]
@@ -31,6 +31,17 @@ async fn check_success_ipcidr() {
         .unwrap();
 }
 
+#[tokio::test]
+async fn check_fixture_ipcidr() {
+    let (router, _, _) =
+        init(serde_yaml::from_str(include_str!("../../configs/success_cidr.yaml")).unwrap())
+            .await
+            .unwrap();
+    let fixture =
+        serde_yaml::from_str(include_str!("../../configs/success_cidr.test.yaml")).unwrap();
+    assert!(router.test(&fixture).await.iter().all(|o| o.passed()));
+}
+
 #[cfg(all(feature = "geoip-maxmind", not(feature = "geoip-cn")))]
 #[tokio::test]
 async fn check_example_maxmind() {
@@ -66,6 +77,28 @@ async fn check_success_query_cache_mode() {
     );
 }
 
+#[tokio::test]
+async fn check_success_scheduled() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_scheduled.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_limits() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_limits.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_modules() {
+    let mut parsed: Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_modules.yaml")).unwrap();
+    parsed.script = parsed.script.path("../configs/success_modules.yaml");
+    init(parsed).await.unwrap();
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn check_success_geoip() {
     assert_eq!(
@@ -97,3 +130,23 @@ async fn check_fail_recursion() {
         e => panic!("Not the right error type: {}", e),
     };
 }
+
+#[test]
+fn serve_metrics_only() {
+    assert!(
+        metrics_response(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .starts_with("HTTP/1.1 200 OK")
+    );
+    assert!(
+        metrics_response(b"GET /metrics?name=dcompass HTTP/1.1\r\n\r\n")
+            .starts_with("HTTP/1.1 200 OK")
+    );
+    assert!(metrics_response(b"GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found"));
+    assert!(
+        metrics_response(b"GET /metricsx HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found")
+    );
+    assert!(
+        metrics_response(b"POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found")
+    );
+    assert!(metrics_response(b"").starts_with("HTTP/1.1 404 Not Found"));
+}
@@ -16,30 +16,43 @@
 use anyhow::Result;
 use bytes::Bytes;
 use domain::base::Message;
-use droute::{builders::RuneScript, QueryContext, Router};
+use droute::{builders::RuneScript, errors::ScriptError, Label, QueryContext, Router, Transport};
 use log::*;
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Instant};
 use tokio::net::UdpSocket;
 
 /// Handle a single incoming packet
 pub async fn worker(
     router: Arc<Router<RuneScript>>,
     socket: Arc<UdpSocket>,
+    listener: Label,
     buf: Bytes,
     src: SocketAddr,
 ) -> Result<()> {
-    socket
-        .send_to(
-            router
-                .resolve(
-                    Message::from_octets(buf)?,
-                    Some(QueryContext { ip: src.ip() }),
-                )
-                .await?
-                .as_slice(),
-            src,
+    let resp = match router
+        .resolve(
+            Message::from_octets(buf)?,
+            Some(QueryContext {
+                ip: src.ip(),
+                port: src.port(),
+                listener,
+                transport: Transport::Udp,
+                received: Instant::now(),
+                deadline: None,
+                marks: HashMap::new(),
+            }),
         )
         .await
+    {
+        Ok(resp) => resp,
+        // Dropped on purpose by the error policy
+        Err(ScriptError::Dropped) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    socket
+        .send_to(resp.as_slice(), src)
+        .await
         .unwrap_or_else(|e| {
             warn!("failed to send back response: {}", e);
             0
@@ -0,0 +1,129 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Heap taken by the matchers built from large lists, as routers with little memory load lists of millions of domains.
+// Run with `cargo bench --bench memory`.
+
+use bytes::Bytes;
+use dmatcher::{
+    domain::Domain,
+    hosts::{Hosts, MatchType},
+};
+use domain::base::Dname;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Count the bytes allocated on the heap and not yet freed.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const TLDS: &[&str] = &[
+    "com", "net", "org", "cn", "io", "info", "xyz", "top", "de", "ru",
+];
+
+// A million domains shaped like those of blocklists: random second-level domains, half of them with a subdomain or two.
+fn generate(n: usize) -> Vec<Dname<Bytes>> {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move |m: u64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed % m
+    };
+    let label = |next: &mut dyn FnMut(u64) -> u64| {
+        let len = 4 + next(9);
+        (0..len)
+            .map(|_| char::from(b"abcdefghijklmnopqrstuvwxyz0123456789-"[next(36) as usize]))
+            .collect::<String>()
+    };
+    (0..n)
+        .map(|_| {
+            let mut domain = format!("{}.{}", label(&mut next), TLDS[next(10) as usize]);
+            for _ in 0..next(3) {
+                domain = format!("{}.{}", label(&mut next), domain);
+            }
+            Dname::from_str(&domain).unwrap()
+        })
+        .collect()
+}
+
+// The heap taken by what `build` returns, which is kept until the measurement is done.
+fn measure<T>(name: &str, n: usize, build: impl FnOnce() -> T) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let built = build();
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!(
+        "{}: {} rules, {:.1} MiB, {:.1} bytes per rule",
+        name,
+        n,
+        bytes as f64 / (1 << 20) as f64,
+        bytes as f64 / n as f64
+    );
+    drop(built);
+}
+
+fn main() {
+    let sample: Vec<Dname<Bytes>> = fs::read_to_string("./benches/sample.txt")
+        .unwrap()
+        .lines()
+        .filter(|x| !x.is_empty())
+        .map(|x| Dname::from_str(x).unwrap())
+        .collect();
+    measure("domain (sample.txt)", sample.len(), || {
+        sample.iter().cloned().collect::<Domain>()
+    });
+
+    let generated = generate(1_000_000);
+    measure("domain (generated)", generated.len(), || {
+        generated.iter().cloned().collect::<Domain>()
+    });
+
+    let ip = "0.0.0.0".parse().unwrap();
+    measure("hosts (generated)", generated.len(), || {
+        let mut hosts = Hosts::new();
+        hosts.extend(
+            generated
+                .iter()
+                .map(|domain| (domain.clone(), MatchType::Subdomain(ip))),
+        );
+        hosts
+    });
+}
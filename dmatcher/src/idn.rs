@@ -0,0 +1,41 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bytes::Bytes;
+use domain::base::Dname;
+use std::{borrow::Cow, str::FromStr};
+
+// Convert a domain whose labels carry raw UTF-8 into its punycode (A-label) form, so that the Unicode and punycode forms of an internationalized domain match each other.
+// Plain ASCII domains are returned as-is without allocation.
+pub(crate) fn normalize(domain: &Dname<Bytes>) -> Cow<'_, Dname<Bytes>> {
+    if domain.as_slice().is_ascii() {
+        return Cow::Borrowed(domain);
+    }
+
+    let unicode = domain
+        .iter()
+        .filter(|lv| !lv.is_root())
+        .map(|lv| String::from_utf8_lossy(lv.as_slice()))
+        .collect::<Vec<_>>()
+        .join(".");
+
+    match idna::domain_to_ascii(&unicode)
+        .ok()
+        .and_then(|s| Dname::from_str(&s).ok())
+    {
+        Some(d) => Cow::Owned(d),
+        None => Cow::Borrowed(domain),
+    }
+}
@@ -18,4 +18,42 @@
 //! This is a library providing a set of domain and IP address matching algorithms.
 
 pub mod domain;
-pub mod hosts;
\ No newline at end of file
+pub mod hosts;
+mod idn;
+mod serial;
+pub mod tagged;
+mod trie;
+
+pub use serial::DecodeError;
+
+use ::domain::base::Dname;
+use bytes::Bytes;
+
+// Read the lines one by one as they arrive, so that huge lists are never held in memory as a whole.
+#[cfg(feature = "tokio")]
+pub(crate) async fn for_each_line<R, E>(
+    reader: R,
+    mut f: impl FnMut(&str) -> Result<(), E>,
+) -> Result<(), E>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+    E: From<std::io::Error>,
+{
+    use tokio::io::AsyncBufReadExt;
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        f(&line)?;
+    }
+    Ok(())
+}
+
+// Build the domain from its labels from the top level down, which start with the empty root label as the tries are keyed.
+pub(crate) fn to_dname(labels: &[&[u8]]) -> Option<Dname<Bytes>> {
+    let mut buf = Vec::new();
+    for lv in labels.iter().rev().filter(|lv| !lv.is_empty()) {
+        buf.push(lv.len() as u8);
+        buf.extend_from_slice(lv);
+    }
+    buf.push(0);
+    Dname::from_octets(Bytes::from(buf)).ok()
+}
@@ -0,0 +1,126 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Helpers to encode and decode the compiled matchers in a compact binary format, so that huge lists don't have to be parsed again on every start.
+
+use domain::base::name::Label;
+use std::{fmt, net::IpAddr};
+
+/// The bytes are not a valid compiled matcher, e.g. truncated or produced by an incompatible version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed compiled matcher")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn new(magic: &[u8; 4]) -> Self {
+        Self(magic.to_vec())
+    }
+
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn label(&mut self, lv: &[u8]) {
+        self.u8(lv.len() as u8);
+        self.0.extend_from_slice(lv);
+    }
+
+    pub(crate) fn ip(&mut self, ip: &IpAddr) {
+        match ip {
+            IpAddr::V4(ip) => {
+                self.u8(4);
+                self.0.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                self.u8(6);
+                self.0.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+}
+
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], magic: &[u8; 4]) -> Result<Self, DecodeError> {
+        let mut reader = Self(bytes);
+        if reader.take(4)? != magic {
+            return Err(DecodeError);
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.0.len() < n {
+            return Err(DecodeError);
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, DecodeError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub(crate) fn label(&mut self) -> Result<&'a Label, DecodeError> {
+        let len = self.u8()? as usize;
+        Label::from_slice(self.take(len)?).map_err(|_| DecodeError)
+    }
+
+    pub(crate) fn ip(&mut self) -> Result<IpAddr, DecodeError> {
+        Ok(match self.u8()? {
+            4 => {
+                let mut buf = [0; 4];
+                buf.copy_from_slice(self.take(4)?);
+                IpAddr::from(buf)
+            }
+            6 => {
+                let mut buf = [0; 16];
+                buf.copy_from_slice(self.take(16)?);
+                IpAddr::from(buf)
+            }
+            _ => return Err(DecodeError),
+        })
+    }
+
+    // All the bytes must have been read.
+    pub(crate) fn finish(self) -> Result<(), DecodeError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError)
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A domain matching algorithm for categorized domain databases, where every rule carries one or more tags (e.g. `ads`, `tracking`) and a single trie serves all the categories.
+
+use crate::{
+    idn::normalize,
+    to_dname,
+    trie::{Level, Trie},
+};
+use bytes::Bytes;
+use domain::base::{name::Label, Dname};
+
+// Indices into the tag table of the rules ending at this level.
+type LevelNode = Trie<Vec<usize>>;
+
+impl Level for Vec<usize> {
+    fn is_empty(&self) -> bool {
+        <[usize]>::is_empty(self)
+    }
+}
+
+/// Tagged domain matcher algorithm
+#[derive(Clone)]
+pub struct TaggedDomain {
+    root: LevelNode,
+    tags: Vec<String>,
+}
+
+impl Default for TaggedDomain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaggedDomain {
+    /// Create a matcher.
+    pub fn new() -> Self {
+        Self {
+            root: LevelNode::default(),
+            tags: Vec::new(),
+        }
+    }
+
+    fn tag_index(&mut self, tag: &str) -> usize {
+        match self.tags.iter().position(|t| t == tag) {
+            Some(idx) => idx,
+            None => {
+                self.tags.push(tag.to_string());
+                self.tags.len() - 1
+            }
+        }
+    }
+
+    /// Pass in a domain and insert it into the matcher under the given tag.
+    pub fn insert(&mut self, domain: &Dname<Bytes>, tag: &str) {
+        let idx = self.tag_index(tag);
+        let domain = normalize(domain);
+        let lvs: Vec<&Label> = domain.iter().rev().collect();
+        let tags = &mut self.root.get_or_insert(&lvs).value;
+        if !tags.contains(&idx) {
+            tags.push(idx);
+        }
+    }
+
+    /// The number of rules in the matcher, counting a domain inserted under different tags once per tag. It walks the whole trie.
+    pub fn len(&self) -> usize {
+        self.root.walk().map(|(_, node)| node.value.len()).sum()
+    }
+
+    /// Whether the matcher has no rules.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Iterate over the rules in the matcher along with their tags, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Dname<Bytes>, &str)> + '_ {
+        self.root.walk().flat_map(move |(labels, node)| {
+            let domain = to_dname(&labels);
+            node.value
+                .iter()
+                .filter_map(move |idx| Some((domain.clone()?, self.tags[*idx].as_str())))
+        })
+    }
+
+    /// Get the tags of all the rules the domain matches. If `apple.com` is inserted, then `apple.com` and `www.apple.com` are considered as matched while `apple.cn` is not.
+    pub fn matches(&self, domain: &Dname<Bytes>) -> Vec<&str> {
+        let domain = normalize(domain);
+        let lvs: Vec<&Label> = domain.iter().rev().collect();
+        let mut found: Vec<usize> = Vec::new();
+        for (_, node) in self.root.path(&lvs) {
+            for idx in &node.value {
+                if !found.contains(idx) {
+                    found.push(*idx);
+                }
+            }
+        }
+        found
+            .into_iter()
+            .map(|idx| self.tags[idx].as_str())
+            .collect()
+    }
+
+    /// Whether the domain matches any rule under the given tag.
+    pub fn matches_tag(&self, domain: &Dname<Bytes>, tag: &str) -> bool {
+        self.matches(domain).contains(&tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TaggedDomain;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    macro_rules! dname {
+        ($s:expr) => {
+            Dname::from_str($s).unwrap()
+        };
+    }
+
+    #[test]
+    fn matches() {
+        let mut matcher = TaggedDomain::new();
+        matcher.insert(&dname!("doubleclick.net"), "ads");
+        matcher.insert(&dname!("stats.doubleclick.net"), "tracking");
+        matcher.insert(&dname!("google-analytics.com"), "tracking");
+        assert_eq!(matcher.matches(&dname!("doubleclick.net")), vec!["ads"]);
+        assert_eq!(
+            matcher.matches(&dname!("a.stats.doubleclick.net")),
+            vec!["ads", "tracking"]
+        );
+        assert_eq!(
+            matcher.matches_tag(&dname!("www.google-analytics.com"), "tracking"),
+            true
+        );
+        assert_eq!(
+            matcher.matches_tag(&dname!("www.google-analytics.com"), "ads"),
+            false
+        );
+        assert_eq!(matcher.matches(&dname!("example.com")).is_empty(), true);
+    }
+
+    #[test]
+    fn iter() {
+        let mut matcher = TaggedDomain::new();
+        assert!(matcher.is_empty());
+        matcher.insert(&dname!("doubleclick.net"), "ads");
+        matcher.insert(&dname!("doubleclick.net"), "tracking");
+        matcher.insert(&dname!("doubleclick.net"), "ads");
+        matcher.insert(&dname!("google-analytics.com"), "tracking");
+        assert_eq!(matcher.len(), 3);
+
+        let mut rules: Vec<(String, &str)> = matcher
+            .iter()
+            .map(|(domain, tag)| (domain.to_string(), tag))
+            .collect();
+        rules.sort();
+        assert_eq!(
+            rules,
+            vec![
+                ("doubleclick.net".to_string(), "ads"),
+                ("doubleclick.net".to_string(), "tracking"),
+                ("google-analytics.com".to_string(), "tracking"),
+            ]
+        );
+    }
+}
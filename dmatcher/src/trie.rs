@@ -0,0 +1,260 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// The path-compressed trie of labels shared by the matchers, keyed from the top level down.
+// Most domains of huge lists share nothing below their second level, so that a level is kept only where a rule ends or the domains branch. The chains of labels in between are kept in the edges.
+
+use crate::serial::{DecodeError, Reader, Writer};
+use domain::base::name::Label;
+use std::cmp::Ordering;
+
+// Vectors of the next levels up to this length grow one by one, so that the many levels with few next levels don't waste memory on spare capacity.
+const EXACT_GROWTH: usize = 16;
+
+// Domains have at most 127 labels, beyond which the bytes given must be malformed.
+const MAX_DEPTH: usize = 128;
+
+// What the matchers keep at a level, e.g. the rules ending there.
+pub(crate) trait Level: Default {
+    // Whether nothing is kept, so that the level can be pruned, or merged into the edge leading to it.
+    fn is_empty(&self) -> bool;
+
+    // The level reached through a `*` label, which stands for any single label.
+    fn wildcard(&self) -> Option<&Trie<Self>> {
+        None
+    }
+}
+
+// Levels of the matchers encoded to bytes.
+pub(crate) trait Encode: Sized {
+    fn encode(&self, w: &mut Writer);
+
+    fn decode(r: &mut Reader, depth: usize) -> Result<Self, DecodeError>;
+}
+
+#[derive(PartialEq, Clone, Default)]
+pub(crate) struct Trie<V> {
+    // The edges to the next levels, sorted by their first labels in lowercase, which are looked up by binary search.
+    // An edge carries the labels of the whole chain leading to the next level, in lowercase wire format, e.g. `\x07example\x03www`.
+    next_lvs: Vec<(Box<[u8]>, Trie<V>)>,
+    pub(crate) value: V,
+}
+
+// The labels of the edge.
+fn labels(key: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = key;
+    std::iter::from_fn(move || {
+        let (&len, tail) = rest.split_first()?;
+        let (lv, tail) = tail.split_at(len as usize);
+        rest = tail;
+        Some(lv)
+    })
+}
+
+fn first(key: &[u8]) -> &[u8] {
+    &key[1..=key[0] as usize]
+}
+
+// Compare the label in lowercase to the label of the key, which is already in lowercase.
+fn cmp_label(lv_key: &[u8], lv: &Label) -> Ordering {
+    lv_key
+        .iter()
+        .copied()
+        .cmp(lv.as_slice().iter().map(u8::to_ascii_lowercase))
+}
+
+// The number of labels of the edge, and how many of them the labels start with.
+fn common(key: &[u8], lvs: &[&Label]) -> (usize, usize) {
+    let (mut len, mut common) = (0, 0);
+    for lv_key in labels(key) {
+        if common == len
+            && lvs
+                .get(len)
+                .map_or(false, |lv| lv.as_slice().eq_ignore_ascii_case(lv_key))
+        {
+            common += 1;
+        }
+        len += 1;
+    }
+    (len, common)
+}
+
+fn to_key(lvs: &[&Label]) -> Box<[u8]> {
+    let mut key = Vec::with_capacity(lvs.iter().map(|lv| lv.len() + 1).sum());
+    for lv in lvs {
+        key.push(lv.len() as u8);
+        key.extend(lv.as_slice().iter().map(u8::to_ascii_lowercase));
+    }
+    key.into_boxed_slice()
+}
+
+impl<V: Level> Trie<V> {
+    fn find(&self, lv: &Label) -> Result<usize, usize> {
+        self.next_lvs
+            .binary_search_by(|(key, _)| cmp_label(first(key), lv))
+    }
+
+    // Whether nothing is kept at this level or any level below it.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.value.is_empty() && self.next_lvs.is_empty()
+    }
+
+    // Get the level of the labels, inserting it if it doesn't exist.
+    pub(crate) fn get_or_insert(&mut self, lvs: &[&Label]) -> &mut Self {
+        let lv = match lvs.first() {
+            Some(lv) => lv,
+            None => return self,
+        };
+        let (idx, len) = match self.find(lv) {
+            Ok(idx) => {
+                let (len, common) = common(&self.next_lvs[idx].0, lvs);
+                if common < len {
+                    self.split(idx, common);
+                }
+                (idx, common)
+            }
+            Err(idx) => {
+                if self.next_lvs.len() < EXACT_GROWTH {
+                    self.next_lvs.reserve_exact(1);
+                }
+                self.next_lvs.insert(idx, (to_key(lvs), Self::default()));
+                (idx, lvs.len())
+            }
+        };
+        self.next_lvs[idx].1.get_or_insert(&lvs[len..])
+    }
+
+    // Keep a level after the first `len` labels of the edge.
+    fn split(&mut self, idx: usize, len: usize) {
+        let (key, next) = &mut self.next_lvs[idx];
+        let at = labels(key).take(len).map(|lv| lv.len() + 1).sum();
+        let tail = key[at..].into();
+        *key = key[..at].into();
+        let below = std::mem::take(next);
+        next.next_lvs = vec![(tail, below)];
+    }
+
+    // The levels along the path of the labels from this level down, along with the number of labels leading to them. Nothing is kept in the middle of the edges.
+    pub(crate) fn path<'a>(
+        &'a self,
+        lvs: &'a [&'a Label],
+    ) -> impl Iterator<Item = (usize, &'a Self)> {
+        let mut next = Some((0, self));
+        std::iter::from_fn(move || {
+            let (depth, node) = next.take()?;
+            let rest = &lvs[depth..];
+            next = rest.first().and_then(|lv| {
+                let (key, below) = &node.next_lvs[node.find(lv).ok()?];
+                let (len, common) = common(key, rest);
+                (common == len).then_some((depth + len, below))
+            });
+            Some((depth, node))
+        })
+    }
+
+    // Apply `f` to what is kept at the level of the labels, returning whether it removed anything.
+    // Levels left empty are pruned, and those left with a single next level are merged into the edges leading to them.
+    pub(crate) fn remove<F: FnOnce(&mut V) -> bool>(&mut self, lvs: &[&Label], f: F) -> bool {
+        let lv = match lvs.first() {
+            Some(lv) => lv,
+            None => return f(&mut self.value),
+        };
+        let idx = match self.find(lv) {
+            Ok(idx) => idx,
+            Err(_) => return false,
+        };
+        let (key, next) = &mut self.next_lvs[idx];
+        let (len, common) = common(key, lvs);
+        if common < len {
+            return false;
+        }
+        let removed = next.remove(&lvs[len..], f);
+        if next.value.is_empty() {
+            match next.next_lvs.len() {
+                0 => {
+                    self.next_lvs.remove(idx);
+                }
+                1 => {
+                    let (tail, below) = next.next_lvs.pop().unwrap();
+                    *key = [&key[..], &tail[..]].concat().into_boxed_slice();
+                    *next = below;
+                }
+                _ => {}
+            }
+        }
+        removed
+    }
+
+    // Walk the trie depth-first, yielding the levels along with the labels leading to them from the top level down.
+    pub(crate) fn walk(&self) -> impl Iterator<Item = (Vec<&[u8]>, &Self)> {
+        let mut stack = vec![(Vec::new(), self)];
+        std::iter::from_fn(move || {
+            let (path, node) = stack.pop()?;
+            if let Some(next) = node.value.wildcard() {
+                let mut path = path.clone();
+                path.push(&b"*"[..]);
+                stack.push((path, next));
+            }
+            for (key, next) in node.next_lvs.iter().rev() {
+                let mut path = path.clone();
+                path.extend(labels(key));
+                stack.push((path, next));
+            }
+            Some((path, node))
+        })
+    }
+}
+
+impl<V: Level + Encode> Trie<V> {
+    pub(crate) fn encode(&self, w: &mut Writer) {
+        self.value.encode(w);
+        w.u32(self.next_lvs.len() as u32);
+        for (key, next) in &self.next_lvs {
+            w.u8(labels(key).count() as u8);
+            labels(key).for_each(|lv| w.label(lv));
+            next.encode(w);
+        }
+    }
+
+    pub(crate) fn decode(r: &mut Reader, depth: usize) -> Result<Self, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError);
+        }
+        let value = V::decode(r, depth)?;
+        let mut next_lvs: Vec<(Box<[u8]>, Self)> = Vec::new();
+        for _ in 0..r.u32()? {
+            let len = r.u8()? as usize;
+            if len == 0 {
+                return Err(DecodeError);
+            }
+            let mut key = Vec::new();
+            for _ in 0..len {
+                let lv = r.label()?;
+                key.push(lv.len() as u8);
+                key.extend(lv.as_slice().iter().map(u8::to_ascii_lowercase));
+            }
+            let next = Self::decode(r, depth + len)?;
+            // Encoded in order, but the order is checked rather than trusted.
+            if let Some((last, _)) = next_lvs.last() {
+                if first(last) >= first(&key) {
+                    return Err(DecodeError);
+                }
+            }
+            next_lvs.push((key.into_boxed_slice(), next));
+        }
+        next_lvs.shrink_to_fit();
+        Ok(Self { next_lvs, value })
+    }
+}
@@ -62,6 +62,13 @@ async fn create_router<T: ScriptBackend>(script_builder: impl ScriptBuilder<T>)
                 max_pool_size: 256,
                 timeout: 1,
                 ratelimit: None,
+                ratelimit_queue: false,
+                case_randomization: false,
+                tcp_fallback: false,
+                retry: Default::default(),
+                edns: Default::default(),
+                tsig: None,
+                dnssec: false,
             }),
         ),
     )
@@ -14,17 +14,32 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use self::RecordStatus::*;
-use crate::{Label, MAX_TTL};
-use bytes::Bytes;
+use crate::{errors::UpstreamError, Label};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use clru::CLruCache;
-use domain::base::{name::ToDname, Message};
+use domain::{
+    base::{
+        iana::{Rcode, Rtype},
+        name::ToDname,
+        Message, MessageBuilder, ParsedDname,
+    },
+    rdata::{AllRecordData, Soa},
+};
 use log::*;
+use serde::{Deserialize, Serialize};
 use std::{
     borrow::Borrow,
+    collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
+    io,
     num::NonZeroUsize,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 // Code to use (&A, &B) for accessing HashMap, clipped from https://stackoverflow.com/questions/45786717/how-to-implement-hashmap-with-two-keys/45795699#45795699.
@@ -75,19 +90,131 @@ where
     }
 }
 
+// Upper bound of the negative caching TTL suggested by RFC 2308
+const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+fn default_max_stale() -> u64 {
+    // Upper bound suggested by RFC 8767
+    3 * 24 * 60 * 60
+}
+
+fn default_stale_ttl() -> u32 {
+    30
+}
+
+fn default_prefetch_top() -> usize {
+    100
+}
+
+fn default_prefetch_before() -> u64 {
+    10
+}
+
+/// Configuration of the response cache shared by all the upstreams
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct CacheConfig {
+    /// Seconds past the TTL during which an expired response is still served with the `persistent` cache mode (RFC 8767)
+    #[serde(default = "default_max_stale")]
+    pub max_stale: u64,
+    /// TTL of the records in the expired responses served, which is also the minimum number of seconds between two refreshes of the same response
+    #[serde(default = "default_stale_ttl")]
+    pub stale_ttl: u32,
+    /// Minimum time in seconds responses are cached for, regardless of the TTLs of the records in them
+    #[serde(default)]
+    pub min_ttl: Option<u32>,
+    /// Maximum time in seconds responses are cached for, regardless of the TTLs of the records in them
+    #[serde(default)]
+    pub max_ttl: Option<u32>,
+    /// Bounds of the cache TTL of the responses to the names under the given domain suffixes, which take precedence over `min_ttl` and `max_ttl`. The longest matching suffix takes precedence.
+    #[serde(default)]
+    pub domain_ttl: HashMap<String, TtlBounds>,
+    /// Refresh the most queried responses before they expire
+    #[serde(default)]
+    pub prefetch: Option<Prefetch>,
+    /// File the cached responses are saved to on shutdown and loaded from on startup
+    #[serde(default)]
+    pub snapshot: Option<PathBuf>,
+    /// Types of the queries never answered from the cache nor cached, e.g. `TXT` for ACME challenges
+    #[serde(default)]
+    pub bypass: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_stale: default_max_stale(),
+            stale_ttl: default_stale_ttl(),
+            min_ttl: None,
+            max_ttl: None,
+            domain_ttl: HashMap::new(),
+            prefetch: None,
+            snapshot: None,
+            bypass: Vec::new(),
+        }
+    }
+}
+
+/// Configuration of the prefetching of popular responses
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct Prefetch {
+    /// Maximum number of responses refreshed at once, the most queried ones first
+    #[serde(default = "default_prefetch_top")]
+    pub top: usize,
+    /// Seconds before the expiry within which responses are refreshed. Responses with TTLs not longer than this are never prefetched.
+    #[serde(default = "default_prefetch_before")]
+    pub before: u64,
+}
+
+impl Default for Prefetch {
+    fn default() -> Self {
+        Self {
+            top: default_prefetch_top(),
+            before: default_prefetch_before(),
+        }
+    }
+}
+
+/// Bounds of the time in seconds a response is cached for, regardless of the TTLs of the records in it
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub struct TtlBounds {
+    /// Minimum cache TTL
+    #[serde(default)]
+    pub min: Option<u32>,
+    /// Maximum cache TTL
+    #[serde(default)]
+    pub max: Option<u32>,
+}
+
+impl TtlBounds {
+    fn apply(&self, ttl: u32) -> u32 {
+        let ttl = self.min.map_or(ttl, |min| ttl.max(min));
+        self.max.map_or(ttl, |max| ttl.min(max))
+    }
+}
+
 #[derive(Clone)]
 pub struct CacheRecord<T> {
     created_instant: Instant,
     content: T,
     ttl: Duration,
+    // Time after which the next refresh of the expired record may be started
+    refresh_instant: Instant,
+    // Number of times the record has been used
+    queries: u64,
 }
 
 impl<T: Clone> CacheRecord<T> {
     pub fn new(content: T, ttl: Duration) -> Self {
+        let created_instant = Instant::now();
         Self {
-            created_instant: Instant::now(),
+            created_instant,
             content,
             ttl,
+            refresh_instant: created_instant + ttl,
+            queries: 0,
         }
     }
 
@@ -96,13 +223,83 @@ impl<T: Clone> CacheRecord<T> {
     }
 
     pub fn validate(&self) -> bool {
-        Instant::now().saturating_duration_since(self.created_instant) <= self.ttl
+        self.elapsed() <= self.ttl
+    }
+
+    // Whether the record has been expired for no longer than `max_stale`.
+    pub fn servable(&self, max_stale: Duration) -> bool {
+        self.elapsed() <= self.ttl + max_stale
+    }
+
+    // Whether a refresh should be started now. If so, the next one is held off for `interval`.
+    pub fn refresh(&mut self, interval: Duration) -> bool {
+        let now = Instant::now();
+        if now >= self.refresh_instant {
+            self.refresh_instant = now + interval;
+            true
+        } else {
+            false
+        }
     }
+
+    // Whether the record is within its TTL but expires within `within`.
+    fn expiring(&self, within: Duration) -> bool {
+        matches!(self.ttl.checked_sub(self.elapsed()), Some(left) if left <= within)
+    }
+
+    fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.created_instant)
+    }
+}
+
+/// Counters of the response cache, shared by all the upstreams
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct CacheStats {
+    /// Number of lookups answered by records within their TTLs
+    pub hits: u64,
+    /// Number of lookups finding no record to be used
+    pub misses: u64,
+    /// Number of lookups answered by expired records
+    pub stale: u64,
+    /// Number of records evicted to make room for new ones
+    pub evictions: u64,
+    /// Number of records currently in the cache
+    pub entries: u64,
+}
+
+/// A response in the cache
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct CacheEntry {
+    /// Tag of the upstream the response came from
+    pub tag: Label,
+    /// Name queried
+    pub name: String,
+    /// Type queried
+    pub qtype: Rtype,
+    /// Seconds left before the response expires, negative if it has expired
+    pub ttl: i64,
+    /// Number of times the response has been used
+    pub queries: u64,
+    /// The response cached
+    pub response: Message<Bytes>,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+    evictions: AtomicU64,
 }
 
 pub enum RecordStatus<T> {
     Alive(T),
+    // Past its TTL, the caller is responsible for refreshing it.
     Expired(T),
+    // Past its TTL, while a refresh has been started recently.
+    Refreshing(T),
 }
 
 // A LRU cache for responses
@@ -110,64 +307,433 @@ pub enum RecordStatus<T> {
 pub struct RespCache {
     #[allow(clippy::type_complexity)]
     cache: Arc<Mutex<CLruCache<(Label, Bytes), CacheRecord<Message<Bytes>>>>>,
+    max_stale: Duration,
+    stale_ttl: u32,
+    ttl_bounds: TtlBounds,
+    // Keyed by lowercase domain suffixes without the trailing dot
+    domain_ttl: Arc<HashMap<String, TtlBounds>>,
+    counters: Arc<Counters>,
+    snapshot: Option<Arc<Path>>,
+    bypass: Arc<HashSet<Rtype>>,
 }
 
 impl RespCache {
-    pub fn new(size: NonZeroUsize) -> Self {
-        Self {
+    pub fn new(size: NonZeroUsize, config: &CacheConfig) -> Result<Self, UpstreamError> {
+        let bypass: HashSet<Rtype> = config
+            .bypass
+            .iter()
+            .map(|t| Rtype::from_str(t).map_err(|_| UpstreamError::UnknownRtype(t.clone())))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
             cache: Arc::new(Mutex::new(CLruCache::new(size))),
+            max_stale: Duration::from_secs(config.max_stale),
+            stale_ttl: config.stale_ttl,
+            ttl_bounds: TtlBounds {
+                min: config.min_ttl,
+                max: config.max_ttl,
+            },
+            domain_ttl: Arc::new(
+                config
+                    .domain_ttl
+                    .iter()
+                    .map(|(k, v)| (normalize(k), v.clone()))
+                    .collect(),
+            ),
+            counters: Arc::new(Counters::default()),
+            snapshot: config.snapshot.as_deref().map(Arc::from),
+            bypass: Arc::new(bypass),
+        })
+    }
+
+    // Whether the query bypasses the cache by its type.
+    pub fn bypass(&self, msg: &Message<Bytes>) -> bool {
+        msg.first_question()
+            .map_or(false, |q| self.bypass.contains(&q.qtype()))
+    }
+
+    // Bounds of the cache TTL for the name given by the longest matching suffix, if any.
+    fn domain_ttl_bounds(&self, qname: &str) -> Option<&TtlBounds> {
+        if self.domain_ttl.is_empty() {
+            return None;
+        }
+        let qname = normalize(qname);
+        let mut name = qname.as_str();
+        loop {
+            if let Some(bounds) = self.domain_ttl.get(name) {
+                return Some(bounds);
+            }
+            name = name.split_once('.')?.1;
         }
     }
 
     pub fn put(&self, tag: Label, query: &Message<Bytes>, msg: Message<Bytes>) {
-        if msg.no_error() {
-            // We are assured that it should parse and exist
-            let ttl = Duration::from_secs(u64::from(
-                query
-                    .answer()
-                    .ok()
-                    .and_then(|records| {
-                        records
-                            .filter(|r| r.is_ok())
-                            .map(|r| r.unwrap().ttl())
-                            .min()
-                    })
-                    .unwrap_or(MAX_TTL),
-            ));
-            self.cache.lock().unwrap().put(
-                // We discard the first two bytes which are the places for ID
-                (tag, query.as_octets().slice(2..)),
-                // Clone should be cheap here
-                CacheRecord::new(msg, ttl),
-            );
+        if let Some(ttl) = cache_ttl(&msg) {
+            let ttl = self.ttl_bounds.apply(ttl);
+            let ttl = match query.first_question() {
+                Some(q) => self
+                    .domain_ttl_bounds(&q.qname().to_string())
+                    .map_or(ttl, |bounds| bounds.apply(ttl)),
+                None => ttl,
+            };
+            self.put_ttl(tag, query, msg, ttl);
         } else {
-            info!("response errored, not caching erroneous upstream response.");
+            info!("response errored or not cacheable, not caching upstream response.");
         };
     }
 
-    pub fn get(&self, tag: &Label, msg: &Message<Bytes>) -> Option<RecordStatus<Message<Bytes>>> {
+    // Cache the response for `ttl` seconds as is, regardless of its records and the TTL bounds.
+    pub fn put_ttl(&self, tag: Label, query: &Message<Bytes>, msg: Message<Bytes>, ttl: u32) {
+        // We discard the first two bytes which are the places for ID
+        let key = (tag, query.as_octets().slice(2..));
+        let mut cache = self.cache.lock().unwrap();
+        // The least recently used record is evicted to make room for a new one.
+        if cache.is_full() && !cache.contains(&key) {
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut record = CacheRecord::new(msg, Duration::from_secs(u64::from(ttl)));
+        // The popularity of the refreshed record decays so that names no longer queried fall out of the prefetching.
+        record.queries = cache.peek(&key).map_or(0, |r| r.queries / 2);
+        // Clone should be cheap here
+        cache.put(key, record);
+    }
+
+    // Expired records are returned only if `serve_stale` is set.
+    pub fn get(
+        &self,
+        tag: &Label,
+        msg: &Message<Bytes>,
+        serve_stale: bool,
+    ) -> Option<RecordStatus<Message<Bytes>>> {
         let question = msg.first_question().unwrap();
         let qname = question.qname().to_bytes();
+        let key = (tag, msg.as_octets().slice(2..));
+        let key = &key as &dyn KeyPair<Label, Bytes>;
+
+        let mut cache = self.cache.lock().unwrap();
+        let r = match cache.get_mut(key) {
+            Some(r) => r,
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        r.queries += 1;
+        // Get record only once.
+        if r.validate() {
+            info!("cache hit for {}", qname);
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            Some(Alive(r.get()))
+        } else if !r.servable(self.max_stale) {
+            info!("expired record for {} is too old to be served.", qname);
+            cache.pop(key);
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        } else if serve_stale {
+            info!("TTL passed for {}, returning expired record.", qname);
+            self.counters.stale.fetch_add(1, Ordering::Relaxed);
+            let refresh = r.refresh(Duration::from_secs(self.stale_ttl.into()));
+            // Clients should come back for the refreshed record soon.
+            let stale = r.get();
+            let stale = set_ttl(&stale, self.stale_ttl).unwrap_or(stale);
+            Some(if refresh {
+                Expired(stale)
+            } else {
+                Refreshing(stale)
+            })
+        } else {
+            info!("TTL passed for {}.", qname);
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    // Remove all the records, or those of the names under the domain suffix given. Returns the number of records removed.
+    pub fn flush(&self, suffix: Option<&str>) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        let len = cache.len();
+        match suffix.map(normalize) {
+            Some(suffix) => cache.retain(|_, r| {
+                !r.content
+                    .first_question()
+                    .map_or(false, |q| under(&q.qname().to_string(), &suffix))
+            }),
+            None => cache.clear(),
+        }
+        len - cache.len()
+    }
+
+    // Queries of the most used records expiring within `within`, the most used ones first. Records with TTLs not longer than `within` are left out.
+    pub fn expiring(&self, within: Duration, top: usize) -> Vec<(Label, Message<Bytes>)> {
+        let cache = self.cache.lock().unwrap();
+        let mut records: Vec<_> = cache
+            .iter()
+            .filter(|(_, r)| r.queries > 0 && r.ttl > within && r.expiring(within))
+            .map(|(k, r)| (r.queries, k))
+            .collect();
+        records.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+        records
+            .into_iter()
+            .take(top)
+            .filter_map(|(_, (tag, query))| {
+                // Put back the ID we discarded
+                let mut octets = BytesMut::with_capacity(query.len() + 2);
+                octets.extend_from_slice(&[0, 0]);
+                octets.extend_from_slice(query);
+                let mut query = Message::from_octets(octets).ok()?;
+                query.header_mut().set_random_id();
+                Some((
+                    tag.clone(),
+                    Message::from_octets(query.into_octets().freeze()).ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    // Save the records within their TTLs to the snapshot file, if any. Returns the number of records saved.
+    pub async fn save(&self) -> io::Result<usize> {
+        let path = match &self.snapshot {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let mut buf = BytesMut::new();
+        buf.put_slice(SNAPSHOT_MAGIC);
+        buf.put_u64(unix_time());
+        let mut count = 0;
+        for ((tag, query), r) in self.cache.lock().unwrap().iter() {
+            let left = match r.ttl.checked_sub(r.elapsed()) {
+                Some(left) if !left.is_zero() => left,
+                _ => continue,
+            };
+            put_field(&mut buf, tag.as_bytes());
+            put_field(&mut buf, query);
+            put_field(&mut buf, r.content.as_slice());
+            buf.put_u64(left.as_secs());
+            buf.put_u64(r.queries);
+            count += 1;
+        }
+        // Written aside and renamed over the snapshot, so that a crash or a full disk halfway never leaves a truncated one behind.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, buf).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(count)
+    }
+
+    // Load the records from the snapshot file, if any, with the TTLs reduced by the time passed since it was saved. Returns the number of records loaded.
+    pub async fn load(&self) -> io::Result<usize> {
+        let path = match &self.snapshot {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let buf = match tokio::fs::read(path).await {
+            Ok(buf) => Bytes::from(buf),
+            // Nothing saved yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let records = parse_snapshot(buf).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed cache snapshot")
+        })?;
+
+        let mut cache = self.cache.lock().unwrap();
+        let mut count = 0;
+        // Records were saved from the most recently used one, put them back in the reverse order to keep the order.
+        for (key, msg, left, queries) in records.into_iter().rev() {
+            let mut record = CacheRecord::new(msg, left);
+            record.queries = queries;
+            cache.put(key, record);
+            count += 1;
+        }
+        Ok(count)
+    }
 
-        match self
+    // Copy the records still servable of the tags kept from the previous cache, e.g. when the upstreams are reloaded, along with their TTLs and order. Returns the number of records copied.
+    pub fn carry_over(&self, previous: &RespCache, keep: impl Fn(&Label) -> bool) -> usize {
+        // Collected first so that the two caches are not locked at once.
+        let records: Vec<_> = previous
             .cache
             .lock()
             .unwrap()
-            .get(&(tag, msg.as_octets().slice(2..)) as &dyn KeyPair<Label, Bytes>)
-        {
-            Some(r) => {
-                // Get record only once.
-                if r.validate() {
-                    info!("cache hit for {}", qname);
-                    Some(Alive(r.get()))
-                } else {
-                    info!("TTL passed for {}, returning expired record.", qname);
-                    Some(Expired(r.get()))
+            .iter()
+            .filter(|((tag, _), r)| keep(tag) && r.servable(self.max_stale))
+            .map(|(key, r)| (key.clone(), r.clone()))
+            .collect();
+        let count = records.len();
+        let mut cache = self.cache.lock().unwrap();
+        // From the least recently used one, so that the most recently used ones are kept if the cache is smaller now.
+        for (key, record) in records.into_iter().rev() {
+            cache.put(key, record);
+        }
+        count
+    }
+
+    // All the records, or those of the names under the domain suffix given, from the most recently used one.
+    pub fn entries(&self, suffix: Option<&str>) -> Vec<CacheEntry> {
+        let suffix = suffix.map(normalize);
+        self.cache
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|((tag, _), r)| {
+                let question = r.content.first_question()?;
+                let name = question.qname().to_string();
+                if let Some(suffix) = &suffix {
+                    if !under(&name, suffix) {
+                        return None;
+                    }
                 }
-            }
-            Option::None => Option::None,
+                let (ttl, elapsed) = (r.ttl.as_secs() as i64, r.elapsed().as_secs() as i64);
+                Some(CacheEntry {
+                    tag: tag.clone(),
+                    name,
+                    qtype: question.qtype(),
+                    ttl: ttl - elapsed,
+                    queries: r.queries,
+                    response: r.get(),
+                })
+            })
+            .collect()
+    }
+
+    // Statistics of the cache since it was created.
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.cache.lock().unwrap().len() as u64;
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            stale: self.counters.stale.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            entries,
+        }
+    }
+}
+
+const SNAPSHOT_MAGIC: &[u8] = b"DCOMPASS-CACHE-1";
+
+// Seconds since the Unix epoch, which, unlike `Instant`, carries on across restarts.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Length prefixed field in the snapshot
+fn put_field(buf: &mut BytesMut, field: &[u8]) {
+    buf.put_u32(field.len() as u32);
+    buf.put_slice(field);
+}
+
+fn get_field(buf: &mut Bytes) -> Option<Bytes> {
+    if buf.remaining() < 4 {
+        return None;
+    }
+    let len = buf.get_u32() as usize;
+    (buf.remaining() >= len).then(|| buf.split_to(len))
+}
+
+fn get_u64(buf: &mut Bytes) -> Option<u64> {
+    (buf.remaining() >= 8).then(|| buf.get_u64())
+}
+
+// Records in the snapshot still within their TTLs by now, along with their TTLs left and popularity.
+#[allow(clippy::type_complexity)]
+fn parse_snapshot(mut buf: Bytes) -> Option<Vec<((Label, Bytes), Message<Bytes>, Duration, u64)>> {
+    if !buf.starts_with(SNAPSHOT_MAGIC) {
+        return None;
+    }
+    buf.advance(SNAPSHOT_MAGIC.len());
+    let passed = unix_time().saturating_sub(get_u64(&mut buf)?);
+
+    let mut records = Vec::new();
+    while buf.has_remaining() {
+        let tag = Label::from(std::str::from_utf8(&get_field(&mut buf)?).ok()?);
+        let query = get_field(&mut buf)?;
+        let msg = Message::from_octets(get_field(&mut buf)?).ok()?;
+        let left = get_u64(&mut buf)?.saturating_sub(passed);
+        let queries = get_u64(&mut buf)?;
+        if left > 0 {
+            records.push(((tag, query), msg, Duration::from_secs(left), queries));
         }
     }
+    Some(records)
+}
+
+// Lowercase domain name without the trailing dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+// Whether the name is the normalized suffix itself or a subdomain of it.
+fn under(name: &str, suffix: &str) -> bool {
+    let name = normalize(name);
+    suffix.is_empty()
+        || name == suffix
+        || name
+            .strip_suffix(suffix)
+            .map_or(false, |prefix| prefix.ends_with('.'))
+}
+
+// Seconds the response can be cached for, or `None` if it shouldn't be cached.
+fn cache_ttl(msg: &Message<Bytes>) -> Option<u32> {
+    let answer_ttl = msg
+        .answer()
+        .ok()?
+        .filter_map(|r| r.ok())
+        .map(|r| r.ttl())
+        .min();
+    match (msg.header().rcode(), answer_ttl) {
+        (Rcode::NoError, Some(ttl)) => Some(ttl),
+        // NODATA and NXDOMAIN, the latter of which may still carry the CNAME chain leading to the nonexistent name.
+        (Rcode::NoError, None) | (Rcode::NXDomain, _) => {
+            negative_ttl(msg).map(|ttl| answer_ttl.map_or(ttl, |a| a.min(ttl)))
+        }
+        _ => None,
+    }
+}
+
+// Negative responses are cached as long as the TTL of the SOA record in the authority section, or its MINIMUM field if it is lower (RFC 2308).
+// Responses without SOA records are not cached.
+fn negative_ttl(msg: &Message<Bytes>) -> Option<u32> {
+    msg.authority()
+        .ok()?
+        .limit_to::<Soa<ParsedDname<&Bytes>>>()
+        .filter_map(|r| r.ok())
+        .map(|r| r.ttl().min(r.data().minimum()))
+        .next()
+        .map(|ttl| ttl.min(MAX_NEGATIVE_TTL))
+}
+
+// Rebuild the message with the TTLs of all the records set to `ttl`. The OPT record is left as is, as its TTL field carries the EDNS flags.
+fn set_ttl(msg: &Message<Bytes>, ttl: u32) -> Option<Message<Bytes>> {
+    macro_rules! copy_section {
+        ($section: expr, $builder: expr) => {
+            for item in $section {
+                if let Some(mut record) = item.ok()?.into_record::<AllRecordData<_, _>>().ok()? {
+                    if record.rtype() != Rtype::Opt {
+                        record.set_ttl(ttl);
+                    }
+                    $builder.push(record).ok()?;
+                }
+            }
+        };
+    }
+
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN)).ok()?;
+    *builder.header_mut() = msg.header();
+
+    let mut builder = builder.question();
+    for item in msg.question() {
+        builder.push(item.ok()?).ok()?;
+    }
+
+    let mut builder = builder.answer();
+    copy_section!(msg.answer().ok()?, builder);
+    let mut builder = builder.authority();
+    copy_section!(msg.authority().ok()?, builder);
+    let mut builder = builder.additional();
+    copy_section!(msg.additional().ok()?, builder);
+
+    Some(builder.into_message())
 }
 
 // Expire every hour
@@ -215,3 +781,346 @@ impl RespCache {
 //         Self::new()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::RecordStatus::Alive;
+    use super::{
+        cache_ttl, set_ttl, CacheConfig, CacheRecord, CacheStats, RespCache, TtlBounds,
+        MAX_NEGATIVE_TTL,
+    };
+    use crate::Label;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{
+            iana::{Class, Rcode},
+            Dname, Message, MessageBuilder, Rtype,
+        },
+        rdata::{Soa, A},
+    };
+    use std::{num::NonZeroUsize, str::FromStr, thread::sleep, time::Duration};
+
+    fn response(ttl: u32) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.answer();
+        builder
+            .push((&name, Class::In, ttl, A::from_octets(1, 2, 3, 4)))
+            .unwrap();
+        let mut builder = builder.additional();
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(1232);
+                Ok(())
+            })
+            .unwrap();
+        builder.into_message()
+    }
+
+    fn negative(rcode: Rcode, soa: Option<(u32, u32)>) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_rcode(rcode);
+        let mut builder = builder.question();
+        builder
+            .push((
+                Dname::<Bytes>::from_str("nx.example.com").unwrap(),
+                Rtype::A,
+            ))
+            .unwrap();
+        let mut builder = builder.authority();
+        if let Some((ttl, minimum)) = soa {
+            builder
+                .push((
+                    Dname::<Bytes>::from_str("example.com").unwrap(),
+                    Class::In,
+                    ttl,
+                    Soa::new(
+                        Dname::<Bytes>::from_str("ns.example.com").unwrap(),
+                        Dname::<Bytes>::from_str("admin.example.com").unwrap(),
+                        1.into(),
+                        3600,
+                        900,
+                        604800,
+                        minimum,
+                    ),
+                ))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    #[test]
+    fn positive_ttl() {
+        assert_eq!(cache_ttl(&response(300)), Some(300));
+        assert_eq!(cache_ttl(&negative(Rcode::ServFail, None)), None);
+    }
+
+    #[test]
+    fn negative_ttl() {
+        // The lower one of the SOA TTL and MINIMUM
+        assert_eq!(
+            cache_ttl(&negative(Rcode::NXDomain, Some((600, 60)))),
+            Some(60)
+        );
+        assert_eq!(
+            cache_ttl(&negative(Rcode::NoError, Some((60, 600)))),
+            Some(60)
+        );
+        assert_eq!(
+            cache_ttl(&negative(Rcode::NXDomain, Some((86400, 86400)))),
+            Some(MAX_NEGATIVE_TTL)
+        );
+        // Not cached without SOA
+        assert_eq!(cache_ttl(&negative(Rcode::NXDomain, None)), None);
+    }
+
+    #[test]
+    fn global_ttl() {
+        let config = CacheConfig {
+            min_ttl: Some(60),
+            max_ttl: Some(0),
+            ..Default::default()
+        };
+        let tag = Label::from("upstream");
+        let msg = response(0);
+
+        // The maximum wins over the minimum
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap(), &config).unwrap();
+        cache.put(tag.clone(), &msg, msg.clone());
+        sleep(Duration::from_millis(10));
+        assert!(cache.get(&tag, &msg, false).is_none());
+
+        let config = CacheConfig {
+            max_ttl: None,
+            ..config
+        };
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap(), &config).unwrap();
+        cache.put(tag.clone(), &msg, msg.clone());
+        sleep(Duration::from_millis(10));
+        assert!(cache.get(&tag, &msg, false).is_some());
+    }
+
+    #[test]
+    fn explicit_ttl() {
+        let config = CacheConfig {
+            max_ttl: Some(0),
+            ..Default::default()
+        };
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap(), &config).unwrap();
+        let tag = Label::from("script");
+        // Not cacheable on its own, and the TTL bounds don't apply
+        let msg = negative(Rcode::ServFail, None);
+        cache.put_ttl(tag.clone(), &msg, msg.clone(), 60);
+        sleep(Duration::from_millis(10));
+        assert!(matches!(cache.get(&tag, &msg, false), Some(Alive(_))));
+    }
+
+    #[test]
+    fn carry_over() {
+        let previous =
+            RespCache::new(NonZeroUsize::new(2).unwrap(), &CacheConfig::default()).unwrap();
+        let msg = response(300);
+        previous.put(Label::from("kept"), &msg, msg.clone());
+        previous.put(Label::from("removed"), &msg, msg.clone());
+
+        let cache = RespCache::new(NonZeroUsize::new(2).unwrap(), &CacheConfig::default()).unwrap();
+        assert_eq!(cache.carry_over(&previous, |tag| *tag == "kept"), 1);
+        assert!(matches!(
+            cache.get(&Label::from("kept"), &msg, false),
+            Some(Alive(_))
+        ));
+        assert!(cache.get(&Label::from("removed"), &msg, false).is_none());
+    }
+
+    #[test]
+    fn domain_ttl() {
+        let mut config = CacheConfig::default();
+        config.domain_ttl.insert(
+            "Corp.example.com.".to_string(),
+            TtlBounds {
+                min: None,
+                max: Some(30),
+            },
+        );
+        config.domain_ttl.insert(
+            "pool.ntp.org".to_string(),
+            TtlBounds {
+                min: Some(3600),
+                max: None,
+            },
+        );
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap(), &config).unwrap();
+
+        let apply = |name, ttl| cache.domain_ttl_bounds(name).map_or(ttl, |b| b.apply(ttl));
+        assert_eq!(apply("git.corp.example.com", 300), 30);
+        assert_eq!(apply("corp.example.com.", 10), 10);
+        assert_eq!(apply("0.POOL.ntp.org", 60), 3600);
+        assert_eq!(apply("example.com", 300), 300);
+        assert_eq!(apply("ntp.org", 60), 60);
+    }
+
+    #[test]
+    fn stats() {
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap(), &CacheConfig::default()).unwrap();
+        let tag = Label::from("upstream");
+        let positive = response(300);
+        let negative = negative(Rcode::NXDomain, Some((60, 60)));
+
+        cache.put(tag.clone(), &positive, positive.clone());
+        assert!(cache.get(&tag, &positive, false).is_some());
+        // Evicts the positive response
+        cache.put(tag.clone(), &negative, negative.clone());
+        assert!(cache.get(&tag, &positive, false).is_none());
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                stale: 0,
+                evictions: 1,
+                entries: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn flush() {
+        let cache = RespCache::new(NonZeroUsize::new(2).unwrap(), &CacheConfig::default()).unwrap();
+        let tag = Label::from("upstream");
+        let positive = response(300);
+        let negative = negative(Rcode::NXDomain, Some((60, 60)));
+        cache.put(tag.clone(), &positive, positive.clone());
+        cache.put(tag.clone(), &negative, negative.clone());
+
+        // Only the names under the suffix are removed, `nx.example.com` is not under `x.example.com`.
+        assert_eq!(cache.flush(Some("x.example.com")), 0);
+        assert_eq!(cache.flush(Some("NX.example.com.")), 1);
+        assert!(cache.get(&tag, &positive, false).is_some());
+        assert_eq!(cache.flush(None), 1);
+        assert!(cache.get(&tag, &positive, false).is_none());
+    }
+
+    #[test]
+    fn expiring() {
+        let cache = RespCache::new(NonZeroUsize::new(2).unwrap(), &CacheConfig::default()).unwrap();
+        let tag = Label::from("upstream");
+        let popular = response(5);
+        let unpopular = negative(Rcode::NXDomain, Some((5, 5)));
+        cache.put(tag.clone(), &popular, popular.clone());
+        cache.put(tag.clone(), &unpopular, unpopular.clone());
+        assert!(cache.get(&tag, &popular, false).is_some());
+
+        assert!(cache.expiring(Duration::from_secs(1), 10).is_empty());
+        sleep(Duration::from_millis(20));
+        let expiring = cache.expiring(Duration::from_millis(4990), 10);
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].0, tag);
+        assert_eq!(expiring[0].1.first_question(), popular.first_question());
+        // Records with TTLs within the window are never prefetched
+        assert!(cache.expiring(Duration::from_secs(5), 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let path = std::env::temp_dir().join("dcompass-cache-snapshot-test");
+        let config = CacheConfig {
+            snapshot: Some(path.clone()),
+            ..Default::default()
+        };
+        let tag = Label::from("upstream");
+        let alive = response(300);
+        let expired = negative(Rcode::NXDomain, Some((0, 0)));
+
+        let cache = RespCache::new(NonZeroUsize::new(2).unwrap(), &config).unwrap();
+        cache.put(tag.clone(), &alive, alive.clone());
+        cache.put(tag.clone(), &expired, expired.clone());
+        // Only the records within their TTLs are saved
+        assert_eq!(cache.save().await.unwrap(), 1);
+        assert!(!std::env::temp_dir()
+            .join("dcompass-cache-snapshot-test.tmp")
+            .exists());
+
+        let cache = RespCache::new(NonZeroUsize::new(2).unwrap(), &config).unwrap();
+        assert_eq!(cache.load().await.unwrap(), 1);
+        match cache.get(&tag, &alive, false) {
+            Some(Alive(msg)) => assert_eq!(msg.as_slice(), alive.as_slice()),
+            _ => panic!("record not loaded"),
+        }
+        std::fs::remove_file(path).unwrap();
+
+        // No snapshot saved
+        assert_eq!(cache.load().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn bypass() {
+        let config = CacheConfig {
+            bypass: vec!["A".to_string()],
+            ..Default::default()
+        };
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap(), &config).unwrap();
+        assert!(cache.bypass(&response(300)));
+
+        let config = CacheConfig {
+            bypass: vec!["TXT".to_string()],
+            ..Default::default()
+        };
+        let cache = RespCache::new(NonZeroUsize::new(1).unwrap(), &config).unwrap();
+        assert!(!cache.bypass(&response(300)));
+
+        let config = CacheConfig {
+            bypass: vec!["NOT-A-TYPE".to_string()],
+            ..Default::default()
+        };
+        assert!(RespCache::new(NonZeroUsize::new(1).unwrap(), &config).is_err());
+    }
+
+    #[test]
+    fn entries() {
+        let cache = RespCache::new(NonZeroUsize::new(2).unwrap(), &CacheConfig::default()).unwrap();
+        let tag = Label::from("upstream");
+        let positive = response(300);
+        let negative = negative(Rcode::NXDomain, Some((60, 60)));
+        cache.put(tag.clone(), &positive, positive.clone());
+        cache.put(tag.clone(), &negative, negative.clone());
+
+        let entries = cache.entries(None);
+        assert_eq!(entries.len(), 2);
+        // The most recently used one first
+        assert_eq!(entries[0].name, "nx.example.com");
+        assert_eq!(entries[0].qtype, Rtype::A);
+        assert!(entries[0].ttl <= 60 && entries[0].ttl >= 59);
+        assert_eq!(entries[1].tag, tag);
+        assert_eq!(entries[1].response.as_slice(), positive.as_slice());
+
+        let entries = cache.entries(Some("nx.example.com"));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "nx.example.com");
+    }
+
+    #[test]
+    fn stale_record() {
+        let mut record = CacheRecord::new(response(0), Duration::from_secs(0));
+        sleep(Duration::from_millis(10));
+        assert!(!record.validate());
+        assert!(record.servable(Duration::from_secs(1)));
+        assert!(!record.servable(Duration::from_secs(0)));
+
+        // Only one refresh is started within the interval
+        assert!(record.refresh(Duration::from_secs(30)));
+        assert!(!record.refresh(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn stale_ttl() {
+        let msg = set_ttl(&response(3600), 30).unwrap();
+        for record in msg.answer().unwrap() {
+            assert_eq!(record.unwrap().ttl(), 30);
+        }
+        assert_eq!(msg.opt().unwrap().udp_payload_size(), 1232);
+    }
+}
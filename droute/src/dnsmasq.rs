@@ -0,0 +1,58 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Parser of the domain-specific directives in dnsmasq configurations, e.g. `address=/example.com/0.0.0.0` and `server=/corp/10.0.0.53`, to ease migrating from dnsmasq.
+
+// A directive with the domains it applies to, e.g. `server=/corp/lan/10.0.0.53` is parsed into `("server", ["corp", "lan"], "10.0.0.53")`.
+pub(crate) type Directive<'a> = (&'a str, Vec<&'a str>, &'a str);
+
+// Parse a line of the configuration, skipping comments and directives which are not domain-specific.
+pub(crate) fn directive(line: &str) -> Option<Directive<'_>> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let (name, rest) = line.split_once('=')?;
+    let (domains, value) = rest.strip_prefix('/')?.rsplit_once('/')?;
+    let domains: Vec<&str> = domains.split('/').filter(|d| !d.is_empty()).collect();
+    if domains.is_empty() {
+        return None;
+    }
+    Some((name.trim(), domains, value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::directive;
+
+    #[test]
+    fn directives() {
+        assert_eq!(
+            directive("address=/example.com/0.0.0.0"),
+            Some(("address", vec!["example.com"], "0.0.0.0"))
+        );
+        assert_eq!(
+            directive("server=/corp/lan/10.0.0.53#5353"),
+            Some(("server", vec!["corp", "lan"], "10.0.0.53#5353"))
+        );
+        assert_eq!(
+            directive("local=/home.arpa/"),
+            Some(("local", vec!["home.arpa"], ""))
+        );
+        assert_eq!(directive("server=1.1.1.1"), None);
+        assert_eq!(directive("# address=/example.com/0.0.0.0"), None);
+        assert_eq!(directive("domain-needed"), None);
+    }
+}
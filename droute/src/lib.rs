@@ -26,6 +26,7 @@
 // Documentation
 //! This is the core library for dcompass. It implements configuration parsing scheme, DNS query routing rules, and upstream managements.
 pub(crate) mod cache;
+pub(crate) mod dnsmasq;
 #[doc(hidden)]
 pub mod mock;
 mod router;
@@ -54,12 +55,20 @@ pub mod errors {
 }
 
 // All the major components
-pub use self::router::{
-    script::{native::NativeScript, utils, QueryContext, ScriptBackend, ScriptBuilder},
-    upstreams::{CacheMode, Upstream, Upstreams},
-    Router,
+pub use self::{
+    cache::{CacheEntry, CacheStats},
+    router::{
+        script::{
+            native::NativeScript, utils, QueryContext, ScriptBackend, ScriptBuilder, Transport,
+        },
+        upstreams::{CacheMode, Upstream, Upstreams},
+        ErrorPolicy, Router,
+    },
 };
 
+#[cfg(any(test, feature = "testing"))]
+pub use self::router::testing;
+
 // Maximum TTL as defined in https://tools.ietf.org/html/rfc2181, 2147483647
 //   Setting this to a value of 1 day, in seconds
 const MAX_TTL: u32 = 86400_u32;
@@ -16,13 +16,18 @@
 //! Router is the core concept of `droute`.
 
 pub mod script;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod upstreams;
 
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+};
 
 use self::{
     script::QueryContext,
-    upstreams::{error::UpstreamError, Upstreams},
+    upstreams::{error::UpstreamError, CacheMode, Upstreams},
 };
 use crate::{
     errors::ScriptError, AsyncTryInto, Label, ScriptBackend, ScriptBuilder, Validatable, MAX_LEN,
@@ -30,17 +35,44 @@ use crate::{
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::base::{iana::rcode::Rcode, Message, MessageBuilder};
-use log::warn;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// What to do with the query when the script fails to route it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// Answer with SERVFAIL
+    ServFail,
+    /// Send the query to the tagged upstream instead, answering with SERVFAIL if it fails too
+    Upstream(Label),
+    /// Leave the query unanswered, so that the client retries or gives up on its own
+    Drop,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self::ServFail
+    }
+}
 
 /// Router implementation.
 pub struct Router<T: ScriptBackend> {
-    script: T,
+    // Queries being routed hold the script they started on, so that swapping it in on reload doesn't interrupt them.
+    script: RwLock<Arc<T>>,
+    on_error: ErrorPolicy,
 }
 
 impl<T: ScriptBackend> Validatable for Router<T> {
     type Error = ScriptError;
     fn validate(&self, _: Option<&Vec<Label>>) -> Result<(), Self::Error> {
-        self.script.validate(None)?;
+        let script = self.script();
+        script.validate(None)?;
+        if let ErrorPolicy::Upstream(tag) = &self.on_error {
+            if !script.upstreams().tags().contains(tag) {
+                return Err(UpstreamError::MissingTag(tag.clone()).into());
+            }
+        }
         Ok(())
     }
 }
@@ -48,11 +80,75 @@ impl<T: ScriptBackend> Validatable for Router<T> {
 impl<T: ScriptBackend> Router<T> {
     /// Create a new `Router` from raw
     pub fn new(script: T) -> Result<Self, ScriptError> {
-        let router = Self { script };
+        Self::with_error_policy(script, ErrorPolicy::default())
+    }
+
+    /// Create a new `Router` from raw, which handles the queries the script fails to route by the error policy given.
+    pub fn with_error_policy(script: T, on_error: ErrorPolicy) -> Result<Self, ScriptError> {
+        let router = Self {
+            script: RwLock::new(Arc::new(script)),
+            on_error,
+        };
         router.validate(None)?;
         Ok(router)
     }
 
+    // The script used by the queries routed afterwards.
+    fn script(&self) -> Arc<T> {
+        self.script.read().unwrap().clone()
+    }
+
+    /// Build the script from the new source with the upstreams in use and swap it in, without interrupting the queries being routed, which finish on the previous script.
+    /// The script in use is kept if the new one fails to build, e.g. on a compile error.
+    pub async fn reload_script<S>(&self, script: S) -> Result<(), ScriptError>
+    where
+        S: ScriptBuilder<T>,
+    {
+        let script = script.build(self.script().upstreams()).await?;
+        script.validate(None)?;
+        *self.script.write().unwrap() = Arc::new(script);
+        Ok(())
+    }
+
+    /// Build the upstreams from the new configuration and swap them in, without interrupting the queries being routed, which finish on the previous upstreams.
+    /// The upstreams in use are kept if the new ones fail to build.
+    ///
+    /// The cached responses of the upstreams whose tags are kept are carried over, even if their configurations changed. Flush the cache to drop them.
+    /// Other states start over with the new upstreams: health states are checked again from healthy, prefetching restarts on the cache carried over, and identical queries are not coalesced with those still in flight on the previous upstreams.
+    pub async fn reload_upstreams<U>(&self, upstreams: U) -> Result<(), ScriptError>
+    where
+        U: AsyncTryInto<Upstreams, Error = UpstreamError>,
+    {
+        let upstreams = upstreams.async_try_into().await?;
+        if let ErrorPolicy::Upstream(tag) = &self.on_error {
+            if !upstreams.tags().contains(tag) {
+                return Err(UpstreamError::MissingTag(tag.clone()).into());
+            }
+        }
+        let carried = upstreams.carry_over(&self.script().upstreams());
+        info!(
+            "{} cached responses carried over to the reloaded upstreams",
+            carried
+        );
+        self.script().replace_upstreams(upstreams);
+        Ok(())
+    }
+
+    /// Remove all the cached responses, or those to the queries on the names under the domain suffix given. Returns the number of responses removed.
+    pub fn flush_cache(&self, suffix: Option<&str>) -> usize {
+        self.script().upstreams().flush_cache(suffix)
+    }
+
+    /// Save the cached responses to the snapshot file configured, if any, e.g. on shutdown. Returns the number of responses saved.
+    pub async fn save_cache(&self) -> std::io::Result<usize> {
+        self.script().upstreams().save_cache().await
+    }
+
+    /// Load the cached responses from the snapshot file configured, if any, e.g. on startup. Returns the number of responses loaded.
+    pub async fn load_cache(&self) -> std::io::Result<usize> {
+        self.script().upstreams().load_cache().await
+    }
+
     /// Resolve the DNS query with routing rules defined.
     pub async fn resolve(
         &self,
@@ -63,28 +159,56 @@ impl<T: ScriptBackend> Router<T> {
         // Not using `query_count()` because it is manually set, and may not be correct.
         Ok(match msg.sole_question() {
             Ok(_) => {
+                let script = self.script();
                 // Clone should be cheap here guaranteed by Bytes
-                match self.script.route(msg.clone(), qctx).await {
+                match script.route(msg.clone(), qctx).await {
                     Ok(m) => m,
-                    Err(e) => {
-                        // Catch all server failure here and return server fail
-                        warn!("upstream encountered error: {}, returning SERVFAIL", e);
-                        MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                            .start_answer(&msg, Rcode::ServFail)?
-                            .into_message()
-                    }
+                    // The script asked not to respond.
+                    Err(ScriptError::Dropped) => return Err(ScriptError::Dropped),
+                    Err(e) => match &self.on_error {
+                        ErrorPolicy::ServFail => {
+                            // Catch all server failure here and return server fail
+                            warn!("upstream encountered error: {}, returning SERVFAIL", e);
+                            servfail(&msg)?
+                        }
+                        ErrorPolicy::Upstream(tag) => {
+                            warn!(
+                                "upstream encountered error: {}, sending the query to `{}`",
+                                e, tag
+                            );
+                            match script
+                                .upstreams()
+                                .send(tag, &CacheMode::default(), &msg)
+                                .await
+                            {
+                                Ok(m) => m,
+                                Err(e) => {
+                                    warn!("`{}` encountered error: {}, returning SERVFAIL", tag, e);
+                                    servfail(&msg)?
+                                }
+                            }
+                        }
+                        ErrorPolicy::Drop => {
+                            warn!("upstream encountered error: {}, dropping the query", e);
+                            return Err(ScriptError::Dropped);
+                        }
+                    },
                 }
             }
             Err(e) => {
                 warn!("DNS message parsing errored: {}.", e);
-                MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                    .start_answer(&msg, Rcode::ServFail)?
-                    .into_message()
+                servfail(&msg)?
             }
         })
     }
 }
 
+// A SERVFAIL response to the query.
+fn servfail(msg: &Message<Bytes>) -> Result<Message<Bytes>, ScriptError> {
+    let builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+    Ok(builder.start_answer(msg, Rcode::ServFail)?.into_message())
+}
+
 /// A Builder for Router.
 pub struct RouterBuilder<U, S, T>
 where
@@ -94,6 +218,7 @@ where
 {
     script: S,
     upstreams: U,
+    on_error: ErrorPolicy,
     _phantom: PhantomData<T>,
 }
 
@@ -108,9 +233,16 @@ where
         Self {
             script,
             upstreams,
+            on_error: ErrorPolicy::default(),
             _phantom: PhantomData::default(),
         }
     }
+
+    /// Set what to do with the queries the script fails to route. Default to answering with SERVFAIL.
+    pub fn on_error(mut self, on_error: ErrorPolicy) -> Self {
+        self.on_error = on_error;
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -125,6 +257,6 @@ where
     /// Build a new `Router` from configuration and check the validity. `data` is the content of the configuration file.
     async fn async_try_into(self) -> Result<Router<T>, ScriptError> {
         let upstreams = self.upstreams.async_try_into().await?;
-        Router::new(self.script.build(upstreams).await?)
+        Router::with_error_policy(self.script.build(upstreams).await?, self.on_error)
     }
 }
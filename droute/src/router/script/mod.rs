@@ -24,12 +24,12 @@ pub mod builders {
     pub use super::rhai_scripting::{RhaiScript, RhaiScriptBuilder};
 
     #[cfg(feature = "rune-scripting")]
-    pub use super::rune_scripting::{RuneScript, RuneScriptBuilder};
+    pub use super::rune_scripting::{RuneScript, RuneScriptBuilder, RuneScriptLimits};
 
     pub use super::native::NativeScriptBuilder;
 }
 
-use crate::{Upstreams, Validatable};
+use crate::{Label, Upstreams, Validatable};
 use async_trait::async_trait;
 use bytes::Bytes;
 use domain::base::{
@@ -39,8 +39,11 @@ use domain::base::{
     Message, ShortBuf,
 };
 use std::{
+    collections::HashMap,
+    fmt::{self, Display},
     net::{AddrParseError, IpAddr},
     string::FromUtf8Error,
+    time::{Duration, Instant, SystemTime},
 };
 use thiserror::Error;
 
@@ -57,6 +60,14 @@ pub enum MessageError {
     #[error("Option not supported or mismatched")]
     OptionUnsupported,
 
+    /// The client cookie is not 8 bytes long in hex.
+    #[error("A client cookie must be 8 bytes long in hex")]
+    InvalidCookie,
+
+    /// The record index is out of the bounds of the section
+    #[error("Index {0} is out of bounds for a section of {1} record(s)")]
+    IndexOutOfBounds(usize, usize),
+
     /// The message has no first question
     #[error("First question not found for the given message")]
     NoFirstQuestion,
@@ -106,6 +117,23 @@ pub enum MessageError {
     ShortBuf(#[from] ShortBuf),
 }
 
+/// Parse an rcode from its mnemonic (e.g. `NXDOMAIN`) or its decimal value, as `Rcode` doesn't implement `FromStr`.
+#[cfg(any(test, feature = "rune-scripting", feature = "testing"))]
+pub(crate) fn parse_rcode(
+    s: &str,
+) -> std::result::Result<domain::base::iana::Rcode, domain::base::iana::rcode::FromStrError> {
+    use domain::base::iana::{rcode, Rcode};
+
+    match s.parse::<u8>() {
+        Ok(value) if value < 16 => Ok(Rcode::from_int(value)),
+        Ok(_) => Err(rcode::FromStrError),
+        Err(_) => (0..16)
+            .map(Rcode::from_int)
+            .find(|rcode| rcode.to_string().eq_ignore_ascii_case(s))
+            .ok_or(rcode::FromStrError),
+    }
+}
+
 /// Errors generated by the `script` module.
 #[derive(Error, Debug)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
@@ -126,6 +154,14 @@ pub enum ScriptError {
     #[error(transparent)]
     UpstreamError(#[from] crate::errors::UpstreamError),
 
+    /// The script did not finish routing the query in time
+    #[error("the script did not finish routing the query within {0:?}")]
+    Timeout(std::time::Duration),
+
+    /// The query is dropped without a response, as the script asked to, or the script failed on it and the error policy says so
+    #[error("the query is dropped without a response")]
+    Dropped,
+
     /// Rune Emit Error
     #[cfg(feature = "rune-scripting")]
     #[error(transparent)]
@@ -147,23 +183,107 @@ pub enum ScriptError {
     RuneVmError(#[from] rune::runtime::VmError),
 }
 
+/// The transport protocol on which a query arrived
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub enum Transport {
+    /// Plain DNS over UDP
+    Udp,
+    /// Plain DNS over TCP
+    Tcp,
+    /// DNS over HTTPS
+    Https,
+    /// DNS over TLS
+    Tls,
+}
+
+impl Transport {
+    /// Whether the transport is encrypted and authenticated
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Self::Https | Self::Tls)
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+            Self::Https => "https",
+            Self::Tls => "tls",
+        })
+    }
+}
+
 /// Query Context
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub struct QueryContext {
     /// Query sender's IP address
     pub ip: IpAddr,
+    /// Query sender's port
+    pub port: u16,
+    /// Tag of the listener on which the query arrived
+    pub listener: Label,
+    /// The transport protocol on which the query arrived
+    pub transport: Transport,
+    /// The instant at which the query was received
+    pub received: Instant,
+    /// The instant by which routing the query must finish, if any
+    pub deadline: Option<Instant>,
+    /// Marks set on the query while routing, used to classify the query once and branch on the result later
+    pub marks: HashMap<String, String>,
+}
+
+impl QueryContext {
+    /// The time at which the query was received.
+    pub fn received_at(&self) -> SystemTime {
+        SystemTime::now() - self.received.elapsed()
+    }
+
+    /// The time left before the deadline, if any. It is zero once the deadline has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // Bring the deadline forward to `timeout` from now, unless an earlier one is set.
+    pub(crate) fn limit(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.deadline = Some(self.deadline.map_or(deadline, |d| d.min(deadline)));
+    }
+
+    /// Set the mark with the given key, replacing the previous value if any.
+    pub fn set_mark(&mut self, key: &str, value: &str) {
+        self.marks.insert(key.to_string(), value.to_string());
+    }
+
+    /// Get the value of the mark with the given key.
+    pub fn mark(&self, key: &str) -> Option<String> {
+        self.marks.get(key).cloned()
+    }
+
+    /// Check if the mark with the given key is set to the given value.
+    pub fn has_mark(&self, key: &str, value: &str) -> bool {
+        self.marks.get(key).map_or(false, |v| v == value)
+    }
 }
 
 /// A script backend routes every message with query context and the query itself.
 #[async_trait]
 pub trait ScriptBackend: Validatable<Error = ScriptError> {
-    /// Process the query.
+    /// Process the query. Return `ScriptError::Dropped` to leave the query unanswered, e.g. for spoofed or junk queries.
     async fn route(
         &self,
         query: Message<Bytes>,
         ctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>>;
+
+    /// Replace the upstreams used by the queries routed afterwards. Queries being routed keep using the previous ones.
+    fn replace_upstreams(&self, upstreams: Upstreams);
+
+    /// The upstreams used by the queries routed afterwards.
+    fn upstreams(&self) -> Upstreams;
 }
 
 /// A script builder is a type that builds itself into a script backend.
@@ -18,6 +18,7 @@ use crate::{Upstreams, Validatable};
 use async_trait::async_trait;
 use bytes::Bytes;
 use domain::base::Message;
+use std::{sync::RwLock, time::Duration};
 
 /// A native "script" engine that allows scripting droute in rust.
 pub struct NativeScript<F, T>
@@ -25,8 +26,9 @@ where
     F: Fn(Upstreams, Message<Bytes>, Option<QueryContext>) -> T + Send + Sync,
     T: std::future::Future<Output = Result<Message<Bytes>>> + Send,
 {
-    upstreams: Upstreams,
+    upstreams: RwLock<Upstreams>,
     script: F,
+    timeout: Option<Duration>,
 }
 
 #[async_trait]
@@ -38,9 +40,28 @@ where
     async fn route(
         &self,
         query: Message<Bytes>,
-        ctx: Option<QueryContext>,
+        mut ctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>> {
-        (self.script)(self.upstreams.clone(), query, ctx).await
+        let upstreams = self.upstreams.read().unwrap().clone();
+        match self.timeout {
+            Some(timeout) => {
+                if let Some(ctx) = &mut ctx {
+                    ctx.limit(timeout);
+                }
+                tokio::time::timeout(timeout, (self.script)(upstreams, query, ctx))
+                    .await
+                    .map_err(|_| ScriptError::Timeout(timeout))?
+            }
+            None => (self.script)(upstreams, query, ctx).await,
+        }
+    }
+
+    fn replace_upstreams(&self, upstreams: Upstreams) {
+        *self.upstreams.write().unwrap() = upstreams;
+    }
+
+    fn upstreams(&self) -> Upstreams {
+        self.upstreams.read().unwrap().clone()
     }
 }
 
@@ -52,7 +73,7 @@ where
     type Error = ScriptError;
 
     fn validate(&self, _: Option<&Vec<crate::Label>>) -> Result<()> {
-        self.upstreams.validate(None)?;
+        self.upstreams.read().unwrap().validate(None)?;
         Ok(())
     }
 }
@@ -64,6 +85,7 @@ where
     T: std::future::Future<Output = Result<Message<Bytes>>> + Send,
 {
     script: F,
+    timeout: Option<Duration>,
 }
 
 impl<F, T> NativeScriptBuilder<F, T>
@@ -73,7 +95,16 @@ where
 {
     /// Create a builder from an async function that returns the resulting message
     pub fn new(script: F) -> Self {
-        Self { script }
+        Self {
+            script,
+            timeout: None,
+        }
+    }
+
+    /// Limit the time routing every query takes, exceeding which fails the query with SERVFAIL.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
     }
 }
 
@@ -85,8 +116,9 @@ where
 {
     async fn build(self, upstreams: Upstreams) -> Result<NativeScript<F, T>> {
         Ok(NativeScript {
-            upstreams,
+            upstreams: RwLock::new(upstreams),
             script: self.script,
+            timeout: self.timeout,
         })
     }
 }
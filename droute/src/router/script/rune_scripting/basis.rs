@@ -13,10 +13,18 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::types::*;
-use crate::{errors::ScriptError, CacheMode, QueryContext, Upstreams};
+use super::{types::*, utils::to_ttl};
+use crate::{
+    errors::{MessageError, ScriptError},
+    utils::with_qtype,
+    CacheEntry, CacheMode, CacheStats, Label, QueryContext, Transport, Upstreams,
+};
 use once_cell::sync::Lazy;
 use rune::{runtime::Protocol, Module};
+use std::{
+    str::FromStr,
+    time::{Duration, UNIX_EPOCH},
+};
 
 // A module containing upstreams methods and query context
 pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
@@ -45,12 +53,285 @@ pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
             .into())
     }
 
+    async fn send_default_timeout(
+        upstreams: &Upstreams,
+        tag: &str,
+        msg: &Message,
+        timeout: i64,
+    ) -> Result<Message, ScriptError> {
+        send_timeout(upstreams, tag, CacheMode::default(), msg, timeout).await
+    }
+
+    // Timeout is in milliseconds
+    async fn send_timeout(
+        upstreams: &Upstreams,
+        tag: &str,
+        cache_mode: CacheMode,
+        msg: &Message,
+        timeout: i64,
+    ) -> Result<Message, ScriptError> {
+        Ok(upstreams
+            .send_timeout(
+                &tag.into(),
+                &cache_mode,
+                &msg.into(),
+                Duration::from_millis(timeout.max(0) as u64),
+            )
+            .await?
+            .into())
+    }
+
+    // Try variants return the error as a string for scripts to inspect and move on, e.g. to the next upstream.
+    async fn try_send_default(
+        upstreams: &Upstreams,
+        tag: &str,
+        msg: &Message,
+    ) -> Result<Message, String> {
+        send(upstreams, tag, CacheMode::default(), msg)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn try_send(
+        upstreams: &Upstreams,
+        tag: &str,
+        cache_mode: CacheMode,
+        msg: &Message,
+    ) -> Result<Message, String> {
+        send(upstreams, tag, cache_mode, msg)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn try_send_default_timeout(
+        upstreams: &Upstreams,
+        tag: &str,
+        msg: &Message,
+        timeout: i64,
+    ) -> Result<Message, String> {
+        send_timeout(upstreams, tag, CacheMode::default(), msg, timeout)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn try_send_timeout(
+        upstreams: &Upstreams,
+        tag: &str,
+        cache_mode: CacheMode,
+        msg: &Message,
+        timeout: i64,
+    ) -> Result<Message, String> {
+        send_timeout(upstreams, tag, cache_mode, msg, timeout)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn race_default(
+        upstreams: &Upstreams,
+        tags: Vec<String>,
+        msg: &Message,
+    ) -> Result<(String, Message), ScriptError> {
+        race(upstreams, tags, CacheMode::default(), msg).await
+    }
+
+    async fn race(
+        upstreams: &Upstreams,
+        tags: Vec<String>,
+        cache_mode: CacheMode,
+        msg: &Message,
+    ) -> Result<(String, Message), ScriptError> {
+        let tags: Vec<Label> = tags.into_iter().map(Label::from).collect();
+        let (tag, resp) = upstreams.race(&tags, &cache_mode, &msg.into()).await?;
+        Ok((tag.to_string(), resp.into()))
+    }
+
+    async fn fallback_default(
+        upstreams: &Upstreams,
+        tags: Vec<String>,
+        msg: &Message,
+    ) -> Result<Message, ScriptError> {
+        fallback(upstreams, tags, CacheMode::default(), msg).await
+    }
+
+    async fn fallback(
+        upstreams: &Upstreams,
+        tags: Vec<String>,
+        cache_mode: CacheMode,
+        msg: &Message,
+    ) -> Result<Message, ScriptError> {
+        let tags: Vec<Label> = tags.into_iter().map(Label::from).collect();
+        Ok(upstreams
+            .fallback(&tags, &cache_mode, &msg.into())
+            .await?
+            .into())
+    }
+
+    async fn send_all_default(
+        upstreams: &Upstreams,
+        tags: Vec<String>,
+        msg: &Message,
+    ) -> Vec<Result<Message, String>> {
+        send_all(upstreams, tags, CacheMode::default(), msg).await
+    }
+
+    async fn send_all(
+        upstreams: &Upstreams,
+        tags: Vec<String>,
+        cache_mode: CacheMode,
+        msg: &Message,
+    ) -> Vec<Result<Message, String>> {
+        let queries: Vec<_> = tags
+            .into_iter()
+            .map(|tag| (Label::from(tag), msg.into()))
+            .collect();
+        send_queries(upstreams, &queries, &cache_mode).await
+    }
+
+    async fn send_qtypes_default(
+        upstreams: &Upstreams,
+        tag: &str,
+        msg: &Message,
+        qtypes: Vec<String>,
+    ) -> Result<Vec<Result<Message, String>>, ScriptError> {
+        send_qtypes(upstreams, tag, CacheMode::default(), msg, qtypes).await
+    }
+
+    async fn send_qtypes(
+        upstreams: &Upstreams,
+        tag: &str,
+        cache_mode: CacheMode,
+        msg: &Message,
+        qtypes: Vec<String>,
+    ) -> Result<Vec<Result<Message, String>>, ScriptError> {
+        let mut queries = Vec::new();
+        for qtype in qtypes {
+            let qtype = domain::base::Rtype::from_str(&qtype).map_err(MessageError::from)?;
+            queries.push((Label::from(tag), with_qtype(&msg.into(), qtype)?));
+        }
+        Ok(send_queries(upstreams, &queries, &cache_mode).await)
+    }
+
+    // Send the queries concurrently, with the errors returned as strings like `try_send`.
+    async fn send_queries(
+        upstreams: &Upstreams,
+        queries: &[(Label, domain::base::Message<bytes::Bytes>)],
+        cache_mode: &CacheMode,
+    ) -> Vec<Result<Message, String>> {
+        upstreams
+            .send_all(queries, cache_mode)
+            .await
+            .into_iter()
+            .map(|r| r.map(Message::from).map_err(|e| e.to_string()))
+            .collect()
+    }
+
     m.ty::<Upstreams>().unwrap();
     m.async_inst_fn("send", send).unwrap();
     m.async_inst_fn("send_default", send_default).unwrap();
+    m.async_inst_fn("send_timeout", send_timeout).unwrap();
+    m.async_inst_fn("send_default_timeout", send_default_timeout)
+        .unwrap();
+    m.async_inst_fn("try_send", try_send).unwrap();
+    m.async_inst_fn("try_send_default", try_send_default)
+        .unwrap();
+    m.async_inst_fn("try_send_timeout", try_send_timeout)
+        .unwrap();
+    m.async_inst_fn("try_send_default_timeout", try_send_default_timeout)
+        .unwrap();
+    m.async_inst_fn("send_all", send_all).unwrap();
+    m.async_inst_fn("send_all_default", send_all_default)
+        .unwrap();
+    m.async_inst_fn("send_qtypes", send_qtypes).unwrap();
+    m.async_inst_fn("send_qtypes_default", send_qtypes_default)
+        .unwrap();
+    m.async_inst_fn("race", race).unwrap();
+    m.async_inst_fn("race_default", race_default).unwrap();
+    m.inst_fn("override_cache_mode", Upstreams::override_cache_mode)
+        .unwrap();
+    m.async_inst_fn("fallback", fallback).unwrap();
+    m.async_inst_fn("fallback_default", fallback_default)
+        .unwrap();
+    m.inst_fn("is_healthy", |upstreams: &Upstreams, tag: &str| {
+        upstreams.is_healthy(&Label::from(tag))
+    })
+    .unwrap();
+
+    m.inst_fn("cache_stats", Upstreams::cache_stats).unwrap();
+    m.inst_fn("cache_entries", |upstreams: &Upstreams| {
+        upstreams.cache_entries(None)
+    })
+    .unwrap();
+    m.inst_fn(
+        "cache_entries_domain",
+        |upstreams: &Upstreams, suffix: &str| upstreams.cache_entries(Some(suffix)),
+    )
+    .unwrap();
+    m.inst_fn(
+        "cache_get",
+        |upstreams: &Upstreams, tag: &str, msg: &Message| -> Result<Option<Message>, ScriptError> {
+            Ok(upstreams
+                .cache_get(&tag.into(), &msg.into())?
+                .map(Message::from))
+        },
+    )
+    .unwrap();
+    m.inst_fn(
+        "cache_put",
+        |upstreams: &Upstreams,
+         tag: &str,
+         msg: &Message,
+         resp: &Message,
+         ttl: i64|
+         -> Result<(), ScriptError> {
+            upstreams.cache_put(tag.into(), &msg.into(), resp.into(), to_ttl(ttl)?);
+            Ok(())
+        },
+    )
+    .unwrap();
+    m.inst_fn("flush_cache", |upstreams: &Upstreams| {
+        upstreams.flush_cache(None) as i64
+    })
+    .unwrap();
+    m.inst_fn(
+        "flush_cache_domain",
+        |upstreams: &Upstreams, suffix: &str| upstreams.flush_cache(Some(suffix)) as i64,
+    )
+    .unwrap();
 
     m.ty::<CacheMode>().unwrap();
 
+    m.ty::<CacheStats>().unwrap();
+    m.field_fn(Protocol::GET, "hits", |s: &CacheStats| s.hits as i64)
+        .unwrap();
+    m.field_fn(Protocol::GET, "misses", |s: &CacheStats| s.misses as i64)
+        .unwrap();
+    m.field_fn(Protocol::GET, "stale", |s: &CacheStats| s.stale as i64)
+        .unwrap();
+    m.field_fn(Protocol::GET, "evictions", |s: &CacheStats| {
+        s.evictions as i64
+    })
+    .unwrap();
+    m.field_fn(Protocol::GET, "entries", |s: &CacheStats| s.entries as i64)
+        .unwrap();
+
+    m.ty::<CacheEntry>().unwrap();
+    m.field_fn(Protocol::GET, "tag", |e: &CacheEntry| e.tag.to_string())
+        .unwrap();
+    m.field_fn(Protocol::GET, "name", |e: &CacheEntry| e.name.clone())
+        .unwrap();
+    m.field_fn(Protocol::GET, "qtype", |e: &CacheEntry| -> Rtype {
+        e.qtype.into()
+    })
+    .unwrap();
+    m.field_fn(Protocol::GET, "ttl", |e: &CacheEntry| e.ttl)
+        .unwrap();
+    m.field_fn(Protocol::GET, "queries", |e: &CacheEntry| e.queries as i64)
+        .unwrap();
+    m.field_fn(Protocol::GET, "response", |e: &CacheEntry| -> Message {
+        e.response.clone().into()
+    })
+    .unwrap();
+
     m.ty::<QueryContext>().unwrap();
     m.field_fn(Protocol::GET, "ip", |qctx: &QueryContext| -> IpAddr {
         qctx.ip.into()
@@ -62,6 +343,51 @@ pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
         |qctx: &mut QueryContext, ip: IpAddr| qctx.ip = ip.into(),
     )
     .unwrap();
+    m.field_fn(Protocol::GET, "port", |qctx: &QueryContext| {
+        qctx.port as i64
+    })
+    .unwrap();
+    m.field_fn(Protocol::GET, "listener", |qctx: &QueryContext| {
+        qctx.listener.to_string()
+    })
+    .unwrap();
+    m.field_fn(Protocol::GET, "transport", |qctx: &QueryContext| {
+        qctx.transport
+    })
+    .unwrap();
+
+    m.inst_fn("elapsed_ms", |qctx: &QueryContext| -> i64 {
+        qctx.received.elapsed().as_millis() as i64
+    })
+    .unwrap();
+    m.inst_fn("received_ms", |qctx: &QueryContext| -> i64 {
+        qctx.received_at()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64
+    })
+    .unwrap();
+    m.inst_fn("remaining_ms", |qctx: &QueryContext| -> Option<i64> {
+        qctx.remaining().map(|d| d.as_millis() as i64)
+    })
+    .unwrap();
+
+    m.inst_fn(
+        "set_mark",
+        |qctx: &mut QueryContext, key: &str, value: &str| qctx.set_mark(key, value),
+    )
+    .unwrap();
+    m.inst_fn("mark", QueryContext::mark).unwrap();
+    m.inst_fn("has_mark", QueryContext::has_mark).unwrap();
+
+    m.ty::<Transport>().unwrap();
+    m.inst_fn("to_str", |this: &Transport| this.to_string())
+        .unwrap();
+    m.inst_fn(Protocol::EQ, |this: &Transport, other: &str| {
+        this.to_string() == other
+    })
+    .unwrap();
+    m.inst_fn("is_encrypted", Transport::is_encrypted).unwrap();
 
     m
 });
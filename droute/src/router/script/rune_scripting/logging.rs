@@ -0,0 +1,103 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Structured logging from scripts, tagging every record with the query being routed.
+
+use crate::{errors::ScriptError, utils::quote, QueryContext};
+use bytes::Bytes;
+use domain::base::Message;
+use log::Level;
+use once_cell::sync::Lazy;
+use rune::{FromValue, Module, Value};
+use std::{collections::HashMap, fmt::Write, future::Future};
+
+tokio::task_local! {
+    static QUERY: QueryFields;
+}
+
+// The question name and the client IP of the query being routed, attached to every record logged while routing it.
+#[derive(Clone)]
+pub(super) struct QueryFields {
+    qname: Option<String>,
+    client: Option<String>,
+}
+
+impl QueryFields {
+    pub(super) fn new(query: &Message<Bytes>, ctx: Option<&QueryContext>) -> Self {
+        Self {
+            qname: query.first_question().map(|q| q.qname().to_string()),
+            client: ctx.map(|ctx| ctx.ip.to_string()),
+        }
+    }
+
+    // Run `f` with the records it logs tagged with the query.
+    pub(super) async fn scope<F: Future>(self, f: F) -> F::Output {
+        QUERY.scope(self, f).await
+    }
+}
+
+fn field(value: Value) -> Result<String, ScriptError> {
+    Ok(match value {
+        Value::Bool(v) => v.to_string(),
+        Value::Integer(v) => v.to_string(),
+        Value::Float(v) => v.to_string(),
+        value => String::from_value(value)?,
+    })
+}
+
+fn log(level: Level, fields: HashMap<String, Value>) -> Result<(), ScriptError> {
+    let mut record = String::new();
+    // Records logged outside of routing, e.g. in `init`, carry no query.
+    if let Ok(query) = QUERY.try_with(QueryFields::clone) {
+        if let Some(qname) = query.qname {
+            write!(record, "qname={} ", quote(&qname)).unwrap();
+        }
+        if let Some(client) = query.client {
+            write!(record, "client={} ", client).unwrap();
+        }
+    }
+
+    // Sort the fields so that records are written in a stable order.
+    let mut fields = fields.into_iter().collect::<Vec<_>>();
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    for (key, value) in fields {
+        write!(record, "{}={} ", quote(&key), quote(&field(value)?)).unwrap();
+    }
+
+    log::log!(target: "script", level, "{}", record.trim_end());
+    Ok(())
+}
+
+// A module containing the leveled logging functions
+pub static LOGGING_MODULE: Lazy<Module> = Lazy::new(|| {
+    let mut m = Module::new();
+
+    m.function(&["log_info"], |fields: HashMap<String, Value>| {
+        log(Level::Info, fields)
+    })
+    .unwrap();
+
+    m.function(&["log_warn"], |fields: HashMap<String, Value>| {
+        log(Level::Warn, fields)
+    })
+    .unwrap();
+
+    m.function(&["log_error"], |fields: HashMap<String, Value>| {
+        log(Level::Error, fields)
+    })
+    .unwrap();
+
+    m
+});
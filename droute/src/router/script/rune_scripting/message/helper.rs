@@ -19,7 +19,10 @@ use super::super::types::{DnsRecord, OptRecordData};
 use crate::errors::MessageError;
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{opt::AllOptData, Dname, Message, MessageBuilder, ParsedDname, Record, Rtype, ToDname},
+    base::{
+        opt::AllOptData, Dname, Header, Message, MessageBuilder, ParsedDname, Record, Rtype,
+        ToDname,
+    },
     rdata::{
         AllRecordData, Cname, Dname as DnameRecord, Mb, Md, Mf, Minfo, Mr, Mx, Ns, Nsec, Ptr,
         Rrsig, Soa, Srv, Tsig,
@@ -155,6 +158,16 @@ impl OptRecordsIter {
     }
 }
 
+// Modify the header of the message in place, leaving the sections untouched.
+pub fn modify_header(
+    msg: &Message<Bytes>,
+    f: impl FnOnce(&mut Header),
+) -> MessageResult<Message<Bytes>> {
+    let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+    f(msg.header_mut());
+    Ok(Message::from_octets(msg.into_octets().freeze())?)
+}
+
 pub fn modify_opt(
     msg: &Message<Bytes>,
     opt: Option<OptRecordsIter>,
@@ -17,7 +17,7 @@ pub mod helper;
 
 use super::types::*;
 use crate::errors::{MessageError, ScriptError};
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use domain::base::ToDname;
 use helper::{DnsRecordsIter, OptRecordsIter};
 use once_cell::sync::Lazy;
@@ -175,6 +175,9 @@ macro_rules! create_section_kit {
 	    $m.inst_fn(stringify!([<clear_ $name>]), |msg: &mut Message| [<update_ $name>](msg, DnsRecordsIter(Vec::new()))).unwrap();
 	    $m.inst_fn(stringify!([<insert_ $name>]), |msg: &mut Message, index: usize, record: DnsRecord| {
 		let mut records = [<get_ $name>](msg)?;
+		if index > records.0.len() {
+		    return Err(MessageError::IndexOutOfBounds(index, records.0.len()).into());
+		}
                 records.0.insert(index, record.0);
 		[<update_ $name>](msg, records)
 	    }).unwrap();
@@ -185,9 +188,20 @@ macro_rules! create_section_kit {
 	    }).unwrap();
 	    $m.inst_fn(stringify!([<remove_ $name>]), |msg: &mut Message, index: usize| {
 		let mut records = [<get_ $name>](msg)?;
+		if index >= records.0.len() {
+		    return Err(MessageError::IndexOutOfBounds(index, records.0.len()).into());
+		}
                 records.0.remove(index);
 		[<update_ $name>](msg, records)
 	    }).unwrap();
+	    $m.inst_fn(stringify!([<replace_ $name>]), |msg: &mut Message, index: usize, record: DnsRecord| {
+		let mut records = [<get_ $name>](msg)?;
+		match records.0.get_mut(index) {
+		    Some(r) => *r = record.0,
+		    None => return Err(MessageError::IndexOutOfBounds(index, records.0.len()).into()),
+		}
+		[<update_ $name>](msg, records)
+	    }).unwrap();
         }
     };
 }
@@ -207,6 +221,19 @@ macro_rules! create_header_bit_kit {
     };
 }
 
+// Set the header bit directly on the message, e.g. `msg.set_aa(true)`.
+macro_rules! create_message_bit_setter {
+    ($name: ident, $m: ident) => {
+        paste! {
+            $m.inst_fn(stringify!([<set_ $name>]), |msg: &mut Message, $name: bool| -> Result<(), ScriptError> {
+                *msg = helper::modify_header(&msg.0, |header| header.[<set_ $name>]($name))?.into();
+                Ok(())
+            })
+            .unwrap();
+        }
+    };
+}
+
 pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
     let mut m = Module::new();
 
@@ -227,10 +254,7 @@ pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
             Protocol::SET,
             "header",
             |msg: &mut Message, header: &Header| -> Result<(), ScriptError> {
-                let mut msg_mut =
-                    domain::base::Message::from_octets(BytesMut::from(msg.0.as_slice()))?;
-                (*msg_mut.header_mut()) = header.0;
-                *msg = domain::base::Message::from_octets(msg_mut.into_octets().freeze())?.into();
+                *msg = helper::modify_header(&msg.0, |h| *h = header.0)?.into();
                 Ok(())
             },
         )
@@ -241,7 +265,7 @@ pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
             create_header_bit_kit!(aa, m);
             create_header_bit_kit!(ad, m);
             create_header_bit_kit!(cd, m);
-            m.field_fn(Protocol::GET, "id", |header: &Header| header.0.aa())
+            m.field_fn(Protocol::GET, "id", |header: &Header| header.0.id())
                 .unwrap();
             m.field_fn(Protocol::SET, "id", |header: &mut Header, id: u16| {
                 header.0.set_id(id)
@@ -273,6 +297,43 @@ pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
                 |header: &mut Header, opcode: Opcode| header.0.set_opcode(opcode.0),
             )
             .unwrap();
+
+            // Shorthands to shape the header of the message without getting and setting it back
+            create_message_bit_setter!(qr, m);
+            create_message_bit_setter!(aa, m);
+            create_message_bit_setter!(tc, m);
+            create_message_bit_setter!(rd, m);
+            create_message_bit_setter!(ra, m);
+            create_message_bit_setter!(ad, m);
+            create_message_bit_setter!(cd, m);
+            m.inst_fn(
+                "set_rcode",
+                |msg: &mut Message, rcode: Rcode| -> Result<(), ScriptError> {
+                    *msg =
+                        helper::modify_header(&msg.0, |header| header.set_rcode(rcode.0))?.into();
+                    Ok(())
+                },
+            )
+            .unwrap();
+        }
+
+        // Wire format
+        {
+            m.inst_fn("to_bytes", |msg: &Message| {
+                rune::runtime::Bytes::from_vec(msg.0.as_slice().to_vec())
+            })
+            .unwrap();
+
+            m.function(
+                &["Message", "from_bytes"],
+                |bytes: &rune::runtime::Bytes| -> Result<Message, ScriptError> {
+                    let res: Result<_, MessageError> =
+                        domain::base::Message::from_octets(Bytes::copy_from_slice(&bytes[..]))
+                            .map_err(|e| e.into());
+                    Ok(res?.into())
+                },
+            )
+            .unwrap();
         }
 
         // Questions
@@ -405,26 +466,54 @@ pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
                 })
                 .unwrap();
 
+                // The name of the kind of the option, or `None` if it is unknown to us.
+                fn opt_kind(data: &domain::base::opt::AllOptData<Bytes>) -> Option<&'static str> {
+                    Some(match data {
+                        domain::base::opt::AllOptData::Chain(_) => "CHAIN",
+                        domain::base::opt::AllOptData::ClientSubnet(_) => "CLIENT_SUBNET",
+                        domain::base::opt::AllOptData::Cookie(_) => "COOKIE",
+                        domain::base::opt::AllOptData::Dau(_) => "DAU",
+                        domain::base::opt::AllOptData::Dhu(_) => "DHU",
+                        domain::base::opt::AllOptData::Expire(_) => "EXPIRE",
+                        domain::base::opt::AllOptData::ExtendedError(_) => "EXTENDED_ERROR",
+                        domain::base::opt::AllOptData::KeyTag(_) => "KEY_TAG",
+                        domain::base::opt::AllOptData::N3u(_) => "N3U",
+                        domain::base::opt::AllOptData::Nsid(_) => "NSID",
+                        domain::base::opt::AllOptData::Other(_) => "OTHER",
+                        domain::base::opt::AllOptData::Padding(_) => "PADDING",
+                        domain::base::opt::AllOptData::TcpKeepalive(_) => "TCP_KEEPALIVE",
+                        _ => return None,
+                    })
+                }
+
                 m.inst_fn(
                     "get_opt_rtype",
                     |data: &AllOptData| -> Result<String, ScriptError> {
-                        Ok(match data.0 {
-                            domain::base::opt::AllOptData::Chain(_) => "CHAIN",
-                            domain::base::opt::AllOptData::ClientSubnet(_) => "CLIENT_SUBNET",
-                            domain::base::opt::AllOptData::Cookie(_) => "COOKIE",
-                            domain::base::opt::AllOptData::Dau(_) => "DAU",
-                            domain::base::opt::AllOptData::Dhu(_) => "DHU",
-                            domain::base::opt::AllOptData::Expire(_) => "EXPIRE",
-                            domain::base::opt::AllOptData::ExtendedError(_) => "EXTENDED_ERROR",
-                            domain::base::opt::AllOptData::KeyTag(_) => "KEY_TAG",
-                            domain::base::opt::AllOptData::N3u(_) => "N3U",
-                            domain::base::opt::AllOptData::Nsid(_) => "NSID",
-                            domain::base::opt::AllOptData::Other(_) => "OTHER",
-                            domain::base::opt::AllOptData::Padding(_) => "PADDING",
-                            domain::base::opt::AllOptData::TcpKeepalive(_) => "TCP_KEEPALIVE",
-                            _ => return Err(MessageError::OptionUnsupported.into()),
-                        }
-                        .into())
+                        Ok(opt_kind(&data.0)
+                            .ok_or(MessageError::OptionUnsupported)?
+                            .into())
+                    },
+                )
+                .unwrap();
+
+                // Options are looked up by the names `get_opt_rtype` returns, e.g. `CLIENT_SUBNET`.
+                m.inst_fn(
+                    "get_opt",
+                    |msg: &Message, kind: &str| -> Result<Option<AllOptData>, ScriptError> {
+                        Ok(get_options_from_msg(msg)?
+                            .0
+                            .into_iter()
+                            .find(|o| opt_kind(o) == Some(kind))
+                            .map(AllOptData))
+                    },
+                )
+                .unwrap();
+                m.inst_fn(
+                    "has_opt",
+                    |msg: &Message, kind: &str| -> Result<bool, ScriptError> {
+                        Ok(get_options_from_msg(msg)?
+                            .iter()
+                            .any(|o| opt_kind(o) == Some(kind)))
                     },
                 )
                 .unwrap();
@@ -473,6 +562,21 @@ pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
                     },
                 )
                 .unwrap();
+                // Remove all the options of the kind given, returning the number of options removed.
+                m.inst_fn(
+                    "remove_opt_kind",
+                    |msg: &mut Message, kind: &str| -> Result<usize, ScriptError> {
+                        let mut opt = get_options_from_msg(msg)?;
+                        let len = opt.0.len();
+                        opt.0.retain(|o| opt_kind(o) != Some(kind));
+                        let removed = len - opt.0.len();
+                        if removed > 0 {
+                            update_opt(msg, opt)?;
+                        }
+                        Ok(removed)
+                    },
+                )
+                .unwrap();
             }
         }
 
@@ -660,6 +764,153 @@ pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
                 Ok(domain::rdata::Txt::from_slice(text.as_bytes())?.into())
             })
             .unwrap();
+
+            // The payload of all the character strings concatenated, which may not be valid UTF-8, e.g. DKIM keys or binary data.
+            m.field_fn(Protocol::GET, "bytes", |data: &Txt| {
+                rune::runtime::Bytes::from_vec(data.0.iter().flatten().copied().collect())
+            })
+            .unwrap();
+
+            m.function(
+                &["Txt", "from_bytes"],
+                |bytes: &rune::runtime::Bytes| -> Result<Txt, ScriptError> {
+                    Ok(domain::rdata::Txt::from_slice(&bytes[..])?.into())
+                },
+            )
+            .unwrap();
+        }
+
+        // Mx
+        {
+            create_record_downcast!(
+                DnsRecord,
+                Mx,
+                domain::rdata::AllRecordData::Mx,
+                MessageError::RecordUnsupported,
+                m
+            );
+
+            m.field_fn(Protocol::GET, "preference", |data: &Mx| -> u16 {
+                data.0.preference()
+            })
+            .unwrap();
+
+            m.field_fn(Protocol::GET, "exchange", |data: &Mx| -> Dname {
+                data.0.exchange().clone().into()
+            })
+            .unwrap();
+
+            m.function(&["Mx", "new"], |preference: u16, exchange: Dname| -> Mx {
+                domain::rdata::Mx::new(preference, exchange.0).into()
+            })
+            .unwrap();
+        }
+
+        // Srv
+        {
+            create_record_downcast!(
+                DnsRecord,
+                Srv,
+                domain::rdata::AllRecordData::Srv,
+                MessageError::RecordUnsupported,
+                m
+            );
+
+            m.field_fn(Protocol::GET, "priority", |data: &Srv| -> u16 {
+                data.0.priority()
+            })
+            .unwrap();
+
+            m.field_fn(Protocol::GET, "weight", |data: &Srv| -> u16 {
+                data.0.weight()
+            })
+            .unwrap();
+
+            m.field_fn(Protocol::GET, "port", |data: &Srv| -> u16 { data.0.port() })
+                .unwrap();
+
+            m.field_fn(Protocol::GET, "target", |data: &Srv| -> Dname {
+                data.0.target().clone().into()
+            })
+            .unwrap();
+
+            m.function(
+                &["Srv", "new"],
+                |priority: u16, weight: u16, port: u16, target: Dname| -> Srv {
+                    domain::rdata::Srv::new(priority, weight, port, target.0).into()
+                },
+            )
+            .unwrap();
+        }
+
+        // Ptr
+        {
+            create_record_downcast!(
+                DnsRecord,
+                Ptr,
+                domain::rdata::AllRecordData::Ptr,
+                MessageError::RecordUnsupported,
+                m
+            );
+
+            m.field_fn(Protocol::GET, "ptrdname", |data: &Ptr| -> Dname {
+                data.0.ptrdname().clone().into()
+            })
+            .unwrap();
+
+            m.function(&["Ptr", "new"], |ptrdname: Dname| -> Ptr {
+                domain::rdata::Ptr::new(ptrdname.0).into()
+            })
+            .unwrap();
+        }
+
+        // Soa
+        {
+            create_record_downcast!(
+                DnsRecord,
+                Soa,
+                domain::rdata::AllRecordData::Soa,
+                MessageError::RecordUnsupported,
+                m
+            );
+
+            m.field_fn(Protocol::GET, "mname", |data: &Soa| -> Dname {
+                data.0.mname().clone().into()
+            })
+            .unwrap();
+
+            m.field_fn(Protocol::GET, "rname", |data: &Soa| -> Dname {
+                data.0.rname().clone().into()
+            })
+            .unwrap();
+
+            m.field_fn(Protocol::GET, "serial", |data: &Soa| -> u32 {
+                data.0.serial().0
+            })
+            .unwrap();
+
+            m.field_fn(Protocol::GET, "minimum", |data: &Soa| -> u32 {
+                data.0.minimum()
+            })
+            .unwrap();
+
+            // Refresh, retry and expire are rarely of interest in answers we synthesize, so they take the common defaults.
+            m.function(
+                &["Soa", "new"],
+                |mname: Dname, rname: Dname, serial: u32, minimum: u32| -> Soa {
+                    domain::rdata::Soa::new(
+                        mname.0,
+                        rname.0,
+                        serial.into(),
+                        1800,
+                        900,
+                        604800,
+                        minimum,
+                    )
+                    .into()
+                },
+            )
+            .unwrap();
         }
 
         // Cookie
@@ -676,6 +927,39 @@ pub static MSG_MODULE: Lazy<Module> = Lazy::new(|| {
                 hex::encode(cookie.0.cookie())
             })
             .unwrap();
+
+            m.function(
+                &["Cookie", "new"],
+                |cookie: &str| -> Result<Cookie, ScriptError> {
+                    let cookie: [u8; 8] = hex::decode(cookie)
+                        .ok()
+                        .and_then(|c| c.try_into().ok())
+                        .ok_or(MessageError::InvalidCookie)?;
+                    Ok(domain::base::opt::Cookie::new(cookie).into())
+                },
+            )
+            .unwrap();
+        }
+
+        // Padding
+        {
+            create_option_downcast!(
+                AllOptData,
+                Padding,
+                domain::base::opt::AllOptData::Padding,
+                MessageError::OptionUnsupported,
+                m
+            );
+
+            m.function(&["Padding", "new"], |len: u16| -> Padding {
+                domain::base::opt::Padding::new(len).into()
+            })
+            .unwrap();
+
+            m.field_fn(Protocol::GET, "len", |padding: &Padding| -> u16 {
+                padding.0.len()
+            })
+            .unwrap();
         }
 
         // ClientSubnet
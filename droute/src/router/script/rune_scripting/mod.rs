@@ -19,7 +19,9 @@
 
 // Basis module should not be placed at the module root because `types` module cannot be imported "AS IS" here.
 mod basis;
+mod logging;
 mod message;
+mod scheduled;
 mod types;
 mod utils;
 
@@ -30,23 +32,54 @@ use crate::{
 use async_trait::async_trait;
 use bytes::Bytes;
 use domain::base::Message;
+use logging::QueryFields;
 use rune::{
-    runtime::RuntimeContext,
+    runtime::{budget, RuntimeContext, VmSendExecution},
     termcolor::{ColorChoice, StandardStream},
-    Context, Diagnostics, FromValue, Source, Sources, Unit, Vm,
+    Context, Diagnostics, FromValue, Source, Sources, Unit, Value, Vm,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use types::Message as NewMessage;
 use utils::Utils;
 
 /// A Rune script backend for droute
 pub struct RuneScript {
-    upstreams: Upstreams,
+    upstreams: RwLock<Upstreams>,
     // We cannot store Vm "as is" here as otherwise RuneScript is not Sync and &RuneScript wouldn't be Send.
     unit: Arc<Unit>,
     context: Arc<RuntimeContext>,
     inited: HashMap<String, Utils>,
+    limits: RuneScriptLimits,
+}
+
+impl RuneScript {
+    // Run the route function to the end within the instruction budget, if any.
+    async fn complete(&self, execution: VmSendExecution) -> Result<Value> {
+        let execution = execution.async_complete();
+        Ok(match self.limits.max_instructions {
+            Some(max) => budget::with(max, execution).await?,
+            None => execution.await?,
+        })
+    }
+
+    // Run the route function within the time limit, if any.
+    async fn run(&self, execution: VmSendExecution) -> Result<Value> {
+        Ok(match self.limits.timeout {
+            Some(timeout) => {
+                let timeout = Duration::from_millis(timeout);
+                tokio::time::timeout(timeout, self.complete(execution))
+                    .await
+                    .map_err(|_| ScriptError::Timeout(timeout))??
+            }
+            None => self.complete(execution).await?,
+        })
+    }
 }
 
 #[async_trait]
@@ -54,24 +87,31 @@ impl ScriptBackend for RuneScript {
     async fn route(
         &self,
         query: Message<Bytes>,
-        ctx: Option<QueryContext>,
+        mut ctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>> {
+        if let (Some(ctx), Some(timeout)) = (&mut ctx, self.limits.timeout) {
+            ctx.limit(Duration::from_millis(timeout));
+        }
+        let fields = QueryFields::new(&query, ctx.as_ref());
         let send_exec = {
             let vm = Vm::new(self.context.clone(), self.unit.clone());
             let query: NewMessage = query.into();
+            let upstreams = self.upstreams.read().unwrap().clone();
 
-            vm.send_execute(
-                ["route"],
-                (self.upstreams.clone(), self.inited.clone(), ctx, query),
-            )?
+            vm.send_execute(["route"], (upstreams, self.inited.clone(), ctx, query))?
         };
 
-        Ok(
-            <std::result::Result<NewMessage, ScriptError> as FromValue>::from_value(
-                send_exec.async_complete().await?,
-            )??
-            .into(),
-        )
+        let value = fields.scope(self.run(send_exec)).await?;
+
+        Ok(<std::result::Result<NewMessage, ScriptError> as FromValue>::from_value(value)??.into())
+    }
+
+    fn replace_upstreams(&self, upstreams: Upstreams) {
+        *self.upstreams.write().unwrap() = upstreams;
+    }
+
+    fn upstreams(&self) -> Upstreams {
+        self.upstreams.read().unwrap().clone()
     }
 }
 
@@ -79,20 +119,85 @@ impl Validatable for RuneScript {
     type Error = ScriptError;
 
     fn validate(&self, _: Option<&Vec<crate::Label>>) -> Result<()> {
-        self.upstreams.validate(None)?;
+        self.upstreams.read().unwrap().validate(None)?;
         Ok(())
     }
 }
 
+/// Limits on routing every query with the script, exceeding any of which fails the query with SERVFAIL, so that a buggy script cannot hang the resolver.
+/// `init` is not limited. Memory usage of the script cannot be limited for now.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RuneScriptLimits {
+    /// Maximum number of instructions run by the VM. Native functions, e.g. sending queries, count as single instructions.
+    #[serde(default)]
+    pub max_instructions: Option<usize>,
+    /// Maximum time in milliseconds, including the time waiting for upstreams.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+// The script is given either as the source code alone, or along with the limits.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum RuneScriptConfig {
+    Source(String),
+    Limited {
+        source: String,
+        #[serde(flatten)]
+        limits: RuneScriptLimits,
+    },
+}
+
+impl From<RuneScriptConfig> for RuneScriptBuilder {
+    fn from(config: RuneScriptConfig) -> Self {
+        match config {
+            RuneScriptConfig::Source(source) => Self::new(source),
+            RuneScriptConfig::Limited { source, limits } => Self::new(source).limits(limits),
+        }
+    }
+}
+
+impl From<RuneScriptBuilder> for RuneScriptConfig {
+    fn from(builder: RuneScriptBuilder) -> Self {
+        Self::Limited {
+            source: builder.source,
+            limits: builder.limits,
+        }
+    }
+}
+
 /// A builder for `RuneScript`.
 /// Two pub async functionas are required in the script: `pub async fn init()` and `pub async fn route(upstreams, init, ctx, query)`.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct RuneScriptBuilder(String);
+#[serde(from = "RuneScriptConfig", into = "RuneScriptConfig")]
+pub struct RuneScriptBuilder {
+    source: String,
+    limits: RuneScriptLimits,
+    path: Option<PathBuf>,
+}
 
 impl RuneScriptBuilder {
     /// Create a `RuneScriptBuilder` from the script source code.
     pub fn new(script: impl ToString) -> Self {
-        Self(script.to_string())
+        Self {
+            source: script.to_string(),
+            limits: RuneScriptLimits::default(),
+            path: None,
+        }
+    }
+
+    /// Set the path of the file the script is loaded from, e.g. the configuration file.
+    /// Modules declared in the script with `mod name;` are loaded from `name.rn` or `name/mod.rn` under the same directory.
+    /// Without the path, the script cannot declare modules in other files.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Set the limits on routing every query with the script.
+    pub fn limits(mut self, limits: RuneScriptLimits) -> Self {
+        self.limits = limits;
+        self
     }
 }
 
@@ -109,10 +214,11 @@ impl ScriptBuilder<RuneScript> for RuneScriptBuilder {
         context.install(&message::MSG_MODULE)?;
         context.install(&basis::BASIS_MODULE)?;
         context.install(&utils::UTILS_MODULE)?;
+        context.install(&logging::LOGGING_MODULE)?;
         let runtime = Arc::new(context.runtime());
 
         let mut sources = Sources::new();
-        sources.insert(Source::new("script", self.0));
+        sources.insert(Source::with_path("script", self.source, self.path));
 
         let mut diagnostics = Diagnostics::new();
 
@@ -141,10 +247,11 @@ impl ScriptBuilder<RuneScript> for RuneScriptBuilder {
         };
 
         Ok(RuneScript {
-            upstreams,
+            upstreams: RwLock::new(upstreams),
             unit,
             context: runtime,
             inited,
+            limits: self.limits,
         })
     }
 }
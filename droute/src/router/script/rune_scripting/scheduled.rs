@@ -0,0 +1,72 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Values produced by script functions rerun on a timer, so that long-lived objects like blocklists stay fresh without a restart.
+
+use super::utils::Utils;
+use crate::errors::ScriptError;
+use rune::runtime::{Function, SyncFunction};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// The latest value produced by a task function, which is rerun periodically in background.
+#[derive(rune::Any, Clone)]
+pub struct Scheduled(Arc<RwLock<Utils>>);
+
+impl Scheduled {
+    /// Run the task once for the initial value, and then every interval until the value is no longer used, e.g. once the script is reloaded.
+    /// The previous value is kept if a run fails.
+    pub async fn new(task: Function, interval: Duration) -> Result<Self, ScriptError> {
+        let task = task.into_sync()?;
+        let value = Arc::new(RwLock::new(run(&task).await?));
+
+        let alive = Arc::downgrade(&value);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if alive.upgrade().is_none() {
+                    break;
+                }
+
+                match run(&task).await {
+                    Ok(v) => match alive.upgrade() {
+                        Some(value) => {
+                            *value.write().unwrap() = v;
+                            log::info!("scheduled task finished, the new value is swapped in");
+                        }
+                        None => break,
+                    },
+                    Err(e) => {
+                        log::warn!("scheduled task failed, keeping the previous value: {}", e)
+                    }
+                }
+            }
+        });
+
+        Ok(Self(value))
+    }
+
+    /// The value produced by the latest successful run.
+    pub fn get(&self) -> Utils {
+        self.0.read().unwrap().clone()
+    }
+}
+
+async fn run(task: &SyncFunction) -> Result<Utils, ScriptError> {
+    task.async_send_call::<_, Result<Utils, ScriptError>>(())
+        .await?
+}
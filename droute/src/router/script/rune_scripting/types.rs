@@ -14,7 +14,10 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::message::helper::{DnsRecordsIter, OptRecordsIter};
-use crate::errors::{MessageError, ScriptError};
+use crate::{
+    errors::{MessageError, ScriptError},
+    router::script::parse_rcode,
+};
 use bytes::Bytes;
 use once_cell::sync::Lazy;
 use rune::{runtime::Protocol, Module};
@@ -91,12 +94,17 @@ create_new_type!(
 create_new_type!(AllOptData, domain::base::opt::AllOptData<Bytes>);
 create_new_type!(ClientSubnet, domain::base::opt::ClientSubnet);
 create_new_type!(Cookie, domain::base::opt::Cookie);
+create_new_type!(Padding, domain::base::opt::Padding);
 create_new_type!(OptRecord, domain::base::opt::OptRecord<Bytes>);
 
 create_new_type!(Aaaa, domain::rdata::Aaaa);
 create_new_type!(Cname, domain::rdata::Cname<domain::base::Dname<Bytes>>);
 create_new_type!(Txt, domain::rdata::Txt<Bytes>);
 create_new_type!(A, domain::rdata::A);
+create_new_type!(Mx, domain::rdata::Mx<domain::base::Dname<Bytes>>);
+create_new_type!(Srv, domain::rdata::Srv<domain::base::Dname<Bytes>>);
+create_new_type!(Ptr, domain::rdata::Ptr<domain::base::Dname<Bytes>>);
+create_new_type!(Soa, domain::rdata::Soa<domain::base::Dname<Bytes>>);
 
 create_new_type!(IpAddr, std::net::IpAddr);
 create_new_type!(
@@ -119,6 +127,60 @@ pub static TYPES_MODULE: Lazy<Module> = Lazy::new(|| {
     m.ty::<Dname>().unwrap();
     create_str_kit!(Dname, domain::base::Dname<Bytes>, m);
 
+    m.inst_fn("parent", |this: &Dname| {
+        let mut parent = this.0.clone();
+        parent.parent().then_some(Dname(parent))
+    })
+    .unwrap();
+
+    // The root label is not counted
+    m.inst_fn("label_count", |this: &Dname| {
+        this.0.label_count() as i64 - 1
+    })
+    .unwrap();
+
+    m.inst_fn(
+        "is_subdomain_of",
+        |this: &Dname, other: &str| -> Result<bool, ScriptError> {
+            let other =
+                domain::base::Dname::<Bytes>::from_str(other).map_err(MessageError::from)?;
+            Ok(this.0.ends_with(&other))
+        },
+    )
+    .unwrap();
+
+    m.inst_fn(
+        "strip_suffix",
+        |this: &Dname, suffix: &str| -> Result<Option<String>, ScriptError> {
+            let suffix =
+                domain::base::Dname::<Bytes>::from_str(suffix).map_err(MessageError::from)?;
+            if !this.0.ends_with(&suffix) {
+                return Ok(None);
+            }
+            let n = this.0.label_count() - suffix.label_count();
+            Ok(Some(
+                this.0
+                    .iter()
+                    .take(n)
+                    .map(|label| label.to_string())
+                    .collect::<Vec<_>>()
+                    .join("."),
+            ))
+        },
+    )
+    .unwrap();
+
+    m.inst_fn(
+        "to_lowercase",
+        |this: &Dname| -> Result<Dname, ScriptError> {
+            let res: Result<_, MessageError> =
+                domain::base::Dname::from_str(&this.0.to_string().to_lowercase())
+                    .map_err(|e| e.into());
+            Ok(Dname(res?))
+        },
+    )
+    .unwrap();
+
     m.ty::<Rcode>().unwrap();
     // Rcode doesn't implment FromStr
     m.inst_fn("to_str", |this: &Rcode| this.0.to_string())
@@ -129,6 +191,15 @@ pub static TYPES_MODULE: Lazy<Module> = Lazy::new(|| {
     })
     .unwrap();
 
+    m.function(
+        &["Rcode", "from_str"],
+        |s: &str| -> Result<Rcode, ScriptError> {
+            let res: Result<_, MessageError> = parse_rcode(s).map_err(|e| e.into());
+            Ok(res?.into())
+        },
+    )
+    .unwrap();
+
     m.ty::<OptRcode>().unwrap();
     // OptRcode doesn't implment FromStr
     m.inst_fn("to_str", |this: &OptRcode| this.0.to_string())
@@ -159,8 +230,13 @@ pub static TYPES_MODULE: Lazy<Module> = Lazy::new(|| {
     m.ty::<Aaaa>().unwrap();
     m.ty::<Cname>().unwrap();
     m.ty::<Txt>().unwrap();
+    m.ty::<Mx>().unwrap();
+    m.ty::<Srv>().unwrap();
+    m.ty::<Ptr>().unwrap();
+    m.ty::<Soa>().unwrap();
     m.ty::<Cookie>().unwrap();
     m.ty::<ClientSubnet>().unwrap();
+    m.ty::<Padding>().unwrap();
 
     // Iterators
     m.ty::<OptRecordsIter>().unwrap();
@@ -13,103 +13,968 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::types::*;
+use super::{scheduled::Scheduled, types::*};
 use crate::{
-    errors::ScriptError,
-    utils::{blackhole, fast_answer, fast_answer_ip, Domain, GeoIp, IpCidr, Hosts},
+    errors::{MessageError, ScriptError},
+    utils::{
+        answer_rtypes, blackhole, blackhole_with, cname_chain, edns_udp_size, fast_answer,
+        fast_answer_ip, filter_records, from_punycode, has_rtype, http_get, http_get_cached,
+        is_special_use, max_ttl, metrics, min_ttl, null_answer, nxdomain, pad_query, pad_response,
+        ptr_to_ip, rand_float, rand_int, refused, rotate_answers, scrub_edns, set_client_ecs,
+        set_ecs, shuffle_answers, strip_edns, to_punycode, truncate_answers, uuid, wire_size,
+        with_qtype, Asn, Counter, Domain, GeoIp, GeoSite, Hosts, IpCidr, IpRemap, NegativeAnswer,
+        QueryLog, Regex, Rewrite, Rpz, StaticAnswer, TaggedDomain, UtilsError, Verdict,
+    },
+    router::script::parse_rcode,
+    QueryContext,
 };
 use once_cell::sync::Lazy;
-use rune::Module;
-use std::sync::Arc;
+use rune::{
+    runtime::{Bytes, Function},
+    Module, Value,
+};
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+#[derive(rune::Any, Clone)]
+pub enum Utils {
+    #[rune(constructor)]
+    Domain(#[rune(get)] SealedDomain),
+    #[rune(constructor)]
+    GeoIp(#[rune(get)] SealedGeoIp),
+    #[rune(constructor)]
+    GeoSite(#[rune(get)] SealedGeoSite),
+    #[rune(constructor)]
+    IpCidr(#[rune(get)] SealedIpCidr),
+    #[rune(constructor)]
+    Hosts(#[rune(get)] SealedHosts),
+    #[rune(constructor)]
+    Asn(#[rune(get)] SealedAsn),
+    #[rune(constructor)]
+    TaggedDomain(#[rune(get)] SealedTaggedDomain),
+    #[rune(constructor)]
+    Rewrite(#[rune(get)] SealedRewrite),
+    #[rune(constructor)]
+    IpRemap(#[rune(get)] SealedIpRemap),
+    #[rune(constructor)]
+    QueryLog(#[rune(get)] SealedQueryLog),
+    #[rune(constructor)]
+    Counter(#[rune(get)] SealedCounter),
+    #[rune(constructor)]
+    StaticAnswer(#[rune(get)] SealedStaticAnswer),
+    #[rune(constructor)]
+    NegativeAnswer(#[rune(get)] SealedNegativeAnswer),
+    #[rune(constructor)]
+    Rpz(#[rune(get)] SealedRpz),
+    #[rune(constructor)]
+    Regex(#[rune(get)] Regex),
+    #[rune(constructor)]
+    Scheduled(#[rune(get)] Scheduled),
+}
+
+// Contents shared by every clone of a sealed utility. `swap` replaces them for all clones at
+// once, so that a scheduled task can rebuild the data without rerunning `init`.
+struct Swappable<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Swappable<T> {
+    fn new(inner: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(inner))))
+    }
+
+    fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn swap(&self, other: &Self) {
+        let inner = other.get();
+        *self.0.write().unwrap() = inner;
+    }
+}
+
+impl<T> Clone for Swappable<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[derive(rune::Any, Clone)]
+pub struct SealedDomain(Swappable<Domain>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedHosts(Swappable<Hosts>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedGeoIp(Swappable<GeoIp>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedGeoSite(Swappable<GeoSite>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedIpCidr(Swappable<IpCidr>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedAsn(Arc<Asn>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedTaggedDomain(Arc<TaggedDomain>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedRewrite(Arc<Rewrite>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedIpRemap(Arc<IpRemap>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedQueryLog(Arc<QueryLog>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedCounter(Arc<Counter>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedStaticAnswer(Arc<StaticAnswer>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedNegativeAnswer(Arc<NegativeAnswer>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedRpz(Swappable<Rpz>);
+
+// TTLs are given as integers in scripts, which may be negative or too large.
+pub(super) fn to_ttl(val: i64) -> Result<u32, UtilsError> {
+    u32::try_from(val).map_err(|_| UtilsError::InvalidTtl(val))
+}
+
+pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
+    let mut m = Module::new();
+
+    m.ty::<Utils>().unwrap();
+
+    // Blackhole
+    {
+        m.function(
+            &["blackhole"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(blackhole(&msg.into())?.into()) },
+        )
+        .unwrap();
+        m.function(
+            &["blackhole_with"],
+            |msg: &Message, rcode: &str, ttl: i64| -> Result<Message, ScriptError> {
+                let rcode = parse_rcode(rcode).map_err(MessageError::from)?;
+                Ok(blackhole_with(&msg.into(), rcode, to_ttl(ttl)?)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["nxdomain"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(nxdomain(&msg.into())?.into()) },
+        )
+        .unwrap();
+        m.function(
+            &["refused"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(refused(&msg.into())?.into()) },
+        )
+        .unwrap();
+        m.function(
+            &["null_answer"],
+            |msg: &Message| -> Result<Message, ScriptError> {
+                Ok(null_answer(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+        m.function(&["no_response"], || -> Result<Message, ScriptError> {
+            Err(ScriptError::Dropped)
+        })
+        .unwrap();
+    }
+
+    // Fast Answer
+    {
+        m.function(
+            &["fast_answer"],
+            |msg: &Message, a: i64, b: i64, c: i64, d: i64| -> Result<Message, ScriptError> {
+                Ok(fast_answer(&msg.into(), a as u8, b as u8, c as u8, d as u8)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["fast_answer_ip"],
+            |msg: &Message, ip: IpAddr| -> Result<Message, ScriptError> {
+                Ok(fast_answer_ip(&msg.into(), ip.into())?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // Delay
+    {
+        async fn delay(ms: i64) {
+            tokio::time::sleep(std::time::Duration::from_millis(ms.max(0) as u64)).await
+        }
+
+        m.async_function(&["delay"], delay).unwrap();
+        m.async_function(&["sleep_ms"], delay).unwrap();
+    }
+
+    // HTTP
+    {
+        async fn get(url: &str) -> Result<String, ScriptError> {
+            Ok(http_get(url).await?)
+        }
+
+        async fn get_cached(url: &str, path: &str) -> Result<String, ScriptError> {
+            Ok(http_get_cached(url, path).await?)
+        }
+
+        m.async_function(&["http_get"], get).unwrap();
+        m.async_function(&["http_get_cached"], get_cached).unwrap();
+    }
+
+    // Encoding
+    {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        m.function(&["base64_encode"], |bytes: &Bytes| {
+            STANDARD.encode(&bytes[..])
+        })
+        .unwrap();
+        m.function(
+            &["base64_decode"],
+            |text: &str| -> Result<Bytes, ScriptError> {
+                Ok(Bytes::from_vec(
+                    STANDARD.decode(text).map_err(UtilsError::from)?,
+                ))
+            },
+        )
+        .unwrap();
+        m.function(&["hex_encode"], |bytes: &Bytes| hex::encode(&bytes[..]))
+            .unwrap();
+        m.function(
+            &["hex_decode"],
+            |text: &str| -> Result<Bytes, ScriptError> {
+                Ok(Bytes::from_vec(
+                    hex::decode(text).map_err(UtilsError::from)?,
+                ))
+            },
+        )
+        .unwrap();
+        m.function(&["str_to_bytes"], |text: &str| {
+            Bytes::from_vec(text.as_bytes().to_vec())
+        })
+        .unwrap();
+        m.function(
+            &["bytes_to_str"],
+            |bytes: &Bytes| -> Result<String, ScriptError> {
+                Ok(String::from_utf8(bytes.to_vec()).map_err(MessageError::from)?)
+            },
+        )
+        .unwrap();
+        m.function(
+            &["list_to_bytes"],
+            |list: Vec<i64>| -> Result<Bytes, ScriptError> {
+                Ok(Bytes::from_vec(
+                    list.into_iter()
+                        .map(|b| u8::try_from(b).map_err(|_| UtilsError::InvalidByte(b)))
+                        .collect::<std::result::Result<_, _>>()?,
+                ))
+            },
+        )
+        .unwrap();
+    }
+
+    // Random
+    {
+        m.function(
+            &["rand_int"],
+            |low: i64, high: i64| -> Result<i64, ScriptError> { Ok(rand_int(low, high)?) },
+        )
+        .unwrap();
+        m.function(&["rand_float"], rand_float).unwrap();
+        m.function(&["uuid"], uuid).unwrap();
+    }
+
+    // Punycode
+    {
+        m.function(
+            &["to_punycode"],
+            |domain: &str| -> Result<String, ScriptError> { Ok(to_punycode(domain)?) },
+        )
+        .unwrap();
+        m.function(
+            &["from_punycode"],
+            |domain: &str| -> Result<String, ScriptError> { Ok(from_punycode(domain)?) },
+        )
+        .unwrap();
+    }
+
+    // Metrics
+    {
+        m.function(
+            &["metrics", "inc"],
+            |name: &str| -> Result<(), ScriptError> { Ok(metrics::inc(name)?) },
+        )
+        .unwrap();
+        m.function(
+            &["metrics", "observe"],
+            |name: &str, value: Value| -> Result<(), ScriptError> {
+                let value = match value {
+                    Value::Integer(v) => v as f64,
+                    Value::Float(v) => v,
+                    _ => {
+                        return Err(UtilsError::InvalidMetric(
+                            name.to_string(),
+                            "the value observed must be a number",
+                        )
+                        .into())
+                    }
+                };
+                Ok(metrics::observe(name, value)?)
+            },
+        )
+        .unwrap();
+    }
+
+    // EDNS
+    {
+        m.function(
+            &["set_ecs"],
+            |msg: &Message, ip: &IpAddr, prefix: i64| -> Result<Message, ScriptError> {
+                Ok(set_ecs(&msg.into(), ip.into(), prefix.clamp(0, 128) as u8)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["set_client_ecs"],
+            |msg: &Message, ip: &IpAddr| -> Result<Message, ScriptError> {
+                Ok(set_client_ecs(&msg.into(), ip.into())?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["pad_query"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(pad_query(&msg.into())?.into()) },
+        )
+        .unwrap();
+        m.function(
+            &["pad_response"],
+            |msg: &Message| -> Result<Message, ScriptError> {
+                Ok(pad_response(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["scrub_edns"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(scrub_edns(&msg.into())?.into()) },
+        )
+        .unwrap();
+        m.function(
+            &["strip_edns"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(strip_edns(&msg.into())?.into()) },
+        )
+        .unwrap();
+    }
+
+    // Answer reordering
+    {
+        m.function(
+            &["shuffle_answers"],
+            |msg: &Message| -> Result<Message, ScriptError> {
+                Ok(shuffle_answers(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["rotate_answers"],
+            |msg: &Message| -> Result<Message, ScriptError> {
+                Ok(rotate_answers(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // Record filtering
+    {
+        m.function(
+            &["filter_records"],
+            |msg: &Message, rtypes: Vec<String>| -> Result<Message, ScriptError> {
+                let rtypes = rtypes
+                    .iter()
+                    .map(|t| domain::base::Rtype::from_str(t))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(MessageError::from)?;
+                Ok(filter_records(&msg.into(), &rtypes)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["truncate_answers"],
+            |msg: &Message, max: i64, set_tc: bool| -> Result<Message, ScriptError> {
+                Ok(truncate_answers(&msg.into(), max.max(0) as usize, set_tc)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["with_qtype"],
+            |msg: &Message, qtype: &str| -> Result<Message, ScriptError> {
+                let qtype = domain::base::Rtype::from_str(qtype).map_err(MessageError::from)?;
+                Ok(with_qtype(&msg.into(), qtype)?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // Response inspection
+    {
+        m.function(
+            &["min_ttl"],
+            |msg: &Message| -> Result<Option<i64>, ScriptError> {
+                Ok(min_ttl(&msg.into())?.map(i64::from))
+            },
+        )
+        .unwrap();
+        m.function(
+            &["max_ttl"],
+            |msg: &Message| -> Result<Option<i64>, ScriptError> {
+                Ok(max_ttl(&msg.into())?.map(i64::from))
+            },
+        )
+        .unwrap();
+        m.function(
+            &["answer_rtypes"],
+            |msg: &Message| -> Result<Vec<Rtype>, ScriptError> {
+                Ok(answer_rtypes(&msg.into())?
+                    .into_iter()
+                    .map(Rtype::from)
+                    .collect())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["has_rtype"],
+            |msg: &Message, rtype: &str| -> Result<bool, ScriptError> {
+                let rtype = domain::base::Rtype::from_str(rtype).map_err(MessageError::from)?;
+                Ok(has_rtype(&msg.into(), rtype)?)
+            },
+        )
+        .unwrap();
+        m.function(&["wire_size"], |msg: &Message| -> i64 {
+            wire_size(&msg.into()) as i64
+        })
+        .unwrap();
+        m.function(&["edns_udp_size"], |msg: &Message| -> Option<i64> {
+            edns_udp_size(&msg.into()).map(i64::from)
+        })
+        .unwrap();
+        m.function(
+            &["cname_chain"],
+            |msg: &Message| -> Result<Vec<Dname>, ScriptError> {
+                Ok(cname_chain(&msg.into())?
+                    .into_iter()
+                    .map(Dname::from)
+                    .collect())
+            },
+        )
+        .unwrap();
+    }
+
+    // Special-use domains
+    {
+        m.function(&["is_special_use"], |qname: &Dname| -> bool {
+            is_special_use(&qname.into())
+        })
+        .unwrap();
+    }
+
+    // Domain list
+    {
+        m.ty::<Domain>().unwrap();
+        m.ty::<SealedDomain>().unwrap();
+
+        m.function(&["Domain", "new"], Domain::new).unwrap();
+        m.inst_fn(
+            "add_qname",
+            |mut domain: Domain, qname: &str| -> Result<Domain, ScriptError> {
+                domain.add_qname(qname)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_qname_exact",
+            |mut domain: Domain, qname: &str| -> Result<Domain, ScriptError> {
+                domain.add_qname_exact(qname)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_list",
+            |mut domain: Domain, list: Vec<String>| -> Result<Domain, ScriptError> {
+                domain.add_list(list)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+
+        async fn domain_add_file(mut domain: Domain, path: &str) -> Result<Domain, ScriptError> {
+            domain.add_file(path).await?;
+            Ok(domain)
+        }
+
+        m.async_inst_fn("add_file", domain_add_file).unwrap();
+
+        async fn domain_add_file_exact(
+            mut domain: Domain,
+            path: &str,
+        ) -> Result<Domain, ScriptError> {
+            domain.add_file_exact(path).await?;
+            Ok(domain)
+        }
+
+        m.async_inst_fn("add_file_exact", domain_add_file_exact)
+            .unwrap();
+
+        m.inst_fn(
+            "add_adblock",
+            |mut domain: Domain, s: &str| -> Result<Domain, ScriptError> {
+                domain.add_adblock(s)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+
+        async fn domain_add_adblock_file(
+            mut domain: Domain,
+            path: &str,
+        ) -> Result<Domain, ScriptError> {
+            domain.add_adblock_file(path).await?;
+            Ok(domain)
+        }
+
+        m.async_inst_fn("add_adblock_file", domain_add_adblock_file)
+            .unwrap();
+
+        async fn domain_add_url(mut domain: Domain, url: &str) -> Result<Domain, ScriptError> {
+            domain.add_url(url, None).await?;
+            Ok(domain)
+        }
+
+        m.async_inst_fn("add_url", domain_add_url).unwrap();
+
+        async fn domain_add_url_cached(
+            mut domain: Domain,
+            url: &str,
+            cache: &str,
+        ) -> Result<Domain, ScriptError> {
+            domain.add_url(url, Some(cache)).await?;
+            Ok(domain)
+        }
+
+        m.async_inst_fn("add_url_cached", domain_add_url_cached)
+            .unwrap();
+
+        m.function(
+            &["Domain", "load"],
+            |path: &str| -> Result<Domain, ScriptError> { Ok(Domain::load(path)?) },
+        )
+        .unwrap();
+        m.inst_fn(
+            "save",
+            |domain: Domain, path: &str| -> Result<Domain, ScriptError> {
+                domain.save(path)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("len", |domain: &Domain| -> i64 { domain.len() as i64 })
+            .unwrap();
+        m.inst_fn("is_empty", |domain: &Domain| -> bool { domain.is_empty() })
+            .unwrap();
+
+        m.inst_fn("seal", |domain: Domain| -> SealedDomain {
+            SealedDomain(Swappable::new(domain))
+        })
+        .unwrap();
+
+        m.inst_fn("swap", |domain: &SealedDomain, other: &SealedDomain| {
+            domain.0.swap(&other.0)
+        })
+        .unwrap();
+
+        m.inst_fn("contains", |domain: &SealedDomain, qname: &Dname| -> bool {
+            domain.0.get().contains(&qname.into())
+        })
+        .unwrap();
+        m.inst_fn("len", |domain: &SealedDomain| -> i64 {
+            domain.0.get().len() as i64
+        })
+        .unwrap();
+        m.inst_fn("is_empty", |domain: &SealedDomain| -> bool {
+            domain.0.get().is_empty()
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "contains_cname",
+            |domain: &SealedDomain, msg: &Message| -> Result<bool, ScriptError> {
+                Ok(domain.0.get().contains_cname(&msg.into())?)
+            },
+        )
+        .unwrap();
+    }
+
+    // Tagged domain list
+    {
+        m.ty::<TaggedDomain>().unwrap();
+        m.ty::<SealedTaggedDomain>().unwrap();
+
+        m.function(&["TaggedDomain", "new"], TaggedDomain::new)
+            .unwrap();
+        m.inst_fn(
+            "add_qname",
+            |mut domain: TaggedDomain,
+             qname: &str,
+             tag: &str|
+             -> Result<TaggedDomain, ScriptError> {
+                domain.add_qname(qname, tag)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_file",
+            |mut domain: TaggedDomain, path: &str| -> Result<TaggedDomain, ScriptError> {
+                domain.add_file(path)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |domain: TaggedDomain| -> SealedTaggedDomain {
+            SealedTaggedDomain(Arc::new(domain))
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "tags",
+            |domain: &SealedTaggedDomain, qname: &Dname| -> Vec<String> {
+                domain.0.tags(&qname.into())
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "contains",
+            |domain: &SealedTaggedDomain, qname: &Dname, tag: &str| -> bool {
+                domain.0.contains(&qname.into(), tag)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "contains_any",
+            |domain: &SealedTaggedDomain, qname: &Dname, tags: Vec<String>| -> bool {
+                domain.0.contains_any(&qname.into(), &tags)
+            },
+        )
+        .unwrap();
+    }
+
+    // Question name rewrite
+    {
+        m.ty::<Rewrite>().unwrap();
+        m.ty::<SealedRewrite>().unwrap();
+
+        m.function(&["Rewrite", "new"], Rewrite::new).unwrap();
+        m.inst_fn(
+            "add_exact",
+            |mut rewrite: Rewrite, from: &str, to: &str| -> Result<Rewrite, ScriptError> {
+                rewrite.add_exact(from, to)?;
+                Ok(rewrite)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_suffix",
+            |mut rewrite: Rewrite, from: &str, to: &str| -> Result<Rewrite, ScriptError> {
+                rewrite.add_suffix(from, to)?;
+                Ok(rewrite)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |rewrite: Rewrite| -> SealedRewrite {
+            SealedRewrite(Arc::new(rewrite))
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "map",
+            |rewrite: &SealedRewrite, qname: &Dname| -> Result<Option<Dname>, ScriptError> {
+                Ok(rewrite.0.map(&qname.into())?.map(Dname::from))
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "rewrite",
+            |rewrite: &SealedRewrite, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(rewrite.0.rewrite(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "restore",
+            |rewrite: &SealedRewrite, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(rewrite.0.restore(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+    }
 
-#[derive(rune::Any, Clone)]
-pub enum Utils {
-    #[rune(constructor)]
-    Domain(#[rune(get)] SealedDomain),
-    #[rune(constructor)]
-    GeoIp(#[rune(get)] SealedGeoIp),
-    #[rune(constructor)]
-    IpCidr(#[rune(get)] SealedIpCidr),
-    #[rune(constructor)]
-    Hosts(#[rune(get)] SealedHosts),
-}
+    // Answer address remap
+    {
+        m.ty::<IpRemap>().unwrap();
+        m.ty::<SealedIpRemap>().unwrap();
 
-#[derive(rune::Any, Clone)]
-pub struct SealedDomain(Arc<Domain>);
+        m.function(&["IpRemap", "new"], IpRemap::new).unwrap();
+        m.inst_fn(
+            "add",
+            |mut remap: IpRemap, from: &str, to: &str| -> Result<IpRemap, ScriptError> {
+                remap.add(from, to)?;
+                Ok(remap)
+            },
+        )
+        .unwrap();
 
-#[derive(rune::Any, Clone)]
-pub struct SealedHosts(Arc<Hosts>);
+        m.inst_fn("seal", |remap: IpRemap| -> SealedIpRemap {
+            SealedIpRemap(Arc::new(remap))
+        })
+        .unwrap();
 
-#[derive(rune::Any, Clone)]
-pub struct SealedGeoIp(Arc<GeoIp>);
+        m.inst_fn(
+            "get",
+            |remap: &SealedIpRemap, ip: &IpAddr| -> Option<IpAddr> {
+                remap.0.get(ip.into()).map(IpAddr::from)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "remap",
+            |remap: &SealedIpRemap, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(remap.0.remap(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+    }
 
-#[derive(rune::Any, Clone)]
-pub struct SealedIpCidr(Arc<IpCidr>);
+    // Query log
+    {
+        m.ty::<QueryLog>().unwrap();
+        m.ty::<SealedQueryLog>().unwrap();
 
-pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
-    let mut m = Module::new();
+        m.function(
+            &["QueryLog", "open"],
+            |path: &str| -> Result<QueryLog, ScriptError> { Ok(QueryLog::open(path)?) },
+        )
+        .unwrap();
 
-    m.ty::<Utils>().unwrap();
+        m.inst_fn("seal", |log: QueryLog| -> SealedQueryLog {
+            SealedQueryLog(Arc::new(log))
+        })
+        .unwrap();
 
-    // Blackhole
+        m.inst_fn(
+            "write",
+            |log: &SealedQueryLog,
+             ctx: &QueryContext,
+             query: &Message,
+             resp: &Message,
+             branch: &str|
+             -> Result<(), ScriptError> {
+                log.0.write(ctx, &query.into(), &resp.into(), branch)?;
+                Ok(())
+            },
+        )
+        .unwrap();
+    }
+
+    // Counter
     {
-        m.function(
-            &["blackhole"],
-            |msg: &Message| -> Result<Message, ScriptError> { Ok(blackhole(&msg.into())?.into()) },
+        m.ty::<Counter>().unwrap();
+        m.ty::<SealedCounter>().unwrap();
+
+        m.function(&["Counter", "new"], |max_entries: i64| {
+            Counter::new(max_entries.max(0) as usize)
+        })
+        .unwrap();
+
+        m.inst_fn("seal", |counter: Counter| -> SealedCounter {
+            SealedCounter(Arc::new(counter))
+        })
+        .unwrap();
+
+        m.inst_fn("inc", |counter: &SealedCounter, qname: &Dname| {
+            counter.0.inc(&qname.into())
+        })
+        .unwrap();
+        m.inst_fn("get", |counter: &SealedCounter, qname: &Dname| -> i64 {
+            counter.0.get(&qname.into()) as i64
+        })
+        .unwrap();
+        m.inst_fn(
+            "top",
+            |counter: &SealedCounter, n: i64| -> Vec<(Dname, i64)> {
+                counter
+                    .0
+                    .top(n.max(0) as usize)
+                    .into_iter()
+                    .map(|(qname, count)| (qname.into(), count as i64))
+                    .collect()
+            },
         )
         .unwrap();
+        m.inst_fn("len", |counter: &SealedCounter| -> i64 {
+            counter.0.len() as i64
+        })
+        .unwrap();
+        m.inst_fn("reset", |counter: &SealedCounter| counter.0.reset())
+            .unwrap();
     }
 
-    // Fast Answer
+    // Static answer
     {
-        m.function(
-            &["fast_answer"],
-            |msg: &Message, a: i64, b: i64, c: i64, d: i64| -> Result<Message, ScriptError> {
-                Ok(fast_answer(&msg.into(), a as u8, b as u8, c as u8, d as u8)?.into())
+        m.ty::<StaticAnswer>().unwrap();
+        m.ty::<SealedStaticAnswer>().unwrap();
+
+        m.function(&["StaticAnswer", "new"], StaticAnswer::new)
+            .unwrap();
+        m.inst_fn(
+            "add_ip",
+            |mut answer: StaticAnswer, ip: &str, ttl: i64| -> Result<StaticAnswer, ScriptError> {
+                answer.add_ip(ip, to_ttl(ttl)?)?;
+                Ok(answer)
             },
         )
         .unwrap();
-        m.function(
-            &["fast_answer_ip"],
-            |msg: &Message, ip: IpAddr| -> Result<Message, ScriptError> {
-                Ok(fast_answer_ip(&msg.into(), ip.into())?.into())
+        m.inst_fn(
+            "add_txt",
+            |mut answer: StaticAnswer, text: &str, ttl: i64| -> Result<StaticAnswer, ScriptError> {
+                answer.add_txt(text, to_ttl(ttl)?)?;
+                Ok(answer)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_cname",
+            |mut answer: StaticAnswer,
+             target: &str,
+             ttl: i64|
+             -> Result<StaticAnswer, ScriptError> {
+                answer.add_cname(target, to_ttl(ttl)?)?;
+                Ok(answer)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |answer: StaticAnswer| -> SealedStaticAnswer {
+            SealedStaticAnswer(Arc::new(answer))
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "answer",
+            |answer: &SealedStaticAnswer, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(answer.0.answer(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "append",
+            |answer: &SealedStaticAnswer, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(answer.0.append(&msg.into())?.into())
             },
         )
         .unwrap();
     }
 
-    // Domain list
+    // Negative answer
     {
-        m.ty::<Domain>().unwrap();
-        m.ty::<SealedDomain>().unwrap();
+        m.ty::<NegativeAnswer>().unwrap();
+        m.ty::<SealedNegativeAnswer>().unwrap();
+
+        m.function(
+            &["NegativeAnswer", "new"],
+            |mname: &str, rname: &str, ttl: i64| -> Result<NegativeAnswer, ScriptError> {
+                Ok(NegativeAnswer::new(mname, rname, to_ttl(ttl)?)?)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |answer: NegativeAnswer| -> SealedNegativeAnswer {
+            SealedNegativeAnswer(Arc::new(answer))
+        })
+        .unwrap();
 
-        m.function(&["Domain", "new"], Domain::new).unwrap();
         m.inst_fn(
-            "add_qname",
-            |mut domain: Domain, qname: &str| -> Result<Domain, ScriptError> {
-                domain.add_qname(qname)?;
-                Ok(domain)
+            "nxdomain",
+            |answer: &SealedNegativeAnswer, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(answer.0.nxdomain(&msg.into())?.into())
             },
         )
         .unwrap();
         m.inst_fn(
-            "add_file",
-            |mut domain: Domain, path: &str| -> Result<Domain, ScriptError> {
-                domain.add_file(path)?;
-                Ok(domain)
+            "nodata",
+            |answer: &SealedNegativeAnswer, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(answer.0.nodata(&msg.into())?.into())
             },
         )
         .unwrap();
+    }
 
-        m.inst_fn("seal", |domain: Domain| -> SealedDomain {
-            SealedDomain(Arc::new(domain))
+    // Response policy zone
+    {
+        m.ty::<Rpz>().unwrap();
+        m.ty::<SealedRpz>().unwrap();
+
+        m.function(
+            &["Rpz", "new"],
+            |origin: &str, text: &str| -> Result<Rpz, ScriptError> { Ok(Rpz::new(origin, text)?) },
+        )
+        .unwrap();
+        m.function(
+            &["Rpz", "from_file"],
+            |origin: &str, path: &str| -> Result<Rpz, ScriptError> {
+                Ok(Rpz::from_file(origin, path)?)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |rpz: Rpz| -> SealedRpz {
+            SealedRpz(Swappable::new(rpz))
         })
         .unwrap();
 
-        m.inst_fn("contains", |domain: &SealedDomain, qname: &Dname| -> bool {
-            domain.0.contains(&qname.into())
+        m.inst_fn("swap", |rpz: &SealedRpz, other: &SealedRpz| {
+            rpz.0.swap(&other.0)
         })
         .unwrap();
+
+        m.inst_fn("matches", |rpz: &SealedRpz, qname: &Dname| -> bool {
+            rpz.0.get().matches(&qname.into())
+        })
+        .unwrap();
+        m.inst_fn(
+            "apply",
+            |rpz: &SealedRpz, msg: &Message| -> Result<Option<Message>, ScriptError> {
+                match rpz.0.get().apply(&msg.into())? {
+                    Verdict::Pass => Ok(None),
+                    Verdict::Respond(resp) => Ok(Some(resp.into())),
+                    Verdict::Drop => Err(ScriptError::Dropped),
+                }
+            },
+        )
+        .unwrap();
     }
 
     // Hosts list
@@ -120,35 +985,90 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         m.function(&["Hosts", "new"], Hosts::new).unwrap();
         m.inst_fn(
             "add_host",
-            |mut hosts: Hosts, host: &str, ip: &str, is_server: bool| -> Result<Hosts, ScriptError> {
+            |mut hosts: Hosts,
+             host: &str,
+             ip: &str,
+             is_server: bool|
+             -> Result<Hosts, ScriptError> {
                 hosts.add_host(host, ip, is_server)?;
                 Ok(hosts)
             },
         )
         .unwrap();
 
+        async fn hosts_add_file(mut hosts: Hosts, path: &str) -> Result<Hosts, ScriptError> {
+            hosts.add_file(path).await?;
+            Ok(hosts)
+        }
+
+        m.async_inst_fn("add_file", hosts_add_file).unwrap();
+
         m.inst_fn(
-            "add_file",
-            |mut hosts: Hosts, path: &str| -> Result<Hosts, ScriptError> {
-                hosts.add_file(path)?;
+            "add_dnsmasq",
+            |mut hosts: Hosts, s: &str| -> Result<Hosts, ScriptError> {
+                hosts.add_dnsmasq(s)?;
                 Ok(hosts)
             },
         )
         .unwrap();
 
+        async fn hosts_add_dnsmasq_file(
+            mut hosts: Hosts,
+            path: &str,
+        ) -> Result<Hosts, ScriptError> {
+            hosts.add_dnsmasq_file(path).await?;
+            Ok(hosts)
+        }
+
+        m.async_inst_fn("add_dnsmasq_file", hosts_add_dnsmasq_file)
+            .unwrap();
+
+        m.inst_fn("len", |hosts: &Hosts| -> i64 { hosts.len() as i64 })
+            .unwrap();
+        m.inst_fn("is_empty", |hosts: &Hosts| -> bool { hosts.is_empty() })
+            .unwrap();
+
         m.inst_fn("seal", |hosts: Hosts| -> SealedHosts {
-            SealedHosts(Arc::new(hosts))
+            SealedHosts(Swappable::new(hosts))
         })
         .unwrap();
 
-        m.inst_fn("reslove", |hosts: &SealedHosts, qname: &Dname| -> Option<IpAddr> {
-            let ip = hosts.0.reslove(&qname.into());
-            match ip {
-                None => None,
-                Some(v) => Some(v.into()),
-            }
+        m.inst_fn("swap", |hosts: &SealedHosts, other: &SealedHosts| {
+            hosts.0.swap(&other.0)
+        })
+        .unwrap();
+        m.inst_fn("len", |hosts: &SealedHosts| -> i64 {
+            hosts.0.get().len() as i64
         })
         .unwrap();
+        m.inst_fn("is_empty", |hosts: &SealedHosts| -> bool {
+            hosts.0.get().is_empty()
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "reslove",
+            |hosts: &SealedHosts, qname: &Dname| -> Option<IpAddr> {
+                let ip = hosts.0.get().reslove(&qname.into());
+                match ip {
+                    None => None,
+                    Some(v) => Some(v.into()),
+                }
+            },
+        )
+        .unwrap();
+
+        m.inst_fn(
+            "lookup",
+            |hosts: &SealedHosts, qname: &Dname, qtype: &Rtype| -> Option<IpAddr> {
+                hosts
+                    .0
+                    .get()
+                    .lookup(&qname.into(), qtype.into())
+                    .map(IpAddr::from)
+            },
+        )
+        .unwrap();
     }
 
     // GeoIP
@@ -159,25 +1079,119 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         m.function(
             &["GeoIp", "create_default"],
             || -> Result<SealedGeoIp, ScriptError> {
-                Ok(SealedGeoIp(Arc::new(GeoIp::create_default()?)))
+                Ok(SealedGeoIp(Swappable::new(GeoIp::create_default()?)))
             },
         )
         .unwrap();
 
         async fn geoip_from_path(path: &str) -> Result<SealedGeoIp, ScriptError> {
-            Ok(SealedGeoIp(Arc::new(GeoIp::from_path(path).await?)))
+            Ok(SealedGeoIp(Swappable::new(GeoIp::from_path(path).await?)))
         }
 
         m.async_function(&["GeoIp", "from_path"], geoip_from_path)
             .unwrap();
 
+        m.inst_fn("swap", |geoip: &SealedGeoIp, other: &SealedGeoIp| {
+            geoip.0.swap(&other.0)
+        })
+        .unwrap();
+
         m.inst_fn(
             "contains",
             |geoip: &SealedGeoIp, ip: &IpAddr, code: &str| -> bool {
-                geoip.0.contains(ip.into(), code)
+                geoip.0.get().contains(ip.into(), code)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "country",
+            |geoip: &SealedGeoIp, ip: &IpAddr| -> Option<String> {
+                geoip.0.get().country(ip.into())
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "continent",
+            |geoip: &SealedGeoIp, ip: &IpAddr| -> Option<String> {
+                geoip.0.get().continent(ip.into())
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "city",
+            |geoip: &SealedGeoIp, ip: &IpAddr| -> Option<String> { geoip.0.get().city(ip.into()) },
+        )
+        .unwrap();
+        m.inst_fn("asn", |geoip: &SealedGeoIp, ip: &IpAddr| -> Option<i64> {
+            geoip.0.get().asn(ip.into()).map(i64::from)
+        })
+        .unwrap();
+    }
+
+    // GeoSite
+    {
+        m.ty::<GeoSite>().unwrap();
+        m.ty::<SealedGeoSite>().unwrap();
+
+        m.function(&["GeoSite", "new"], GeoSite::new).unwrap();
+        m.inst_fn(
+            "add_file",
+            |mut site: GeoSite, path: &str, tag: &str| -> Result<GeoSite, ScriptError> {
+                site.add_file(path, tag)?;
+                Ok(site)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |site: GeoSite| -> SealedGeoSite {
+            SealedGeoSite(Swappable::new(site))
+        })
+        .unwrap();
+
+        m.inst_fn("swap", |site: &SealedGeoSite, other: &SealedGeoSite| {
+            site.0.swap(&other.0)
+        })
+        .unwrap();
+
+        m.inst_fn("contains", |site: &SealedGeoSite, qname: &Dname| -> bool {
+            site.0.get().contains(&qname.into())
+        })
+        .unwrap();
+    }
+
+    // ASN
+    {
+        m.ty::<Asn>().unwrap();
+        m.ty::<SealedAsn>().unwrap();
+
+        async fn asn_from_path(path: &str) -> Result<Asn, ScriptError> {
+            Ok(Asn::from_path(path).await?)
+        }
+
+        m.async_function(&["Asn", "from_path"], asn_from_path)
+            .unwrap();
+
+        m.inst_fn(
+            "add_asn",
+            |mut asn: Asn, n: i64| -> Result<Asn, ScriptError> {
+                asn.add_asn(u32::try_from(n).map_err(|_| UtilsError::InvalidAsn(n))?);
+                Ok(asn)
             },
         )
         .unwrap();
+
+        m.inst_fn("seal", |asn: Asn| -> SealedAsn { SealedAsn(Arc::new(asn)) })
+            .unwrap();
+
+        m.inst_fn("lookup", |asn: &SealedAsn, ip: &IpAddr| -> Option<i64> {
+            asn.0.lookup(ip.into()).map(i64::from)
+        })
+        .unwrap();
+
+        m.inst_fn("contains", |asn: &SealedAsn, ip: &IpAddr| -> bool {
+            asn.0.contains(ip.into())
+        })
+        .unwrap();
     }
 
     // IP CIDR
@@ -194,16 +1208,85 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
             },
         )
         .unwrap();
+        m.inst_fn(
+            "add_cidr",
+            |mut ipcidr: IpCidr, cidr: &str| -> Result<IpCidr, ScriptError> {
+                ipcidr.add_cidr(cidr)?;
+                Ok(ipcidr)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_str",
+            |mut ipcidr: IpCidr, data: &str| -> Result<IpCidr, ScriptError> {
+                ipcidr.add_str(data)?;
+                Ok(ipcidr)
+            },
+        )
+        .unwrap();
 
         m.inst_fn("seal", |cidr: IpCidr| -> SealedIpCidr {
-            SealedIpCidr(Arc::new(cidr))
+            SealedIpCidr(Swappable::new(cidr))
+        })
+        .unwrap();
+
+        m.inst_fn("swap", |ipcidr: &SealedIpCidr, other: &SealedIpCidr| {
+            ipcidr.0.swap(&other.0)
         })
         .unwrap();
 
         m.inst_fn("contains", |ipcidr: &SealedIpCidr, ip: &IpAddr| -> bool {
-            ipcidr.0.contains(ip.into())
+            ipcidr.0.get().contains(ip.into())
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "contains_ptr",
+            |ipcidr: &SealedIpCidr, qname: &Dname| -> bool {
+                ipcidr.0.get().contains_ptr(&qname.into())
+            },
+        )
+        .unwrap();
+
+        m.function(&["ptr_to_ip"], |qname: &Dname| -> Option<IpAddr> {
+            ptr_to_ip(&qname.into()).map(IpAddr::from)
+        })
+        .unwrap();
+    }
+
+    // Scheduled task
+    {
+        m.ty::<Scheduled>().unwrap();
+
+        async fn scheduled_new(task: Function, interval: i64) -> Result<Scheduled, ScriptError> {
+            Scheduled::new(task, Duration::from_secs(interval.max(1) as u64)).await
+        }
+
+        m.async_function(&["Scheduled", "new"], scheduled_new)
+            .unwrap();
+        m.inst_fn("get", Scheduled::get).unwrap();
+    }
+
+    // Regular expression
+    {
+        m.ty::<Regex>().unwrap();
+
+        m.function(
+            &["Regex", "new"],
+            |pattern: &str| -> Result<Regex, ScriptError> { Ok(Regex::new(pattern)?) },
+        )
+        .unwrap();
+
+        m.inst_fn("is_match", |regex: &Regex, text: &str| -> bool {
+            regex.is_match(text)
         })
         .unwrap();
+
+        m.inst_fn(
+            "captures",
+            |regex: &Regex, text: &str| -> Option<Vec<Option<String>>> { regex.captures(text) },
+        )
+        .unwrap();
     }
 
     m
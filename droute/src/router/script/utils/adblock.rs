@@ -0,0 +1,90 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Parser of filter lists in the AdBlock syntax (as used by AdBlock Plus, uBlock Origin and AdGuard), in which nearly every public blocklist is published.
+// Only the rules on whole domains are meaningful to a DNS server, e.g. `||example.com^`, the others are skipped.
+
+use super::domain::{parse_domain, Rule};
+
+// Options which don't restrict the rules, as opposed to e.g. `third-party` or `script`, which apply to some requests only.
+const NEUTRAL_OPTIONS: [&str; 2] = ["important", "all"];
+
+// Parse a line of the filter list into the domain and whether it is an exception (prefixed with `@@`), skipping comments and rules not on whole domains.
+pub(super) fn adblock_rule(line: &str) -> Option<Rule> {
+    let line = line.trim();
+    let (line, excluded) = match line.strip_prefix("@@") {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+    // Comments (`!`), the header (`[Adblock Plus 2.0]`) and element hiding rules (`##`) never start with `||`.
+    let line = line.strip_prefix("||")?;
+    let line = match line.split_once('$') {
+        Some((line, options)) => {
+            if !options.split(',').all(|o| NEUTRAL_OPTIONS.contains(&o)) {
+                return None;
+            }
+            line
+        }
+        None => line,
+    };
+    let line = line.strip_suffix('|').unwrap_or(line);
+    // The separator `^` ends the domain. Rules without it match any domain starting with the text, e.g. `||example.com` matches `example.community`.
+    let domain = line.strip_suffix('^')?;
+    Some(parse_domain(domain)?.map(|domain| (domain, excluded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::adblock_rule;
+
+    fn rule(line: &str) -> Option<(String, bool)> {
+        adblock_rule(line).map(|r| {
+            let (domain, excluded) = r.unwrap();
+            (domain.to_string(), excluded)
+        })
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(
+            rule("||ads.example.com^"),
+            Some(("ads.example.com".to_string(), false))
+        );
+        assert_eq!(
+            rule("@@||www.example.com^"),
+            Some(("www.example.com".to_string(), true))
+        );
+        assert_eq!(
+            rule("||example.com^$important"),
+            Some(("example.com".to_string(), false))
+        );
+        assert_eq!(
+            rule("||example.com^|"),
+            Some(("example.com".to_string(), false))
+        );
+        assert_eq!(
+            rule("||*.example.net^"),
+            Some(("*.example.net".to_string(), false))
+        );
+
+        assert_eq!(rule("! Title: EasyList"), None);
+        assert_eq!(rule("[Adblock Plus 2.0]"), None);
+        assert_eq!(rule("example.com##.banner"), None);
+        assert_eq!(rule("||example.com/ads/*"), None);
+        assert_eq!(rule("||example.com"), None);
+        assert_eq!(rule("||example.com^$third-party"), None);
+        assert_eq!(rule("/banner\\d+/"), None);
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use log::trace;
+use maxminddb::{geoip2, Reader};
+use std::{collections::HashSet, net::IpAddr, path::PathBuf, str::FromStr, sync::Arc};
+
+/// A matcher that matches if the IP address is announced by any of the autonomous systems in the set, using an ASN database like GeoLite2-ASN.
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct Asn {
+    db: Arc<Reader<Vec<u8>>>,
+    set: HashSet<u32>,
+}
+
+impl Asn {
+    /// Create an ASN matcher with an empty set from the database file with the given path
+    pub async fn from_path(path: impl AsRef<str>) -> Result<Self> {
+        // Per std documentation, this is infallible
+        let buf: Vec<u8> = tokio::fs::read(PathBuf::from_str(path.as_ref()).unwrap()).await?;
+        Ok(Self {
+            db: Arc::new(Reader::from_source(buf)?),
+            set: HashSet::new(),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn from_buf(buf: Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            db: Arc::new(Reader::from_source(buf)?),
+            set: HashSet::new(),
+        })
+    }
+
+    /// Add an autonomous system number to the set
+    pub fn add_asn(&mut self, asn: u32) {
+        self.set.insert(asn);
+    }
+
+    /// Get the number of the autonomous system that announces the given IP address
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        self.db
+            .lookup::<geoip2::Asn>(ip)
+            .ok()
+            .and_then(|r| r.autonomous_system_number)
+    }
+
+    /// Whether the IP address is announced by any of the autonomous systems in the set
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.lookup(ip)
+            .map(|n| {
+                trace!("IP `{}` belongs to AS{}", ip, n);
+                self.set.contains(&n)
+            })
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Asn;
+    use once_cell::sync::Lazy;
+
+    // Starting from droute's crate root. It only maps 1.1.1.0/24 to AS13335 and 8.8.8.0/24 to AS15169.
+    static DB: Lazy<Vec<u8>> =
+        Lazy::new(|| include_bytes!("../../../../../data/asn.mmdb").to_vec());
+
+    #[test]
+    fn lookup() {
+        let asn = Asn::from_buf(DB.clone()).unwrap();
+        assert_eq!(asn.lookup("1.1.1.1".parse().unwrap()), Some(13335));
+        assert_eq!(asn.lookup("8.8.8.8".parse().unwrap()), Some(15169));
+        assert_eq!(asn.lookup("9.9.9.9".parse().unwrap()), None);
+        assert_eq!(asn.lookup("2606:4700::1111".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn contains() {
+        let mut asn = Asn::from_buf(DB.clone()).unwrap();
+        asn.add_asn(13335);
+        assert!(asn.contains("1.1.1.1".parse().unwrap()));
+        assert!(!asn.contains("8.8.8.8".parse().unwrap()));
+        assert!(!asn.contains("9.9.9.9".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn from_path() {
+        let asn = Asn::from_path("../data/asn.mmdb").await.unwrap();
+        assert_eq!(asn.lookup("1.1.1.1".parse().unwrap()), Some(13335));
+    }
+}
@@ -17,14 +17,17 @@ use super::Result;
 use crate::MAX_TTL;
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{Dname, Message, MessageBuilder},
-    rdata::Soa,
+    base::{
+        iana::{Class, Rcode},
+        Dname, Message, MessageBuilder, Rtype,
+    },
+    rdata::{Aaaa, Soa, A},
 };
 use once_cell::sync::Lazy;
 use std::str::FromStr;
 
 // Data from smartdns. https://github.com/pymumu/smartdns/blob/42b3e98b2a3ca90ea548f8cb5ed19a3da6011b74/src/dns_server.c#L651
-static SOA_RDATA: Lazy<(Dname<Bytes>, u32, Soa<Dname<Bytes>>)> = Lazy::new(|| {
+pub(super) static SOA_RDATA: Lazy<(Dname<Bytes>, u32, Soa<Dname<Bytes>>)> = Lazy::new(|| {
     (
         Dname::root_bytes(),
         MAX_TTL,
@@ -51,3 +54,121 @@ pub fn blackhole(query: &Message<Bytes>) -> Result<Message<Bytes>> {
 
     Ok(builder.into_message())
 }
+
+/// Create a response with the given rcode, e.g. NXDOMAIN for ad-blocking or REFUSED for abusive clients, carrying a SOA record in the authority section so that the requestor caches it for `ttl` seconds.
+pub fn blackhole_with(query: &Message<Bytes>, rcode: Rcode, ttl: u32) -> Result<Message<Bytes>> {
+    let (name, _, soa) = SOA_RDATA.clone();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(50))?
+        .start_answer(query, rcode)?
+        .authority();
+
+    // Per RFC 2308, the negative caching TTL is the minimum of the TTL of SOA record and its MINIMUM field.
+    builder.push((
+        name,
+        ttl,
+        Soa::new(
+            soa.mname().clone(),
+            soa.rname().clone(),
+            soa.serial(),
+            soa.refresh(),
+            soa.retry(),
+            soa.expire(),
+            ttl,
+        ),
+    ))?;
+
+    Ok(builder.into_message())
+}
+
+// Create a negative response carrying the SOA record in the authority section so that it can be cached.
+fn negative(query: &Message<Bytes>, rcode: Rcode) -> Result<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(50))?
+        .start_answer(query, rcode)?
+        .authority();
+
+    builder.push(SOA_RDATA.clone())?;
+
+    Ok(builder.into_message())
+}
+
+/// Create a NXDOMAIN response, which tells the requestor that the domain doesn't exist.
+pub fn nxdomain(query: &Message<Bytes>) -> Result<Message<Bytes>> {
+    negative(query, Rcode::NXDomain)
+}
+
+/// Create a REFUSED response, which tells the requestor that the query is refused by policy.
+pub fn refused(query: &Message<Bytes>) -> Result<Message<Bytes>> {
+    Ok(MessageBuilder::from_target(BytesMut::with_capacity(50))?
+        .start_answer(query, Rcode::Refused)?
+        .into_message())
+}
+
+/// Create a response that answers A queries with `0.0.0.0` and AAAA queries with `::`, and all other queries with no data.
+pub fn null_answer(query: &Message<Bytes>) -> Result<Message<Bytes>> {
+    let question = match query.first_question() {
+        Some(q) => q,
+        None => return negative(query, Rcode::NoError),
+    };
+
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(50))?
+        .start_answer(query, Rcode::NoError)?;
+    match question.qtype() {
+        Rtype::A => builder.push((
+            question.qname(),
+            Class::In,
+            SOA_RDATA.1,
+            A::from_octets(0, 0, 0, 0),
+        ))?,
+        Rtype::Aaaa => builder.push((
+            question.qname(),
+            Class::In,
+            SOA_RDATA.1,
+            Aaaa::new(std::net::Ipv6Addr::UNSPECIFIED),
+        ))?,
+        _ => return negative(query, Rcode::NoError),
+    }
+
+    Ok(builder.into_message())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::blackhole_with;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, MessageBuilder, ParsedDname, Rtype},
+        rdata::Soa,
+    };
+    use std::str::FromStr;
+
+    #[test]
+    fn rcode_and_ttl() {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((
+                Dname::<Bytes>::from_str("ads.example.com").unwrap(),
+                Rtype::A,
+            ))
+            .unwrap();
+        let query = builder.into_message();
+
+        let resp = blackhole_with(&query, Rcode::NXDomain, 300).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert_eq!(resp.header_counts().ancount(), 0);
+
+        let soa = resp
+            .authority()
+            .unwrap()
+            .limit_to::<Soa<ParsedDname<&Bytes>>>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(soa.ttl(), 300);
+        assert_eq!(soa.data().minimum(), 300);
+
+        let resp = blackhole_with(&query, Rcode::Refused, 60).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::Refused);
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bytes::Bytes;
+use domain::base::Dname;
+use std::{collections::HashMap, sync::Mutex};
+
+/// Query counts per domain, e.g. to find the domains queried the most.
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct Counter {
+    counts: Mutex<HashMap<Dname<Bytes>, u64>>,
+    max_entries: usize,
+}
+
+impl Counter {
+    /// Create an empty counter which keeps counts of at most `max_entries` domains. Domains not counted yet are ignored once it is full, so that queries on random names cannot exhaust the memory.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            counts: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    /// Increase the count of the domain by one. Domains are compared case-insensitively.
+    pub fn inc(&self, qname: &Dname<Bytes>) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(qname) {
+            *count += 1;
+        } else if counts.len() < self.max_entries {
+            counts.insert(qname.clone(), 1);
+        }
+    }
+
+    /// The count of the domain.
+    pub fn get(&self, qname: &Dname<Bytes>) -> u64 {
+        self.counts.lock().unwrap().get(qname).copied().unwrap_or(0)
+    }
+
+    /// The `n` domains counted the most along with their counts, in descending order of the counts.
+    pub fn top(&self, n: usize) -> Vec<(Dname<Bytes>, u64)> {
+        let mut counts: Vec<_> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(qname, count)| (qname.clone(), *count))
+            .collect();
+        // Ties are broken by the names to keep the order stable.
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+
+    /// The number of domains counted.
+    pub fn len(&self) -> usize {
+        self.counts.lock().unwrap().len()
+    }
+
+    /// Whether no domain is counted.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all the counts, e.g. at the start of a new day.
+    pub fn reset(&self) {
+        self.counts.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Counter;
+    use bytes::Bytes;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    fn dname(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn counts() {
+        let counter = Counter::new(2);
+        counter.inc(&dname("a.com"));
+        counter.inc(&dname("b.com"));
+        counter.inc(&dname("B.com"));
+        // Full
+        counter.inc(&dname("c.com"));
+
+        assert_eq!(counter.len(), 2);
+        assert_eq!(counter.get(&dname("b.com")), 2);
+        assert_eq!(counter.get(&dname("c.com")), 0);
+        assert_eq!(counter.top(1), vec![(dname("b.com"), 2)]);
+
+        counter.reset();
+        assert!(counter.is_empty());
+        counter.inc(&dname("c.com"));
+        assert_eq!(counter.get(&dname("c.com")), 1);
+    }
+}
@@ -13,30 +13,49 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::Result;
+use super::{adblock::adblock_rule, cname_chain, fetch::fetch, lines, Result};
 use bytes::Bytes;
-use dmatcher::domain::Domain as DomainAlg;
-use domain::base::{name::FromStrError, Dname};
-use std::{path::PathBuf, str::FromStr};
+use dmatcher::domain::{Domain as DomainAlg, RuleType};
+use domain::base::{name::FromStrError, Dname, Message};
+use std::{borrow::Cow, str::FromStr};
 
 /// The domain matcher
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub struct Domain(DomainAlg);
 
-fn into_dnames(list: &str) -> std::result::Result<Vec<Dname<Bytes>>, FromStrError> {
-    list.split('\n')
-        .filter(|&x| {
-            (!x.is_empty())
-                && (x.chars().all(|c| {
-                    char::is_ascii_alphabetic(&c)
-                        | char::is_ascii_digit(&c)
-                        | (c == '-')
-                        | (c == '.')
-                }))
+// A domain parsed from a list along with whether it is an exclusion.
+pub(super) type Rule = std::result::Result<(Dname<Bytes>, bool), FromStrError>;
+
+// Parse the domain, skipping those which are empty or contain characters not allowed.
+pub(super) fn parse_domain(s: &str) -> Option<std::result::Result<Dname<Bytes>, FromStrError>> {
+    // Internationalized domains are converted to their punycode form
+    let s = if s.is_ascii() {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(idna::domain_to_ascii(s).ok()?)
+    };
+    if s.is_empty()
+        || !s.chars().all(|c| {
+            char::is_ascii_alphabetic(&c)
+                | char::is_ascii_digit(&c)
+                | (c == '-')
+                | (c == '.')
+                | (c == '*')
         })
-        .map(Dname::from_str)
-        .collect()
+    {
+        return None;
+    }
+    Some(Dname::from_str(&s))
+}
+
+// Parse a line of the list into the domain and whether it is an exclusion (prefixed with `!`), skipping lines which are empty or not domains.
+fn into_rule(line: &str) -> Option<Rule> {
+    let (line, excluded) = match line.strip_prefix('!') {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+    Some(parse_domain(line)?.map(|domain| (domain, excluded)))
 }
 
 impl Default for Domain {
@@ -51,24 +70,124 @@ impl Domain {
         Self(DomainAlg::new())
     }
 
-    /// Add a question name to the domain matcher's list
+    fn insert_rule(&mut self, rule: Option<Rule>, exact: bool) -> Result<()> {
+        if let Some(rule) = rule {
+            match rule? {
+                (domain, true) => self.0.insert_excluded(&domain),
+                (domain, false) if exact => self.0.insert_exact(&domain),
+                (domain, false) => self.0.insert(&domain),
+            }
+        }
+        Ok(())
+    }
+
+    // Insert the domains in the file line by line as they are read, so that huge lists are never held in memory as a whole.
+    async fn insert_file(
+        &mut self,
+        path: impl AsRef<str>,
+        parse: fn(&str) -> Option<Rule>,
+        exact: bool,
+    ) -> Result<()> {
+        let rule_type = |excluded| match excluded {
+            true => RuleType::Excluded,
+            false if exact => RuleType::Exact,
+            false => RuleType::Suffix,
+        };
+        self.0
+            .extend_lines(lines::open(path).await?, |line| {
+                Ok(match parse(line) {
+                    Some(rule) => {
+                        let (domain, excluded) = rule?;
+                        Some((domain, rule_type(excluded)))
+                    }
+                    None => None,
+                })
+            })
+            .await
+    }
+
+    /// Add a question name to the domain matcher's list. Question names prefixed with `!` are excluded along with their subdomains instead.
     pub fn add_qname(&mut self, s: impl AsRef<str>) -> Result<()> {
-        self.0.insert_multi(&into_dnames(s.as_ref())?);
+        s.as_ref()
+            .split('\n')
+            .try_for_each(|line| self.insert_rule(into_rule(line), false))
+    }
+
+    /// Add a list of question names to the domain matcher's list
+    pub fn add_list<I, S>(&mut self, list: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for s in list {
+            self.add_qname(s)?;
+        }
         Ok(())
     }
 
+    /// Add a question name to the domain matcher's list as an exact rule, which doesn't cover subdomains
+    pub fn add_qname_exact(&mut self, s: impl AsRef<str>) -> Result<()> {
+        s.as_ref()
+            .split('\n')
+            .try_for_each(|line| self.insert_rule(into_rule(line), true))
+    }
+
     /// Add all question names in a file to the domain matcher's list
-    pub fn add_file(&mut self, path: impl AsRef<str>) -> Result<()> {
-        // from_str is Infallible
-        let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
-        let mut data = String::new();
-        file.read_to_string(&mut data)?;
-        self.0.insert_multi(&into_dnames(&data)?);
+    pub async fn add_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.insert_file(path, into_rule, false).await
+    }
+
+    /// Add all question names in a file to the domain matcher's list as exact rules, which don't cover subdomains
+    pub async fn add_file_exact(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.insert_file(path, into_rule, true).await
+    }
+
+    /// Add the domain rules of a filter list in the AdBlock syntax, e.g. `||example.com^` and the exception `@@||www.example.com^`. Rules of other kinds (e.g. on URLs or page elements) are skipped.
+    pub fn add_adblock(&mut self, s: impl AsRef<str>) -> Result<()> {
+        s.as_ref()
+            .lines()
+            .try_for_each(|line| self.insert_rule(adblock_rule(line), false))
+    }
+
+    /// Add the domain rules of a filter list file in the AdBlock syntax.
+    pub async fn add_adblock_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.insert_file(path, adblock_rule, false).await
+    }
+
+    /// Download question names from the URL and add them to the domain matcher's list.
+    /// If `cache` is given, the downloaded list is saved to that path and used in place of the URL whenever the URL is unreachable.
+    pub async fn add_url(&mut self, url: impl AsRef<str>, cache: Option<&str>) -> Result<()> {
+        self.add_qname(fetch(url.as_ref(), cache).await?)
+    }
+
+    /// Save the compiled matcher to the file at the given path, so that it can be loaded with `load` much faster than parsing the lists again.
+    pub fn save(&self, path: impl AsRef<str>) -> Result<()> {
+        std::fs::write(path.as_ref(), self.0.to_bytes())?;
         Ok(())
     }
 
+    /// Load the compiled matcher saved with `save`.
+    pub fn load(path: impl AsRef<str>) -> Result<Self> {
+        Ok(Self(DomainAlg::from_bytes(&std::fs::read(path.as_ref())?)?))
+    }
+
+    /// The number of rules in the matcher, e.g. to check that a list downloaded is not truncated.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the matcher has no rules.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     /// Check if the question name matches any in the matcher.
     pub fn contains(&self, qname: &Dname<Bytes>) -> bool {
         self.0.matches(qname)
     }
+
+    /// Check if any CNAME target in the answer section's chain (e.g. a tracker cloaked behind a first-party subdomain) matches any in the matcher.
+    pub fn contains_cname(&self, msg: &Message<Bytes>) -> Result<bool> {
+        Ok(cname_chain(msg)?.iter().any(|target| self.contains(target)))
+    }
 }
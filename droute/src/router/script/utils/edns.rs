@@ -0,0 +1,367 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    rebuild::{copy_head, copy_records},
+    Result,
+};
+use bytes::Bytes;
+use domain::{
+    base::{
+        iana::OptRcode,
+        opt::{AllOptData, ClientSubnet, Padding},
+        Message, Rtype,
+    },
+    rdata::AllRecordData,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// UDP payload size advertised when we have to create an OPT record, as recommended by DNS Flag Day 2020.
+const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+// EDNS parameters and options carried by the OPT record of a message
+#[derive(Clone)]
+pub(crate) struct Edns {
+    pub udp_payload_size: u16,
+    pub dnssec_ok: bool,
+    pub version: u8,
+    pub rcode: OptRcode,
+    pub options: Vec<AllOptData<Bytes>>,
+}
+
+impl Edns {
+    pub fn new() -> Self {
+        Self {
+            udp_payload_size: DEFAULT_UDP_PAYLOAD_SIZE,
+            dnssec_ok: false,
+            version: 0,
+            rcode: OptRcode::NoError,
+            options: Vec::new(),
+        }
+    }
+
+    // Read the EDNS parameters of the message, if it has an OPT record.
+    pub fn from_message(msg: &Message<Bytes>) -> Result<Option<Self>> {
+        Ok(match msg.opt() {
+            Some(opt) => Some(Self {
+                udp_payload_size: opt.udp_payload_size(),
+                dnssec_ok: opt.dnssec_ok(),
+                version: opt.version(),
+                rcode: opt.rcode(msg.header()),
+                options: opt
+                    .iter()
+                    .collect::<std::result::Result<Vec<AllOptData<Bytes>>, _>>()?,
+            }),
+            None => None,
+        })
+    }
+
+    // Read the EDNS parameters of the message, or the defaults if it has no OPT record.
+    pub fn from_message_or_new(msg: &Message<Bytes>) -> Result<Self> {
+        Ok(Self::from_message(msg)?.unwrap_or_else(Self::new))
+    }
+}
+
+// Rebuild the message with its OPT record replaced by the given EDNS parameters, or removed if `None` is given.
+pub(crate) fn set_edns(msg: &Message<Bytes>, edns: Option<&Edns>) -> Result<Message<Bytes>> {
+    let mut builder = copy_head(msg)?;
+    copy_records!(msg.answer()?, builder);
+
+    let mut builder = builder.authority();
+    copy_records!(msg.authority()?, builder);
+
+    // Per RFC 6891, there can only be one OPT record, which we put at the end.
+    let mut builder = builder.additional();
+    for item in msg.additional()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            if record.rtype() != Rtype::Opt {
+                builder.push(record)?;
+            }
+        }
+    }
+    if let Some(edns) = edns {
+        builder.opt(|opt| {
+            opt.set_udp_payload_size(edns.udp_payload_size);
+            opt.set_dnssec_ok(edns.dnssec_ok);
+            opt.set_version(edns.version);
+            opt.set_rcode(edns.rcode);
+            for option in &edns.options {
+                opt.push(option)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(builder.into_message())
+}
+
+// Pad the message with the given EDNS parameters to a multiple of the block size, replacing any existing padding.
+fn pad(msg: &Message<Bytes>, mut edns: Edns, block: usize) -> Result<Message<Bytes>> {
+    edns.options
+        .retain(|o| !matches!(o, AllOptData::Padding(_)));
+    // Padding option header (code and length) is 4 bytes long.
+    let len = set_edns(msg, Some(&edns))?.as_slice().len() + 4;
+    let padding = (block - len % block) % block;
+    edns.options
+        .push(AllOptData::Padding(Padding::new(padding as u16)));
+    set_edns(msg, Some(&edns))
+}
+
+/// Pad the query to a multiple of 128 bytes as recommended by RFC 8467, adding an OPT record if needed.
+pub fn pad_query(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    pad(msg, Edns::from_message_or_new(msg)?, 128)
+}
+
+/// Pad the response to a multiple of 468 bytes as recommended by RFC 8467.
+/// Responses without an OPT record are returned unchanged, as the client doesn't support EDNS.
+pub fn pad_response(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    match Edns::from_message(msg)? {
+        Some(edns) => pad(msg, edns, 468),
+        None => Ok(msg.clone()),
+    }
+}
+
+// Keep only the leading `prefix` bits of the address.
+fn truncate_ip(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+/// Attach an EDNS Client Subnet option carrying the network of the given IP address truncated to `prefix` bits, replacing any existing one.
+pub fn set_ecs(msg: &Message<Bytes>, ip: IpAddr, prefix: u8) -> Result<Message<Bytes>> {
+    let prefix = match ip {
+        IpAddr::V4(_) => prefix.min(32),
+        IpAddr::V6(_) => prefix.min(128),
+    };
+    let mut edns = Edns::from_message_or_new(msg)?;
+    edns.options
+        .retain(|o| !matches!(o, AllOptData::ClientSubnet(_)));
+    edns.options
+        .push(AllOptData::ClientSubnet(ClientSubnet::new(
+            prefix,
+            0,
+            truncate_ip(ip, prefix),
+        )));
+    set_edns(msg, Some(&edns))
+}
+
+/// Attach an EDNS Client Subnet option for the client's IP address, truncated to /24 for IPv4 and /56 for IPv6 to preserve privacy, replacing any existing one.
+pub fn set_client_ecs(msg: &Message<Bytes>, ip: IpAddr) -> Result<Message<Bytes>> {
+    match ip {
+        IpAddr::V4(_) => set_ecs(msg, ip, 24),
+        IpAddr::V6(_) => set_ecs(msg, ip, 56),
+    }
+}
+
+/// Remove all EDNS options (e.g. Client Subnet, cookies) from the message while keeping its OPT record.
+pub fn scrub_edns(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    match Edns::from_message(msg)? {
+        Some(mut edns) => {
+            edns.options.clear();
+            set_edns(msg, Some(&edns))
+        }
+        None => Ok(msg.clone()),
+    }
+}
+
+/// Remove the OPT record, and thus all EDNS information, from the message.
+pub fn strip_edns(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    if msg.opt().is_some() {
+        set_edns(msg, None)
+    } else {
+        Ok(msg.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        pad_query, pad_response, scrub_edns, set_client_ecs, set_ecs, strip_edns, truncate_ip,
+    };
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{
+        opt::{Cookie, Opt},
+        Dname, Message, MessageBuilder, Rtype,
+    };
+    use std::{net::IpAddr, str::FromStr};
+
+    fn query() -> Message<Bytes> {
+        query_of("example.com")
+    }
+
+    fn query_of(name: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    // A query from a client advertising 4096 bytes, asking for DNSSEC records and carrying a cookie.
+    fn edns_query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        let mut builder = builder.additional();
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(4096);
+                opt.set_dnssec_ok(true);
+                Cookie::push(opt, [1, 2, 3, 4, 5, 6, 7, 8])
+            })
+            .unwrap();
+        builder.into_message()
+    }
+
+    // The options of the OPT record in wire format
+    fn assert_options(msg: &Message<Bytes>, options: &[u8]) {
+        assert_eq!(
+            *msg.opt().unwrap().as_opt(),
+            Opt::from_octets(options).unwrap()
+        );
+    }
+
+    const COOKIE: [u8; 12] = [0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[test]
+    fn truncation() {
+        let v4 = "192.0.2.77".parse().unwrap();
+        assert_eq!(truncate_ip(v4, 24), "192.0.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!(truncate_ip(v4, 20), "192.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(truncate_ip(v4, 0), "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(truncate_ip(v4, 32), v4);
+        assert_eq!(truncate_ip(v4, 40), v4);
+
+        let v6 = "2001:db8:1234:5678:9abc::1".parse().unwrap();
+        assert_eq!(
+            truncate_ip(v6, 56),
+            "2001:db8:1234:5600::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(truncate_ip(v6, 0), "::".parse::<IpAddr>().unwrap());
+        assert_eq!(truncate_ip(v6, 200), v6);
+    }
+
+    #[test]
+    fn ecs() {
+        // An OPT record is added with the default UDP payload size.
+        let msg = set_ecs(&query(), "192.0.2.77".parse().unwrap(), 24).unwrap();
+        assert_eq!(msg.opt().unwrap().udp_payload_size(), 1232);
+        // Code 8, length 7, family 1, source /24, scope /0, then the three leading bytes of the address
+        assert_options(&msg, &[0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2]);
+
+        // The existing option is replaced, and the other EDNS parameters are kept.
+        let msg = set_ecs(&edns_query(), "192.0.2.77".parse().unwrap(), 24).unwrap();
+        let msg = set_ecs(&msg, "10.1.2.3".parse().unwrap(), 12).unwrap();
+        let opt = msg.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 4096);
+        assert!(opt.dnssec_ok());
+        assert_options(
+            &msg,
+            &[COOKIE.as_slice(), &[0, 8, 0, 6, 0, 1, 12, 0, 10, 0]].concat(),
+        );
+
+        // Prefixes longer than the address are capped.
+        let msg = set_ecs(&query(), "192.0.2.77".parse().unwrap(), 40).unwrap();
+        assert_options(&msg, &[0, 8, 0, 8, 0, 1, 32, 0, 192, 0, 2, 77]);
+    }
+
+    #[test]
+    fn client_ecs() {
+        let msg = set_client_ecs(&query(), "192.0.2.77".parse().unwrap()).unwrap();
+        assert_options(&msg, &[0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2]);
+
+        let msg = set_client_ecs(&query(), "2001:db8:1234:5678:9abc::1".parse().unwrap()).unwrap();
+        assert_options(
+            &msg,
+            &[
+                0, 8, 0, 11, 0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56,
+            ],
+        );
+    }
+
+    #[test]
+    fn scrub() {
+        let msg = set_client_ecs(&edns_query(), "192.0.2.77".parse().unwrap()).unwrap();
+        let scrubbed = scrub_edns(&msg).unwrap();
+        // The OPT record is kept with its parameters but without options.
+        let opt = scrubbed.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 4096);
+        assert!(opt.dnssec_ok());
+        assert_options(&scrubbed, &[]);
+        // 12 bytes of the cookie and 11 bytes of the ECS option
+        assert_eq!(scrubbed.as_slice().len(), msg.as_slice().len() - 23);
+
+        // Messages without OPT records are left untouched.
+        assert_eq!(scrub_edns(&query()).unwrap().as_slice(), query().as_slice());
+    }
+
+    #[test]
+    fn strip() {
+        let stripped = strip_edns(&edns_query()).unwrap();
+        assert!(stripped.opt().is_none());
+        assert_eq!(stripped.header_counts().arcount(), 0);
+        assert_eq!(stripped.as_slice(), query().as_slice());
+
+        assert_eq!(strip_edns(&query()).unwrap().as_slice(), query().as_slice());
+    }
+
+    #[test]
+    fn padding() {
+        // 29 bytes of the query, 11 bytes of the OPT record added and 4 bytes of the option header leave 84 bytes to pad.
+        let padded = pad_query(&query()).unwrap();
+        assert_eq!(padded.as_slice().len(), 128);
+        assert_options(&padded, &[&[0, 12, 0, 84][..], &[0; 84]].concat());
+        // The padding is replaced rather than added to.
+        assert_eq!(pad_query(&padded).unwrap().as_slice(), padded.as_slice());
+
+        // A query with a 97-byte name fills the block exactly once the option header is added.
+        let name = format!("{}.{}", "a".repeat(63), "b".repeat(31));
+        let padded = pad_query(&query_of(&name)).unwrap();
+        assert_eq!(padded.as_slice().len(), 128);
+        assert_options(&padded, &[0, 12, 0, 0]);
+
+        // Responses are padded to 468 bytes, with the other options kept.
+        let padded = pad_response(&edns_query()).unwrap();
+        assert_eq!(padded.as_slice().len(), 468);
+        assert_options(
+            &padded,
+            &[&COOKIE[..], &[0, 12, 1, 156], &[0; 412]].concat(),
+        );
+        assert_eq!(padded.opt().unwrap().udp_payload_size(), 4096);
+
+        // Unless the client doesn't support EDNS
+        assert_eq!(
+            pad_response(&query()).unwrap().as_slice(),
+            query().as_slice()
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use log::{info, warn};
+use std::time::Duration;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn download(url: &str) -> Result<String> {
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?)
+}
+
+// Download the content behind the URL. If a cache path is given, a successful download is saved to it, and the saved copy is used when the URL is unreachable.
+pub(super) async fn fetch(url: &str, cache: Option<&str>) -> Result<String> {
+    match (download(url).await, cache) {
+        (Ok(data), Some(path)) => {
+            if let Err(e) = tokio::fs::write(path, &data).await {
+                warn!("failed to cache `{}` to `{}`: {}", url, path, e);
+            }
+            Ok(data)
+        }
+        (Ok(data), None) => Ok(data),
+        (Err(e), Some(path)) => {
+            warn!(
+                "failed to download `{}`: {}, falling back to cached copy `{}`",
+                url, e, path
+            );
+            let data = tokio::fs::read_to_string(path).await?;
+            info!("loaded cached copy of `{}` from `{}`", url, path);
+            Ok(data)
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// Download the content behind the URL as text, e.g. domain lists or hosts files in the init script.
+pub async fn http_get(url: &str) -> Result<String> {
+    download(url).await
+}
+
+/// Same as `http_get`, but a successful download is saved to the path given, which is read instead when the URL is unreachable.
+pub async fn http_get_cached(url: &str, path: &str) -> Result<String> {
+    fetch(url, Some(path)).await
+}
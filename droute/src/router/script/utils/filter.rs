@@ -0,0 +1,189 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    blackhole::SOA_RDATA,
+    rebuild::{copy_head, copy_records},
+    Result,
+};
+use bytes::Bytes;
+use domain::{
+    base::{Message, Rtype},
+    rdata::AllRecordData,
+};
+
+/// Remove all records of the given types (e.g. AAAA or HTTPS) from the answer section.
+/// If no answer is left, the response becomes a NODATA response carrying a SOA record.
+pub fn filter_records(msg: &Message<Bytes>, rtypes: &[Rtype]) -> Result<Message<Bytes>> {
+    let mut builder = copy_head(msg)?;
+    let mut kept = 0;
+    for item in msg.answer()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            if !rtypes.contains(&record.rtype()) {
+                builder.push(record)?;
+                kept += 1;
+            }
+        }
+    }
+
+    let mut builder = builder.authority();
+    let mut has_soa = false;
+    for item in msg.authority()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            has_soa |= record.rtype() == Rtype::Soa;
+            builder.push(record)?;
+        }
+    }
+    // Negative answers should carry a SOA record to be cached properly.
+    if kept == 0 && !has_soa {
+        builder.push(SOA_RDATA.clone())?;
+    }
+
+    let mut builder = builder.additional();
+    copy_records!(msg.additional()?, builder);
+
+    Ok(builder.into_message())
+}
+
+/// Keep only the first `max` records of the answer section, setting the TC (truncated) flag if `set_tc` is true and any record is trimmed.
+pub fn truncate_answers(msg: &Message<Bytes>, max: usize, set_tc: bool) -> Result<Message<Bytes>> {
+    let mut builder = copy_head(msg)?;
+    let mut trimmed = false;
+    for (i, item) in msg.answer()?.enumerate() {
+        if i >= max {
+            trimmed = true;
+            break;
+        }
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            builder.push(record)?;
+        }
+    }
+    if trimmed && set_tc {
+        builder.header_mut().set_tc(true);
+    }
+
+    let mut builder = builder.authority();
+    copy_records!(msg.authority()?, builder);
+
+    let mut builder = builder.additional();
+    copy_records!(msg.additional()?, builder);
+
+    Ok(builder.into_message())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::filter_records;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Class, message::RecordSection, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Aaaa, AllRecordData, Cname, Soa, A},
+    };
+    use std::str::FromStr;
+
+    fn dname(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    // A CNAME record followed by an A and an AAAA record, with a SOA record in the authority section if asked
+    fn response(soa: bool) -> Message<Bytes> {
+        let name = dname("example.com");
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .answer();
+        builder
+            .push((
+                dname("www.example.com"),
+                Class::In,
+                300,
+                Cname::new(name.clone()),
+            ))
+            .unwrap();
+        builder
+            .push((&name, Class::In, 300, A::from_octets(192, 0, 2, 1)))
+            .unwrap();
+        builder
+            .push((
+                &name,
+                Class::In,
+                300,
+                Aaaa::new("2001:db8::1".parse().unwrap()),
+            ))
+            .unwrap();
+        let mut builder = builder.authority();
+        if soa {
+            builder
+                .push((
+                    &name,
+                    Class::In,
+                    300,
+                    Soa::new(
+                        dname("ns.example.com"),
+                        dname("admin.example.com"),
+                        1.into(),
+                        7200,
+                        3600,
+                        1209600,
+                        300,
+                    ),
+                ))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn rtypes(section: RecordSection<&Bytes>) -> Vec<Rtype> {
+        section
+            .limit_to::<AllRecordData<_, _>>()
+            .map(|r| r.unwrap().rtype())
+            .collect()
+    }
+
+    // The types of the records in the answer and authority sections
+    fn sections(msg: &Message<Bytes>) -> (Vec<Rtype>, Vec<Rtype>) {
+        (
+            rtypes(msg.answer().unwrap()),
+            rtypes(msg.authority().unwrap()),
+        )
+    }
+
+    #[test]
+    fn filter() {
+        let msg = filter_records(&response(false), &[Rtype::Aaaa]).unwrap();
+        assert_eq!(sections(&msg), (vec![Rtype::Cname, Rtype::A], vec![]));
+
+        let msg = filter_records(&response(false), &[Rtype::Aaaa, Rtype::Https]).unwrap();
+        assert_eq!(sections(&msg), (vec![Rtype::Cname, Rtype::A], vec![]));
+    }
+
+    #[test]
+    fn nodata() {
+        // No answer is left, so that a SOA record is added for the NODATA response to be cached.
+        let msg = filter_records(&response(false), &[Rtype::Cname, Rtype::A, Rtype::Aaaa]).unwrap();
+        assert_eq!(sections(&msg), (vec![], vec![Rtype::Soa]));
+
+        // Unless there is one already
+        let msg = filter_records(&response(true), &[Rtype::Cname, Rtype::A, Rtype::Aaaa]).unwrap();
+        assert_eq!(sections(&msg), (vec![], vec![Rtype::Soa]));
+        let soa = msg
+            .authority()
+            .unwrap()
+            .limit_to::<Soa<_>>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(soa.owner(), &dname("example.com"));
+    }
+}
@@ -17,7 +17,10 @@ use super::Result;
 #[cfg(not(any(feature = "geoip-cn", feature = "geoip-maxmind")))]
 use super::UtilsError;
 use log::info;
-use maxminddb::{geoip2::Country, Reader};
+use maxminddb::{
+    geoip2::{self, Country},
+    Reader,
+};
 use std::{net::IpAddr, path::PathBuf, str::FromStr, sync::Arc};
 
 /// A matcher that matches if IP address in the record of the first A/AAAA response is in the list of countries.
@@ -80,6 +83,44 @@ impl GeoIp {
             })
             .unwrap_or(false)
     }
+
+    /// The ISO code of the country the IP address is located in, e.g. `CN`
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        self.db
+            .lookup::<Country>(ip)
+            .ok()
+            .and_then(|r| r.country)
+            .and_then(|c| c.iso_code)
+            .map(ToString::to_string)
+    }
+
+    /// The code of the continent the IP address is located in, e.g. `AS` or `EU`
+    pub fn continent(&self, ip: IpAddr) -> Option<String> {
+        self.db
+            .lookup::<Country>(ip)
+            .ok()
+            .and_then(|r| r.continent)
+            .and_then(|c| c.code)
+            .map(ToString::to_string)
+    }
+
+    /// The English name of the city the IP address is located in, if the database is a city one like GeoLite2-City
+    pub fn city(&self, ip: IpAddr) -> Option<String> {
+        self.db
+            .lookup::<geoip2::City>(ip)
+            .ok()
+            .and_then(|r| r.city)
+            .and_then(|c| c.names)
+            .and_then(|n| n.get("en").map(ToString::to_string))
+    }
+
+    /// The number of the autonomous system that announces the IP address, if the database is an ASN one like GeoLite2-ASN
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        self.db
+            .lookup::<geoip2::Asn>(ip)
+            .ok()
+            .and_then(|r| r.autonomous_system_number)
+    }
 }
 
 #[cfg(test)]
@@ -117,4 +158,19 @@ mod tests {
         assert_eq!(geoip.contains("180.101.49.12".parse().unwrap(), "CN"), true);
         assert_eq!(geoip.contains("69.162.81.155".parse().unwrap(), "US"), true)
     }
+
+    #[tokio::test]
+    async fn lookups() {
+        let geoip = GeoIp::from_buf(DB.clone()).unwrap();
+        let ip = "180.101.49.12".parse().unwrap();
+        assert_eq!(geoip.country(ip), Some("CN".to_string()));
+        assert_eq!(geoip.continent(ip), Some("AS".to_string()));
+        assert_eq!(
+            geoip.continent("69.162.81.155".parse().unwrap()),
+            Some("NA".to_string())
+        );
+        // Not provided by a country database
+        assert_eq!(geoip.city(ip), None);
+        assert_eq!(geoip.asn(ip), None);
+    }
 }
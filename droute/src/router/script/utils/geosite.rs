@@ -0,0 +1,288 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Loader of the geosite.dat database of v2ray and Xray, which is a `GeoSiteList` message in the protobuf wire format:
+//
+// message GeoSiteList { repeated GeoSite entry = 1; }
+// message GeoSite { string country_code = 1; repeated Domain domain = 2; }
+// message Domain { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+// message Attribute { string key = 1; oneof typed_value { bool bool_value = 2; int64 int_value = 3; } }
+
+use super::{domain::parse_domain, Result, UtilsError};
+use bytes::Bytes;
+use dmatcher::domain::Domain as DomainAlg;
+use domain::base::Dname;
+use regex::RegexSet;
+use std::{io::Read, path::PathBuf, str::FromStr};
+
+// Types of the domain entries
+const PLAIN: u64 = 0;
+const REGEX: u64 = 1;
+const DOMAIN: u64 = 2;
+const FULL: u64 = 3;
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    // 32-bit and 64-bit values, which are not used in the database
+    Fixed,
+}
+
+fn invalid(reason: &'static str) -> UtilsError {
+    UtilsError::InvalidGeoSite(reason)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(invalid("truncated field"));
+    }
+    let (value, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(value)
+}
+
+fn varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = take(buf, 1)?[0];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("overlong varint"))
+}
+
+// The fields of the message as their numbers and values
+fn fields(mut buf: &[u8]) -> impl Iterator<Item = Result<(u64, Value<'_>)>> {
+    std::iter::from_fn(move || {
+        (!buf.is_empty()).then(|| {
+            let key = varint(&mut buf)?;
+            let value = match key & 7 {
+                0 => Value::Varint(varint(&mut buf)?),
+                1 => take(&mut buf, 8).map(|_| Value::Fixed)?,
+                2 => {
+                    let len = varint(&mut buf)? as usize;
+                    Value::Bytes(take(&mut buf, len)?)
+                }
+                5 => take(&mut buf, 4).map(|_| Value::Fixed)?,
+                _ => return Err(invalid("unsupported wire type")),
+            };
+            Ok((key >> 3, value))
+        })
+    })
+}
+
+fn string(value: &[u8]) -> Result<&str> {
+    std::str::from_utf8(value).map_err(|_| invalid("string is not in UTF-8"))
+}
+
+// The type, the value and the attribute keys of the domain entry
+fn entry(buf: &[u8]) -> Result<(u64, &str, Vec<&str>)> {
+    let (mut kind, mut value, mut attrs) = (PLAIN, "", Vec::new());
+    for field in fields(buf) {
+        match field? {
+            (1, Value::Varint(v)) => kind = v,
+            (2, Value::Bytes(v)) => value = string(v)?,
+            (3, Value::Bytes(attr)) => {
+                for field in fields(attr) {
+                    if let (1, Value::Bytes(key)) = field? {
+                        attrs.push(string(key)?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((kind, value, attrs))
+}
+
+/// The matcher of domains in categories of the geosite.dat database of v2ray and Xray, e.g. `cn` or `category-ads-all`
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct GeoSite {
+    domain: DomainAlg,
+    keywords: Vec<String>,
+    patterns: Vec<String>,
+    regexes: RegexSet,
+}
+
+impl Default for GeoSite {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GeoSite {
+    /// Create an empty geosite matcher
+    pub fn new() -> Self {
+        Self {
+            domain: DomainAlg::new(),
+            keywords: Vec::new(),
+            patterns: Vec::new(),
+            regexes: RegexSet::empty(),
+        }
+    }
+
+    /// Add the domains of the category tagged in the database, e.g. `cn` or `geosite:category-ads-all`. Tags are case-insensitive.
+    /// Attributes after `@` select only the domains with all of them, e.g. `category-ads-all@ads`.
+    pub fn add_bytes(&mut self, data: &[u8], tag: &str) -> Result<()> {
+        let tag = tag.strip_prefix("geosite:").unwrap_or(tag);
+        let mut selectors = tag.split('@');
+        // split always yields at least one item
+        let code = selectors.next().unwrap();
+        let required: Vec<&str> = selectors.collect();
+
+        let mut found = false;
+        for site in fields(data) {
+            let site = match site? {
+                (1, Value::Bytes(site)) => site,
+                _ => continue,
+            };
+            // The country code may come after the domains.
+            let mut matched = false;
+            for field in fields(site) {
+                if let (1, Value::Bytes(c)) = field? {
+                    matched = string(c)?.eq_ignore_ascii_case(code);
+                }
+            }
+            if !matched {
+                continue;
+            }
+            found = true;
+
+            for field in fields(site) {
+                let (kind, value, attrs) = match field? {
+                    (2, Value::Bytes(e)) => entry(e)?,
+                    _ => continue,
+                };
+                if !required.iter().all(|a| attrs.contains(a)) {
+                    continue;
+                }
+                match kind {
+                    PLAIN => self.keywords.push(value.to_ascii_lowercase()),
+                    REGEX => self.patterns.push(value.to_string()),
+                    DOMAIN | FULL => {
+                        if let Some(domain) = parse_domain(value) {
+                            let domain = domain?;
+                            if kind == FULL {
+                                self.domain.insert_exact(&domain);
+                            } else {
+                                self.domain.insert(&domain);
+                            }
+                        }
+                    }
+                    _ => return Err(invalid("unknown domain type")),
+                }
+            }
+        }
+        if !found {
+            return Err(UtilsError::MissingGeoSiteTag(code.to_string()));
+        }
+
+        self.regexes = RegexSet::new(&self.patterns)?;
+        Ok(())
+    }
+
+    /// Add the domains of the category tagged in the database file. See `add_bytes` for the tags.
+    pub fn add_file(&mut self, path: impl AsRef<str>, tag: &str) -> Result<()> {
+        // from_str is Infallible
+        let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        self.add_bytes(&data, tag)
+    }
+
+    /// Check if the question name matches any domain, keyword or regular expression added.
+    pub fn contains(&self, qname: &Dname<Bytes>) -> bool {
+        if self.domain.matches(qname) {
+            return true;
+        }
+        if self.keywords.is_empty() && self.patterns.is_empty() {
+            return false;
+        }
+        let name = qname.to_string().to_ascii_lowercase();
+        self.keywords.iter().any(|k| name.contains(k.as_str())) || self.regexes.is_match(&name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GeoSite;
+    use bytes::Bytes;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    fn field(num: u8, value: &[u8]) -> Vec<u8> {
+        let mut buf = vec![(num << 3) | 2, value.len() as u8];
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    fn entry(kind: u8, value: &str, attr: Option<&str>) -> Vec<u8> {
+        let mut buf = vec![1 << 3, kind];
+        buf.extend(field(2, value.as_bytes()));
+        if let Some(attr) = attr {
+            let mut attr = field(1, attr.as_bytes());
+            attr.extend([2 << 3, 1]);
+            buf.extend(field(3, &attr));
+        }
+        field(2, &buf)
+    }
+
+    fn database() -> Vec<u8> {
+        let mut ads = field(1, b"CATEGORY-ADS");
+        ads.extend(entry(2, "ads.example.com", Some("ads")));
+        ads.extend(entry(3, "tracker.example.net", None));
+        ads.extend(entry(0, "doubleclick", None));
+        ads.extend(entry(1, r"^ad\d+\.", None));
+        let mut cn = entry(2, "example.cn", None);
+        // The country code after the domains
+        cn.extend(field(1, b"CN"));
+
+        let mut db = field(1, &ads);
+        db.extend(field(1, &cn));
+        db
+    }
+
+    #[test]
+    fn categories() {
+        let name = |s| Dname::<Bytes>::from_str(s).unwrap();
+        let mut site = GeoSite::new();
+        site.add_bytes(&database(), "geosite:category-ads").unwrap();
+        assert!(site.contains(&name("www.ads.example.com")));
+        assert!(site.contains(&name("tracker.example.net")));
+        assert!(!site.contains(&name("www.tracker.example.net")));
+        assert!(site.contains(&name("stats.doubleclick.net")));
+        assert!(site.contains(&name("ad1.example.org")));
+        assert!(!site.contains(&name("example.cn")));
+
+        site.add_bytes(&database(), "cn").unwrap();
+        assert!(site.contains(&name("www.example.cn")));
+
+        assert!(site.add_bytes(&database(), "us").is_err());
+        assert!(site.add_bytes(&database()[..10], "cn").is_err());
+    }
+
+    #[test]
+    fn attributes() {
+        let name = |s| Dname::<Bytes>::from_str(s).unwrap();
+        let mut site = GeoSite::new();
+        site.add_bytes(&database(), "category-ads@ads").unwrap();
+        assert!(site.contains(&name("ads.example.com")));
+        assert!(!site.contains(&name("tracker.example.net")));
+        assert!(!site.contains(&name("doubleclick.net")));
+    }
+}
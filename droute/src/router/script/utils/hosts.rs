@@ -13,49 +13,54 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::Result;
+use super::{lines, Result};
+use crate::dnsmasq::directive;
 use bytes::Bytes;
 use dmatcher::hosts::{Hosts as HostsAlg, MatchType};
-use domain::base::{
-    name::FromStrError,
-    net::{IpAddr, Ipv4Addr},
-    Dname,
-};
-use std::{path::PathBuf, str::FromStr};
+use domain::base::{net::IpAddr, Dname, Rtype};
+use std::str::FromStr;
 
 /// The domain matcher
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub struct Hosts(HostsAlg);
 
-fn into_hosts_config(
-    list: &str,
-) -> std::result::Result<Vec<(Dname<Bytes>, MatchType)>, FromStrError> {
-    let mut cfg: Vec<(Dname<Bytes>, MatchType)> = Vec::new();
-    for line in list.split('\n') {
-        if line.is_empty() {
-            continue;
-        }
+// Parse the IP address, which matches only the server name itself if it is prefixed with `!`.
+fn into_match_type(ip: &str) -> Result<MatchType> {
+    Ok(match ip.strip_prefix('!') {
+        Some(ip) => MatchType::Server(IpAddr::from_str(ip)?),
+        None => MatchType::Subdomain(IpAddr::from_str(ip)?),
+    })
+}
 
-        let c: Vec<&str> = line.split_whitespace().collect();
-        if !c[0].chars().all(|c| {
+// Parse a line of the hosts list, skipping those which are not entries.
+fn into_host(line: &str) -> Result<Option<(Dname<Bytes>, MatchType)>> {
+    let c: Vec<&str> = line.split_whitespace().collect();
+    if c.len() < 2
+        || !c[0].chars().all(|c| {
             char::is_ascii_alphabetic(&c) | char::is_ascii_digit(&c) | (c == '-') | (c == '.')
-        }) || c[1].is_empty()
-        {
-            continue;
-        }
+        })
+    {
+        return Ok(None);
+    }
 
-        let host_str: Dname<Bytes> = Dname::from_str(c[0])?;
-        let ip = if c[1].as_bytes()[0] == b'!' {
-            MatchType::Server(IpAddr::V4(Ipv4Addr::from_str(&c[1][1..]).unwrap()))
-        } else {
-            MatchType::Subdomain(IpAddr::V4(Ipv4Addr::from_str(c[1]).unwrap()))
-        };
+    Ok(Some((Dname::from_str(c[0])?, into_match_type(c[1])?)))
+}
 
-        cfg.push((host_str, ip));
+// Parse the `address=` directive of dnsmasq into entries covering the subdomains, skipping other directives and those answering no address, e.g. `address=/example.com/` for NXDOMAIN.
+fn into_dnsmasq_hosts(line: &str) -> Result<Vec<(Dname<Bytes>, MatchType)>> {
+    match directive(line) {
+        Some(("address", domains, ip)) if !ip.is_empty() && ip != "#" => {
+            let ip = IpAddr::from_str(ip)?;
+            domains
+                .into_iter()
+                // `#` matches all domains, which is out of the hosts' reach
+                .filter(|domain| *domain != "#")
+                .map(|domain| Ok((Dname::from_str(domain)?, MatchType::Subdomain(ip))))
+                .collect()
+        }
+        _ => Ok(Vec::new()),
     }
-
-    Ok(cfg)
 }
 
 impl Default for Hosts {
@@ -72,9 +77,9 @@ impl Hosts {
 
     /// Add a server name to the domain matcher's list
     pub fn add_host(&mut self, s: &str, ip: &str, is_server: bool) -> Result<()> {
-        let domain: Dname<Bytes> = Dname::from_str(s).unwrap();
+        let domain: Dname<Bytes> = Dname::from_str(s)?;
 
-        let ip = IpAddr::V4(Ipv4Addr::from_str(ip).unwrap());
+        let ip = IpAddr::from_str(ip)?;
         let ip_match = if is_server {
             MatchType::Server(ip)
         } else {
@@ -86,19 +91,161 @@ impl Hosts {
     }
 
     /// Add all question names in a file to the domain matcher's list
-    pub fn add_file(&mut self, path: impl AsRef<str>) -> Result<()> {
-        // from_str is Infallible
-        let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
-        let mut data = String::new();
-        file.read_to_string(&mut data)?;
-        into_hosts_config(&data)?
-            .iter()
-            .for_each(|d| self.0.insert(&d.0, &d.1));
+    pub async fn add_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        // Inserted line by line as they are read, so that huge lists are never held in memory as a whole.
+        self.0
+            .extend_lines(lines::open(path).await?, into_host)
+            .await
+    }
+
+    /// Add the `address=` directives in a dnsmasq configuration, e.g. `address=/example.com/0.0.0.0`, which cover the subdomains as well. Other directives are skipped.
+    pub fn add_dnsmasq(&mut self, s: impl AsRef<str>) -> Result<()> {
+        for line in s.as_ref().lines() {
+            for (domain, ip) in into_dnsmasq_hosts(line)? {
+                self.0.insert(&domain, &ip);
+            }
+        }
         Ok(())
     }
 
-    /// Check if the question name matches any in the matcher.
+    /// Add the `address=` directives in a dnsmasq configuration file.
+    pub async fn add_dnsmasq_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.0
+            .extend_lines(lines::open(path).await?, into_dnsmasq_hosts)
+            .await
+    }
+
+    /// The number of domains mapped to addresses in the matcher.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the matcher has no domains mapped to addresses.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if the question name matches any in the matcher, preferring the IPv4 address if it is mapped to addresses of both families.
     pub fn reslove(&self, qname: &Dname<Bytes>) -> Option<IpAddr> {
         self.0.matches(qname)
     }
+
+    /// The address answering the query, i.e. the IPv4 address mapped to the question name for `A` and the IPv6 one for `AAAA`.
+    pub fn lookup(&self, qname: &Dname<Bytes>, qtype: Rtype) -> Option<IpAddr> {
+        self.0.lookup(qname, qtype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hosts;
+    use bytes::Bytes;
+    use domain::base::{Dname, Rtype};
+    use std::str::FromStr;
+
+    #[test]
+    fn ipv6() {
+        let mut hosts = Hosts::new();
+        hosts.add_host("example.com", "2001:db8::1", false).unwrap();
+        hosts.add_host("example.net", "192.0.2.1", true).unwrap();
+        assert!(hosts.add_host("example.org", "192.0.2", false).is_err());
+
+        let name = |s| Dname::<Bytes>::from_str(s).unwrap();
+        assert_eq!(
+            hosts.reslove(&name("www.example.com")),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(
+            hosts.reslove(&name("example.net")),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(hosts.reslove(&name("www.example.net")), None);
+    }
+
+    #[test]
+    fn config() {
+        let cfg: Vec<_> =
+            "example.com 2001:db8::1\n\nexample.net !192.0.2.1\r\n# comment\nlonely\n"
+                .lines()
+                .map(|line| super::into_host(line).unwrap())
+                .collect();
+        assert_eq!(cfg.iter().flatten().count(), 2);
+        assert!(super::into_host("example.com 2001:db8::g").is_err());
+    }
+
+    #[test]
+    fn dnsmasq() {
+        let mut hosts = Hosts::new();
+        hosts
+            .add_dnsmasq("address=/example.com/example.net/0.0.0.0\naddress=/example.org/\nserver=/corp/10.0.0.53\n# address=/example.edu/::\n")
+            .unwrap();
+        assert!(hosts.add_dnsmasq("address=/example.com/192.0.2").is_err());
+
+        let name = |s| Dname::<Bytes>::from_str(s).unwrap();
+        assert_eq!(
+            hosts.reslove(&name("ads.example.com")),
+            Some("0.0.0.0".parse().unwrap())
+        );
+        assert_eq!(
+            hosts.reslove(&name("example.net")),
+            Some("0.0.0.0".parse().unwrap())
+        );
+        assert_eq!(hosts.reslove(&name("example.org")), None);
+        assert_eq!(hosts.reslove(&name("corp")), None);
+        assert_eq!(hosts.reslove(&name("example.edu")), None);
+        assert_eq!(hosts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let path = std::env::temp_dir().join("dcompass-hosts-test.txt");
+        tokio::fs::write(&path, "localhost !127.0.0.1\nlocalhost !::1\n# comment\n")
+            .await
+            .unwrap();
+        let mut hosts = Hosts::new();
+        hosts.add_file(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(hosts.len(), 2);
+        tokio::fs::write(&path, "example.com 192.0.2\n")
+            .await
+            .unwrap();
+        assert!(hosts.add_file(path.to_str().unwrap()).await.is_err());
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(hosts.add_file(path.to_str().unwrap()).await.is_err());
+    }
+
+    #[test]
+    fn families() {
+        let mut hosts = Hosts::new();
+        for line in "localhost !127.0.0.1\nlocalhost !::1\n".lines() {
+            let (domain, ip) = super::into_host(line).unwrap().unwrap();
+            hosts.0.insert(&domain, &ip);
+        }
+        hosts
+            .add_dnsmasq("address=/example.com/0.0.0.0\naddress=/example.com/::\n")
+            .unwrap();
+        assert_eq!(hosts.len(), 4);
+
+        let name = |s| Dname::<Bytes>::from_str(s).unwrap();
+        assert_eq!(
+            hosts.lookup(&name("localhost"), Rtype::A),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            hosts.lookup(&name("localhost"), Rtype::Aaaa),
+            Some("::1".parse().unwrap())
+        );
+        assert_eq!(
+            hosts.reslove(&name("localhost")),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            hosts.lookup(&name("ads.example.com"), Rtype::A),
+            Some("0.0.0.0".parse().unwrap())
+        );
+        assert_eq!(
+            hosts.lookup(&name("ads.example.com"), Rtype::Aaaa),
+            Some("::".parse().unwrap())
+        );
+        assert_eq!(hosts.lookup(&name("example.com"), Rtype::Txt), None);
+    }
 }
@@ -1,8 +1,7 @@
-use super::Result;
-use cidr_utils::{
-    cidr::{IpCidr as Cidr, IpCidrError},
-    utils::IpCidrCombiner as CidrCombiner,
-};
+use super::{ptr_to_ip, Result};
+use bytes::Bytes;
+use cidr_utils::{cidr::IpCidr as Cidr, utils::IpCidrCombiner as CidrCombiner};
+use domain::base::Dname;
 use std::{net::IpAddr, path::Path};
 
 /// IP CIDR matcher.
@@ -25,20 +24,34 @@ impl IpCidr {
         let (mut file, _) = niffler::from_path(path)?;
         let mut data = String::new();
         file.read_to_string(&mut data)?;
-        // This gets rid of empty substrings for stability reasons. See also https://github.com/LEXUGE/dcompass/issues/33.
-        data.split('\n').filter(|&x| !x.is_empty()).try_for_each(
-            |x| -> std::result::Result<(), IpCidrError> {
-                self.matcher.push(Cidr::from_str(x)?);
-                Ok(())
-            },
-        )?;
+        self.add_str(&data)
+    }
+
+    /// Add a single IP CIDR, e.g. `10.0.0.0/8`.
+    pub fn add_cidr(&mut self, cidr: &str) -> Result<()> {
+        self.matcher.push(Cidr::from_str(cidr)?);
         Ok(())
     }
 
+    /// Add IP CIDRs from a string where IP CIDRs are seperated from one another by whitespaces or commas, e.g. `10.0.0.0/8, 192.168.0.0/16`.
+    pub fn add_str(&mut self, data: &str) -> Result<()> {
+        // This gets rid of empty substrings for stability reasons. See also https://github.com/LEXUGE/dcompass/issues/33.
+        data.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|&x| !x.is_empty())
+            .try_for_each(|x| self.add_cidr(x))
+    }
+
     /// Check if IP CIDR set contains the given IP address.
     pub fn contains(&self, ip: IpAddr) -> bool {
         self.matcher.contains(ip)
     }
+
+    /// Check if IP CIDR set contains the IP address a reverse lookup name (e.g. `4.3.2.1.in-addr.arpa`) refers to.
+    pub fn contains_ptr(&self, qname: &Dname<Bytes>) -> bool {
+        ptr_to_ip(qname)
+            .map(|ip| self.contains(ip))
+            .unwrap_or(false)
+    }
 }
 
 impl Default for IpCidr {
@@ -46,3 +59,22 @@ impl Default for IpCidr {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::IpCidr;
+
+    #[test]
+    fn add_inline() {
+        let mut ipcidr = IpCidr::new();
+        ipcidr.add_cidr("10.0.0.0/8").unwrap();
+        ipcidr
+            .add_str("192.168.0.0/16, 172.16.0.0/12\nfc00::/7\r\n")
+            .unwrap();
+        for ip in ["10.1.2.3", "192.168.1.1", "172.20.0.1", "fd00::1"] {
+            assert!(ipcidr.contains(ip.parse().unwrap()));
+        }
+        assert!(!ipcidr.contains("1.1.1.1".parse().unwrap()));
+        assert!(ipcidr.add_cidr("10.0.0.0/33").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use tokio::{
+    fs::File,
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+};
+
+pub(super) type Lines = Box<dyn AsyncBufRead + Unpin + Send>;
+
+// Open the list file to be read line by line without blocking the runtime. Lists compressed with gzip, bzip2, xz or zstd are decompressed as they are read, like the other files opened with niffler.
+pub(super) async fn open(path: impl AsRef<str>) -> Result<Lines> {
+    let mut file = BufReader::new(File::open(path.as_ref()).await?);
+    let magic = file.fill_buf().await?;
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        let mut decoder = GzipDecoder::new(file);
+        // Block gzip (bgzip) files are made of many gzip members.
+        decoder.multiple_members(true);
+        Box::new(BufReader::new(decoder))
+    } else if magic.starts_with(b"BZh") {
+        Box::new(BufReader::new(BzDecoder::new(file)))
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Box::new(BufReader::new(XzDecoder::new(file)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(BufReader::new(ZstdDecoder::new(file)))
+    } else {
+        Box::new(file)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::open;
+    use async_compression::tokio::write::GzipEncoder;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn gzip() {
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder
+            .write_all(b"example.com\nexample.net\n")
+            .await
+            .unwrap();
+        encoder.shutdown().await.unwrap();
+        let path = std::env::temp_dir().join("dcompass-lines-test.gz");
+        tokio::fs::write(&path, encoder.into_inner()).await.unwrap();
+
+        let mut lines = open(path.to_str().unwrap()).await.unwrap().lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "example.com");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "example.net");
+        assert_eq!(lines.next_line().await.unwrap(), None);
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
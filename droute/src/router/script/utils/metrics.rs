@@ -0,0 +1,120 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//! Counters and observations recorded by scripts, e.g. the number of queries answered by each branch, rendered in the Prometheus text format.
+
+use super::{Result, UtilsError};
+use once_cell::sync::Lazy;
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<String, u64>,
+    // Count and sum of the values observed
+    summaries: BTreeMap<String, (u64, f64)>,
+}
+
+// Metrics are shared by all the scripts, so that they survive script reloads.
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+// Names must match `[a-zA-Z_:][a-zA-Z0-9_:]*` in Prometheus.
+fn check_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => (),
+        _ => {
+            return Err(UtilsError::InvalidMetric(
+                name.to_string(),
+                "names must start with a letter, `_` or `:`",
+            ))
+        }
+    }
+    if chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+        Ok(())
+    } else {
+        Err(UtilsError::InvalidMetric(
+            name.to_string(),
+            "names may only contain letters, digits, `_` and `:`",
+        ))
+    }
+}
+
+/// Increment the counter of the given name by one, which starts from zero.
+pub fn inc(name: &str) -> Result<()> {
+    check_name(name)?;
+    let mut registry = REGISTRY.lock().unwrap();
+    if registry.summaries.contains_key(name) {
+        return Err(UtilsError::InvalidMetric(
+            name.to_string(),
+            "already observed as a summary",
+        ));
+    }
+    *registry.counters.entry(name.to_string()).or_default() += 1;
+    Ok(())
+}
+
+/// Observe a value, e.g. a latency, in the summary of the given name, which exports the count and the sum of the values.
+pub fn observe(name: &str, value: f64) -> Result<()> {
+    check_name(name)?;
+    let mut registry = REGISTRY.lock().unwrap();
+    if registry.counters.contains_key(name) {
+        return Err(UtilsError::InvalidMetric(
+            name.to_string(),
+            "already incremented as a counter",
+        ));
+    }
+    let (count, sum) = registry.summaries.entry(name.to_string()).or_default();
+    *count += 1;
+    *sum += value;
+    Ok(())
+}
+
+/// Render all the metrics recorded in the Prometheus text format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut text = String::new();
+    for (name, value) in &registry.counters {
+        let _ = write!(text, "# TYPE {0} counter\n{0} {1}\n", name, value);
+    }
+    for (name, (count, sum)) in &registry.summaries {
+        let _ = write!(
+            text,
+            "# TYPE {0} summary\n{0}_sum {1}\n{0}_count {2}\n",
+            name, sum, count
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inc, observe, render};
+
+    #[test]
+    fn record() {
+        inc("test_china").unwrap();
+        inc("test_china").unwrap();
+        observe("test_latency", 1.5).unwrap();
+        observe("test_latency", 2.0).unwrap();
+        let text = render();
+        assert!(text.contains("# TYPE test_china counter\ntest_china 2\n"));
+        assert!(text
+            .contains("# TYPE test_latency summary\ntest_latency_sum 3.5\ntest_latency_count 2\n"));
+
+        assert!(inc("1st").is_err());
+        assert!(inc("test-china").is_err());
+        assert!(observe("test_china", 1.0).is_err());
+        assert!(inc("test_latency").is_err());
+    }
+}
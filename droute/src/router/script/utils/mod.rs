@@ -15,22 +15,76 @@
 
 // proc-macro on non-inline modules are unstable
 
+mod adblock;
+mod asn;
 mod blackhole;
+mod counter;
 mod domain;
+pub(crate) mod edns;
 mod fastanswer;
+mod fetch;
+mod filter;
 mod geoip;
-mod ipcidr;
+mod geosite;
 mod hosts;
+mod ipcidr;
+mod lines;
+pub mod metrics;
+mod negative;
+mod ptr;
+mod punycode;
+mod query;
+mod querylog;
+mod random;
+pub(crate) mod rebuild;
+mod regex;
+mod remap;
+mod response;
+mod rewrite;
+mod rpz;
+mod shuffle;
+mod special;
+mod staticanswer;
+mod tagged;
 
 pub use self::domain::Domain;
-pub use blackhole::blackhole;
+pub use self::regex::Regex;
+pub use asn::Asn;
+pub use blackhole::{blackhole, blackhole_with, null_answer, nxdomain, refused};
+pub use counter::Counter;
+pub use edns::{pad_query, pad_response, scrub_edns, set_client_ecs, set_ecs, strip_edns};
 pub use fastanswer::{fast_answer, fast_answer_ip};
+pub use fetch::{http_get, http_get_cached};
+pub use filter::{filter_records, truncate_answers};
 pub use geoip::GeoIp;
-pub use ipcidr::IpCidr;
+pub use geosite::GeoSite;
 pub use hosts::Hosts;
+pub use ipcidr::IpCidr;
+pub use negative::NegativeAnswer;
+pub use ptr::ptr_to_ip;
+pub use punycode::{from_punycode, to_punycode};
+pub use query::with_qtype;
+#[cfg(feature = "rune-scripting")]
+pub(crate) use querylog::quote;
+pub use querylog::QueryLog;
+pub use random::{rand_float, rand_int, uuid};
+pub use remap::IpRemap;
+pub use response::{
+    answer_rtypes, cname_chain, edns_udp_size, has_rtype, max_ttl, min_ttl, wire_size,
+};
+pub use rewrite::Rewrite;
+pub use rpz::{Rpz, Verdict};
+pub use shuffle::{rotate_answers, shuffle_answers};
+pub use special::is_special_use;
+pub use staticanswer::StaticAnswer;
+pub use tagged::TaggedDomain;
 
-use ::domain::base::{name::FromStrError, octets::ParseError};
+use ::domain::base::{
+    name::{FromStrError, PushError},
+    octets::ParseError,
+};
 use maxminddb::MaxMindDBError;
+use std::net::{AddrParseError, IpAddr};
 use thiserror::Error;
 
 /// A shorthand for returning utils error.
@@ -71,4 +125,73 @@ pub enum UtilsError {
     /// Short Buf
     #[error(transparent)]
     ShortBuf(#[from] ::domain::base::ShortBuf),
+
+    /// Failed to convert to Dname
+    #[error(transparent)]
+    PushError(#[from] PushError),
+
+    /// Failed to download the resource
+    #[error("Failed to download the resource: {0}")]
+    FetchError(#[from] reqwest::Error),
+
+    /// Failed to compile the regular expression
+    #[error(transparent)]
+    RegexError(#[from] ::regex::Error),
+
+    /// Failed to parse the IP address
+    #[error(transparent)]
+    AddrParseError(#[from] AddrParseError),
+
+    /// The metric name is invalid, or the metric is already recorded as another type
+    #[error("Invalid metric `{0}`: {1}")]
+    InvalidMetric(String, &'static str),
+
+    /// Failed to decode the hex string
+    #[error(transparent)]
+    HexError(#[from] hex::FromHexError),
+
+    /// Failed to decode the base64 string
+    #[cfg(feature = "base64")]
+    #[error(transparent)]
+    Base64Error(#[from] base64::DecodeError),
+
+    /// The number is out of the range of a byte
+    #[error("{0} is out of the range of a byte")]
+    InvalidByte(i64),
+
+    /// The number is out of the range of a TTL
+    #[error("{0} is out of the range of a TTL")]
+    InvalidTtl(i64),
+
+    /// The number is out of the range of an autonomous system number
+    #[error("{0} is out of the range of an autonomous system number")]
+    InvalidAsn(i64),
+
+    /// The lower bound of the range is greater than the upper one
+    #[error("Invalid range from {0} to {1}")]
+    InvalidRange(i64, i64),
+
+    /// The compiled matcher saved is malformed
+    #[error("Failed to load the compiled matcher: {0}")]
+    DecodeError(#[from] dmatcher::DecodeError),
+
+    /// The domain cannot be converted between its Unicode and punycode forms
+    #[error("`{0}` is not a valid internationalized domain name")]
+    InvalidIdn(String),
+
+    /// The response policy zone is malformed
+    #[error("Failed to load the response policy zone: {0}")]
+    ZoneError(#[from] crate::router::upstreams::zone::ZoneError),
+
+    /// The geosite database is malformed
+    #[error("Malformed geosite database: {0}")]
+    InvalidGeoSite(&'static str),
+
+    /// The tag is not in the geosite database
+    #[error("The tag `{0}` is not found in the geosite database")]
+    MissingGeoSiteTag(String),
+
+    /// Tried to remap an address to one of a different family
+    #[error("Cannot remap `{0}` to `{1}` as they are of different address families")]
+    MismatchedFamily(IpAddr, IpAddr),
 }
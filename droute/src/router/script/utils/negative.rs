@@ -0,0 +1,154 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        iana::{Class, Rcode},
+        Dname, Message, MessageBuilder,
+    },
+    rdata::Soa,
+};
+use std::str::FromStr;
+
+/// The negative answer, which responds with a synthesized SOA record so that the clients cache the negative response
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct NegativeAnswer {
+    mname: Dname<Bytes>,
+    rname: Dname<Bytes>,
+    ttl: u32,
+}
+
+impl NegativeAnswer {
+    /// Create a negative answer with the given primary name server (`mname`), responsible mailbox (`rname`), and negative caching TTL in seconds.
+    pub fn new(mname: &str, rname: &str, ttl: u32) -> Result<Self> {
+        Ok(Self {
+            mname: Dname::from_str(mname)?,
+            rname: Dname::from_str(rname)?,
+            ttl,
+        })
+    }
+
+    fn respond(&self, query: &Message<Bytes>, rcode: Rcode) -> Result<Message<Bytes>> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?
+            .start_answer(query, rcode)?
+            .authority();
+
+        // The question name is presented as the apex of the zone synthesized.
+        if let Some(question) = query.first_question() {
+            // Per RFC 2308, the negative caching TTL is the minimum of the TTL of SOA record and its MINIMUM field.
+            builder.push((
+                question.qname(),
+                Class::In,
+                self.ttl,
+                Soa::new(
+                    self.mname.clone(),
+                    self.rname.clone(),
+                    1.into(),
+                    1800,
+                    900,
+                    604800,
+                    self.ttl,
+                ),
+            ))?;
+        }
+
+        Ok(builder.into_message())
+    }
+
+    /// Create a NXDOMAIN response, which tells the requestor that the domain doesn't exist.
+    pub fn nxdomain(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.respond(query, Rcode::NXDomain)
+    }
+
+    /// Create a NODATA response, which tells the requestor that the domain has no records of the queried type.
+    pub fn nodata(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.respond(query, Rcode::NoError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NegativeAnswer;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::Soa,
+    };
+    use std::str::FromStr;
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((
+                Dname::<Bytes>::from_str("ads.example.com").unwrap(),
+                Rtype::A,
+            ))
+            .unwrap();
+        builder.into_message()
+    }
+
+    fn answer() -> NegativeAnswer {
+        NegativeAnswer::new("ns.blocked.invalid", "admin.blocked.invalid", 3600).unwrap()
+    }
+
+    #[test]
+    fn nxdomain() {
+        let resp = answer().nxdomain(&query()).unwrap();
+        assert!(resp.is_answer(&query()));
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert_eq!(resp.header_counts().ancount(), 0);
+
+        // The SOA record is owned by the question name, with its TTL and MINIMUM both being the negative caching TTL.
+        let soa = resp
+            .authority()
+            .unwrap()
+            .limit_to::<Soa<_>>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            soa.owner(),
+            &Dname::<Bytes>::from_str("ads.example.com").unwrap()
+        );
+        assert_eq!(soa.ttl(), 3600);
+        assert_eq!(soa.data().minimum(), 3600);
+        assert_eq!(
+            soa.data().mname(),
+            &Dname::<Bytes>::from_str("ns.blocked.invalid").unwrap()
+        );
+        assert_eq!(
+            soa.data().rname(),
+            &Dname::<Bytes>::from_str("admin.blocked.invalid").unwrap()
+        );
+    }
+
+    #[test]
+    fn nodata() {
+        let resp = answer().nodata(&query()).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 0);
+        assert_eq!(resp.header_counts().nscount(), 1);
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(NegativeAnswer::new("ns..invalid", "admin.blocked.invalid", 3600).is_err());
+    }
+}
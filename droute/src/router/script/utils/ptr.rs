@@ -0,0 +1,92 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bytes::Bytes;
+use domain::base::Dname;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Decode the IP address a reverse lookup name (e.g. `4.3.2.1.in-addr.arpa` or a nibble-format `ip6.arpa` name) refers to.
+/// Names that don't spell out a full address, like the ones of reverse zones, yield `None`.
+pub fn ptr_to_ip(qname: &Dname<Bytes>) -> Option<IpAddr> {
+    let name = qname.to_string().to_ascii_lowercase();
+    let name = name.trim_end_matches('.');
+
+    if let Some(rev) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = rev
+            .split('.')
+            .map(|o| o.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )))
+    } else if let Some(rev) = name.strip_suffix(".ip6.arpa") {
+        let nibbles = rev
+            .split('.')
+            .map(|n| match n.len() {
+                1 => u8::from_str_radix(n, 16).ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut octets = [0u8; 16];
+        for (i, pair) in nibbles.rchunks(2).enumerate() {
+            // Within each reversed pair, the low nibble comes first.
+            octets[i] = (pair[1] << 4) | pair[0];
+        }
+        Some(IpAddr::V6(Ipv6Addr::from(octets)))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ptr_to_ip;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    #[test]
+    fn ipv4() {
+        assert_eq!(
+            ptr_to_ip(&Dname::from_str("4.3.2.1.in-addr.arpa").unwrap()),
+            Some("1.2.3.4".parse().unwrap())
+        );
+        assert_eq!(
+            ptr_to_ip(&Dname::from_str("3.2.1.in-addr.arpa").unwrap()),
+            None
+        );
+        assert_eq!(ptr_to_ip(&Dname::from_str("example.com").unwrap()), None);
+    }
+
+    #[test]
+    fn ipv6() {
+        assert_eq!(
+            ptr_to_ip(
+                &Dname::from_str(
+                    "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.IP6.ARPA"
+                )
+                .unwrap()
+            ),
+            Some("4321:0:1:2:3:4:567:89ab".parse().unwrap())
+        );
+        assert_eq!(ptr_to_ip(&Dname::from_str("d.f.ip6.arpa").unwrap()), None);
+    }
+}
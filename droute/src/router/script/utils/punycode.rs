@@ -0,0 +1,42 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{Result, UtilsError};
+
+/// Convert the domain to its punycode (ASCII) form, e.g. `中国.cn` to `xn--fiqs8s.cn`, as it appears on the wire. ASCII labels are lowercased.
+pub fn to_punycode(domain: &str) -> Result<String> {
+    idna::domain_to_ascii(domain).map_err(|_| UtilsError::InvalidIdn(domain.to_string()))
+}
+
+/// Convert the domain to its Unicode form, e.g. `xn--fiqs8s.cn` to `中国.cn`, as it is usually written by humans.
+pub fn from_punycode(domain: &str) -> Result<String> {
+    match idna::domain_to_unicode(domain) {
+        (unicode, Ok(())) => Ok(unicode),
+        (_, Err(_)) => Err(UtilsError::InvalidIdn(domain.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_punycode, to_punycode};
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(to_punycode("中国.cn").unwrap(), "xn--fiqs8s.cn");
+        assert_eq!(from_punycode("xn--fiqs8s.cn").unwrap(), "中国.cn");
+        assert_eq!(to_punycode("Example.COM").unwrap(), "example.com");
+        assert_eq!(from_punycode("example.com").unwrap(), "example.com");
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{rebuild::copy_records, Result};
+use bytes::{Bytes, BytesMut};
+use domain::base::{Message, MessageBuilder, Rtype};
+
+/// Copy the query with the type of its questions replaced by the given one, e.g. to query AAAA records along with the A records of the same name.
+/// The EDNS OPT record and other additional records are kept.
+pub fn with_qtype(msg: &Message<Bytes>, qtype: Rtype) -> Result<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+    *builder.header_mut() = msg.header();
+
+    let mut builder = builder.question();
+    for item in msg.question() {
+        let question = item?;
+        builder.push((*question.qname(), qtype, question.qclass()))?;
+    }
+
+    let mut builder = builder.additional();
+    copy_records!(msg.additional()?, builder);
+
+    Ok(builder.into_message())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::with_qtype;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    #[test]
+    fn qtype() {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        let mut builder = builder.additional();
+        builder.opt(|_| Ok(())).unwrap();
+        let query = builder.into_message();
+
+        let query = with_qtype(&query, Rtype::Aaaa).unwrap();
+        let question = query.first_question().unwrap();
+        assert_eq!(question.qtype(), Rtype::Aaaa);
+        assert_eq!(question.qname().to_string(), "example.com");
+        assert!(query.opt().is_some());
+    }
+}
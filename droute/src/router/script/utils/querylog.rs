@@ -0,0 +1,237 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use crate::QueryContext;
+use bytes::Bytes;
+use domain::base::Message;
+use log::warn;
+use std::{
+    borrow::Cow,
+    fs::OpenOptions,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+// Entries waiting to be written, beyond which they are dropped rather than piling up in memory while the disk is stalled.
+const MAX_PENDING: usize = 4096;
+
+// Buffered entries are flushed at least this often, so that the log lags behind the queries by no more than that.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Quote the value if it cannot be written in logfmt as is.
+pub(crate) fn quote(s: &str) -> Cow<'_, str> {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        Cow::Owned(format!("{:?}", s))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+// The logfmt line of the entry written at `ts` since the Unix epoch.
+fn entry(
+    ts: Duration,
+    ctx: &QueryContext,
+    query: &Message<Bytes>,
+    resp: &Message<Bytes>,
+    branch: &str,
+) -> String {
+    let (qname, qtype) = match query.first_question() {
+        Some(q) => (q.qname().to_string(), q.qtype().to_string()),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    format!(
+        "ts={}.{:03} client={} transport={} qname={} qtype={} branch={} rcode={} latency_ms={}\n",
+        ts.as_secs(),
+        ts.subsec_millis(),
+        ctx.ip,
+        ctx.transport,
+        quote(&qname),
+        qtype,
+        quote(branch),
+        resp.header().rcode(),
+        ctx.received.elapsed().as_millis()
+    )
+}
+
+// Write the entries as they arrive until the log is dropped.
+async fn run(mut file: BufWriter<File>, mut rx: mpsc::Receiver<String>, dropped: Arc<AtomicUsize>) {
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            line = rx.recv() => match line {
+                Some(line) => {
+                    if let Err(e) = file.write_all(line.as_bytes()).await {
+                        warn!("failed to write the query log: {}", e);
+                    }
+                }
+                None => break,
+            },
+            _ = flush.tick() => {
+                if let Err(e) = file.flush().await {
+                    warn!("failed to write the query log: {}", e);
+                }
+                let dropped = dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!("{} entries dropped from the query log as writing lags behind", dropped);
+                }
+            }
+        }
+    }
+    if let Err(e) = file.flush().await {
+        warn!("failed to write the query log: {}", e);
+    }
+}
+
+/// The query log, which appends a logfmt entry per query to a file
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct QueryLog {
+    tx: mpsc::Sender<String>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl QueryLog {
+    /// Open the log file at the given path for appending, creating it if it doesn't exist.
+    /// Entries are written by a background task, buffered and flushed every second, so that queries never wait on the disk. It must be called within a Tokio runtime.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::channel(MAX_PENDING);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(run(
+            BufWriter::new(File::from_std(file)),
+            rx,
+            dropped.clone(),
+        ));
+        Ok(Self { tx, dropped })
+    }
+
+    /// Append an entry recording the timestamp, client IP, question name and type, the given branch name, response code and latency.
+    /// The entry is dropped, with a warning logged, if too many entries are still waiting to be written.
+    pub fn write(
+        &self,
+        ctx: &QueryContext,
+        query: &Message<Bytes>,
+        resp: &Message<Bytes>,
+        branch: &str,
+    ) -> Result<()> {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // Each line is sent as a whole, so that entries never interleave.
+        if self
+            .tx
+            .try_send(entry(ts, ctx, query, resp, branch))
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry, quote, QueryLog};
+    use crate::{router::script::Transport, QueryContext};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+    use std::{collections::HashMap, str::FromStr, time::Duration, time::Instant};
+
+    fn ctx() -> QueryContext {
+        QueryContext {
+            ip: "192.168.1.2".parse().unwrap(),
+            port: 0,
+            listener: "test".into(),
+            transport: Transport::Udp,
+            received: Instant::now(),
+            deadline: None,
+            marks: HashMap::new(),
+        }
+    }
+
+    fn messages(qname: &str) -> (Message<Bytes>, Message<Bytes>) {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(qname).unwrap(), Rtype::A))
+            .unwrap();
+        let query = builder.into_message();
+        let mut resp = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query, Rcode::NXDomain)
+            .unwrap();
+        resp.header_mut().set_qr(true);
+        (query, resp.into_message())
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("blocklist"), "blocklist");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("ads list"), "\"ads list\"");
+        assert_eq!(quote("a=b"), "\"a=b\"");
+        assert_eq!(quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote("tab\there"), "\"tab\\there\"");
+    }
+
+    #[test]
+    fn logfmt() {
+        let (query, resp) = messages("ads.example.com");
+        assert_eq!(
+            entry(Duration::from_millis(1672531200123), &ctx(), &query, &resp, "block list"),
+            "ts=1672531200.123 client=192.168.1.2 transport=udp qname=ads.example.com qtype=A branch=\"block list\" rcode=NXDOMAIN latency_ms=0\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn write() {
+        let path = std::env::temp_dir().join("dcompass-querylog-test.log");
+        let _ = std::fs::remove_file(&path);
+        let log = QueryLog::open(path.to_str().unwrap()).unwrap();
+        let (query, resp) = messages("example.com");
+        log.write(&ctx(), &query, &resp, "a").unwrap();
+        log.write(&ctx(), &query, &resp, "b").unwrap();
+        // Dropping the log flushes the entries left.
+        drop(log);
+
+        let mut written = String::new();
+        for _ in 0..100 {
+            written = std::fs::read_to_string(&path).unwrap();
+            if written.lines().count() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let branches: Vec<_> = written
+            .lines()
+            .map(|line| {
+                line.split(' ')
+                    .find(|kv| kv.starts_with("branch="))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(branches, ["branch=a", "branch=b"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
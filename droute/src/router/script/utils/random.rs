@@ -0,0 +1,70 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{Result, UtilsError};
+use rand::Rng;
+
+/// A random integer within the inclusive range from `low` to `high`.
+pub fn rand_int(low: i64, high: i64) -> Result<i64> {
+    if low > high {
+        return Err(UtilsError::InvalidRange(low, high));
+    }
+    Ok(rand::thread_rng().gen_range(low..=high))
+}
+
+/// A random float within the range from 0 (inclusive) to 1 (exclusive).
+pub fn rand_float() -> f64 {
+    rand::thread_rng().gen()
+}
+
+/// A random (version 4) UUID in the hyphenated form, e.g. `5f0c5a9e-3b1d-4c6e-9a57-0e8f2d7b4c31`.
+pub fn uuid() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    // Version 4, variant 1 (RFC 4122)
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex::encode(&bytes[..4]),
+        hex::encode(&bytes[4..6]),
+        hex::encode(&bytes[6..8]),
+        hex::encode(&bytes[8..10]),
+        hex::encode(&bytes[10..])
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rand_float, rand_int, uuid};
+
+    #[test]
+    fn ranges() {
+        for _ in 0..100 {
+            assert!((-3..=3).contains(&rand_int(-3, 3).unwrap()));
+            assert!((0.0..1.0).contains(&rand_float()));
+        }
+        assert_eq!(rand_int(5, 5).unwrap(), 5);
+        assert!(rand_int(1, 0).is_err());
+    }
+
+    #[test]
+    fn uuid_v4() {
+        let id = uuid();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(id, uuid());
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Helpers to rebuild a message with some of its sections altered.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::base::{message_builder::AnswerBuilder, Message, MessageBuilder};
+
+// Copy every record of a parsed section into the section builder.
+macro_rules! copy_records {
+    ($section: expr, $builder: expr) => {
+        for item in $section {
+            if let Some(record) = item?.into_record::<::domain::rdata::AllRecordData<_, _>>()? {
+                $builder.push(record)?;
+            }
+        }
+    };
+}
+pub(crate) use copy_records;
+
+// Start a new message with the header and the questions copied from the given one, ready for the answers to be pushed.
+pub(super) fn copy_head(msg: &Message<Bytes>) -> Result<AnswerBuilder<BytesMut>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+    *builder.header_mut() = msg.header();
+
+    let mut builder = builder.question();
+    for item in msg.question() {
+        builder.push(item?)?;
+    }
+
+    Ok(builder.answer())
+}
@@ -0,0 +1,71 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+
+/// A compiled regular expression, to match question names or TXT payloads against patterns.
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct Regex(::regex::Regex);
+
+impl Regex {
+    /// Compile the pattern given, in the syntax of the `regex` crate.
+    pub fn new(pattern: &str) -> Result<Self> {
+        Ok(Self(::regex::Regex::new(pattern)?))
+    }
+
+    /// Whether the pattern matches anywhere in the text.
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+
+    /// The capture groups of the leftmost match in the text, the whole match being the first one, or `None` if it doesn't match.
+    /// Groups that don't participate in the match are `None`.
+    pub fn captures(&self, text: &str) -> Option<Vec<Option<String>>> {
+        self.0.captures(text).map(|caps| {
+            caps.iter()
+                .map(|m| m.map(|m| m.as_str().to_string()))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Regex;
+
+    #[test]
+    fn is_match() {
+        let re = Regex::new(r"^ad[sv]?\d*\.").unwrap();
+        assert!(re.is_match("ads1.example.com"));
+        assert!(re.is_match("ad.example.com"));
+        assert!(!re.is_match("www.ads.example.com"));
+        assert!(Regex::new("(").is_err());
+    }
+
+    #[test]
+    fn captures() {
+        let re = Regex::new(r"^v=(\w+)(?: (\d+))?").unwrap();
+        assert_eq!(
+            re.captures("v=spf1 include:example.com"),
+            Some(vec![
+                Some("v=spf1".to_string()),
+                Some("spf1".to_string()),
+                None
+            ])
+        );
+        assert_eq!(re.captures("include:example.com"), None);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    rebuild::{copy_head, copy_records},
+    Result, UtilsError,
+};
+use bytes::Bytes;
+use domain::{
+    base::{Message, Record},
+    rdata::{Aaaa, AllRecordData, A},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// The answer address remapper, which replaces specific A/AAAA answers with configured addresses
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct IpRemap {
+    v4: HashMap<Ipv4Addr, Ipv4Addr>,
+    v6: HashMap<Ipv6Addr, Ipv6Addr>,
+}
+
+impl IpRemap {
+    /// Create an empty remapper
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the address `from` with `to` in answers. Both addresses must be of the same family.
+    pub fn add(&mut self, from: &str, to: &str) -> Result<()> {
+        match (IpAddr::from_str(from)?, IpAddr::from_str(to)?) {
+            (IpAddr::V4(from), IpAddr::V4(to)) => {
+                self.v4.insert(from, to);
+            }
+            (IpAddr::V6(from), IpAddr::V6(to)) => {
+                self.v6.insert(from, to);
+            }
+            (from, to) => return Err(UtilsError::MismatchedFamily(from, to)),
+        }
+        Ok(())
+    }
+
+    /// Get the address that the given one is remapped to, if any.
+    pub fn get(&self, ip: IpAddr) -> Option<IpAddr> {
+        match ip {
+            IpAddr::V4(v4) => self.v4.get(&v4).map(|ip| IpAddr::V4(*ip)),
+            IpAddr::V6(v6) => self.v6.get(&v6).map(|ip| IpAddr::V6(*ip)),
+        }
+    }
+
+    /// Rewrite the A/AAAA records in the answer section of the response whose addresses are remapped.
+    pub fn remap(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut builder = copy_head(msg)?;
+        for item in msg.answer()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                match record.data() {
+                    AllRecordData::A(a) if self.v4.contains_key(&a.addr()) => {
+                        builder.push(Record::new(
+                            record.owner(),
+                            record.class(),
+                            record.ttl(),
+                            A::new(self.v4[&a.addr()]),
+                        ))?;
+                    }
+                    AllRecordData::Aaaa(aaaa) if self.v6.contains_key(&aaaa.addr()) => {
+                        builder.push(Record::new(
+                            record.owner(),
+                            record.class(),
+                            record.ttl(),
+                            Aaaa::new(self.v6[&aaaa.addr()]),
+                        ))?;
+                    }
+                    _ => builder.push(record)?,
+                }
+            }
+        }
+
+        let mut builder = builder.authority();
+        copy_records!(msg.authority()?, builder);
+
+        let mut builder = builder.additional();
+        copy_records!(msg.additional()?, builder);
+
+        Ok(builder.into_message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpRemap, UtilsError};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Class, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Aaaa, AllRecordData, A},
+    };
+    use std::{net::IpAddr, str::FromStr};
+
+    fn remap() -> IpRemap {
+        let mut remap = IpRemap::new();
+        remap.add("203.0.113.1", "192.168.1.1").unwrap();
+        remap.add("2001:db8::1", "fd00::1").unwrap();
+        remap
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn add() {
+        let remap = remap();
+        assert_eq!(remap.get(ip("203.0.113.1")), Some(ip("192.168.1.1")));
+        assert_eq!(remap.get(ip("2001:db8::1")), Some(ip("fd00::1")));
+        assert_eq!(remap.get(ip("203.0.113.2")), None);
+
+        let mut remap = IpRemap::new();
+        assert!(matches!(
+            remap.add("203.0.113.1", "fd00::1"),
+            Err(UtilsError::MismatchedFamily(_, _))
+        ));
+        assert!(remap.add("203.0.113.1", "not an address").is_err());
+    }
+
+    #[test]
+    fn answers() {
+        let name = Dname::<Bytes>::from_str("nas.example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .answer();
+        builder
+            .push((&name, Class::In, 300, A::from_octets(203, 0, 113, 1)))
+            .unwrap();
+        builder
+            .push((&name, Class::In, 300, A::from_octets(203, 0, 113, 2)))
+            .unwrap();
+        builder
+            .push((
+                &name,
+                Class::In,
+                60,
+                Aaaa::new("2001:db8::1".parse().unwrap()),
+            ))
+            .unwrap();
+        let msg: Message<Bytes> = builder.into_message();
+
+        let records: Vec<_> = remap()
+            .remap(&msg)
+            .unwrap()
+            .answer()
+            .unwrap()
+            .limit_to::<AllRecordData<_, _>>()
+            .map(|r| r.unwrap())
+            .map(|r| (r.rtype(), r.ttl(), r.data().to_string()))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (Rtype::A, 300, "192.168.1.1".to_string()),
+                (Rtype::A, 300, "203.0.113.2".to_string()),
+                (Rtype::Aaaa, 60, "fd00::1".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::Bytes;
+use domain::{
+    base::{Dname, Message, ParsedDname, Rtype, ToDname},
+    rdata::Cname,
+};
+
+fn answer_ttls(msg: &Message<Bytes>) -> Result<Vec<u32>> {
+    let mut ttls = Vec::new();
+    for record in msg.answer()? {
+        ttls.push(record?.ttl());
+    }
+    Ok(ttls)
+}
+
+/// The minimum TTL among the records in the answer section, or `None` if there is no answer.
+pub fn min_ttl(msg: &Message<Bytes>) -> Result<Option<u32>> {
+    Ok(answer_ttls(msg)?.into_iter().min())
+}
+
+/// The maximum TTL among the records in the answer section, or `None` if there is no answer.
+pub fn max_ttl(msg: &Message<Bytes>) -> Result<Option<u32>> {
+    Ok(answer_ttls(msg)?.into_iter().max())
+}
+
+/// The CNAME targets in the answer section, followed one after another from the first question's name.
+pub fn cname_chain(msg: &Message<Bytes>) -> Result<Vec<Dname<Bytes>>> {
+    let mut cnames = Vec::new();
+    for record in msg.answer()?.limit_to::<Cname<ParsedDname<&Bytes>>>() {
+        let record = record?;
+        cnames.push((
+            record.owner().to_dname::<Bytes>()?,
+            record.data().cname().to_dname::<Bytes>()?,
+        ));
+    }
+
+    let mut current = match msg.first_question() {
+        Some(q) => q.qname().to_dname::<Bytes>()?,
+        None => return Ok(Vec::new()),
+    };
+    let mut chain = Vec::new();
+    // Each record is followed at most once, so looping chains terminate.
+    while let Some(pos) = cnames.iter().position(|(owner, _)| *owner == current) {
+        let (_, target) = cnames.swap_remove(pos);
+        chain.push(target.clone());
+        current = target;
+    }
+    Ok(chain)
+}
+
+/// The record types present in the answer section, without duplicates.
+pub fn answer_rtypes(msg: &Message<Bytes>) -> Result<Vec<Rtype>> {
+    let mut rtypes = Vec::new();
+    for record in msg.answer()? {
+        let rtype = record?.rtype();
+        if !rtypes.contains(&rtype) {
+            rtypes.push(rtype);
+        }
+    }
+    Ok(rtypes)
+}
+
+/// Whether the answer section contains any record of the given type.
+pub fn has_rtype(msg: &Message<Bytes>, rtype: Rtype) -> Result<bool> {
+    Ok(answer_rtypes(msg)?.contains(&rtype))
+}
+
+/// The size of the message in wire format.
+pub fn wire_size(msg: &Message<Bytes>) -> usize {
+    msg.as_slice().len()
+}
+
+/// The UDP payload size advertised in the message's OPT record, or `None` if the message doesn't carry EDNS.
+pub fn edns_udp_size(msg: &Message<Bytes>) -> Option<u16> {
+    msg.opt().map(|opt| opt.udp_payload_size())
+}
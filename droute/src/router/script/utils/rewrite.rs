@@ -0,0 +1,294 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{Dname, Message, MessageBuilder, Question, Record, ToDname},
+    rdata::{AllRecordData, Cname},
+};
+use std::{collections::HashMap, str::FromStr};
+
+// Push every record of a parsed section into the section builder with its owner (and CNAME target) mapped by the given function.
+macro_rules! map_records {
+    ($section: expr, $builder: expr, $map: expr) => {
+        for item in $section {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                let owner = $map(&record.owner().to_dname::<Bytes>()?)?;
+                if let AllRecordData::Cname(cname) = record.data() {
+                    let target = $map(&cname.cname().to_dname::<Bytes>()?)?;
+                    $builder.push(Record::new(
+                        owner,
+                        record.class(),
+                        record.ttl(),
+                        Cname::new(target),
+                    ))?;
+                } else {
+                    $builder.push(Record::new(
+                        owner,
+                        record.class(),
+                        record.ttl(),
+                        record.data().clone(),
+                    ))?;
+                }
+            }
+        }
+    };
+}
+
+#[derive(Clone, Default)]
+struct Rules {
+    exact: HashMap<Dname<Bytes>, Dname<Bytes>>,
+    suffix: Vec<(Dname<Bytes>, Dname<Bytes>)>,
+}
+
+impl Rules {
+    fn map(&self, name: &Dname<Bytes>) -> Result<Option<Dname<Bytes>>> {
+        if let Some(to) = self.exact.get(name) {
+            return Ok(Some(to.clone()));
+        }
+        // Suffix rules are tried in the order they were added.
+        for (from, to) in &self.suffix {
+            if name.ends_with(from) {
+                let mut s = String::new();
+                for label in name.iter().take(name.label_count() - from.label_count()) {
+                    s.push_str(&label.to_string());
+                    s.push('.');
+                }
+                s.push_str(&to.to_string());
+                return Ok(Some(Dname::from_str(&s)?));
+            }
+        }
+        Ok(None)
+    }
+
+    fn map_or_keep(&self, name: &Dname<Bytes>) -> Result<Dname<Bytes>> {
+        Ok(self.map(name)?.unwrap_or_else(|| name.clone()))
+    }
+
+    fn apply(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+        *builder.header_mut() = msg.header();
+
+        let mut builder = builder.question();
+        for item in msg.question() {
+            let question = item?;
+            builder.push(Question::new(
+                self.map_or_keep(&question.qname().to_dname::<Bytes>()?)?,
+                question.qtype(),
+                question.qclass(),
+            ))?;
+        }
+
+        let mut builder = builder.answer();
+        map_records!(msg.answer()?, builder, |n| self.map_or_keep(n));
+
+        let mut builder = builder.authority();
+        map_records!(msg.authority()?, builder, |n| self.map_or_keep(n));
+
+        let mut builder = builder.additional();
+        map_records!(msg.additional()?, builder, |n| self.map_or_keep(n));
+
+        Ok(builder.into_message())
+    }
+}
+
+/// The question name rewriter, which maps domains to other domains either exactly or by suffix
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct Rewrite {
+    forward: Rules,
+    backward: Rules,
+}
+
+fn to_dname(s: &str) -> Result<Dname<Bytes>> {
+    let s = match idna::domain_to_ascii(s) {
+        Ok(s) => s,
+        Err(_) => s.to_string(),
+    };
+    Ok(Dname::from_str(&s)?)
+}
+
+impl Rewrite {
+    /// Create an empty rewriter
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rewrite the domain `from` into `to`, leaving its subdomains untouched.
+    pub fn add_exact(&mut self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (to_dname(from)?, to_dname(to)?);
+        self.forward.exact.insert(from.clone(), to.clone());
+        self.backward.exact.insert(to, from);
+        Ok(())
+    }
+
+    /// Rewrite the domain `from` and all of its subdomains into the ones under `to`, e.g. `www.corp.example.com` becomes `www.corp.internal` with `from` being `corp.example.com` and `to` being `corp.internal`.
+    pub fn add_suffix(&mut self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (to_dname(from)?, to_dname(to)?);
+        self.forward.suffix.push((from.clone(), to.clone()));
+        self.backward.suffix.push((to, from));
+        Ok(())
+    }
+
+    /// Get the name that the given domain is rewritten into, if any rule applies.
+    pub fn map(&self, qname: &Dname<Bytes>) -> Result<Option<Dname<Bytes>>> {
+        self.forward.map(qname)
+    }
+
+    /// Rewrite the question names of the query. The query is returned unchanged if no rule applies.
+    pub fn rewrite(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.forward.apply(msg)
+    }
+
+    /// Restore the original names in the response to a rewritten query, including the question, the record owners and CNAME targets.
+    pub fn restore(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.backward.apply(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rewrite;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{
+            iana::{Class, Rcode},
+            Dname, Message, MessageBuilder, Rtype, ToDname,
+        },
+        rdata::{AllRecordData, Cname, A},
+    };
+    use std::str::FromStr;
+
+    fn dname(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    fn query(name: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((dname(name), Rtype::A)).unwrap();
+        builder.into_message()
+    }
+
+    fn qname(msg: &Message<Bytes>) -> Dname<Bytes> {
+        msg.first_question()
+            .unwrap()
+            .qname()
+            .to_dname::<Bytes>()
+            .unwrap()
+    }
+
+    fn rewrite() -> Rewrite {
+        let mut rewrite = Rewrite::new();
+        rewrite.add_exact("nas.example.com", "nas.lan").unwrap();
+        rewrite
+            .add_suffix("corp.example.com", "corp.internal")
+            .unwrap();
+        rewrite.add_suffix("example.com", "example.net").unwrap();
+        rewrite.add_exact("bücher.example", "books.lan").unwrap();
+        rewrite
+    }
+
+    #[test]
+    fn map() {
+        let rewrite = rewrite();
+        assert_eq!(
+            rewrite.map(&dname("nas.example.com")).unwrap(),
+            Some(dname("nas.lan"))
+        );
+        // Exact rules leave the subdomains to the other rules.
+        assert_eq!(
+            rewrite.map(&dname("a.nas.example.com")).unwrap(),
+            Some(dname("a.nas.example.net"))
+        );
+        // The suffix rule added first wins.
+        assert_eq!(
+            rewrite.map(&dname("www.corp.example.com")).unwrap(),
+            Some(dname("www.corp.internal"))
+        );
+        assert_eq!(
+            rewrite.map(&dname("corp.example.com")).unwrap(),
+            Some(dname("corp.internal"))
+        );
+        assert_eq!(
+            rewrite.map(&dname("xn--bcher-kva.example")).unwrap(),
+            Some(dname("books.lan"))
+        );
+        assert_eq!(rewrite.map(&dname("example.org")).unwrap(), None);
+        // Only whole labels match.
+        assert_eq!(rewrite.map(&dname("badexample.com")).unwrap(), None);
+    }
+
+    #[test]
+    fn rewrite_and_restore() {
+        let rewrite = rewrite();
+        let msg = rewrite.rewrite(&query("www.corp.example.com")).unwrap();
+        assert_eq!(qname(&msg), dname("www.corp.internal"));
+
+        // The response of the upstream to the rewritten query
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&msg, Rcode::NoError)
+            .unwrap();
+        builder
+            .push((
+                dname("www.corp.internal"),
+                Class::In,
+                300,
+                Cname::new(dname("web.corp.internal")),
+            ))
+            .unwrap();
+        builder
+            .push((
+                dname("web.corp.internal"),
+                Class::In,
+                300,
+                A::from_octets(10, 0, 0, 1),
+            ))
+            .unwrap();
+        let resp = rewrite.restore(&builder.into_message()).unwrap();
+
+        assert_eq!(qname(&resp), dname("www.corp.example.com"));
+        let records: Vec<_> = resp
+            .answer()
+            .unwrap()
+            .limit_to::<AllRecordData<_, _>>()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].owner().to_dname::<Bytes>().unwrap(),
+            dname("www.corp.example.com")
+        );
+        match records[0].data() {
+            AllRecordData::Cname(cname) => assert_eq!(
+                cname.cname().to_dname::<Bytes>().unwrap(),
+                dname("web.corp.example.com")
+            ),
+            _ => panic!("not a CNAME record"),
+        }
+        assert_eq!(
+            records[1].owner().to_dname::<Bytes>().unwrap(),
+            dname("web.corp.example.com")
+        );
+        assert_eq!(records[1].ttl(), 300);
+
+        // Queries no rule applies to are kept as they are.
+        let msg = query("example.org");
+        assert_eq!(rewrite.rewrite(&msg).unwrap().as_slice(), msg.as_slice());
+    }
+}
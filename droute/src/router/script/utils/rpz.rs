@@ -0,0 +1,321 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{NegativeAnswer, Result};
+use crate::router::upstreams::zone::{parse, ZoneError};
+use bytes::{Bytes, BytesMut};
+use domain::base::{
+    iana::{Class, Rcode, Rtype},
+    rdata::UnknownRecordData,
+    Dname, Message, MessageBuilder, ToDname,
+};
+use std::{collections::HashMap, io::Read, path::PathBuf, str::FromStr};
+
+// Subtrees of triggers other than question names, which are not supported
+const UNSUPPORTED_TRIGGERS: [&str; 4] = ["rpz-ip", "rpz-nsdname", "rpz-nsip", "rpz-client-ip"];
+
+// The action on queries triggering the policy
+#[derive(Clone)]
+enum Policy {
+    // `CNAME .`
+    NxDomain,
+    // `CNAME *.`
+    NoData,
+    // `CNAME rpz-passthru.`
+    Passthru,
+    // `CNAME rpz-drop.`
+    Drop,
+    // Records answering the query in place of the real ones, whose data is in wire format
+    LocalData(Vec<(Rtype, u32, Bytes)>),
+}
+
+/// What to do with a query according to the response policy zone
+pub enum Verdict {
+    /// No policy is triggered, or the policy is PASSTHRU, so the query is resolved as usual.
+    Pass,
+    /// Respond with the message synthesized by the policy.
+    Respond(Message<Bytes>),
+    /// Drop the query without responding.
+    Drop,
+}
+
+/// Response policy zone (RPZ), in which threat feeds are commonly distributed. Only the triggers on question names are supported.
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct Rpz {
+    exact: HashMap<Dname<Bytes>, Policy>,
+    // Policies of `*.example.com`, keyed by `example.com`
+    wildcard: HashMap<Dname<Bytes>, Policy>,
+    negative: NegativeAnswer,
+}
+
+// Length of the uncompressed domain name at the start of the data
+fn name_len(data: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let label = *data.get(len)? as usize;
+        len += 1 + label;
+        if label == 0 {
+            return Some(len);
+        }
+    }
+}
+
+// The primary name server, the responsible mailbox, and the negative caching TTL of the SOA record
+fn soa(ttl: u32, data: &Bytes) -> Option<(Dname<Bytes>, Dname<Bytes>, u32)> {
+    let mname_len = name_len(data)?;
+    let rname_len = name_len(data.get(mname_len..)?)?;
+    let minimum = data
+        .get(data.len().checked_sub(4)?..)
+        .and_then(|m| m.try_into().ok())
+        .map(u32::from_be_bytes)?;
+    Some((
+        Dname::from_octets(data.slice(..mname_len)).ok()?,
+        Dname::from_octets(data.slice(mname_len..mname_len + rname_len)).ok()?,
+        // Per RFC 2308, the negative caching TTL is the minimum of the TTL of SOA record and its MINIMUM field.
+        std::cmp::min(ttl, minimum),
+    ))
+}
+
+impl Rpz {
+    /// Load the policy zone from the text of a zone file, e.g. with `origin` of `rpz.example.com`.
+    pub fn new(origin: &str, text: &str) -> Result<Self> {
+        let apex = Dname::<Bytes>::from_str(origin.trim_end_matches('.'))
+            .map_err(|_| ZoneError::InvalidOrigin(origin.to_string()))?;
+        let (mut exact, mut wildcard) = (HashMap::new(), HashMap::new());
+        let mut negative = None;
+
+        for record in parse(text, apex.clone())? {
+            if !record.owner.ends_with(&apex) {
+                return Err(ZoneError::OutOfZone(record.owner.to_string()).into());
+            }
+            if record.owner == apex {
+                if record.rtype == Rtype::Soa {
+                    negative = soa(record.ttl, &record.data);
+                }
+                continue;
+            }
+
+            // The trigger is the owner relative to the apex.
+            let labels: Vec<String> = record
+                .owner
+                .iter()
+                .take(record.owner.label_count() - apex.label_count())
+                .map(|l| l.to_string().to_ascii_lowercase())
+                .collect();
+            if UNSUPPORTED_TRIGGERS.contains(&labels[labels.len() - 1].as_str()) {
+                continue;
+            }
+            let (policies, labels) = match labels.split_first() {
+                Some((first, rest)) if first == "*" => (&mut wildcard, rest),
+                _ => (&mut exact, labels.as_slice()),
+            };
+            let name = if labels.is_empty() {
+                Dname::root_bytes()
+            } else {
+                Dname::from_str(&labels.join("."))?
+            };
+
+            let action = match (record.rtype, record.target()) {
+                (Rtype::Cname, Some(target)) if target.is_root() => Some(Policy::NxDomain),
+                (Rtype::Cname, Some(target)) => {
+                    match target.to_string().to_ascii_lowercase().as_str() {
+                        "*" => Some(Policy::NoData),
+                        "rpz-passthru" => Some(Policy::Passthru),
+                        "rpz-drop" => Some(Policy::Drop),
+                        // Not supported
+                        "rpz-tcp-only" => continue,
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            let policy = policies
+                .entry(name)
+                .or_insert_with(|| Policy::LocalData(Vec::new()));
+            match (action, policy) {
+                (Some(action), policy) => *policy = action,
+                (None, Policy::LocalData(records)) => {
+                    records.push((record.rtype, record.ttl, record.data))
+                }
+                // The action prevails over the local data of the same trigger.
+                (None, _) => {}
+            }
+        }
+
+        let (mname, rname, ttl) = negative.ok_or(ZoneError::InvalidSoa)?;
+        Ok(Self {
+            exact,
+            wildcard,
+            negative: NegativeAnswer::new(&mname.to_string(), &rname.to_string(), ttl)?,
+        })
+    }
+
+    /// Load the policy zone from the zone file at the path.
+    pub fn from_file(origin: &str, path: impl AsRef<str>) -> Result<Self> {
+        // from_str is Infallible
+        let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        Self::new(origin, &text)
+    }
+
+    // The policy triggered by the question name. Exact triggers take precedence over wildcard ones, of which the closest one wins.
+    fn policy(&self, qname: &Dname<Bytes>) -> Option<&Policy> {
+        self.exact.get(qname).or_else(|| {
+            qname
+                .iter_suffixes()
+                .skip(1)
+                .find_map(|name| self.wildcard.get(&name))
+        })
+    }
+
+    /// Check if the question name triggers any policy other than PASSTHRU.
+    pub fn matches(&self, qname: &Dname<Bytes>) -> bool {
+        !matches!(self.policy(qname), None | Some(Policy::Passthru))
+    }
+
+    /// Decide what to do with the query by the policy its question name triggers.
+    pub fn apply(&self, query: &Message<Bytes>) -> Result<Verdict> {
+        let question = match query.first_question() {
+            Some(q) => q,
+            None => return Ok(Verdict::Pass),
+        };
+        let qname = question.qname().to_dname::<Bytes>()?;
+
+        Ok(match self.policy(&qname) {
+            None | Some(Policy::Passthru) => Verdict::Pass,
+            Some(Policy::Drop) => Verdict::Drop,
+            Some(Policy::NxDomain) => Verdict::Respond(self.negative.nxdomain(query)?),
+            Some(Policy::NoData) => Verdict::Respond(self.negative.nodata(query)?),
+            Some(Policy::LocalData(records)) => {
+                // CNAME records answer queries of all types.
+                let qtype = question.qtype();
+                let matched: Vec<_> = records
+                    .iter()
+                    .filter(|(rtype, _, _)| {
+                        qtype == Rtype::Any || *rtype == qtype || *rtype == Rtype::Cname
+                    })
+                    .collect();
+                if matched.is_empty() {
+                    return Ok(Verdict::Respond(self.negative.nodata(query)?));
+                }
+
+                let mut builder =
+                    MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?
+                        .start_answer(query, Rcode::NoError)?;
+                for (rtype, ttl, data) in matched {
+                    builder.push((
+                        &qname,
+                        Class::In,
+                        *ttl,
+                        UnknownRecordData::from_octets(*rtype, data.clone()),
+                    ))?;
+                }
+                Verdict::Respond(builder.into_message())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rpz, Verdict};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{
+        iana::{Rcode, Rtype},
+        Dname, Message, MessageBuilder,
+    };
+    use std::str::FromStr;
+
+    const ZONE: &str = r#"
+$TTL 300
+@                   SOA ns.rpz.example. admin.rpz.example. 1 3600 600 86400 60
+                    NS  localhost.
+bad.example.com     CNAME .
+*.bad.example.com   CNAME .
+ok.bad.example.com  CNAME rpz-passthru.
+nodata.example.com  CNAME *.
+drop.example.com    CNAME rpz-drop.
+local.example.com   A   192.0.2.1
+local.example.com   AAAA    2001:db8::1
+*.example.net       CNAME walled.example.org.
+32.1.2.0.192.rpz-ip CNAME .
+"#;
+
+    fn apply(name: &str, rtype: Rtype) -> Verdict {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), rtype))
+            .unwrap();
+        Rpz::new("rpz.example", ZONE)
+            .unwrap()
+            .apply(&builder.into_message())
+            .unwrap()
+    }
+
+    fn respond(name: &str, rtype: Rtype) -> Message<Bytes> {
+        match apply(name, rtype) {
+            Verdict::Respond(resp) => resp,
+            _ => panic!("{} is not responded", name),
+        }
+    }
+
+    #[test]
+    fn actions() {
+        let resp = respond("bad.example.com", Rtype::A);
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert_eq!(resp.header_counts().nscount(), 1);
+
+        let resp = respond("nodata.example.com", Rtype::A);
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 0);
+
+        assert!(matches!(apply("drop.example.com", Rtype::A), Verdict::Drop));
+        assert!(matches!(apply("example.com", Rtype::A), Verdict::Pass));
+        // Triggers on IP addresses are skipped.
+        assert!(matches!(
+            apply("32.1.2.0.192.rpz-ip", Rtype::A),
+            Verdict::Pass
+        ));
+    }
+
+    #[test]
+    fn local_data() {
+        let resp = respond("LOCAL.example.com", Rtype::Aaaa);
+        assert_eq!(resp.header_counts().ancount(), 1);
+
+        let resp = respond("local.example.com", Rtype::Txt);
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 0);
+
+        // CNAME answers queries of all types.
+        let resp = respond("www.example.net", Rtype::Mx);
+        assert_eq!(resp.header_counts().ancount(), 1);
+    }
+
+    #[test]
+    fn precedence() {
+        let rpz = Rpz::new("rpz.example", ZONE).unwrap();
+        let name = |s| Dname::<Bytes>::from_str(s).unwrap();
+        assert!(rpz.matches(&name("www.bad.example.com")));
+        assert!(!rpz.matches(&name("ok.bad.example.com")));
+        // Wildcards don't cover the name itself.
+        assert!(!rpz.matches(&name("example.net")));
+        assert!(rpz.matches(&name("a.b.example.net")));
+    }
+}
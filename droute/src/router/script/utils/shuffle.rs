@@ -0,0 +1,151 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    rebuild::{copy_head, copy_records},
+    Result,
+};
+use bytes::Bytes;
+use domain::{
+    base::{Message, Rtype},
+    rdata::AllRecordData,
+};
+use rand::seq::SliceRandom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Offset of the next rotation, shared by all the responses.
+static ROTATION: AtomicUsize = AtomicUsize::new(0);
+
+// Rebuild the message with the address records of the answer section reordered by the given function, leaving other records (e.g. CNAME) in place.
+fn reorder<F>(msg: &Message<Bytes>, f: F) -> Result<Message<Bytes>>
+where
+    F: FnOnce(&mut [usize]),
+{
+    let mut records = Vec::new();
+    for item in msg.answer()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            records.push(record);
+        }
+    }
+    let mut positions: Vec<usize> = records
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| matches!(r.rtype(), Rtype::A | Rtype::Aaaa))
+        .map(|(i, _)| i)
+        .collect();
+    let slots = positions.clone();
+    f(&mut positions);
+
+    let mut order: Vec<usize> = (0..records.len()).collect();
+    for (slot, pos) in slots.into_iter().zip(positions) {
+        order[slot] = pos;
+    }
+
+    let mut builder = copy_head(msg)?;
+    for i in order {
+        builder.push(records[i].clone())?;
+    }
+
+    let mut builder = builder.authority();
+    copy_records!(msg.authority()?, builder);
+
+    let mut builder = builder.additional();
+    copy_records!(msg.additional()?, builder);
+
+    Ok(builder.into_message())
+}
+
+/// Randomly shuffle the order of the A/AAAA records in the answer section.
+pub fn shuffle_answers(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    reorder(msg, |p| p.shuffle(&mut rand::thread_rng()))
+}
+
+/// Rotate the order of the A/AAAA records in the answer section by one more position on each call, in a round-robin manner.
+pub fn rotate_answers(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    reorder(msg, |p| {
+        if !p.is_empty() {
+            let len = p.len();
+            p.rotate_left(ROTATION.fetch_add(1, Ordering::Relaxed) % len)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reorder, rotate_answers, shuffle_answers};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Class, Dname, Message, MessageBuilder},
+        rdata::{AllRecordData, Cname, A},
+    };
+    use std::str::FromStr;
+
+    // A CNAME record followed by four A records of 10.0.0.1 to 10.0.0.4
+    fn response() -> Message<Bytes> {
+        let alias = Dname::<Bytes>::from_str("www.example.com").unwrap();
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .answer();
+        builder
+            .push((&alias, Class::In, 300, Cname::new(name.clone())))
+            .unwrap();
+        for i in 1..=4 {
+            builder
+                .push((&name, Class::In, 300, A::from_octets(10, 0, 0, i)))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn answers(msg: &Message<Bytes>) -> Vec<String> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<AllRecordData<_, _>>()
+            .map(|r| r.unwrap().data().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn cname_in_place() {
+        let msg = reorder(&response(), |p| p.reverse()).unwrap();
+        assert_eq!(
+            answers(&msg),
+            [
+                "example.com.",
+                "10.0.0.4",
+                "10.0.0.3",
+                "10.0.0.2",
+                "10.0.0.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn shuffle() {
+        let mut shuffled = answers(&shuffle_answers(&response()).unwrap());
+        assert_eq!(shuffled[0], "example.com.");
+        shuffled[1..].sort();
+        assert_eq!(shuffled, answers(&response()));
+    }
+
+    #[test]
+    fn rotate() {
+        let mut next = answers(&rotate_answers(&response()).unwrap());
+        next[1..].rotate_left(1);
+        assert_eq!(answers(&rotate_answers(&response()).unwrap()), next);
+        assert_eq!(next[0], "example.com.");
+    }
+}
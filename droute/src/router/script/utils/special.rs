@@ -0,0 +1,104 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bytes::Bytes;
+use dmatcher::domain::Domain as DomainAlg;
+use domain::base::Dname;
+use once_cell::sync::Lazy;
+use std::str::FromStr;
+
+// Special-use domain names that must not be forwarded to the global DNS.
+// See also: https://www.iana.org/assignments/special-use-domain-names/special-use-domain-names.xhtml
+const SPECIAL_USE_DOMAINS: &[&str] = &[
+    // RFC 6761
+    "localhost",
+    "invalid",
+    "test",
+    "example",
+    // RFC 6762, multicast DNS
+    "local",
+    "254.169.in-addr.arpa",
+    "8.e.f.ip6.arpa",
+    "9.e.f.ip6.arpa",
+    "a.e.f.ip6.arpa",
+    "b.e.f.ip6.arpa",
+    // RFC 7686
+    "onion",
+    // RFC 8375
+    "home.arpa",
+    // RFC 9476
+    "alt",
+    // RFC 6303, locally served zones
+    "0.in-addr.arpa",
+    "127.in-addr.arpa",
+    "10.in-addr.arpa",
+    "16.172.in-addr.arpa",
+    "17.172.in-addr.arpa",
+    "18.172.in-addr.arpa",
+    "19.172.in-addr.arpa",
+    "20.172.in-addr.arpa",
+    "21.172.in-addr.arpa",
+    "22.172.in-addr.arpa",
+    "23.172.in-addr.arpa",
+    "24.172.in-addr.arpa",
+    "25.172.in-addr.arpa",
+    "26.172.in-addr.arpa",
+    "27.172.in-addr.arpa",
+    "28.172.in-addr.arpa",
+    "29.172.in-addr.arpa",
+    "30.172.in-addr.arpa",
+    "31.172.in-addr.arpa",
+    "168.192.in-addr.arpa",
+    "c.f.ip6.arpa",
+    "d.f.ip6.arpa",
+    "0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+    "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+];
+
+static SPECIAL_USE: Lazy<DomainAlg> = Lazy::new(|| {
+    let mut matcher = DomainAlg::new();
+    SPECIAL_USE_DOMAINS
+        .iter()
+        .for_each(|d| matcher.insert(&Dname::from_str(d).unwrap()));
+    matcher
+});
+
+/// Whether the question name falls under a special-use domain (e.g. `.local`, `.onion`, `home.arpa`, or reverse zones of private addresses), which should be answered locally rather than leaked upstream.
+pub fn is_special_use(qname: &Dname<Bytes>) -> bool {
+    SPECIAL_USE.matches(qname)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_special_use;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    #[test]
+    fn special_use() {
+        assert!(is_special_use(&Dname::from_str("printer.local").unwrap()));
+        assert!(is_special_use(
+            &Dname::from_str("router.home.arpa").unwrap()
+        ));
+        assert!(is_special_use(
+            &Dname::from_str("1.1.168.192.in-addr.arpa").unwrap()
+        ));
+        assert!(is_special_use(&Dname::from_str("localhost").unwrap()));
+        assert!(!is_special_use(&Dname::from_str("example.com").unwrap()));
+        assert!(!is_special_use(
+            &Dname::from_str("1.1.1.1.in-addr.arpa").unwrap()
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    rebuild::{copy_head, copy_records},
+    Result,
+};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        iana::{Class, Rcode},
+        Dname, Message, MessageBuilder, Record, RecordData, Rtype,
+    },
+    rdata::{Aaaa, AllRecordData, Cname, Txt, A},
+};
+use std::{net::IpAddr, str::FromStr};
+
+/// The static answer, which holds records configured beforehand to answer for any question name
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct StaticAnswer {
+    records: Vec<(u32, AllRecordData<Bytes, Dname<Bytes>>)>,
+}
+
+impl StaticAnswer {
+    /// Create an empty static answer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an A or AAAA record with the given address, depending on its family.
+    pub fn add_ip(&mut self, ip: &str, ttl: u32) -> Result<()> {
+        let data = match IpAddr::from_str(ip)? {
+            IpAddr::V4(v4) => AllRecordData::A(A::new(v4)),
+            IpAddr::V6(v6) => AllRecordData::Aaaa(Aaaa::new(v6)),
+        };
+        self.records.push((ttl, data));
+        Ok(())
+    }
+
+    /// Add a TXT record with the given text.
+    pub fn add_txt(&mut self, text: &str, ttl: u32) -> Result<()> {
+        self.records
+            .push((ttl, AllRecordData::Txt(Txt::from_slice(text.as_bytes())?)));
+        Ok(())
+    }
+
+    /// Add a CNAME record pointing to the given domain.
+    pub fn add_cname(&mut self, target: &str, ttl: u32) -> Result<()> {
+        self.records.push((
+            ttl,
+            AllRecordData::Cname(Cname::new(Dname::from_str(target)?)),
+        ));
+        Ok(())
+    }
+
+    // The records answering a question of the given type, CNAME records answer all of them.
+    fn matching(
+        &self,
+        qtype: Rtype,
+    ) -> impl Iterator<Item = &(u32, AllRecordData<Bytes, Dname<Bytes>>)> {
+        self.records.iter().filter(move |(_, data)| {
+            qtype == Rtype::Any || data.rtype() == qtype || data.rtype() == Rtype::Cname
+        })
+    }
+
+    /// Create a response to the query, answered with the configured records matching the question type.
+    pub fn answer(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?
+            .start_answer(query, Rcode::NoError)?;
+        if let Some(question) = query.first_question() {
+            for (ttl, data) in self.matching(question.qtype()) {
+                builder.push(Record::new(question.qname(), Class::In, *ttl, data.clone()))?;
+            }
+        }
+        Ok(builder.into_message())
+    }
+
+    /// Append the configured records matching the question type to the answer section of the response.
+    pub fn append(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut builder = copy_head(msg)?;
+        copy_records!(msg.answer()?, builder);
+        if let Some(question) = msg.first_question() {
+            for (ttl, data) in self.matching(question.qtype()) {
+                builder.push(Record::new(question.qname(), Class::In, *ttl, data.clone()))?;
+            }
+        }
+
+        let mut builder = builder.authority();
+        copy_records!(msg.authority()?, builder);
+
+        let mut builder = builder.additional();
+        copy_records!(msg.additional()?, builder);
+
+        Ok(builder.into_message())
+    }
+}
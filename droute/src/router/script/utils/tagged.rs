@@ -0,0 +1,90 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::Bytes;
+use dmatcher::tagged::TaggedDomain as TaggedDomainAlg;
+use domain::base::Dname;
+use std::{path::PathBuf, str::FromStr};
+
+/// The categorized domain matcher, in which every rule carries one or more tags
+#[derive(Clone)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct TaggedDomain(TaggedDomainAlg);
+
+impl Default for TaggedDomain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaggedDomain {
+    /// Create an empty tagged domain matcher
+    pub fn new() -> Self {
+        Self(TaggedDomainAlg::new())
+    }
+
+    /// Add a question name to the matcher's list under the given tag
+    pub fn add_qname(&mut self, s: impl AsRef<str>, tag: impl AsRef<str>) -> Result<()> {
+        let s = match idna::domain_to_ascii(s.as_ref()) {
+            Ok(s) => s,
+            Err(_) => s.as_ref().to_string(),
+        };
+        self.0.insert(&Dname::from_str(&s)?, tag.as_ref());
+        Ok(())
+    }
+
+    /// Add all question names in a file to the matcher's list.
+    /// Each line consists of a domain followed by its whitespace-separated tags, e.g. `doubleclick.net ads tracking`. Lines starting with `#` are comments.
+    pub fn add_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        // from_str is Infallible
+        let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+        for line in data.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            if let Some(qname) = fields.next() {
+                for tag in fields {
+                    self.add_qname(qname, tag)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the tags of all the rules that the question name matches.
+    pub fn tags(&self, qname: &Dname<Bytes>) -> Vec<String> {
+        self.0
+            .matches(qname)
+            .into_iter()
+            .map(|t| t.to_string())
+            .collect()
+    }
+
+    /// Check if the question name matches any rule under the given tag.
+    pub fn contains(&self, qname: &Dname<Bytes>, tag: &str) -> bool {
+        self.0.matches_tag(qname, tag)
+    }
+
+    /// Check if the question name matches any rule under any of the given tags.
+    pub fn contains_any(&self, qname: &Dname<Bytes>, tags: &[impl AsRef<str>]) -> bool {
+        let found = self.0.matches(qname);
+        tags.iter().any(|t| found.contains(&t.as_ref()))
+    }
+}
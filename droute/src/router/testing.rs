@@ -0,0 +1,298 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//! Route synthetic queries with the upstreams mocked, and check the responses against the expectations, e.g. to test routing scripts in CI before deployment.
+
+use super::{
+    script::parse_rcode,
+    upstreams::{QHandle, QHandleError},
+    Router,
+};
+use crate::{Label, QueryContext, ScriptBackend, Transport, MAX_LEN};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Rcode, name::PushError, Dname, Message, MessageBuilder, Rtype, ShortBuf},
+    rdata::{Aaaa, A},
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use thiserror::Error;
+
+/// Errors raised by the mocked upstreams
+#[derive(Debug, Error)]
+pub enum MockError {
+    /// The upstream queried is not mocked in the test case
+    #[error("the upstream `{0}` is not mocked in the test case")]
+    NotMocked(Label),
+
+    /// Failed to build the response
+    #[error(transparent)]
+    PushError(#[from] PushError),
+
+    /// The buffer is too short
+    #[error(transparent)]
+    ShortBuf(#[from] ShortBuf),
+}
+
+const fn default_ttl() -> u32 {
+    300
+}
+
+fn default_qtype() -> String {
+    "A".to_string()
+}
+
+const fn default_client() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+/// A set of test cases, usually deserialized from a YAML fixture file.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    /// The cases run in order
+    pub cases: Vec<Case>,
+}
+
+/// A synthetic query along with the answers of the mocked upstreams and the expected response.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    /// Name of the case shown in the outcome
+    pub name: String,
+    /// Name queried
+    pub qname: String,
+    /// Type queried, e.g. `AAAA`
+    #[serde(default = "default_qtype")]
+    pub qtype: String,
+    /// IP address of the client sending the query
+    #[serde(default = "default_client")]
+    pub client: IpAddr,
+    /// Answers of the upstreams by tags. Queries sent to the upstreams not mocked fail.
+    #[serde(default)]
+    pub mocks: HashMap<Label, Mock>,
+    /// The expected response
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+/// Answer of a mocked upstream
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Mock {
+    /// Response code, `NOERROR` if not given
+    #[serde(default)]
+    pub rcode: Option<String>,
+    /// Addresses answered as A or AAAA records to the name queried, regardless of the type queried
+    #[serde(default)]
+    pub answers: Vec<IpAddr>,
+    /// TTL of the records answered
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+}
+
+/// Expectations on the response, those not given are not checked.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// Response code, e.g. `NXDOMAIN`
+    #[serde(default)]
+    pub rcode: Option<String>,
+    /// Addresses in the A and AAAA records of the answer section, in any order
+    #[serde(default)]
+    pub answers: Option<Vec<IpAddr>>,
+    /// Tags of the mocked upstreams queried, in any order
+    #[serde(default)]
+    pub upstreams: Option<Vec<Label>>,
+}
+
+/// Outcome of a test case
+pub struct Outcome {
+    /// Name of the case
+    pub name: String,
+    /// Expectations unmet and errors encountered, empty if the case passed
+    pub failures: Vec<String>,
+}
+
+impl Outcome {
+    /// Whether the case passed
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+// An upstream answering with the mock given, recording the tag on every query.
+struct MockHandle {
+    tag: Label,
+    mock: Option<Mock>,
+    rcode: Rcode,
+    queried: Arc<Mutex<Vec<Label>>>,
+}
+
+impl MockHandle {
+    fn answer(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>, MockError> {
+        let mock = self
+            .mock
+            .as_ref()
+            .ok_or_else(|| MockError::NotMocked(self.tag.clone()))?;
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(msg, self.rcode)?;
+        if let Some(question) = msg.first_question() {
+            for ip in &mock.answers {
+                match ip {
+                    IpAddr::V4(ip) => builder.push((question.qname(), mock.ttl, A::new(*ip)))?,
+                    IpAddr::V6(ip) => builder.push((question.qname(), mock.ttl, Aaaa::new(*ip)))?,
+                }
+            }
+        }
+        Ok(builder.into_message())
+    }
+}
+
+#[async_trait]
+impl QHandle for MockHandle {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>, QHandleError> {
+        self.queried.lock().unwrap().push(self.tag.clone());
+        Ok(self.answer(msg)?)
+    }
+}
+
+impl<T: ScriptBackend> Router<T> {
+    /// Route the query of every case with the upstreams mocked, and check the response against the expectations.
+    /// The cache is flushed before every case, and the upstreams in use are swapped back afterwards, so this is not meant to be run on a router serving queries.
+    pub async fn test(&self, fixture: &Fixture) -> Vec<Outcome> {
+        let upstreams = self.script().upstreams();
+        let mut outcomes = Vec::new();
+        for case in &fixture.cases {
+            let queried = Arc::new(Mutex::new(Vec::new()));
+            let failures = match self.test_case(case, &queried).await {
+                Ok(failures) => failures,
+                Err(e) => vec![e],
+            };
+            outcomes.push(Outcome {
+                name: case.name.clone(),
+                failures,
+            });
+        }
+        self.script().replace_upstreams(upstreams);
+        self.flush_cache(None);
+        outcomes
+    }
+
+    async fn test_case(
+        &self,
+        case: &Case,
+        queried: &Arc<Mutex<Vec<Label>>>,
+    ) -> Result<Vec<String>, String> {
+        let mut mocks = HashMap::new();
+        for (tag, mock) in &case.mocks {
+            let rcode = match &mock.rcode {
+                Some(rcode) => parse_rcode(rcode)
+                    .map_err(|_| format!("unknown rcode `{}` mocked for `{}`", rcode, tag))?,
+                None => Rcode::NoError,
+            };
+            mocks.insert(tag.clone(), (mock.clone(), rcode));
+        }
+        let upstreams = self.script().upstreams().mock_handles(|tag| {
+            let (mock, rcode) = match mocks.get(tag) {
+                Some((mock, rcode)) => (Some(mock.clone()), *rcode),
+                None => (None, Rcode::NoError),
+            };
+            Arc::new(MockHandle {
+                tag: tag.clone(),
+                mock,
+                rcode,
+                queried: queried.clone(),
+            })
+        });
+        self.script().replace_upstreams(upstreams);
+        self.flush_cache(None);
+
+        let qname = Dname::<Bytes>::from_str(&case.qname)
+            .map_err(|e| format!("invalid qname `{}`: {}", case.qname, e))?;
+        let qtype =
+            Rtype::from_str(&case.qtype).map_err(|_| format!("unknown qtype `{}`", case.qtype))?;
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))
+            .map_err(|e| e.to_string())?;
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        builder.push((&qname, qtype)).map_err(|e| e.to_string())?;
+
+        let resp = self
+            .resolve(
+                builder.into_message(),
+                Some(QueryContext {
+                    ip: case.client,
+                    port: 0,
+                    listener: "test".into(),
+                    transport: Transport::Udp,
+                    received: Instant::now(),
+                    deadline: None,
+                    marks: HashMap::new(),
+                }),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut failures = Vec::new();
+        if let Some(rcode) = &case.expect.rcode {
+            let expected =
+                parse_rcode(rcode).map_err(|_| format!("unknown rcode `{}` expected", rcode))?;
+            if resp.header().rcode() != expected {
+                failures.push(format!(
+                    "expected rcode {}, got {}",
+                    expected,
+                    resp.header().rcode()
+                ));
+            }
+        }
+        if let Some(answers) = &case.expect.answers {
+            let expected: BTreeSet<_> = answers.iter().copied().collect();
+            let got = addresses(&resp).map_err(|e| e.to_string())?;
+            if got != expected {
+                failures.push(format!("expected answers {:?}, got {:?}", expected, got));
+            }
+        }
+        if let Some(upstreams) = &case.expect.upstreams {
+            let expected: BTreeSet<_> = upstreams.iter().cloned().collect();
+            let got: BTreeSet<_> = queried.lock().unwrap().iter().cloned().collect();
+            if got != expected {
+                failures.push(format!(
+                    "expected upstreams {:?} queried, got {:?}",
+                    expected, got
+                ));
+            }
+        }
+        Ok(failures)
+    }
+}
+
+// The addresses in the A and AAAA records of the answer section.
+fn addresses(msg: &Message<Bytes>) -> Result<BTreeSet<IpAddr>, domain::base::octets::ParseError> {
+    let mut addrs = BTreeSet::new();
+    for record in msg.answer()?.limit_to::<A>() {
+        addrs.insert(IpAddr::V4(record?.data().addr()));
+    }
+    for record in msg.answer()?.limit_to::<Aaaa>() {
+        addrs.insert(IpAddr::V6(record?.data().addr()));
+    }
+    Ok(addrs)
+}
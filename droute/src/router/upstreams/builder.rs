@@ -13,7 +13,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-pub use super::upstream::builder::*;
+pub use super::{health::HealthCheck, upstream::builder::*};
+pub use crate::cache::{CacheConfig, Prefetch, TtlBounds};
 
 use super::{
     error::{Result, UpstreamError},
@@ -35,6 +36,10 @@ pub struct UpstreamsBuilder<U: AsyncTryInto<Upstream, Error = QHandleError>> {
     upstreams: HashMap<Label, U>,
     #[serde(default = "default_cache_size")]
     cache_size: NonZeroUsize,
+    #[serde(default)]
+    health_check: Option<HealthCheck>,
+    #[serde(default)]
+    cache: CacheConfig,
 }
 
 impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
@@ -43,6 +48,8 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         Self {
             upstreams: upstreams.into_iter().map(|(k, v)| (k.into(), v)).collect(),
             cache_size,
+            health_check: None,
+            cache: CacheConfig::default(),
         }
     }
 
@@ -51,6 +58,8 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         std::num::NonZeroUsize::new(cache_size).map(|c| Self {
             upstreams: HashMap::new(),
             cache_size: c,
+            health_check: None,
+            cache: CacheConfig::default(),
         })
     }
 
@@ -59,6 +68,18 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         self.upstreams.insert(tag.into(), upstream);
         self
     }
+
+    /// Enable health checks on the upstreams
+    pub fn health_check(mut self, config: HealthCheck) -> Self {
+        self.health_check = Some(config);
+        self
+    }
+
+    /// Configure the response cache
+    pub fn cache(mut self, config: CacheConfig) -> Self {
+        self.cache = config;
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -73,6 +94,13 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> AsyncTryInto<Upstreams>
         for (tag, u) in self.upstreams {
             v.insert(tag, u.async_try_into().await?);
         }
-        Upstreams::new(v, self.cache_size)
+        let mut upstreams = Upstreams::new(v, self.cache_size, &self.cache)?;
+        if let Some(config) = self.health_check {
+            upstreams.start_health_check(config);
+        }
+        if let Some(config) = self.cache.prefetch {
+            upstreams.start_prefetch(config);
+        }
+        Ok(upstreams)
     }
 }
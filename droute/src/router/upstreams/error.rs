@@ -35,6 +35,26 @@ pub enum UpstreamError {
     #[error("`hybrid` upstream method with tag `{0}` contains no upstreams to race")]
     EmptyHybrid(Label),
 
+    /// The upstream is marked down by health checks.
+    #[error("Upstream `{0}` is marked down by health checks")]
+    Unhealthy(Label),
+
+    /// The query matches none of the zones of the forwarding upstream, which has no default upstream.
+    #[error("The query matches none of the zones of `forward` upstream `{0}`")]
+    NoForwardZone(Label),
+
+    /// The identical query sent concurrently, whose response is shared, failed.
+    #[error("The identical query sent concurrently failed: {0}")]
+    Coalesced(String),
+
+    /// No response is received within the timeout given on sending.
+    #[error("Upstream `{0}` did not respond in time")]
+    Timeout(Label),
+
+    /// No upstream is given to send the query to.
+    #[error("No upstreams are given to send the query to")]
+    NoUpstreams,
+
     /// Error forwarded from `QHandle`.
     #[error(transparent)]
     QHandleError(#[from] QHandleError),
@@ -43,6 +63,10 @@ pub enum UpstreamError {
     #[error(transparent)]
     ShortBuf(#[from] domain::base::ShortBuf),
 
+    /// The record type given in the cache configuration is unknown.
+    #[error("Unknown record type `{0}` in the cache configuration")]
+    UnknownRtype(String),
+
     /// Some of the upstreams are unused.
     #[error("Some of the upstreams are not used: {0:?}")]
     UnusedUpstreams(HashSet<Label>),
@@ -0,0 +1,197 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Background health checks on upstreams, which mark unresponsive ones down so that queries skip them instead of timing out.
+
+use super::QHandle;
+use crate::Label;
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use serde::{Deserialize, Serialize};
+use std::{
+    num::NonZeroU64,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+fn default_interval() -> NonZeroU64 {
+    NonZeroU64::new(30).unwrap()
+}
+
+fn default_threshold() -> u32 {
+    3
+}
+
+fn default_max_backoff() -> NonZeroU64 {
+    NonZeroU64::new(300).unwrap()
+}
+
+fn default_probe() -> String {
+    "example.com".to_string()
+}
+
+/// Configuration of the health checks on upstreams
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct HealthCheck {
+    /// Seconds between two probes on a healthy upstream
+    #[serde(default = "default_interval")]
+    pub interval: NonZeroU64,
+    /// Number of consecutive failed probes before an upstream is marked down
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+    /// Maximum seconds between two recovery probes on an unhealthy upstream. Recovery probes start at `interval` and back off exponentially.
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: NonZeroU64,
+    /// The domain whose A record is queried as the probe
+    #[serde(default = "default_probe")]
+    pub probe: String,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            threshold: default_threshold(),
+            max_backoff: default_max_backoff(),
+            probe: default_probe(),
+        }
+    }
+}
+
+/// Health state of a single upstream
+pub(super) struct Health {
+    up: AtomicBool,
+    failures: AtomicU32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            up: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Health {
+    pub(super) fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    // Record the result of a probe, returning the new state if it changed.
+    pub(super) fn record(&self, success: bool, threshold: u32) -> Option<bool> {
+        if success {
+            self.failures.store(0, Ordering::Relaxed);
+            (!self.up.swap(true, Ordering::Relaxed)).then_some(true)
+        } else {
+            let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            (failures >= threshold && self.up.swap(false, Ordering::Relaxed)).then_some(false)
+        }
+    }
+}
+
+fn probe_query(domain: &str) -> Option<Message<Bytes>> {
+    let name = Dname::<Bytes>::from_str(domain).ok()?;
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512)).ok()?;
+    builder.header_mut().set_random_id();
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).ok()?;
+    Some(builder.into_message())
+}
+
+/// Probe the upstream periodically until its health state is dropped.
+pub(super) async fn watch(
+    tag: Label,
+    handle: Arc<dyn QHandle>,
+    health: Weak<Health>,
+    config: HealthCheck,
+) {
+    let probe = match probe_query(&config.probe) {
+        Some(probe) => probe,
+        None => {
+            log::error!("invalid health check probe domain: {}", config.probe);
+            return;
+        }
+    };
+    let interval = Duration::from_secs(config.interval.get());
+    let max_backoff = Duration::from_secs(config.max_backoff.get());
+    let mut wait = interval;
+
+    loop {
+        tokio::time::sleep(wait).await;
+        // All the `Upstreams` using this state are gone.
+        let health = match health.upgrade() {
+            Some(health) => health,
+            None => break,
+        };
+
+        let success = matches!(handle.query(&probe).await, Ok(resp) if resp.header().rcode() != Rcode::ServFail);
+        match health.record(success, config.threshold) {
+            Some(true) => log::info!("upstream `{}` is back up", tag),
+            Some(false) => log::warn!(
+                "upstream `{}` is marked down after failing health checks",
+                tag
+            ),
+            None => {}
+        }
+
+        wait = if health.is_up() {
+            interval
+        } else {
+            // Back off exponentially on recovery probes
+            std::cmp::min(wait * 2, max_backoff)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Health, HealthCheck};
+    use serde::{
+        de::value::{self, MapDeserializer},
+        Deserialize,
+    };
+
+    #[test]
+    fn mark_down_and_up() {
+        let health = Health::default();
+        assert_eq!(health.record(false, 2), None);
+        assert!(health.is_up());
+        assert_eq!(health.record(false, 2), Some(false));
+        assert!(!health.is_up());
+        assert_eq!(health.record(false, 2), None);
+        assert_eq!(health.record(true, 2), Some(true));
+        assert!(health.is_up());
+        // Failure counter is reset on success
+        assert_eq!(health.record(false, 2), None);
+    }
+
+    fn config(field: &str, secs: u64) -> Result<HealthCheck, value::Error> {
+        HealthCheck::deserialize(MapDeserializer::new(std::iter::once((field, secs))))
+    }
+
+    #[test]
+    fn reject_zero_intervals() {
+        assert!(config("interval", 0).is_err());
+        assert!(config("max_backoff", 0).is_err());
+        assert_eq!(config("interval", 10).unwrap().interval.get(), 10);
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Coalescing of concurrent identical queries, so that a burst of them (e.g. on cache expiry) costs a single upstream round trip.
+
+use super::error::Result;
+use crate::Label;
+use bytes::Bytes;
+use domain::base::{
+    iana::{Class, Rtype},
+    opt::ClientSubnet,
+    Dname, Message, ToDname,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+// Errors are not `Clone`, so followers only get the message.
+pub type Shared = std::result::Result<Message<Bytes>, String>;
+
+// Queries are identical if they are sent to the same upstream with the same question, DNSSEC flags and client subnet.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    tag: Label,
+    qname: Dname<Bytes>,
+    qtype: Rtype,
+    qclass: Class,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+    // Answers may be tailored to the subnet, so queries from different subnets can't share them.
+    ecs: Option<ClientSubnet>,
+}
+
+impl Key {
+    pub fn new(tag: &Label, msg: &Message<Bytes>) -> Option<Self> {
+        let question = msg.first_question()?;
+        Some(Self {
+            tag: tag.clone(),
+            qname: question.qname().to_dname::<Bytes>().ok()?,
+            qtype: question.qtype(),
+            qclass: question.qclass(),
+            dnssec_ok: msg.opt().map_or(false, |opt| opt.dnssec_ok()),
+            checking_disabled: msg.header().cd(),
+            ecs: msg
+                .opt()
+                .and_then(|opt| opt.iter::<ClientSubnet>().find_map(|o| o.ok())),
+        })
+    }
+}
+
+pub enum Role {
+    // The first query sends to the upstream, and shares the result on finish.
+    Leader(Guard),
+    // The others wait for the result. The channel is closed if the leader is cancelled.
+    Follower(oneshot::Receiver<Shared>),
+}
+
+#[derive(Default)]
+struct Waiters {
+    senders: Vec<oneshot::Sender<Shared>>,
+    // Whether any of the followers wants the response cached
+    store: bool,
+}
+
+#[derive(Default)]
+pub struct InFlight(Mutex<HashMap<Key, Waiters>>);
+
+impl InFlight {
+    // `store` tells whether the response should be cached, which the leader does on behalf of all.
+    pub fn join(self: &Arc<Self>, key: Key, store: bool) -> Role {
+        let mut inflight = self.0.lock().unwrap();
+        match inflight.get_mut(&key) {
+            Some(waiters) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.senders.push(tx);
+                waiters.store |= store;
+                Role::Follower(rx)
+            }
+            None => {
+                inflight.insert(key.clone(), Waiters::default());
+                Role::Leader(Guard {
+                    inflight: self.clone(),
+                    key: Some(key),
+                })
+            }
+        }
+    }
+}
+
+// Removes the query from the in-flight ones on drop, which closes the channels of the followers if the leader is cancelled.
+pub struct Guard {
+    inflight: Arc<InFlight>,
+    key: Option<Key>,
+}
+
+impl Guard {
+    // Share the result with the followers, returning whether any of them wants the response cached.
+    pub fn finish(mut self, r: &Result<Message<Bytes>>) -> bool {
+        let waiters = self
+            .key
+            .take()
+            .and_then(|key| self.inflight.0.lock().unwrap().remove(&key))
+            .unwrap_or_default();
+        for waiter in waiters.senders {
+            let _ = waiter.send(match r {
+                Ok(resp) => Ok(resp.clone()),
+                Err(e) => Err(e.to_string()),
+            });
+        }
+        waiters.store
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.inflight.0.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InFlight, Key, Role};
+    use crate::{utils::set_ecs, Label};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use std::{str::FromStr, sync::Arc};
+
+    fn query(name: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[tokio::test]
+    async fn coalesce_identical_queries() {
+        let inflight = Arc::new(InFlight::default());
+        let tag = Label::from("udp");
+        let key = Key::new(&tag, &query("example.com")).unwrap();
+
+        let leader = match inflight.join(key.clone(), false) {
+            Role::Leader(guard) => guard,
+            Role::Follower(_) => panic!("the first query should lead"),
+        };
+        let follower = match inflight.join(Key::new(&tag, &query("EXAMPLE.com")).unwrap(), false) {
+            Role::Follower(rx) => rx,
+            Role::Leader(_) => panic!("identical queries should be coalesced"),
+        };
+        assert!(matches!(
+            inflight.join(Key::new(&tag, &query("example.org")).unwrap(), false),
+            Role::Leader(_)
+        ));
+
+        leader.finish(&Ok(query("example.com")));
+        assert!(follower.await.unwrap().is_ok());
+        // The query is no longer in flight.
+        assert!(matches!(inflight.join(key, false), Role::Leader(_)));
+    }
+
+    #[test]
+    fn distinguish_client_subnets() {
+        let tag = Label::from("udp");
+        let ecs = |ip: &str| set_ecs(&query("example.com"), ip.parse().unwrap(), 24).unwrap();
+        let key = Key::new(&tag, &ecs("192.0.2.1")).unwrap();
+
+        assert!(key == Key::new(&tag, &ecs("192.0.2.2")).unwrap());
+        assert!(key != Key::new(&tag, &ecs("198.51.100.1")).unwrap());
+        assert!(key != Key::new(&tag, &query("example.com")).unwrap());
+    }
+
+    #[test]
+    fn followers_ask_to_store() {
+        let inflight = Arc::new(InFlight::default());
+        let key = Key::new(&Label::from("udp"), &query("example.com")).unwrap();
+
+        let leader = match inflight.join(key.clone(), false) {
+            Role::Leader(guard) => guard,
+            Role::Follower(_) => panic!("the first query should lead"),
+        };
+        let _ = inflight.join(key.clone(), true);
+        let _ = inflight.join(key, false);
+        assert!(leader.finish(&Ok(query("example.com"))));
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_releases_followers() {
+        let inflight = Arc::new(InFlight::default());
+        let key = Key::new(&Label::from("udp"), &query("example.com")).unwrap();
+
+        let leader = inflight.join(key.clone(), false);
+        let follower = match inflight.join(key, false) {
+            Role::Follower(rx) => rx,
+            Role::Leader(_) => panic!("identical queries should be coalesced"),
+        };
+        drop(leader);
+        assert!(follower.await.is_err());
+    }
+}
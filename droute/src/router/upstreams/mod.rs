@@ -20,15 +20,31 @@
 pub mod builder;
 /// Module which contains the error type for the `upstreams` section.
 pub mod error;
+mod health;
+mod inflight;
+mod prefetch;
 mod upstream;
 
-use self::error::{Result, UpstreamError};
-use crate::{cache::RespCache, Label, Validatable, ValidateCell};
+use self::{
+    error::{Result, UpstreamError},
+    health::{Health, HealthCheck},
+    inflight::InFlight,
+};
+use crate::{
+    cache::{CacheConfig, CacheEntry, CacheStats, Prefetch, RecordStatus, RespCache},
+    Label, Validatable, ValidateCell,
+};
 use bytes::{Bytes, BytesMut};
-use domain::base::Message;
-use futures::future::{select_ok, BoxFuture, FutureExt};
+use domain::base::{iana::Rcode, Message};
+use futures::future::{join_all, select_ok, BoxFuture, FutureExt};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, num::NonZeroUsize, str::FromStr};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 pub use upstream::*;
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -43,7 +59,7 @@ pub enum CacheMode {
     /// Use cache records within the TTL
     Standard,
     #[cfg_attr(feature = "rune-scripting", rune(constructor))]
-    /// Use cache results regardless of the time elapsed, and update the results on need.
+    /// Use cache results past the TTL within the staleness budget, and update the results in background.
     Persistent,
 }
 
@@ -73,6 +89,14 @@ pub struct Upstreams {
     upstreams: HashMap<Label, Upstream>,
     // All the responses are cached together, however, they are seperately tagged, so there should be no contamination in place.
     cache: RespCache,
+    // Cache mode that takes precedence over the one given on sending.
+    cache_override: Option<CacheMode>,
+    // Health states of upstreams under health checks.
+    health: Arc<HashMap<Label, Arc<Health>>>,
+    // Queries missing the cache being sent, which identical queries missing the cache meanwhile wait for instead of being sent again.
+    inflight: Arc<InFlight>,
+    // Dropped with the last clone of these upstreams, which stops their background prefetching.
+    alive: Arc<()>,
 }
 
 impl Validatable for Upstreams {
@@ -92,11 +116,19 @@ impl Validatable for Upstreams {
 }
 
 impl Upstreams {
-    /// Create a new `Upstreams` by passing a bunch of `Upstream`s, with their respective labels, and cache capacity.
-    pub fn new(upstreams: HashMap<Label, Upstream>, cache_size: NonZeroUsize) -> Result<Self> {
+    /// Create a new `Upstreams` by passing a bunch of `Upstream`s, with their respective labels, cache capacity, and cache configuration.
+    pub fn new(
+        upstreams: HashMap<Label, Upstream>,
+        cache_size: NonZeroUsize,
+        cache_config: &CacheConfig,
+    ) -> Result<Self> {
         let u = Self {
             upstreams,
-            cache: RespCache::new(cache_size),
+            cache: RespCache::new(cache_size, cache_config)?,
+            cache_override: None,
+            health: Arc::new(HashMap::new()),
+            inflight: Arc::new(InFlight::default()),
+            alive: Arc::new(()),
         };
         // Validate on the assumption that every upstream is gonna be used.
         u.validate(Some(&u.tags()))?;
@@ -108,6 +140,117 @@ impl Upstreams {
         self.upstreams.keys().cloned().collect()
     }
 
+    /// Override the cache mode of all the queries sent afterwards via this `Upstreams`, regardless of the cache mode given on sending.
+    /// As `Upstreams` is cloned for every query routed, this only affects the remainder of the current query.
+    pub fn override_cache_mode(&mut self, cache_mode: CacheMode) {
+        self.cache_override = Some(cache_mode);
+    }
+
+    /// Start probing all the non-hybrid upstreams in background. Upstreams failing the health checks are skipped on sending until they recover.
+    pub fn start_health_check(&mut self, config: HealthCheck) {
+        let mut states = HashMap::new();
+        for (tag, u) in &self.upstreams {
+            if let Upstream::Others(inner) = u {
+                let state = Arc::new(Health::default());
+                tokio::spawn(health::watch(
+                    tag.clone(),
+                    inner.clone(),
+                    Arc::downgrade(&state),
+                    config.clone(),
+                ));
+                states.insert(tag.clone(), state);
+            }
+        }
+        self.health = Arc::new(states);
+    }
+
+    /// Start refreshing the most queried cached responses in background shortly before they expire.
+    pub fn start_prefetch(&self, config: Prefetch) {
+        let handles = self
+            .upstreams
+            .iter()
+            .filter_map(|(tag, u)| match u {
+                Upstream::Others(inner) => Some((tag.clone(), inner.clone())),
+                _ => None,
+            })
+            .collect();
+        tokio::spawn(prefetch::watch(
+            self.cache.clone(),
+            self.inflight.clone(),
+            handles,
+            Arc::downgrade(&self.alive),
+            config,
+        ));
+    }
+
+    // Replace the handles of all the non-hybrid upstreams, dropping their health states, e.g. to mock them in tests.
+    // The upstreams dedicated to the zones of a forwarding upstream share its tag, so they are replaced by the handle of that tag.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn mock_handles(&self, mut f: impl FnMut(&Label) -> Arc<dyn QHandle>) -> Self {
+        let mut u = self.clone();
+        for (tag, upstream) in u.upstreams.iter_mut() {
+            match upstream {
+                Upstream::Others(inner) => *inner = f(tag),
+                Upstream::Forward(forward) => *forward = Arc::new(forward.mock_handles(f(tag))),
+                _ => {}
+            }
+        }
+        u.health = Arc::new(HashMap::new());
+        u
+    }
+
+    // Carry the cached responses of the upstreams still present over from the previous upstreams, e.g. on reload. Returns the number of responses carried over.
+    pub(crate) fn carry_over(&self, previous: &Upstreams) -> usize {
+        self.cache
+            .carry_over(&previous.cache, |tag| self.upstreams.contains_key(tag))
+    }
+
+    /// Remove all the cached responses, or those to the queries on the names under the domain suffix given. Returns the number of responses removed.
+    pub fn flush_cache(&self, suffix: Option<&str>) -> usize {
+        self.cache.flush(suffix)
+    }
+
+    /// Save the cached responses within their TTLs to the snapshot file configured, if any. Returns the number of responses saved.
+    pub async fn save_cache(&self) -> std::io::Result<usize> {
+        self.cache.save().await
+    }
+
+    /// Load the cached responses from the snapshot file configured, if any, with their TTLs reduced by the time passed since they were saved. Returns the number of responses loaded.
+    pub async fn load_cache(&self) -> std::io::Result<usize> {
+        self.cache.load().await
+    }
+
+    /// The cached responses, or those to the queries on the names under the domain suffix given, from the most recently used one.
+    pub fn cache_entries(&self, suffix: Option<&str>) -> Vec<CacheEntry> {
+        self.cache.entries(suffix)
+    }
+
+    /// The response to the query cached under the tag given, if it is within its TTL. The tag needs not to be of any upstream, so that responses can be cached under tags of their own.
+    pub fn cache_get(&self, tag: &Label, msg: &Message<Bytes>) -> Result<Option<Message<Bytes>>> {
+        if msg.first_question().is_none() {
+            return Ok(None);
+        }
+        match self.cache.get(tag, msg, false) {
+            Some(RecordStatus::Alive(resp)) => Ok(Some(with_id(&resp, msg.header().id())?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Cache the response to the query under the tag given for `ttl` seconds, regardless of the TTLs of its records and the cache configuration.
+    pub fn cache_put(&self, tag: Label, msg: &Message<Bytes>, resp: Message<Bytes>, ttl: u32) {
+        self.cache.put_ttl(tag, msg, resp, ttl)
+    }
+
+    /// Statistics of the response cache shared by all the upstreams.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Whether the tagged upstream is considered healthy. Upstreams not under health checks are always healthy.
+    pub fn is_healthy(&self, tag: &Label) -> bool {
+        self.health.get(tag).map_or(true, |h| h.is_up())
+    }
+
     // Check any upstream types
     fn traverse(
         bucket: &mut HashMap<&Label, (ValidateCell, &Upstream)>,
@@ -146,6 +289,7 @@ impl Upstreams {
 
     // Write out in this way to allow recursion for async functions
     /// Send the query to a tagged upstream and a given cache mode.
+    /// Identical queries (same question and DNSSEC flags) missing the cache of the same upstream concurrently share a single upstream query.
     pub fn send<'a>(
         &'a self,
         tag: &'a Label,
@@ -153,37 +297,280 @@ impl Upstreams {
         msg: &'a Message<Bytes>,
     ) -> BoxFuture<'a, Result<Message<Bytes>>> {
         async move {
-            let u = self
-                .upstreams
-                .get(tag)
-                .ok_or_else(|| UpstreamError::MissingTag(tag.clone()))?;
-            let resp = if let Some(v) = u.try_hybrid() {
-                // Hybrid will never call `u.send_internal()`
+            let cache_mode = self.cache_override.as_ref().unwrap_or(cache_mode);
+            let resp = self.dispatch(tag, cache_mode, msg).await?;
+
+            // Set back the message ID
+            with_id(&resp, msg.header().id())
+        }
+        .boxed()
+    }
+
+    /// Send the query to a tagged upstream and a given cache mode, giving up if no response arrives within the timeout, regardless of the timeouts of the upstreams.
+    pub async fn send_timeout(
+        &self,
+        tag: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+        timeout: Duration,
+    ) -> Result<Message<Bytes>> {
+        tokio::time::timeout(timeout, self.send(tag, cache_mode, msg))
+            .await
+            .map_err(|_| UpstreamError::Timeout(tag.clone()))?
+    }
+
+    async fn dispatch(
+        &self,
+        tag: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let u = self
+            .upstreams
+            .get(tag)
+            .ok_or_else(|| UpstreamError::MissingTag(tag.clone()))?;
+        Ok(match u {
+            // Hybrid will never call `u.send_internal()`
+            Upstream::Hybrid(v) => {
                 let v = v.iter().map(|t| self.send(t, cache_mode, msg));
                 let (r, _) = select_ok(v).await?;
                 r
-            } else {
-                u.resolve(tag, &self.cache, cache_mode, msg).await?
-            };
+            }
+            Upstream::LoadBalance(lb) => self.balance(lb, cache_mode, msg).await?,
+            Upstream::Forward(f) => match f.route(msg) {
+                Some(Forwarded::Tag(t)) => self.send(&t, cache_mode, msg).await?,
+                // Dedicated upstreams of zones share the tag of the forwarding upstream in cache. There is no collision as each query name is routed to a single zone.
+                Some(Forwarded::Upstream(u)) => {
+                    u.resolve(tag, &self.cache, &self.inflight, cache_mode, msg, true)
+                        .await?
+                }
+                None => return Err(UpstreamError::NoForwardZone(tag.clone())),
+            },
+            Upstream::Overflow { primary, alternate } => {
+                match self.send(primary, cache_mode, msg).await {
+                    Err(UpstreamError::QHandleError(QHandleError::Throttled)) => {
+                        log::debug!(
+                            "upstream `{}` is throttled, sending the query to `{}`",
+                            primary,
+                            alternate
+                        );
+                        self.send(alternate, cache_mode, msg).await?
+                    }
+                    r => r?,
+                }
+            }
+            Upstream::Others(_) => {
+                u.resolve(
+                    tag,
+                    &self.cache,
+                    &self.inflight,
+                    cache_mode,
+                    msg,
+                    self.is_healthy(tag),
+                )
+                .await?
+            }
+        })
+    }
 
-            // Set back the message ID
-            let mut resp = Message::from_octets(BytesMut::from(resp.as_slice()))?;
-            resp.header_mut().set_id(msg.header().id());
+    // Send the query to the members picked by weights, trying the next one on failure.
+    async fn balance(
+        &self,
+        lb: &LoadBalance,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let mut last = Err(UpstreamError::NoUpstreams);
+        for idx in lb.pick_order() {
+            let tag = lb.tag(idx);
+            let start = Instant::now();
+            match self.send(tag, cache_mode, msg).await {
+                Ok(resp) => {
+                    lb.record(idx, start.elapsed());
+                    return Ok(resp);
+                }
+                Err(e) => {
+                    log::debug!("upstream `{}` failed: {}, trying the next member", tag, e);
+                    last = Err(e);
+                }
+            }
+        }
+        last
+    }
+
+    /// Send every query to its tagged upstream concurrently, and return all the results in the order of the queries.
+    pub async fn send_all(
+        &self,
+        queries: &[(Label, Message<Bytes>)],
+        cache_mode: &CacheMode,
+    ) -> Vec<Result<Message<Bytes>>> {
+        join_all(
+            queries
+                .iter()
+                .map(|(tag, msg)| self.send(tag, cache_mode, msg)),
+        )
+        .await
+    }
 
-            Ok(Message::from_octets(resp.into_octets().freeze())?)
+    /// Send the query to all the tagged upstreams concurrently, and return the first successful response along with the tag of the upstream that yielded it.
+    pub async fn race(
+        &self,
+        tags: &[Label],
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<(Label, Message<Bytes>)> {
+        if tags.is_empty() {
+            return Err(UpstreamError::NoUpstreams);
         }
-        .boxed()
+        let v = tags.iter().map(|t| {
+            self.send(t, cache_mode, msg)
+                .map(move |r| r.map(|m| (t.clone(), m)))
+        });
+        let ((tag, resp), _) = select_ok(v).await?;
+        log::debug!("upstream `{}` won the race", tag);
+        Ok((tag, resp))
+    }
+
+    /// Send the query to the tagged upstreams one after another until one of them yields a response that is neither SERVFAIL nor REFUSED.
+    /// If none of them succeeds, the last response (or error if there is no response at all) is returned.
+    pub async fn fallback(
+        &self,
+        tags: &[Label],
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let mut last = Err(UpstreamError::NoUpstreams);
+        for tag in tags {
+            match self.send(tag, cache_mode, msg).await {
+                Ok(resp) if !matches!(resp.header().rcode(), Rcode::ServFail | Rcode::Refused) => {
+                    return Ok(resp)
+                }
+                Ok(resp) => {
+                    log::debug!(
+                        "upstream `{}` responded with {}, falling back",
+                        tag,
+                        resp.header().rcode()
+                    );
+                    last = Ok(resp);
+                }
+                Err(e) => {
+                    log::debug!("upstream `{}` failed: {}, falling back", tag, e);
+                    // Prefer a failing response over an error
+                    if last.is_err() {
+                        last = Err(e);
+                    }
+                }
+            }
+        }
+        last
     }
 }
 
+// Copy the response with the message ID given.
+fn with_id(resp: &Message<Bytes>, id: u16) -> Result<Message<Bytes>> {
+    let mut resp = Message::from_octets(BytesMut::from(resp.as_slice()))?;
+    resp.header_mut().set_id(id);
+    Ok(Message::from_octets(resp.into_octets().freeze())?)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::AsyncTryInto;
+    use crate::{cache::CacheConfig, AsyncTryInto, Label, MAX_LEN};
 
     use super::{
         builder::{HybridBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
-        UpstreamError,
+        CacheMode, Health, QHandle, QHandleError, Upstream, UpstreamError, Upstreams,
+    };
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
     };
+    use std::{
+        collections::HashMap,
+        num::NonZeroUsize,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    // An upstream answering every query with 1.1.1.1, counting the queries.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    #[async_trait]
+    impl QHandle for Counting {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>, QHandleError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let question = msg.first_question().unwrap();
+            let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+                .start_answer(msg, Rcode::NoError)?;
+            builder.push((question.qname(), 60, A::from_octets(1, 1, 1, 1)))?;
+            Ok(builder.into_message())
+        }
+    }
+
+    fn query(name: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN)).unwrap();
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[tokio::test]
+    async fn serve_cache_of_unhealthy() {
+        let tag = Label::from("mock");
+        let handle = Arc::new(Counting::default());
+        let mut upstreams = Upstreams::new(
+            HashMap::from([(tag.clone(), Upstream::Others(handle.clone()))]),
+            NonZeroUsize::new(8).unwrap(),
+            &CacheConfig::default(),
+        )
+        .unwrap();
+        let health = Arc::new(Health::default());
+        upstreams.health = Arc::new(HashMap::from([(tag.clone(), health.clone())]));
+
+        // Fill the cache while the upstream is healthy
+        let cached = query("cached.example.com");
+        upstreams
+            .send(&tag, &CacheMode::Standard, &cached)
+            .await
+            .unwrap();
+        assert_eq!(handle.0.load(Ordering::Relaxed), 1);
+
+        health.record(false, 1);
+        assert!(!upstreams.is_healthy(&tag));
+        // Cache hits are served as usual
+        upstreams
+            .send(&tag, &CacheMode::Standard, &cached)
+            .await
+            .unwrap();
+        // Misses fail without reaching the upstream
+        assert!(matches!(
+            upstreams
+                .send(&tag, &CacheMode::Standard, &query("missed.example.com"))
+                .await,
+            Err(UpstreamError::Unhealthy(_))
+        ));
+        assert!(matches!(
+            upstreams.send(&tag, &CacheMode::Disabled, &cached).await,
+            Err(UpstreamError::Unhealthy(_))
+        ));
+        assert_eq!(handle.0.load(Ordering::Relaxed), 1);
+
+        health.record(true, 1);
+        upstreams
+            .send(&tag, &CacheMode::Standard, &query("missed.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(handle.0.load(Ordering::Relaxed), 2);
+    }
 
     #[tokio::test]
     async fn should_not_fail_recursion() {
@@ -198,6 +585,13 @@ mod tests {
                     max_pool_size: 32,
                     timeout: 1,
                     ratelimit: None,
+                    ratelimit_queue: false,
+                    case_randomization: false,
+                    tcp_fallback: false,
+                    retry: Default::default(),
+                    edns: Default::default(),
+                    tsig: None,
+                    dnssec: false,
                 }),
             )
             .add_upstream(
@@ -225,6 +619,13 @@ mod tests {
                     max_pool_size: 256,
                     timeout: 1,
                     ratelimit: None,
+                    ratelimit_queue: false,
+                    case_randomization: false,
+                    tcp_fallback: false,
+                    retry: Default::default(),
+                    edns: Default::default(),
+                    tsig: None,
+                    dnssec: false,
                 }),
             )
             .add_upstream(
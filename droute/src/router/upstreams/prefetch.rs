@@ -0,0 +1,64 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Refresh the most queried cached responses in background before they expire, so that clients querying them never wait on the upstreams.
+
+use super::{inflight::InFlight, upstream::fetch, QHandle};
+use crate::{
+    cache::{Prefetch, RespCache},
+    Label,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+/// Refresh the popular responses periodically until the `Upstreams` owning the cache are dropped.
+pub(super) async fn watch(
+    cache: RespCache,
+    inflight: Arc<InFlight>,
+    handles: HashMap<Label, Arc<dyn QHandle>>,
+    alive: Weak<()>,
+    config: Prefetch,
+) {
+    let before = Duration::from_secs(config.before);
+    // Every response is checked at least once within the window
+    let interval = std::cmp::max(before / 2, Duration::from_secs(1));
+
+    loop {
+        tokio::time::sleep(interval).await;
+        if alive.upgrade().is_none() {
+            break;
+        }
+
+        for (tag, query) in cache.expiring(before, config.top) {
+            if let Some(handle) = handles.get(&tag) {
+                let handle = handle.clone();
+                let cache = cache.clone();
+                let inflight = inflight.clone();
+                tokio::spawn(async move {
+                    // Failures are left to the usual cache misses.
+                    if fetch(&handle, &tag, &cache, &inflight, true, &query)
+                        .await
+                        .is_ok()
+                    {
+                        log::debug!("prefetched a response from upstream `{}`", tag);
+                    }
+                });
+            }
+        }
+    }
+}
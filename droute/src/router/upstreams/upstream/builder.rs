@@ -13,20 +13,58 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-rustls",
+    feature = "dot-native-tls"
+))]
+pub use super::qhandle::client_cert::ClientCert;
+#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+pub use super::qhandle::https::HttpMethod;
+pub use super::qhandle::retry::RetryOn;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-rustls",
+    feature = "dot-native-tls",
+    feature = "doq"
+))]
+pub use super::qhandle::trust::TlsTrust;
+
+#[cfg(feature = "dnscrypt")]
+use super::qhandle::dnscrypt::{DnsCrypt, Stamp};
+#[cfg(feature = "dnssec")]
+use super::qhandle::dnssec::Dnssec;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 use super::qhandle::https::Https;
+#[cfg(feature = "doq")]
+use super::qhandle::quic::Quic;
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
 use super::qhandle::tls::Tls;
+#[cfg(feature = "tsig")]
+use super::qhandle::tsig::Tsig;
 use super::{
-    qhandle::{udp::Udp, ConnPool, Result},
-    QHandleError, Upstream,
+    qhandle::{
+        edns::EdnsControl, qos::QosPolicy, retry::Retry, rotate::Rotate, tcp::Tcp, udp::Udp,
+        zone::Zone, ConnPool, QHandle, Result,
+    },
+    Forward, Forwarded, LoadBalance, QHandleError, Upstream,
 };
-use crate::{AsyncTryInto, Label};
+use crate::{dnsmasq::directive, AsyncTryInto, Label};
 use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Dname;
 use serde::{Deserialize, Serialize};
-#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
-use std::net::IpAddr;
-use std::{net::SocketAddr, num::NonZeroU32, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 // Default value for timeout
 const fn default_timeout() -> u64 {
@@ -64,6 +102,29 @@ const fn default_tls_reuse_timeout() -> u64 {
     60000
 }
 
+// Most servers close idle connections after 10 to 30 seconds.
+const fn default_idle_timeout() -> u64 {
+    10000
+}
+
+// Keep a couple of connections established on start so that the first queries don't pay for the handshakes.
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+const fn default_tls_warm() -> usize {
+    2
+}
+
+// Each pooled QUIC connection carries a query at a time, while they are cheap to resume with 0-RTT.
+#[cfg(feature = "doq")]
+const fn default_quic_max_pool_size() -> usize {
+    256
+}
+
+// Each pooled DNSCrypt connection is a UDP socket with its own ephemeral key pair.
+#[cfg(feature = "dnscrypt")]
+const fn default_dnscrypt_max_pool_size() -> usize {
+    43
+}
+
 // We don't cache HTTPS connections. That means we wouldn't need any recovery! Indeed, we store clients.
 // On average, HTTPS query roundtrip time is 750ms. That means a bigger connection pool is almost always better.
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -71,6 +132,207 @@ const fn default_https_max_pool_size() -> usize {
     1024
 }
 
+fn default_retry_backoff() -> u64 {
+    100
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::Timeout]
+}
+
+/// Retry policy of an upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt fails
+    #[serde(default)]
+    pub retries: usize,
+    /// The time in millisecond to wait before the first retry, which is doubled on each retry afterwards
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: u64,
+    /// The failures to retry on
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            retry_backoff: default_retry_backoff(),
+            retry_on: default_retry_on(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn wrap(&self, handle: Arc<dyn QHandle>) -> Upstream {
+        if self.retries == 0 {
+            Upstream::Others(handle)
+        } else {
+            Upstream::Others(Arc::new(Retry::new(
+                handle,
+                self.retries,
+                Duration::from_millis(self.retry_backoff),
+                self.retry_on.clone(),
+            )))
+        }
+    }
+}
+
+fn default_edns() -> bool {
+    true
+}
+
+/// EDNS policy of an upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct EdnsPolicy {
+    /// Whether to send the OPT record. If false, the OPT record is stripped from queries for servers choking on it.
+    #[serde(default = "default_edns")]
+    pub edns: bool,
+    /// The EDNS UDP payload size advertised to the upstream, overriding the one in queries
+    #[serde(default)]
+    pub edns_udp_size: Option<u16>,
+}
+
+impl Default for EdnsPolicy {
+    fn default() -> Self {
+        Self {
+            edns: default_edns(),
+            edns_udp_size: None,
+        }
+    }
+}
+
+impl EdnsPolicy {
+    fn wrap(&self, handle: Arc<dyn QHandle>) -> Arc<dyn QHandle> {
+        if self.edns && self.edns_udp_size.is_none() {
+            handle
+        } else {
+            Arc::new(EdnsControl::new(handle, self.edns, self.edns_udp_size))
+        }
+    }
+}
+
+fn default_tsig_algorithm() -> String {
+    "hmac-sha256".to_string()
+}
+
+/// TSIG key used to sign queries
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct TsigKey {
+    /// Name of the key, e.g. `transfer-key`
+    pub name: String,
+    /// HMAC algorithm, one of `hmac-md5`, `hmac-sha1`, `hmac-sha256`, `hmac-sha384` and `hmac-sha512`
+    #[serde(default = "default_tsig_algorithm")]
+    pub algorithm: String,
+    /// Base64 encoded secret
+    pub secret: String,
+}
+
+impl TsigKey {
+    #[cfg(feature = "tsig")]
+    fn wrap(&self, handle: Arc<dyn QHandle>) -> Result<Arc<dyn QHandle>> {
+        Ok(Arc::new(Tsig::new(
+            handle,
+            &self.name,
+            &self.algorithm,
+            &self.secret,
+        )?))
+    }
+
+    #[cfg(not(feature = "tsig"))]
+    fn wrap(&self, _: Arc<dyn QHandle>) -> Result<Arc<dyn QHandle>> {
+        Err(QHandleError::TsigUnsupported)
+    }
+}
+
+// Sign queries with the key if there is any.
+fn sign(tsig: &Option<TsigKey>, handle: Arc<dyn QHandle>) -> Result<Arc<dyn QHandle>> {
+    match tsig {
+        Some(key) => key.wrap(handle),
+        None => Ok(handle),
+    }
+}
+
+// Validate the responses if asked to.
+#[cfg(feature = "dnssec")]
+fn validate(dnssec: bool, handle: Arc<dyn QHandle>) -> Result<Arc<dyn QHandle>> {
+    Ok(if dnssec {
+        Arc::new(Dnssec::new(handle))
+    } else {
+        handle
+    })
+}
+
+#[cfg(not(feature = "dnssec"))]
+fn validate(dnssec: bool, handle: Arc<dyn QHandle>) -> Result<Arc<dyn QHandle>> {
+    if dnssec {
+        Err(QHandleError::DnssecUnsupported)
+    } else {
+        Ok(handle)
+    }
+}
+
+/// One or more addresses of the same server, e.g. both of its IPv4 and IPv6 ones
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Addrs<T> {
+    /// A single address
+    One(T),
+    /// Addresses in the order of preference
+    Many(Vec<T>),
+}
+
+impl<T> Addrs<T> {
+    /// Get all the addresses in the order of preference
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::One(addr) => vec![addr],
+            Self::Many(addrs) => addrs,
+        }
+    }
+}
+
+impl<T> From<T> for Addrs<T> {
+    fn from(addr: T) -> Self {
+        Self::One(addr)
+    }
+}
+
+impl<T: FromStr> FromStr for Addrs<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Self::One(s.parse()?))
+    }
+}
+
+// Send queries to the addresses in turns if there are several, in which case the ratelimit is applied across all of them instead of on each one.
+fn rotate(mut handles: Vec<Arc<dyn QHandle>>, ratelimiter: QosPolicy) -> Result<Arc<dyn QHandle>> {
+    Ok(if handles.len() == 1 {
+        handles.pop().unwrap()
+    } else {
+        Arc::new(Rotate::new(handles, ratelimiter)?)
+    })
+}
+
+// Ratelimiter of an upstream, which queues the queries over the ratelimit for at most the timeout length if asked to.
+fn qos(ratelimit: Option<NonZeroU32>, queue: bool, timeout: u64) -> QosPolicy {
+    QosPolicy::new(ratelimit, queue.then(|| Duration::from_secs(timeout)))
+}
+
+// The ratelimit applied on each of the addresses
+fn addr_ratelimit<T>(addrs: &[T], ratelimit: Option<NonZeroU32>) -> Option<NonZeroU32> {
+    if addrs.len() == 1 {
+        ratelimit
+    } else {
+        None
+    }
+}
+
 /// A builder for hybrid upstream
 #[derive(Serialize, Deserialize, Clone)]
 pub struct HybridBuilder(Vec<Label>);
@@ -103,6 +365,169 @@ impl AsyncTryInto<Upstream> for HybridBuilder {
     }
 }
 
+/// A builder for load-balancing upstream
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub struct LoadBalanceBuilder {
+    /// Tags of the member upstreams and their weights. Members with zero weight are only used when all the others fail.
+    pub members: HashMap<Label, u32>,
+    /// Prefer members responding faster by dividing their weights by the measured response time
+    #[serde(default)]
+    pub latency: bool,
+}
+
+impl LoadBalanceBuilder {
+    /// Create an empty load-balancing builder
+    pub fn new(latency: bool) -> Self {
+        Self {
+            members: HashMap::new(),
+            latency,
+        }
+    }
+
+    /// Add another upstream with its weight to the load-balancing upstream about to build
+    pub fn add_tag(mut self, tag: impl Into<Label>, weight: u32) -> Self {
+        self.members.insert(tag.into(), weight);
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for LoadBalanceBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::LoadBalance(Arc::new(LoadBalance::new(
+            self.members.into_iter().collect(),
+            self.latency,
+        ))))
+    }
+}
+
+/// Where queries in a zone are forwarded to
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ForwardTarget {
+    /// Address of a UDP server, e.g. `127.0.0.1:8600`
+    Addr(SocketAddr),
+    /// IP address of a UDP server on port 53, e.g. `10.0.0.53`
+    Ip(IpAddr),
+    /// Tag of another upstream
+    Tag(Label),
+}
+
+/// A builder for zone-based forwarding upstream
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub struct ForwardBuilder {
+    /// Zones mapped to where queries in them are forwarded to. Queries are forwarded by the longest zone matching.
+    pub zones: HashMap<String, ForwardTarget>,
+    /// Tag of the upstream for queries in none of the zones
+    #[serde(default)]
+    pub default: Option<Label>,
+    /// Path to a dnsmasq configuration, whose `server=/zone/addr` directives are added to the zones. Zones in `zones` take precedence.
+    #[serde(default)]
+    pub dnsmasq: Option<PathBuf>,
+}
+
+// Parse the server address of the dnsmasq `server=` directive, e.g. `10.0.0.53`, `10.0.0.53#5353` or `10.0.0.53@eth0`, of which the interface is ignored.
+fn dnsmasq_target(s: &str) -> Option<ForwardTarget> {
+    let s = s.split('@').next()?;
+    Some(match s.split_once('#') {
+        Some((ip, port)) => {
+            ForwardTarget::Addr(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?))
+        }
+        None => ForwardTarget::Ip(s.parse().ok()?),
+    })
+}
+
+impl ForwardBuilder {
+    /// Create an empty forwarding builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forward queries in the zone to the target
+    pub fn add_zone(mut self, zone: impl Into<String>, target: ForwardTarget) -> Self {
+        self.zones.insert(zone.into(), target);
+        self
+    }
+
+    /// Set the upstream for queries in none of the zones
+    pub fn default_tag(mut self, tag: impl Into<Label>) -> Self {
+        self.default = Some(tag.into());
+        self
+    }
+
+    /// Add the zones forwarded by the `server=` directives in the dnsmasq configuration file
+    pub fn dnsmasq(mut self, path: impl Into<PathBuf>) -> Self {
+        self.dnsmasq = Some(path.into());
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for ForwardBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        let mut targets = self.zones;
+        if let Some(path) = &self.dnsmasq {
+            for line in tokio::fs::read_to_string(path).await?.lines() {
+                if let Some(("server", domains, addr)) = directive(line) {
+                    // Domains without an address are answered locally only by dnsmasq, and `#` stands for its default servers. Both are left to the default upstream here.
+                    if addr.is_empty() || addr == "#" {
+                        continue;
+                    }
+                    let target = dnsmasq_target(addr)
+                        .ok_or_else(|| QHandleError::InvalidDnsmasq(line.to_string()))?;
+                    for domain in domains.into_iter().filter(|d| *d != "#") {
+                        targets
+                            .entry(domain.to_string())
+                            .or_insert_with(|| target.clone());
+                    }
+                }
+            }
+        }
+
+        let mut zones = Vec::new();
+        for (zone, target) in targets {
+            let name = idna::domain_to_ascii(&zone)
+                .ok()
+                .and_then(|s| Dname::<Bytes>::from_str(&s).ok())
+                .ok_or_else(|| QHandleError::InvalidZone(zone.clone()))?;
+            let addr = match target {
+                ForwardTarget::Tag(t) => {
+                    zones.push((name, Forwarded::Tag(t)));
+                    continue;
+                }
+                ForwardTarget::Addr(addr) => addr,
+                ForwardTarget::Ip(ip) => SocketAddr::new(ip, 53),
+            };
+            let upstream = UdpBuilder {
+                addr: addr.into(),
+                max_pool_size: default_udp_max_pool_size(),
+                ratelimit: None,
+                ratelimit_queue: false,
+                timeout: default_timeout(),
+                case_randomization: false,
+                tcp_fallback: true,
+                retry: RetryPolicy::default(),
+                edns: EdnsPolicy::default(),
+                tsig: None,
+                dnssec: false,
+            }
+            .async_try_into()
+            .await?;
+            zones.push((name, Forwarded::Upstream(upstream)));
+        }
+        Ok(Upstream::Forward(Arc::new(Forward::new(
+            zones,
+            self.default,
+        ))))
+    }
+}
+
 /// A builder for DNS over HTTPS upstream
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 #[derive(Serialize, Deserialize, Clone)]
@@ -110,8 +535,8 @@ impl AsyncTryInto<Upstream> for HybridBuilder {
 pub struct HttpsBuilder {
     /// The URL of the DoH server. e.g. `https://cloudflare-dns.com/dns-query`
     pub uri: String,
-    /// The address of the server. e.g. `1.1.1.1` for Cloudflare DNS.
-    pub addr: IpAddr,
+    /// The address of the server. e.g. `1.1.1.1` for Cloudflare DNS. Connections to multiple addresses are raced, preferring the one that responded last.
+    pub addr: Addrs<IpAddr>,
     /// The Proxy URL used to connect the upstream server. Supporting HTTP and SOCKS5 proxy formats.
     pub proxy: Option<String>,
     /// Timeout length
@@ -123,9 +548,30 @@ pub struct HttpsBuilder {
     /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
     #[serde(default)]
     pub ratelimit: Option<NonZeroU32>,
+    /// Let queries over the ratelimit wait for their turns, for at most the timeout length, instead of failing them right away
+    #[serde(default)]
+    pub ratelimit_queue: bool,
+    /// Retry policy on failures
+    #[serde(flatten)]
+    pub retry: RetryPolicy,
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Send queries over HTTP/3 first, falling back to HTTP/2 on failure
+    #[serde(default)]
+    pub http3: bool,
+    /// HTTP method to send queries with
+    #[serde(default)]
+    pub method: HttpMethod,
+    /// Extra HTTP headers sent along with queries, e.g. `Authorization` for private servers
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Client certificate for mutual TLS
+    #[serde(default)]
+    pub client_cert: Option<ClientCert>,
+    /// CA certificates and SPKI pins to verify the server with
+    #[serde(flatten)]
+    pub trust: TlsTrust,
 }
 
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -134,11 +580,22 @@ impl AsyncTryInto<Upstream> for HttpsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Https::new(self.uri, self.addr, self.proxy, self.sni).await?,
+        Ok(self.retry.wrap(Arc::new(ConnPool::new(
+            Https::new(
+                self.uri,
+                self.addr.into_vec(),
+                self.proxy,
+                self.sni,
+                self.http3,
+                self.client_cert,
+                self.trust,
+                self.method,
+                self.headers,
+            )
+            .await?,
             self.max_pool_size,
             Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
+            qos(self.ratelimit, self.ratelimit_queue, self.timeout),
         )?)))
     }
 }
@@ -150,8 +607,8 @@ impl AsyncTryInto<Upstream> for HttpsBuilder {
 pub struct TlsBuilder {
     /// The domain of the DoH server. e.g. `cloudflare-dns.com`
     pub domain: String,
-    /// The address of the server. e.g. `1.1.1.1:853` for Cloudflare DNS.
-    pub addr: SocketAddr,
+    /// The address of the server. e.g. `1.1.1.1:853` for Cloudflare DNS. Connections to multiple addresses are raced, preferring the one that connected last.
+    pub addr: Addrs<SocketAddr>,
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
@@ -164,12 +621,33 @@ pub struct TlsBuilder {
     /// The maximum number of queries allowed to send over a single underlying TCP connection
     #[serde(default = "default_tls_max_reuse")]
     pub max_reuse: usize,
+    /// The time in millisecond an idle connection is kept for reuse, which is lowered to the one advertised by the server via EDNS TCP keepalive
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// The number of connections established in advance
+    #[serde(default = "default_tls_warm")]
+    pub warm: usize,
+    /// Send queries as TLS 1.3 early data (0-RTT) on resumed sessions
+    #[serde(default)]
+    pub early_data: bool,
     /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
     #[serde(default)]
     pub ratelimit: Option<NonZeroU32>,
+    /// Let queries over the ratelimit wait for their turns, for at most the timeout length, instead of failing them right away
+    #[serde(default)]
+    pub ratelimit_queue: bool,
+    /// Retry policy on failures
+    #[serde(flatten)]
+    pub retry: RetryPolicy,
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Client certificate for mutual TLS
+    #[serde(default)]
+    pub client_cert: Option<ClientCert>,
+    /// CA certificates and SPKI pins to verify the server with
+    #[serde(flatten)]
+    pub trust: TlsTrust,
 }
 
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
@@ -178,17 +656,112 @@ impl AsyncTryInto<Upstream> for TlsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
+        let pool = Arc::new(ConnPool::new(
             Tls::new(
                 self.domain,
-                self.addr,
+                self.addr.into_vec(),
                 self.sni,
                 self.reuse_timeout,
                 self.max_reuse,
+                self.idle_timeout,
+                self.early_data,
+                self.client_cert,
+                self.trust,
             )?,
             self.max_pool_size,
             Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
+            qos(self.ratelimit, self.ratelimit_queue, self.timeout),
+        )?);
+        if self.warm > 0 {
+            let (pool, warm) = (pool.clone(), self.warm);
+            tokio::spawn(async move { pool.warm_up(warm).await });
+        }
+        Ok(self.retry.wrap(pool))
+    }
+}
+
+/// A builder for DNS over QUIC upstream
+#[cfg(feature = "doq")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct QuicBuilder {
+    /// The domain of the DoQ server. e.g. `dns.adguard-dns.com`
+    pub domain: String,
+    /// The address of the server. e.g. `94.140.14.14:853` for AdGuard DNS.
+    pub addr: SocketAddr,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Max connection pool size
+    #[serde(default = "default_quic_max_pool_size")]
+    pub max_pool_size: usize,
+    /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+    /// Let queries over the ratelimit wait for their turns, for at most the timeout length, instead of failing them right away
+    #[serde(default)]
+    pub ratelimit_queue: bool,
+    /// Retry policy on failures
+    #[serde(flatten)]
+    pub retry: RetryPolicy,
+    /// CA certificates and SPKI pins to verify the server with
+    #[serde(flatten)]
+    pub trust: TlsTrust,
+}
+
+#[cfg(feature = "doq")]
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for QuicBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(self.retry.wrap(Arc::new(ConnPool::new(
+            Quic::new(self.domain, self.addr, self.trust)?,
+            self.max_pool_size,
+            Duration::from_secs(self.timeout),
+            qos(self.ratelimit, self.ratelimit_queue, self.timeout),
+        )?)))
+    }
+}
+
+/// A builder for DNSCrypt upstream
+#[cfg(feature = "dnscrypt")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct DnsCryptBuilder {
+    /// The DNS stamp of the DNSCrypt server. e.g. `sdns://AQcAAAAAAAAADjIwOC42Ny4yMjAuMjIw...`
+    pub stamp: String,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Max connection pool size
+    #[serde(default = "default_dnscrypt_max_pool_size")]
+    pub max_pool_size: usize,
+    /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+    /// Let queries over the ratelimit wait for their turns, for at most the timeout length, instead of failing them right away
+    #[serde(default)]
+    pub ratelimit_queue: bool,
+    /// Retry policy on failures
+    #[serde(flatten)]
+    pub retry: RetryPolicy,
+}
+
+#[cfg(feature = "dnscrypt")]
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for DnsCryptBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        let stamp: Stamp = self.stamp.parse()?;
+        let initiator =
+            tokio::time::timeout(Duration::from_secs(self.timeout), DnsCrypt::new(stamp)).await??;
+        Ok(self.retry.wrap(Arc::new(ConnPool::new(
+            initiator,
+            self.max_pool_size,
+            Duration::from_secs(self.timeout),
+            qos(self.ratelimit, self.ratelimit_queue, self.timeout),
         )?)))
     }
 }
@@ -197,17 +770,38 @@ impl AsyncTryInto<Upstream> for TlsBuilder {
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub struct UdpBuilder {
-    /// Address of the remote server
-    pub addr: SocketAddr,
+    /// Address of the remote server. Queries are sent to multiple addresses in turns, failing over to the next one on errors.
+    pub addr: Addrs<SocketAddr>,
     /// Max connection pool size
     #[serde(default = "default_udp_max_pool_size")]
     pub max_pool_size: usize,
     /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
     #[serde(default)]
     pub ratelimit: Option<NonZeroU32>,
+    /// Let queries over the ratelimit wait for their turns, for at most the timeout length, instead of failing them right away
+    #[serde(default)]
+    pub ratelimit_queue: bool,
+    /// Retry policy on failures
+    #[serde(flatten)]
+    pub retry: RetryPolicy,
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
+    /// Randomize the letter case of question names and reject responses not matching it (DNS 0x20 encoding)
+    #[serde(default)]
+    pub case_randomization: bool,
+    /// Send the query again over TCP if the UDP response is truncated
+    #[serde(default)]
+    pub tcp_fallback: bool,
+    /// EDNS policy
+    #[serde(flatten)]
+    pub edns: EdnsPolicy,
+    /// TSIG key to sign queries with
+    #[serde(default)]
+    pub tsig: Option<TsigKey>,
+    /// Validate the DNSSEC signatures in responses against the root trust anchor, answering SERVFAIL on bogus ones
+    #[serde(default)]
+    pub dnssec: bool,
 }
 
 #[async_trait(?Send)]
@@ -215,12 +809,157 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Udp::new(self.addr).await?,
-            self.max_pool_size,
-            Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
-        )?)))
+        // Randomizing the case of signed queries would invalidate the TSIG signature.
+        let case_randomization = self.case_randomization && self.tsig.is_none();
+        if self.case_randomization && !case_randomization {
+            log::warn!("case randomization is disabled for TSIG signed queries");
+        }
+        let addrs = self.addr.into_vec();
+        let ratelimit = addr_ratelimit(&addrs, self.ratelimit);
+        let mut pools: Vec<Arc<dyn QHandle>> = Vec::new();
+        for addr in addrs {
+            pools.push(Arc::new(ConnPool::new(
+                Udp::new(addr, case_randomization, self.tcp_fallback).await?,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                qos(ratelimit, self.ratelimit_queue, self.timeout),
+            )?));
+        }
+        let pool = rotate(
+            pools,
+            qos(self.ratelimit, self.ratelimit_queue, self.timeout),
+        )?;
+        if self.dnssec && !self.edns.edns {
+            log::warn!(
+                "DNSSEC validation requires EDNS, all the responses will be treated as insecure"
+            );
+        }
+        // Sign the query after all the other modifications.
+        Ok(self.retry.wrap(validate(
+            self.dnssec,
+            self.edns.wrap(sign(&self.tsig, pool)?),
+        )?))
+    }
+}
+
+/// A builder for TCP upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct TcpBuilder {
+    /// Address of the remote server. Queries are sent to multiple addresses in turns, failing over to the next one on errors.
+    pub addr: Addrs<SocketAddr>,
+    /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+    /// Let queries over the ratelimit wait for their turns, for at most the timeout length, instead of failing them right away
+    #[serde(default)]
+    pub ratelimit_queue: bool,
+    /// Retry policy on failures
+    #[serde(flatten)]
+    pub retry: RetryPolicy,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// The time in millisecond an idle connection is kept for reuse, which is lowered to the one advertised by the server via EDNS TCP keepalive
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// EDNS policy
+    #[serde(flatten)]
+    pub edns: EdnsPolicy,
+    /// TSIG key to sign queries with
+    #[serde(default)]
+    pub tsig: Option<TsigKey>,
+    /// Validate the DNSSEC signatures in responses against the root trust anchor, answering SERVFAIL on bogus ones
+    #[serde(default)]
+    pub dnssec: bool,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for TcpBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        let addrs = self.addr.into_vec();
+        let ratelimit = addr_ratelimit(&addrs, self.ratelimit);
+        let conns = addrs
+            .into_iter()
+            .map(|addr| {
+                Arc::new(Tcp::new(
+                    addr,
+                    Duration::from_secs(self.timeout),
+                    Duration::from_millis(self.idle_timeout),
+                    // The keepalive option would bring back the OPT record stripped, or invalidate the TSIG signature.
+                    self.edns.edns && self.tsig.is_none(),
+                    qos(ratelimit, self.ratelimit_queue, self.timeout),
+                )) as Arc<dyn QHandle>
+            })
+            .collect();
+        let tcp = rotate(
+            conns,
+            qos(self.ratelimit, self.ratelimit_queue, self.timeout),
+        )?;
+        if self.dnssec && !self.edns.edns {
+            log::warn!(
+                "DNSSEC validation requires EDNS, all the responses will be treated as insecure"
+            );
+        }
+        Ok(self.retry.wrap(validate(
+            self.dnssec,
+            self.edns.wrap(sign(&self.tsig, tcp)?),
+        )?))
+    }
+}
+
+/// A builder for upstream sending the queries over the ratelimit of an upstream to another one
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct OverflowBuilder {
+    /// Tag of the upstream queries are sent to, which should have `ratelimit` set
+    pub primary: Label,
+    /// Tag of the upstream taking the queries throttled by the primary one
+    pub alternate: Label,
+}
+
+impl OverflowBuilder {
+    /// Create an overflow builder from the tags of the primary and alternate upstreams
+    pub fn new(primary: impl Into<Label>, alternate: impl Into<Label>) -> Self {
+        Self {
+            primary: primary.into(),
+            alternate: alternate.into(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for OverflowBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Overflow {
+            primary: self.primary,
+            alternate: self.alternate,
+        })
+    }
+}
+
+/// A builder for upstream answering queries authoritatively from a zone file
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct ZoneBuilder {
+    /// The apex of the zone, e.g. `home.arpa`. Relative names in the zone file are relative to it unless `$ORIGIN` is given.
+    pub origin: String,
+    /// Path to the zone file in the RFC 1035 format
+    pub path: PathBuf,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for ZoneBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Others(Arc::new(
+            Zone::load(&self.origin, &self.path).await?,
+        )))
     }
 }
 
@@ -230,14 +969,30 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
 pub enum UpstreamBuilder {
     /// Race various different upstreams concurrently. You can use it recursively, meaning Hybrid over (Hybrid over (DoH + UDP) + UDP) is legal.
     Hybrid(HybridBuilder),
+    /// Distribute queries across various different upstreams by weights instead of racing them.
+    LoadBalance(LoadBalanceBuilder),
+    /// Forward queries to different upstreams by zones.
+    Forward(ForwardBuilder),
+    /// Send the queries over the ratelimit of an upstream to another one.
+    Overflow(OverflowBuilder),
+    /// Zone file served locally.
+    Zone(ZoneBuilder),
     /// UDP connection.
     Udp(UdpBuilder),
+    /// TCP connection with pipelined queries.
+    Tcp(TcpBuilder),
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     /// HTTPS connection.
     Https(HttpsBuilder),
     #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
     /// HTTPS connection.
     Tls(TlsBuilder),
+    #[cfg(feature = "doq")]
+    /// QUIC connection.
+    Quic(QuicBuilder),
+    #[cfg(feature = "dnscrypt")]
+    /// DNSCrypt connection.
+    DnsCrypt(DnsCryptBuilder),
 }
 
 #[async_trait(?Send)]
@@ -247,14 +1002,31 @@ impl AsyncTryInto<Upstream> for UpstreamBuilder {
         Ok(match self {
             Self::Hybrid(v) => v.async_try_into().await?,
 
+            Self::LoadBalance(l) => l.async_try_into().await?,
+
+            Self::Forward(f) => f.async_try_into().await?,
+
+            Self::Overflow(o) => o.async_try_into().await?,
+
+            Self::Zone(z) => z.async_try_into().await?,
+
             // UDP Upstream
             Self::Udp(u) => u.async_try_into().await?,
 
+            // TCP Upstream
+            Self::Tcp(t) => t.async_try_into().await?,
+
             #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
             Self::Https(h) => h.async_try_into().await?,
 
             #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
             Self::Tls(t) => t.async_try_into().await?,
+
+            #[cfg(feature = "doq")]
+            Self::Quic(q) => q.async_try_into().await?,
+
+            #[cfg(feature = "dnscrypt")]
+            Self::DnsCrypt(d) => d.async_try_into().await?,
         })
     }
 
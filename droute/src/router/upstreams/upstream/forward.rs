@@ -0,0 +1,85 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Upstream;
+use crate::Label;
+use bytes::Bytes;
+use domain::base::{Dname, Message};
+
+/// Where queries in a zone are forwarded to
+#[derive(Clone)]
+pub enum Forwarded {
+    /// Another upstream defined
+    Tag(Label),
+    /// An upstream dedicated to the zone
+    Upstream(Upstream),
+}
+
+/// Upstream forwarding queries to different upstreams by the zones they belong to
+pub struct Forward {
+    // Sorted so that longer zones are matched first
+    zones: Vec<(Dname<Bytes>, Forwarded)>,
+    default: Option<Label>,
+}
+
+impl Forward {
+    /// Create a forwarding upstream with zones and where queries in them go. Queries in none of the zones are sent to the `default` upstream.
+    pub fn new(mut zones: Vec<(Dname<Bytes>, Forwarded)>, default: Option<Label>) -> Self {
+        zones.sort_by_key(|(zone, _)| std::cmp::Reverse(zone.label_count()));
+        Self { zones, default }
+    }
+
+    /// Tags of the upstreams it forwards to
+    pub fn tags(&self) -> impl Iterator<Item = &Label> {
+        self.zones
+            .iter()
+            .filter_map(|(_, f)| match f {
+                Forwarded::Tag(t) => Some(t),
+                Forwarded::Upstream(_) => None,
+            })
+            .chain(self.default.iter())
+    }
+
+    // Find where the query goes by the longest zone matching its name.
+    pub(crate) fn route(&self, msg: &Message<Bytes>) -> Option<Forwarded> {
+        if let Some(question) = msg.first_question() {
+            let qname = question.qname();
+            if let Some((_, f)) = self.zones.iter().find(|(zone, _)| qname.ends_with(zone)) {
+                return Some(f.clone());
+            }
+        }
+        self.default.clone().map(Forwarded::Tag)
+    }
+
+    // Replace the handles of the upstreams dedicated to zones with the one given, e.g. to mock them in tests.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn mock_handles(&self, handle: std::sync::Arc<dyn super::QHandle>) -> Self {
+        let zones = self
+            .zones
+            .iter()
+            .map(|(zone, f)| {
+                let f = match f {
+                    Forwarded::Tag(t) => Forwarded::Tag(t.clone()),
+                    Forwarded::Upstream(_) => Forwarded::Upstream(Upstream::Others(handle.clone())),
+                };
+                (zone.clone(), f)
+            })
+            .collect();
+        Self {
+            zones,
+            default: self.default.clone(),
+        }
+    }
+}
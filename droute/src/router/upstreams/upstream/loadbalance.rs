@@ -0,0 +1,141 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::Label;
+use rand::Rng;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upstreams distributing queries across the members by weights
+pub struct LoadBalance {
+    members: Vec<(Label, u32)>,
+    latency_aware: bool,
+    // Moving average of the response time of each member in microseconds, zero if not measured yet.
+    latencies: Vec<AtomicU64>,
+}
+
+impl LoadBalance {
+    /// Create a load-balancing upstream with the weights of its members. If `latency_aware` is true, weights are further divided by the measured response time.
+    pub fn new(members: Vec<(Label, u32)>, latency_aware: bool) -> Self {
+        let latencies = members.iter().map(|_| AtomicU64::new(0)).collect();
+        Self {
+            members,
+            latency_aware,
+            latencies,
+        }
+    }
+
+    /// Tags of the members
+    pub fn tags(&self) -> impl Iterator<Item = &Label> {
+        self.members.iter().map(|(t, _)| t)
+    }
+
+    pub(crate) fn tag(&self, idx: usize) -> &Label {
+        &self.members[idx].0
+    }
+
+    fn latency(&self, idx: usize) -> u64 {
+        self.latencies[idx].load(Ordering::Relaxed)
+    }
+
+    /// Record the response time of a member.
+    pub(crate) fn record(&self, idx: usize, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        let old = self.latency(idx);
+        let new = if old == 0 {
+            sample.max(1)
+        } else {
+            (old * 7 + sample) / 8
+        };
+        self.latencies[idx].store(new.max(1), Ordering::Relaxed);
+    }
+
+    // The weight used on picking, which is scaled down by the response time if latency-aware.
+    fn effective_weight(&self, idx: usize) -> f64 {
+        let weight = self.members[idx].1 as f64;
+        if !self.latency_aware {
+            return weight;
+        }
+        // Members not measured yet are assumed to be as fast as the fastest one so that they get a chance.
+        let latency = match self.latency(idx) {
+            0 => (0..self.members.len())
+                .map(|i| self.latency(i))
+                .filter(|l| *l > 0)
+                .min()
+                .unwrap_or(1),
+            l => l,
+        };
+        weight / latency as f64
+    }
+
+    /// The order in which members are tried, sampled randomly by their weights without replacement.
+    /// Members with zero weight are only used as backups after all the others.
+    pub(crate) fn pick_order(&self) -> Vec<usize> {
+        let mut rng = rand::thread_rng();
+        let (mut candidates, backups): (Vec<usize>, Vec<usize>) =
+            (0..self.members.len()).partition(|i| self.members[*i].1 > 0);
+        let mut weights: Vec<f64> = candidates
+            .iter()
+            .map(|i| self.effective_weight(*i))
+            .collect();
+
+        let mut order = Vec::with_capacity(self.members.len());
+        while !candidates.is_empty() {
+            let mut point = rng.gen::<f64>() * weights.iter().sum::<f64>();
+            let mut pick = candidates.len() - 1;
+            for (pos, w) in weights.iter().enumerate() {
+                if point < *w {
+                    pick = pos;
+                    break;
+                }
+                point -= w;
+            }
+            order.push(candidates.remove(pick));
+            weights.remove(pick);
+        }
+        order.extend(backups);
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoadBalance;
+    use std::time::Duration;
+
+    #[test]
+    fn backups_last() {
+        let lb = LoadBalance::new(
+            vec![("a".into(), 0), ("b".into(), 1), ("c".into(), 3)],
+            false,
+        );
+        for _ in 0..100 {
+            let order = lb.pick_order();
+            assert_eq!(order.len(), 3);
+            assert_eq!(order[2], 0);
+        }
+    }
+
+    #[test]
+    fn prefer_fast_members() {
+        let lb = LoadBalance::new(vec![("a".into(), 1), ("b".into(), 1)], true);
+        lb.record(0, Duration::from_millis(1));
+        lb.record(1, Duration::from_millis(1000));
+        let first = (0..1000).filter(|_| lb.pick_order()[0] == 0).count();
+        assert!(first > 900);
+    }
+}
@@ -14,14 +14,24 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod builder;
+mod forward;
+mod loadbalance;
 mod qhandle;
 
 use std::sync::Arc;
 
 use bytes::Bytes;
+pub use forward::{Forward, Forwarded};
+pub use loadbalance::LoadBalance;
 pub use qhandle::{QHandle, QHandleError};
+// Zone files are parsed for response policy zones as well
+pub(crate) use qhandle::zone;
 
-use super::{error::Result, CacheMode};
+use super::{
+    error::{Result, UpstreamError},
+    inflight::{self, InFlight, Role},
+    CacheMode,
+};
 use crate::{
     cache::{RecordStatus::*, RespCache},
     Label,
@@ -34,63 +44,100 @@ pub enum Upstream {
     /// Hybrid upstream type
     // We don't use HashSet because we don't need to look up
     Hybrid(Vec<Label>),
+    /// Load-balancing upstream type
+    LoadBalance(Arc<LoadBalance>),
+    /// Zone-based forwarding upstream type
+    Forward(Arc<Forward>),
+    /// Upstream type sending the queries throttled by the ratelimit of the primary upstream to the alternate one
+    Overflow {
+        /// Tag of the upstream queries are sent to
+        primary: Label,
+        /// Tag of the upstream taking the queries over the ratelimit of the primary one
+        alternate: Label,
+    },
     /// Other upstream types, like Zone or ClientPool.
     Others(Arc<dyn QHandle>),
 }
 
 impl Upstream {
+    // Get the tags of upstreams this upstream sends queries to, if any.
     pub(super) fn try_hybrid(&self) -> Option<Vec<&Label>> {
         match &self {
             Self::Hybrid(v) => Some(v.iter().collect()),
+            Self::LoadBalance(lb) => Some(lb.tags().collect()),
+            Self::Forward(f) => Some(f.tags().collect()),
+            Self::Overflow { primary, alternate } => Some(vec![primary, alternate]),
             _ => None,
         }
     }
 
     /// Resolve the query into a response.
+    /// Identical queries missing the cache concurrently share a single upstream query, whose response is cached once.
+    /// Cached responses are still served if the upstream is not `healthy`, but queries missing the cache fail right away instead of being sent to it.
     pub async fn resolve(
         &self,
         tag: &Label,
         cache: &RespCache,
+        inflight: &Arc<InFlight>,
         cache_mode: &CacheMode,
         msg: &Message<Bytes>,
+        healthy: bool,
     ) -> Result<Message<Bytes>> {
         if let Self::Others(inner) = &self {
             log::info!("querying with upstream: {}", tag);
+            let cache_mode = if cache.bypass(msg) {
+                &CacheMode::Disabled
+            } else {
+                cache_mode
+            };
             // Manage cache with caching policies
-            let r = match cache_mode {
-                CacheMode::Disabled => inner.query(msg).await?,
-                CacheMode::Standard => match cache.get(tag, msg) {
+            let cached = match cache_mode {
+                CacheMode::Disabled => None,
+                CacheMode::Standard => match cache.get(tag, msg, false) {
                     // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
+                    Some(Alive(r)) => Some(r),
                     // No cache or cache expired
-                    Some(Expired(_)) | None => inner.query(msg).await?,
+                    Some(Expired(_)) | Some(Refreshing(_)) | None => None,
                 },
-                CacheMode::Persistent => match cache.get(tag, msg) {
-                    // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
+                CacheMode::Persistent => match cache.get(tag, msg, true) {
+                    // Cache available within TTL constraints, or being refreshed by another query
+                    Some(Alive(r)) | Some(Refreshing(r)) => Some(r),
+                    // Refreshing on a dead upstream would fail anyway.
+                    Some(Expired(r)) if !healthy => Some(r),
                     Some(Expired(r)) => {
                         // Cache records exists, but TTL exceeded.
                         // We try to update the cache and return back the outdated value.
                         let inner = inner.clone();
                         // Arc inside
                         let cache = cache.clone();
+                        let inflight = inflight.clone();
                         let msg = msg.clone();
                         let tag = tag.clone();
                         tokio::spawn(async move {
-                            // We have to update the cache though
                             // We don't care about failures here.
-                            if let Ok(r) = inner.query(&msg).await {
-                                cache.put(tag, &msg, r)
-                            }
+                            let _ = fetch(&inner, &tag, &cache, &inflight, true, &msg).await;
                         });
-                        r
+                        Some(r)
                     }
-                    None => inner.query(msg).await?,
+                    None => None,
                 },
             };
-            if cache_mode != &CacheMode::Disabled {
-                cache.put(tag.clone(), msg, r.clone());
-            }
+            let r = match cached {
+                Some(r) => r,
+                // Fail fast instead of waiting for the timeout on a dead upstream
+                None if !healthy => return Err(UpstreamError::Unhealthy(tag.clone())),
+                None => {
+                    fetch(
+                        inner,
+                        tag,
+                        cache,
+                        inflight,
+                        cache_mode != &CacheMode::Disabled,
+                        msg,
+                    )
+                    .await?
+                }
+            };
             log::info!("query successfully completed.");
             Ok(r)
         } else {
@@ -98,3 +145,39 @@ impl Upstream {
         }
     }
 }
+
+/// Query the upstream and cache the response if `store` is set. If an identical query is being sent meanwhile, its response is shared instead.
+/// The shared response is cached if any of the identical queries sets `store`.
+pub(super) async fn fetch(
+    inner: &Arc<dyn QHandle>,
+    tag: &Label,
+    cache: &RespCache,
+    inflight: &Arc<InFlight>,
+    store: bool,
+    msg: &Message<Bytes>,
+) -> Result<Message<Bytes>> {
+    let query = || async {
+        let r = inner.query(msg).await?;
+        if store {
+            cache.put(tag.clone(), msg, r.clone());
+        }
+        Ok::<_, UpstreamError>(r)
+    };
+    match inflight::Key::new(tag, msg).map(|key| inflight.join(key, store)) {
+        Some(Role::Leader(guard)) => {
+            let r = inner.query(msg).await.map_err(UpstreamError::from);
+            if guard.finish(&r) || store {
+                if let Ok(r) = &r {
+                    cache.put(tag.clone(), msg, r.clone());
+                }
+            }
+            r
+        }
+        Some(Role::Follower(rx)) => match rx.await {
+            Ok(r) => r.map_err(UpstreamError::Coalesced),
+            // The leading query is cancelled, e.g. on losing a race.
+            Err(_) => query().await,
+        },
+        None => query().await,
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Client certificates for mutual TLS with DoH and DoT upstreams
+
+use super::{QHandleError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Client certificate chain and private key in PEM files, presented to upstreams requiring mutual TLS
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct ClientCert {
+    /// Path to the certificate chain
+    pub cert: PathBuf,
+    /// Path to the private key in PKCS#8, or for rustls also PKCS#1 and SEC1
+    pub key: PathBuf,
+}
+
+impl ClientCert {
+    fn read(path: &PathBuf) -> Result<Vec<u8>> {
+        std::fs::read(path).map_err(|e| {
+            QHandleError::InvalidClientCert(format!("failed to read {}: {}", path.display(), e))
+        })
+    }
+
+    #[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+    pub(super) fn to_rustls(&self) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+        use rustls_pemfile::Item;
+
+        let certs: Vec<_> = rustls_pemfile::certs(&mut Self::read(&self.cert)?.as_slice())
+            .map_err(|e| QHandleError::InvalidClientCert(e.to_string()))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(QHandleError::InvalidClientCert(format!(
+                "no certificate found in {}",
+                self.cert.display()
+            )));
+        }
+
+        let key = rustls_pemfile::read_all(&mut Self::read(&self.key)?.as_slice())
+            .map_err(|e| QHandleError::InvalidClientCert(e.to_string()))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                QHandleError::InvalidClientCert(format!(
+                    "no private key found in {}",
+                    self.key.display()
+                ))
+            })?;
+
+        Ok((certs, rustls::PrivateKey(key)))
+    }
+
+    #[cfg(any(feature = "doh-native-tls", feature = "dot-native-tls"))]
+    pub(super) fn to_native_tls(&self) -> Result<native_tls::Identity> {
+        native_tls::Identity::from_pkcs8(&Self::read(&self.cert)?, &Self::read(&self.key)?)
+            .map_err(|e| QHandleError::InvalidClientCert(e.to_string()))
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// DNSCrypt certificates. See also https://dnscrypt.info/protocol
+
+use super::{DnsCryptError, Stamp};
+use crate::MAX_LEN;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{Dname, Message, MessageBuilder, Rtype},
+    rdata::Txt,
+};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+// Magic(4) + ES version(2) + minor version(2) + signature(64) + resolver key(32) + client magic(8) + serial(4) + start(4) + end(4)
+const CERT_LEN: usize = 124;
+
+/// The encryption system used by a certificate
+// Ordered by preference
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EsVersion {
+    /// X25519-XSalsa20Poly1305
+    XSalsa20Poly1305,
+    /// X25519-XChacha20Poly1305
+    XChacha20Poly1305,
+}
+
+/// A verified certificate of the resolver
+#[derive(Clone, Debug)]
+pub struct Cert {
+    pub es_version: EsVersion,
+    pub resolver_pk: [u8; 32],
+    pub client_magic: [u8; 8],
+    pub serial: u32,
+    pub ts_start: u32,
+    pub ts_end: u32,
+}
+
+pub(super) fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+impl Cert {
+    // Parse and verify a certificate with the provider's public key.
+    fn parse(data: &[u8], provider_pk: &PublicKey) -> Result<Self, DnsCryptError> {
+        if data.len() < CERT_LEN || &data[0..4] != CERT_MAGIC {
+            return Err(DnsCryptError::InvalidCert);
+        }
+        let es_version = match u16::from_be_bytes([data[4], data[5]]) {
+            1 => EsVersion::XSalsa20Poly1305,
+            2 => EsVersion::XChacha20Poly1305,
+            _ => return Err(DnsCryptError::InvalidCert),
+        };
+
+        // The signature covers everything after itself, including extensions.
+        let signature =
+            Signature::from_bytes(&data[8..72]).map_err(|_| DnsCryptError::InvalidCert)?;
+        let signed = &data[72..];
+        provider_pk
+            .verify(signed, &signature)
+            .map_err(|_| DnsCryptError::InvalidCert)?;
+
+        let u32_at = |pos: usize| u32::from_be_bytes(signed[pos..pos + 4].try_into().unwrap());
+        Ok(Self {
+            es_version,
+            resolver_pk: signed[0..32].try_into().unwrap(),
+            client_magic: signed[32..40].try_into().unwrap(),
+            serial: u32_at(40),
+            ts_start: u32_at(44),
+            ts_end: u32_at(48),
+        })
+    }
+
+    /// Whether the certificate is within its validity period
+    pub fn is_valid(&self) -> bool {
+        let now = now();
+        self.ts_start <= now && now < self.ts_end
+    }
+
+    /// Fetch the certificates of the resolver, and return the valid one with the highest serial number.
+    /// Certificates using XChacha20Poly1305 are preferred.
+    pub async fn fetch(stamp: &Stamp) -> Result<Self, DnsCryptError> {
+        let provider_pk =
+            PublicKey::from_bytes(&stamp.provider_pk).map_err(|_| DnsCryptError::InvalidStamp)?;
+
+        let name = Dname::<Bytes>::from_str(&stamp.provider_name)
+            .map_err(|_| DnsCryptError::InvalidStamp)?;
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+        builder.header_mut().set_random_id();
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::Txt))?;
+        let query = builder.into_message();
+
+        let socket = UdpSocket::bind(if stamp.addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })
+        .await?;
+        socket.connect(stamp.addr).await?;
+        socket.send(query.as_slice()).await?;
+
+        let resp = loop {
+            let mut buf = BytesMut::with_capacity(MAX_LEN);
+            buf.resize(MAX_LEN, 0);
+            let len = socket.recv(&mut buf).await?;
+            buf.resize(len, 0);
+            match Message::from_octets(buf.freeze()) {
+                Ok(resp) if resp.is_answer(&query) => break resp,
+                _ => continue,
+            }
+        };
+
+        let mut best: Option<Self> = None;
+        for record in resp.answer()?.limit_to::<Txt<_>>() {
+            let mut data = Vec::new();
+            for s in record?.data().iter() {
+                data.extend_from_slice(s);
+            }
+            let cert = match Self::parse(&data, &provider_pk) {
+                Ok(cert) if cert.is_valid() => cert,
+                _ => continue,
+            };
+            best = match best {
+                Some(b) if (b.es_version, b.serial) >= (cert.es_version, cert.serial) => Some(b),
+                _ => Some(cert),
+            };
+        }
+        best.ok_or(DnsCryptError::NoValidCert)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::{now, Cert, EsVersion, CERT_LEN};
+    use crate::router::upstreams::upstream::qhandle::dnscrypt::{DnsCryptError, Stamp};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{
+            iana::{Class, Rcode},
+            Message, MessageBuilder,
+        },
+        rdata::Txt,
+    };
+    use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+    use tokio::net::UdpSocket;
+
+    // The long-term key pair of the provider
+    pub(in super::super) fn provider() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        Keypair {
+            public: PublicKey::from(&secret),
+            secret,
+        }
+    }
+
+    // A certificate signed by the provider, valid from `start` to `end`
+    pub(in super::super) fn signed(
+        es_version: u16,
+        resolver_pk: [u8; 32],
+        serial: u32,
+        start: u32,
+        end: u32,
+    ) -> Vec<u8> {
+        let mut signed = resolver_pk.to_vec();
+        signed.extend_from_slice(b"clientmg");
+        for n in [serial, start, end] {
+            signed.extend_from_slice(&n.to_be_bytes());
+        }
+        let mut cert = b"DNSC".to_vec();
+        cert.extend_from_slice(&es_version.to_be_bytes());
+        cert.extend_from_slice(&[0, 0]);
+        cert.extend_from_slice(&provider().sign(&signed).to_bytes());
+        cert.extend_from_slice(&signed);
+        cert
+    }
+
+    // A certificate valid for an hour either way
+    fn valid(es_version: u16, serial: u32) -> Vec<u8> {
+        signed(
+            es_version,
+            [serial as u8; 32],
+            serial,
+            now() - 3600,
+            now() + 3600,
+        )
+    }
+
+    // A resolver answering the certificate query with a TXT record of each certificate.
+    async fn serve(certs: Vec<Vec<u8>>) -> Stamp {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stamp = Stamp {
+            addr: socket.local_addr().unwrap(),
+            provider_pk: provider().public.to_bytes(),
+            provider_name: "2.dnscrypt-cert.example.com".to_string(),
+        };
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+            let query = Message::from_octets(Bytes::copy_from_slice(&buf[..len])).unwrap();
+            let mut builder = MessageBuilder::from_target(BytesMut::new())
+                .unwrap()
+                .start_answer(&query, Rcode::NoError)
+                .unwrap();
+            let name = query.first_question().unwrap().qname().to_owned();
+            for cert in certs {
+                builder
+                    .push((
+                        &name,
+                        Class::In,
+                        3600,
+                        Txt::<Bytes>::from_slice(&cert).unwrap(),
+                    ))
+                    .unwrap();
+            }
+            socket.send_to(&builder.finish(), src).await.unwrap();
+        });
+        stamp
+    }
+
+    #[test]
+    fn parse() {
+        let provider_pk = provider().public;
+        let cert = Cert::parse(&signed(2, [1; 32], 42, 10, 20), &provider_pk).unwrap();
+        assert_eq!(cert.es_version, EsVersion::XChacha20Poly1305);
+        assert_eq!(cert.resolver_pk, [1; 32]);
+        assert_eq!(&cert.client_magic, b"clientmg");
+        assert_eq!((cert.serial, cert.ts_start, cert.ts_end), (42, 10, 20));
+        assert!(!cert.is_valid());
+
+        let data = valid(1, 1);
+        assert_eq!(data.len(), CERT_LEN);
+        let cert = Cert::parse(&data, &provider_pk).unwrap();
+        assert_eq!(cert.es_version, EsVersion::XSalsa20Poly1305);
+        assert!(cert.is_valid());
+    }
+
+    #[test]
+    fn reject() {
+        let provider_pk = provider().public;
+        let data = valid(2, 1);
+        let invalid = |data: &[u8]| {
+            matches!(
+                Cert::parse(data, &provider_pk),
+                Err(DnsCryptError::InvalidCert)
+            )
+        };
+        // Any byte signed is tampered with
+        let mut tampered = data.clone();
+        tampered[CERT_LEN - 1] ^= 1;
+        assert!(invalid(&tampered));
+        // Wrong magic
+        let mut magic = data.clone();
+        magic[0] = b'X';
+        assert!(invalid(&magic));
+        // Unknown encryption system
+        assert!(invalid(&signed(3, [1; 32], 1, 0, u32::MAX)));
+        assert!(invalid(&data[..CERT_LEN - 1]));
+        // Signed by another provider
+        let other = PublicKey::from(&SecretKey::from_bytes(&[8; 32]).unwrap());
+        assert!(Cert::parse(&data, &other).is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch() {
+        let mut forged = valid(2, 9);
+        forged[CERT_LEN - 1] ^= 1;
+        let stamp = serve(vec![
+            valid(1, 5),
+            valid(2, 3),
+            valid(2, 2),
+            // Expired or forged ones are skipped despite their higher serials.
+            signed(2, [4; 32], 4, now() - 7200, now() - 3600),
+            forged,
+        ])
+        .await;
+        let cert = Cert::fetch(&stamp).await.unwrap();
+        // XChacha20Poly1305 is preferred over a higher serial.
+        assert_eq!(cert.es_version, EsVersion::XChacha20Poly1305);
+        assert_eq!(cert.serial, 3);
+        assert_eq!(cert.resolver_pk, [3; 32]);
+
+        let stamp = serve(vec![signed(2, [4; 32], 4, now() - 7200, now() - 3600)]).await;
+        assert!(matches!(
+            Cert::fetch(&stamp).await,
+            Err(DnsCryptError::NoValidCert)
+        ));
+    }
+}
@@ -0,0 +1,450 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+mod cert;
+mod stamp;
+
+use self::cert::{now, Cert, EsVersion};
+pub use self::stamp::Stamp;
+use super::{ConnInitiator, QHandle, Result};
+use crate::MAX_LEN;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use crypto_box::{
+    aead::{generic_array::GenericArray, AeadInPlace},
+    ChaChaBox, PublicKey, SalsaBox, SecretKey,
+};
+use domain::base::{name::PushError, octets::ParseError, Message, ShortBuf};
+use rand::RngCore;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+const RESOLVER_MAGIC: &[u8; 8] = b"r6fnvWj8";
+// Queries over UDP are padded to at least this length to mitigate amplification attacks.
+const MIN_QUERY_LEN: usize = 256;
+const TAG_LEN: usize = 16;
+const HALF_NONCE_LEN: usize = 12;
+// Certificates are fetched again after this amount of seconds to pick up rotated ones.
+const CERT_REFRESH_INTERVAL: u32 = 3600;
+
+/// Errors related to DNSCrypt
+#[derive(Debug, Error)]
+pub enum DnsCryptError {
+    /// The `sdns://` stamp is malformed or not for DNSCrypt servers
+    #[error("invalid DNSCrypt stamp")]
+    InvalidStamp,
+
+    /// The certificate is malformed or its signature is invalid
+    #[error("invalid DNSCrypt certificate")]
+    InvalidCert,
+
+    /// No valid certificate is provided by the resolver
+    #[error("no valid DNSCrypt certificate found")]
+    NoValidCert,
+
+    /// Failed to encrypt the query or decrypt the response
+    #[error("failed to encrypt or decrypt the DNSCrypt message")]
+    CryptoFailed,
+
+    /// IO Error
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+
+    /// Failed to parse the certificate response
+    #[error(transparent)]
+    ParseError(#[from] ParseError),
+
+    /// Failed to build the certificate query
+    #[error(transparent)]
+    PushError(#[from] PushError),
+
+    /// The buffer is too short
+    #[error(transparent)]
+    ShortBuf(#[from] ShortBuf),
+}
+
+// The certificate currently in use and the time it was fetched
+struct CertState {
+    stamp: Stamp,
+    cert: RwLock<(Arc<Cert>, u32)>,
+}
+
+impl CertState {
+    // Get a valid certificate, fetching new ones if the current one expires or hasn't been refreshed for a while.
+    async fn get(&self) -> std::result::Result<Arc<Cert>, DnsCryptError> {
+        {
+            let (cert, fetched) = &*self.cert.read().unwrap();
+            if cert.is_valid() && now().saturating_sub(*fetched) < CERT_REFRESH_INTERVAL {
+                return Ok(cert.clone());
+            }
+        }
+        let cert = Arc::new(Cert::fetch(&self.stamp).await?);
+        log::debug!(
+            "DNSCrypt certificate of {} refreshed with serial {}",
+            self.stamp.provider_name,
+            cert.serial
+        );
+        *self.cert.write().unwrap() = (cert.clone(), now());
+        Ok(cert)
+    }
+}
+
+/// Client instance for DNSCrypt connections
+#[derive(Clone)]
+pub struct DnsCrypt {
+    state: Arc<CertState>,
+}
+
+impl DnsCrypt {
+    /// Create a new DNSCrypt client creator instance with the given `sdns://` stamp, fetching the certificates of the resolver.
+    pub async fn new(stamp: Stamp) -> std::result::Result<Self, DnsCryptError> {
+        let cert = Arc::new(Cert::fetch(&stamp).await?);
+        Ok(Self {
+            state: Arc::new(CertState {
+                stamp,
+                cert: RwLock::new((cert, now())),
+            }),
+        })
+    }
+}
+
+/// A DNSCrypt connection to the upstream with its own key pair
+pub struct DnsCryptConn {
+    socket: UdpSocket,
+    state: Arc<CertState>,
+    secret_key: SecretKey,
+}
+
+#[async_trait]
+impl ConnInitiator for DnsCrypt {
+    type Connection = DnsCryptConn;
+
+    async fn create(&self) -> std::io::Result<Self::Connection> {
+        let addr = self.state.stamp.addr;
+        let socket = UdpSocket::bind(if addr.is_ipv4() {
+            SocketAddr::from(([0u8; 4], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        })
+        .await?;
+        socket.connect(addr).await?;
+        Ok(DnsCryptConn {
+            socket,
+            state: self.state.clone(),
+            secret_key: SecretKey::generate(&mut rand::rngs::OsRng),
+        })
+    }
+
+    fn conn_type(&self) -> &'static str {
+        "DNSCrypt"
+    }
+}
+
+// The shared box between the client and the resolver for the encryption system of the certificate
+enum CryptoBox {
+    Salsa(SalsaBox),
+    ChaCha(ChaChaBox),
+}
+
+impl CryptoBox {
+    fn new(cert: &Cert, secret_key: &SecretKey) -> Self {
+        let resolver_pk = PublicKey::from(cert.resolver_pk);
+        match cert.es_version {
+            EsVersion::XSalsa20Poly1305 => Self::Salsa(SalsaBox::new(&resolver_pk, secret_key)),
+            EsVersion::XChacha20Poly1305 => Self::ChaCha(ChaChaBox::new(&resolver_pk, secret_key)),
+        }
+    }
+
+    // Encrypt in place and return the tag, which is put before the ciphertext on the wire.
+    fn encrypt(
+        &self,
+        nonce: &[u8; 24],
+        buf: &mut [u8],
+    ) -> std::result::Result<[u8; TAG_LEN], DnsCryptError> {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = match self {
+            Self::Salsa(b) => b.encrypt_in_place_detached(nonce, b"", buf),
+            Self::ChaCha(b) => b.encrypt_in_place_detached(nonce, b"", buf),
+        }
+        .map_err(|_| DnsCryptError::CryptoFailed)?;
+        Ok(tag.into())
+    }
+
+    fn decrypt(
+        &self,
+        nonce: &[u8],
+        tag: &[u8],
+        buf: &mut [u8],
+    ) -> std::result::Result<(), DnsCryptError> {
+        let (nonce, tag) = (
+            GenericArray::from_slice(nonce),
+            GenericArray::from_slice(tag),
+        );
+        match self {
+            Self::Salsa(b) => b.decrypt_in_place_detached(nonce, b"", buf, tag),
+            Self::ChaCha(b) => b.decrypt_in_place_detached(nonce, b"", buf, tag),
+        }
+        .map_err(|_| DnsCryptError::CryptoFailed)
+    }
+}
+
+impl DnsCryptConn {
+    fn decrypt_response(
+        &self,
+        crypto_box: &CryptoBox,
+        client_nonce: &[u8; HALF_NONCE_LEN],
+        data: &[u8],
+    ) -> std::result::Result<Bytes, DnsCryptError> {
+        // Resolver magic(8) + client nonce(12) + resolver nonce(12) + tag(16) + encrypted response
+        let header_len = RESOLVER_MAGIC.len() + 2 * HALF_NONCE_LEN;
+        if data.len() < header_len + TAG_LEN
+            || &data[..8] != RESOLVER_MAGIC
+            || &data[8..8 + HALF_NONCE_LEN] != client_nonce
+        {
+            return Err(DnsCryptError::CryptoFailed);
+        }
+        let nonce = &data[8..header_len];
+        let tag = &data[header_len..header_len + TAG_LEN];
+        let mut buf = BytesMut::from(&data[header_len + TAG_LEN..]);
+        crypto_box.decrypt(nonce, tag, &mut buf)?;
+
+        // Remove the ISO/IEC 7816-4 padding
+        let len = buf
+            .iter()
+            .rposition(|b| *b != 0)
+            .filter(|pos| buf[*pos] == 0x80)
+            .ok_or(DnsCryptError::CryptoFailed)?;
+        buf.truncate(len);
+        Ok(buf.freeze())
+    }
+}
+
+#[async_trait]
+impl QHandle for DnsCryptConn {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let cert = self.state.get().await?;
+        let crypto_box = CryptoBox::new(&cert, &self.secret_key);
+
+        // Randomnize the message
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        msg.header_mut().set_random_id();
+        let msg = msg.for_slice();
+
+        // Pad the query with ISO/IEC 7816-4 padding to a multiple of 64 bytes
+        let mut buf = BytesMut::from(msg.as_slice());
+        buf.extend_from_slice(&[0x80]);
+        let padded_len = std::cmp::max(MIN_QUERY_LEN, (buf.len() + 63) / 64 * 64);
+        buf.resize(padded_len, 0);
+
+        let mut client_nonce = [0u8; HALF_NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut client_nonce);
+        let mut nonce = [0u8; 24];
+        nonce[..HALF_NONCE_LEN].copy_from_slice(&client_nonce);
+        let tag = crypto_box.encrypt(&nonce, &mut buf)?;
+
+        // Client magic(8) + client public key(32) + client nonce(12) + tag(16) + encrypted query
+        let mut packet = BytesMut::with_capacity(8 + 32 + HALF_NONCE_LEN + TAG_LEN + buf.len());
+        packet.extend_from_slice(&cert.client_magic);
+        packet.extend_from_slice(self.secret_key.public_key().as_bytes());
+        packet.extend_from_slice(&client_nonce);
+        packet.extend_from_slice(&tag);
+        packet.extend_from_slice(&buf);
+
+        self.socket.send(&packet).await?;
+
+        loop {
+            let mut buf = BytesMut::with_capacity(MAX_LEN);
+            buf.resize(MAX_LEN, 0);
+            let len = self.socket.recv(&mut buf).await?;
+            buf.resize(len, 0);
+
+            // We ignore garbage since there is a timer on this whole thing.
+            let answer = match self
+                .decrypt_response(&crypto_box, &client_nonce, &buf)
+                .ok()
+                .and_then(|data| Message::from_octets(data).ok())
+            {
+                Some(answer) => answer,
+                None => continue,
+            };
+            if !answer.is_answer(&msg) {
+                continue;
+            }
+            return Ok(answer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cert::{
+            now,
+            tests::{provider, signed},
+        },
+        ConnInitiator, CryptoBox, DnsCrypt, QHandle, Stamp, HALF_NONCE_LEN, MIN_QUERY_LEN,
+        RESOLVER_MAGIC, TAG_LEN,
+    };
+    use bytes::{Bytes, BytesMut};
+    use crypto_box::{ChaChaBox, PublicKey, SalsaBox, SecretKey};
+    use domain::{
+        base::{
+            iana::{Class, Rcode},
+            Dname, Message, MessageBuilder, Rtype,
+        },
+        rdata::{Txt, A},
+    };
+    use std::str::FromStr;
+    use tokio::net::UdpSocket;
+
+    // Pad with ISO/IEC 7816-4 padding to a multiple of 64 bytes.
+    fn pad(buf: &mut Vec<u8>) {
+        buf.push(0x80);
+        buf.resize((buf.len() + 63) / 64 * 64, 0);
+    }
+
+    fn unpad(buf: &mut Vec<u8>) {
+        let len = buf.iter().rposition(|b| *b != 0).unwrap();
+        assert_eq!(buf[len], 0x80);
+        buf.truncate(len);
+    }
+
+    // A resolver with a certificate of the given encryption system, answering A queries with 192.0.2.1.
+    // Each answer is preceded by one for another client nonce, which must be ignored.
+    async fn serve(es_version: u16) -> Stamp {
+        let secret_key = SecretKey::from([9; 32]);
+        let cert = signed(
+            es_version,
+            *secret_key.public_key().as_bytes(),
+            1,
+            now() - 3600,
+            now() + 3600,
+        );
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stamp = Stamp {
+            addr: socket.local_addr().unwrap(),
+            provider_pk: provider().public.to_bytes(),
+            provider_name: "2.dnscrypt-cert.example.com".to_string(),
+        };
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            loop {
+                let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+                let data = &buf[..len];
+
+                // Certificate query in plain DNS
+                if !data.starts_with(b"clientmg") {
+                    let query = Message::from_octets(Bytes::copy_from_slice(data)).unwrap();
+                    let mut builder = MessageBuilder::from_target(BytesMut::new())
+                        .unwrap()
+                        .start_answer(&query, Rcode::NoError)
+                        .unwrap();
+                    let name = query.first_question().unwrap().qname().to_owned();
+                    builder
+                        .push((
+                            &name,
+                            Class::In,
+                            3600,
+                            Txt::<Bytes>::from_slice(&cert).unwrap(),
+                        ))
+                        .unwrap();
+                    socket.send_to(&builder.finish(), src).await.unwrap();
+                    continue;
+                }
+
+                // Client magic(8) + client public key(32) + client nonce(12) + tag(16) + encrypted query
+                let client_pk = PublicKey::from(<[u8; 32]>::try_from(&data[8..40]).unwrap());
+                let crypto_box = match es_version {
+                    1 => CryptoBox::Salsa(SalsaBox::new(&client_pk, &secret_key)),
+                    _ => CryptoBox::ChaCha(ChaChaBox::new(&client_pk, &secret_key)),
+                };
+                let mut nonce = [0; 24];
+                nonce[..HALF_NONCE_LEN].copy_from_slice(&data[40..52]);
+                let mut query = data[52 + TAG_LEN..].to_vec();
+                assert!(query.len() >= MIN_QUERY_LEN && query.len() % 64 == 0);
+                crypto_box
+                    .decrypt(&nonce, &data[52..52 + TAG_LEN], &mut query)
+                    .unwrap();
+                unpad(&mut query);
+                let query = Message::from_octets(Bytes::from(query)).unwrap();
+
+                let mut builder = MessageBuilder::from_target(BytesMut::new())
+                    .unwrap()
+                    .start_answer(&query, Rcode::NoError)
+                    .unwrap();
+                let name = query.first_question().unwrap().qname().to_owned();
+                builder
+                    .push((&name, Class::In, 300, A::from_octets(192, 0, 2, 1)))
+                    .unwrap();
+                let answer = builder.finish().to_vec();
+
+                // Resolver magic(8) + client nonce(12) + resolver nonce(12) + tag(16) + encrypted response
+                let respond = |nonce: [u8; 24]| {
+                    let mut answer = answer.clone();
+                    pad(&mut answer);
+                    let tag = crypto_box.encrypt(&nonce, &mut answer).unwrap();
+                    [&RESOLVER_MAGIC[..], &nonce, &tag, &answer].concat()
+                };
+                nonce[HALF_NONCE_LEN..].copy_from_slice(&[5; HALF_NONCE_LEN]);
+                let mut other = nonce;
+                other[0] ^= 1;
+                socket.send_to(&respond(other), src).await.unwrap();
+                socket.send_to(&respond(nonce), src).await.unwrap();
+            }
+        });
+        stamp
+    }
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    async fn round_trip(es_version: u16) {
+        let dnscrypt = DnsCrypt::new(serve(es_version).await).await.unwrap();
+        let conn = dnscrypt.create().await.unwrap();
+        for _ in 0..2 {
+            let resp = conn.query(&query()).await.unwrap();
+            assert_eq!(resp.header().rcode(), Rcode::NoError);
+            let a = resp
+                .answer()
+                .unwrap()
+                .limit_to::<A>()
+                .next()
+                .unwrap()
+                .unwrap();
+            assert_eq!(a.data(), &A::from_octets(192, 0, 2, 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn xsalsa20poly1305() {
+        round_trip(1).await;
+    }
+
+    #[tokio::test]
+    async fn xchacha20poly1305() {
+        round_trip(2).await;
+    }
+}
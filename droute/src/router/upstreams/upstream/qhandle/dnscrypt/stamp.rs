@@ -0,0 +1,129 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// DNS stamp parsing. See also https://dnscrypt.info/stamps-specifications
+
+use super::DnsCryptError;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use std::{net::SocketAddr, str::FromStr};
+
+// Protocol identifier of DNSCrypt stamps
+const DNSCRYPT_PROTOCOL: u8 = 0x01;
+const DEFAULT_PORT: u16 = 443;
+
+/// A parsed `sdns://` stamp of a DNSCrypt server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    /// Address of the resolver
+    pub addr: SocketAddr,
+    /// Long-term Ed25519 public key of the provider, used to verify the certificates
+    pub provider_pk: [u8; 32],
+    /// Provider name, e.g. `2.dnscrypt-cert.example.com`
+    pub provider_name: String,
+}
+
+// Read a length-prefixed field
+fn read_lp<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], DnsCryptError> {
+    let (len, rest) = data.split_first().ok_or(DnsCryptError::InvalidStamp)?;
+    let len = *len as usize;
+    if rest.len() < len {
+        return Err(DnsCryptError::InvalidStamp);
+    }
+    let (field, rest) = rest.split_at(len);
+    *data = rest;
+    Ok(field)
+}
+
+impl FromStr for Stamp {
+    type Err = DnsCryptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let data = URL_SAFE_NO_PAD
+            .decode(
+                s.strip_prefix("sdns://")
+                    .ok_or(DnsCryptError::InvalidStamp)?,
+            )
+            .map_err(|_| DnsCryptError::InvalidStamp)?;
+
+        // Protocol identifier followed by 8 bytes of properties, which we don't make use of.
+        if data.len() < 9 || data[0] != DNSCRYPT_PROTOCOL {
+            return Err(DnsCryptError::InvalidStamp);
+        }
+        let mut data = &data[9..];
+
+        let addr =
+            std::str::from_utf8(read_lp(&mut data)?).map_err(|_| DnsCryptError::InvalidStamp)?;
+        // The port is optional and defaults to 443.
+        let addr = match SocketAddr::from_str(addr) {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(
+                addr.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .map_err(|_| DnsCryptError::InvalidStamp)?,
+                DEFAULT_PORT,
+            ),
+        };
+
+        let provider_pk = read_lp(&mut data)?
+            .try_into()
+            .map_err(|_| DnsCryptError::InvalidStamp)?;
+
+        let provider_name = String::from_utf8(read_lp(&mut data)?.to_vec())
+            .map_err(|_| DnsCryptError::InvalidStamp)?;
+
+        Ok(Self {
+            addr,
+            provider_pk,
+            provider_name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stamp;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use std::str::FromStr;
+
+    fn encode(addr: &str, pk: &[u8; 32], name: &str) -> String {
+        let mut data = vec![0x01, 0, 0, 0, 0, 0, 0, 0, 0];
+        for field in [addr.as_bytes(), pk, name.as_bytes()] {
+            data.push(field.len() as u8);
+            data.extend_from_slice(field);
+        }
+        format!("sdns://{}", URL_SAFE_NO_PAD.encode(data))
+    }
+
+    #[test]
+    fn parse_stamp() {
+        let stamp = Stamp::from_str(&encode(
+            "208.67.220.220:5353",
+            &[7; 32],
+            "2.dnscrypt-cert.example.com",
+        ))
+        .unwrap();
+        assert_eq!(stamp.addr, "208.67.220.220:5353".parse().unwrap());
+        assert_eq!(stamp.provider_pk, [7; 32]);
+        assert_eq!(stamp.provider_name, "2.dnscrypt-cert.example.com");
+    }
+
+    #[test]
+    fn parse_stamp_default_port() {
+        let stamp = Stamp::from_str(&encode("[2620:0:ccc::2]", &[7; 32], "a")).unwrap();
+        assert_eq!(stamp.addr, "[2620:0:ccc::2]:443".parse().unwrap());
+        assert!(Stamp::from_str("sdns://AgcAAAAAAAAA").is_err());
+    }
+}
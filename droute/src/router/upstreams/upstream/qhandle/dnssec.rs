@@ -0,0 +1,1570 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// DNSSEC (RFC 4033-4035) validation of the signed RRsets in upstream responses.
+
+use super::{QHandle, QHandleError, Result};
+use crate::{
+    router::script::utils::edns::{set_edns, Edns},
+    MAX_LEN,
+};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use clru::CLruCache;
+use domain::{
+    base::{
+        iana::{DigestAlg, Nsec3HashAlg, OptionCode, Rcode, Rtype, SecAlg},
+        name::{PushError, RelativeDname},
+        octets::ParseError,
+        opt::{AllOptData, UnknownOptData},
+        Dname, Message, MessageBuilder, ParsedDname, Record, RecordSection, Serial, ShortBuf,
+        ToDname,
+    },
+    rdata::{rfc4034::RtypeBitmap, AllRecordData, Dnskey, Ds, Nsec, Nsec3, Rrsig, ZoneRecordData},
+    validate::{DnskeyExt, RrsigExt},
+};
+use ring::digest;
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+// Root zone KSK-2017 and KSK-2024 as published by IANA.
+const ROOT_ANCHORS: [(u16, u8, &str); 2] = [
+    (
+        20326,
+        8,
+        "e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d",
+    ),
+    (
+        38696,
+        8,
+        "683d2d0acb8c9b712a1948b27f741219298d0a450d612c483af444a4c0fb2b16",
+    ),
+];
+
+// Validated keys are revalidated at least this often even if their TTL is longer.
+const MAX_KEY_TTL: Duration = Duration::from_secs(3600);
+// The number of zone cuts whose keys or lack of signatures are remembered.
+const MAX_ZONE_CUTS: usize = 1024;
+
+type Rec<'a> = Record<ParsedDname<&'a Bytes>, ZoneRecordData<Bytes, ParsedDname<&'a Bytes>>>;
+type Sig = Rrsig<Bytes, Dname<Bytes>>;
+type NsecRecord = Nsec<Bytes, Dname<Bytes>>;
+type Keys = Arc<Vec<Dnskey<Bytes>>>;
+type Rrsets<'a> = HashMap<(Dname<Bytes>, Rtype), Vec<Rec<'a>>>;
+type Sigs = HashMap<(Dname<Bytes>, Rtype), Vec<Sig>>;
+
+/// Extended DNS Error (RFC 8914) info codes attached to the SERVFAIL responses of bogus answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ede {
+    /// No signature could be verified
+    Bogus = 6,
+    /// The signatures have expired
+    SignatureExpired = 7,
+    /// The signatures are not yet valid
+    SignatureNotYetValid = 8,
+    /// No key matching the DS records or the signatures is found
+    DnskeyMissing = 9,
+    /// The RRset is expected to be signed but no signature is found
+    RrsigsMissing = 10,
+    /// The records proving the absence of the answer are missing or invalid
+    NsecMissing = 12,
+}
+
+/// Errors related to DNSSEC validation
+#[derive(Debug, Error)]
+pub enum DnssecError {
+    /// The response failed the validation
+    #[error("DNSSEC validation failed: {0:?}")]
+    Bogus(Ede),
+
+    /// Failed to fetch the keys from the upstream
+    #[error("failed to fetch DNSSEC keys: {0}")]
+    Upstream(Box<QHandleError>),
+
+    /// The query can't be rewritten to request signatures
+    #[error("failed to set the DO bit on the query")]
+    InvalidQuery,
+
+    /// Failed to parse the response
+    #[error(transparent)]
+    ParseError(#[from] ParseError),
+
+    /// Failed to build the query
+    #[error(transparent)]
+    PushError(#[from] PushError),
+
+    /// The buffer is too short
+    #[error(transparent)]
+    ShortBuf(#[from] ShortBuf),
+}
+
+type DnssecResult<T> = std::result::Result<T, DnssecError>;
+
+// Where a name stands in the chain of trust.
+#[derive(Clone)]
+enum Zone {
+    // The name belongs to the signed zone with these validated keys.
+    Secure(Dname<Bytes>, Keys),
+    // The name is at or below a delegation proved to be unsigned.
+    Insecure,
+}
+
+// What the parent zone tells about a name below it.
+enum Delegation {
+    // The name is the apex of a zone, signed or not.
+    Cut(Zone, Instant),
+    // The name belongs to the parent zone.
+    Parent,
+    // The name doesn't exist.
+    Nonexistent,
+}
+
+// What the NSEC or NSEC3 records prove about a name without records of some type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Denial {
+    // The name exists, but not with that type.
+    NoData,
+    // The name doesn't exist.
+    NxDomain,
+    // The name may be covered by an unsigned delegation, nothing can be proved.
+    Unsigned,
+}
+
+// Validate responses from the inner handle, fetching the DNSKEY and DS records needed from it as well.
+pub struct Dnssec {
+    inner: Arc<dyn QHandle>,
+    // The DS records the keys of the root zone must match
+    anchors: Vec<Ds<Bytes>>,
+    // The zone cuts found so far walking down from the root, by the names they are at
+    zones: Mutex<CLruCache<Dname<Bytes>, (Zone, Instant)>>,
+}
+
+impl Dnssec {
+    pub fn new(inner: Arc<dyn QHandle>) -> Self {
+        Self {
+            inner,
+            anchors: root_anchors(),
+            zones: Mutex::new(CLruCache::new(NonZeroUsize::new(MAX_ZONE_CUTS).unwrap())),
+        }
+    }
+
+    async fn fetch(&self, name: &Dname<Bytes>, rtype: Rtype) -> DnssecResult<Message<Bytes>> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+        builder.header_mut().set_random_id();
+        builder.header_mut().set_rd(true);
+        // We do the validation ourselves, the upstream shouldn't hide bogus records from us.
+        builder.header_mut().set_cd(true);
+        let mut builder = builder.question();
+        builder.push((name, rtype))?;
+        let mut builder = builder.additional();
+        builder.opt(|opt| {
+            opt.set_udp_payload_size(1232);
+            opt.set_dnssec_ok(true);
+            Ok(())
+        })?;
+        let query = builder.into_message();
+        self.inner
+            .query(&query)
+            .await
+            .map_err(|e| DnssecError::Upstream(Box::new(e)))
+    }
+
+    // Find the zone the name belongs to, walking the chain of trust down from the closest zone cut
+    // known. Only zone cuts are remembered, the names in between are asked about again.
+    async fn zone_of(&self, name: &Dname<Bytes>) -> DnssecResult<(Zone, Instant)> {
+        let (mut walked, mut zone, mut expiry) = match self.closest_cut(name) {
+            Some(cut) => cut,
+            None => {
+                let root = Dname::root_bytes();
+                let (keys, expiry) = self.dnskeys(&root, &self.anchors).await?;
+                let zone = Zone::Secure(root.clone(), keys);
+                self.zones
+                    .lock()
+                    .unwrap()
+                    .put(root.clone(), (zone.clone(), expiry));
+                (root, zone, expiry)
+            }
+        };
+
+        while walked.label_count() < name.label_count() {
+            let (parent, keys) = match zone {
+                Zone::Secure(parent, keys) => (parent, keys),
+                Zone::Insecure => break,
+            };
+            walked = suffix(name, walked.label_count() + 1);
+            zone = match self.delegation(&walked, &parent, &keys).await? {
+                Delegation::Cut(child, child_expiry) => {
+                    expiry = expiry.min(child_expiry);
+                    self.zones
+                        .lock()
+                        .unwrap()
+                        .put(walked.clone(), (child.clone(), expiry));
+                    child
+                }
+                Delegation::Parent => Zone::Secure(parent, keys),
+                // Nothing exists below a name that doesn't exist, let alone a zone cut.
+                Delegation::Nonexistent => return Ok((Zone::Secure(parent, keys), expiry)),
+            };
+        }
+        Ok((zone, expiry))
+    }
+
+    // Find the zone of the name starting from the closest signer of the records about it, as only
+    // that zone can have made the signatures. Names in signed zones then need no walk down to them.
+    async fn zone_signed<'a>(
+        &self,
+        name: &Dname<Bytes>,
+        sigs: impl Iterator<Item = &'a Sig>,
+    ) -> DnssecResult<(Zone, Instant)> {
+        let signer = sigs
+            .map(|sig| sig.signer_name())
+            .filter(|signer| name.ends_with(signer))
+            .max_by_key(|signer| signer.label_count())
+            .cloned();
+        self.zone_of(signer.as_ref().unwrap_or(name)).await
+    }
+
+    // The closest zone cut at or above the name still known, dropping the expired ones on the way.
+    fn closest_cut(&self, name: &Dname<Bytes>) -> Option<(Dname<Bytes>, Zone, Instant)> {
+        let mut zones = self.zones.lock().unwrap();
+        let now = Instant::now();
+        let mut name = name.clone();
+        loop {
+            match zones.get(&name) {
+                Some((zone, expiry)) if *expiry > now => {
+                    return Some((name, zone.clone(), *expiry))
+                }
+                Some(_) => {
+                    zones.pop(&name);
+                }
+                None => {}
+            }
+            if !name.parent() {
+                return None;
+            }
+        }
+    }
+
+    // Tell whether the name is the apex of a signed zone, an unsigned delegation, or still part of
+    // the parent zone, from the DS records or the parent's authenticated denial of them.
+    async fn delegation(
+        &self,
+        name: &Dname<Bytes>,
+        parent: &Dname<Bytes>,
+        keys: &Keys,
+    ) -> DnssecResult<Delegation> {
+        let resp = self.fetch(name, Rtype::Ds).await?;
+        let (mut rrsets, sigs) = rrsets(resp.answer()?)?;
+
+        let key = (name.clone(), Rtype::Ds);
+        if let Some(mut ds) = rrsets.remove(&key) {
+            verify(&mut ds, sigs.get(&key), parent, keys)?;
+            let anchors: Vec<Ds<Bytes>> = ds
+                .iter()
+                .filter_map(|r| match r.data() {
+                    ZoneRecordData::Ds(ds) => Some(ds.clone()),
+                    _ => None,
+                })
+                .collect();
+            let (keys, expiry) = self.dnskeys(name, &anchors).await?;
+            return Ok(Delegation::Cut(Zone::Secure(name.clone(), keys), expiry));
+        }
+
+        // A name owning a CNAME can't be a zone cut.
+        let key = (name.clone(), Rtype::Cname);
+        if let Some(mut cname) = rrsets.remove(&key) {
+            verify(&mut cname, sigs.get(&key), parent, keys)?;
+            return Ok(Delegation::Parent);
+        }
+
+        match Proofs::new(&resp, parent, keys)?.deny(name, Rtype::Ds)? {
+            Denial::Unsigned => Ok(Delegation::Cut(
+                Zone::Insecure,
+                Instant::now() + MAX_KEY_TTL,
+            )),
+            Denial::NoData => Ok(Delegation::Parent),
+            Denial::NxDomain => Ok(Delegation::Nonexistent),
+        }
+    }
+
+    // Fetch the DNSKEY RRset of the zone, which must be signed by a key matching one of the DS records.
+    async fn dnskeys(
+        &self,
+        zone: &Dname<Bytes>,
+        anchors: &[Ds<Bytes>],
+    ) -> DnssecResult<(Keys, Instant)> {
+        let resp = self.fetch(zone, Rtype::Dnskey).await?;
+        let (mut rrsets, sigs) = rrsets(resp.answer()?)?;
+        let key = (zone.clone(), Rtype::Dnskey);
+        let mut dnskeys = rrsets
+            .remove(&key)
+            .ok_or(DnssecError::Bogus(Ede::DnskeyMissing))?;
+        let keys: Vec<Dnskey<Bytes>> = dnskeys
+            .iter()
+            .filter_map(|r| match r.data() {
+                ZoneRecordData::Dnskey(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        let entries: Vec<Dnskey<Bytes>> = keys
+            .iter()
+            .filter(|key| anchors.iter().any(|ds| matches_ds(key, zone, ds)))
+            .cloned()
+            .collect();
+        if entries.is_empty() {
+            return Err(DnssecError::Bogus(Ede::DnskeyMissing));
+        }
+        verify(&mut dnskeys, sigs.get(&key), zone, &entries)?;
+
+        let ttl = dnskeys.iter().map(|r| r.ttl()).min().unwrap_or(0);
+        Ok((
+            Arc::new(keys),
+            Instant::now() + MAX_KEY_TTL.min(Duration::from_secs(ttl.into())),
+        ))
+    }
+
+    // Validate the answer, returning whether it is secure. Every RRset from a signed zone must
+    // carry valid signatures, and negative answers must come with an authenticated denial.
+    #[allow(clippy::mutable_key_type)]
+    async fn validate(&self, resp: &Message<Bytes>) -> DnssecResult<bool> {
+        let (_, authority_sigs) = rrsets(resp.authority()?)?;
+        let (mut rrsets, sigs) = rrsets(resp.answer()?)?;
+        let mut secure = true;
+
+        // CNAMEs synthesized from DNAMEs are never signed, they are checked against the DNAMEs instead.
+        let mut dnames = Vec::new();
+        let mut cnames = HashMap::new();
+        for ((owner, _), rrset) in rrsets.iter() {
+            for record in rrset {
+                match record.data() {
+                    ZoneRecordData::Dname(dname) => {
+                        dnames.push((owner.clone(), dname.dname().to_dname::<Bytes>()?))
+                    }
+                    ZoneRecordData::Cname(cname) => {
+                        cnames.insert(owner.clone(), cname.cname().to_dname::<Bytes>()?);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for (key, rrset) in rrsets.iter_mut() {
+            // DS records are served and signed by the parent zone.
+            let owner = if key.1 == Rtype::Ds {
+                parent_of(&key.0).ok_or(DnssecError::Bogus(Ede::Bogus))?
+            } else {
+                key.0.clone()
+            };
+            let (zone, keys) = match self
+                .zone_signed(&owner, sigs.get(key).into_iter().flatten())
+                .await?
+                .0
+            {
+                Zone::Secure(zone, keys) => (zone, keys),
+                Zone::Insecure => {
+                    secure = false;
+                    continue;
+                }
+            };
+            if key.1 == Rtype::Cname && !sigs.contains_key(key) {
+                if let Some(target) = cnames.get(&key.0) {
+                    if dnames
+                        .iter()
+                        .any(|(dname, to)| synthesized(&key.0, target, dname, to))
+                    {
+                        continue;
+                    }
+                }
+            }
+            let labels = verify(rrset, sigs.get(key), &zone, &keys)?;
+            // An RRset expanded from a wildcard also needs a proof that the name itself doesn't exist.
+            if usize::from(labels) + 1 < key.0.label_count()
+                && !Proofs::new(resp, &zone, &keys)?.no_closer(&key.0, labels)
+            {
+                return Err(DnssecError::Bogus(Ede::NsecMissing));
+            }
+        }
+
+        let question = match resp.first_question() {
+            Some(question) => question,
+            None => return Ok(false),
+        };
+        let qtype = question.qtype();
+        let rcode = resp.header().rcode();
+
+        // Follow the CNAME chain to the name the answer is about.
+        let mut target = question.qname().to_dname::<Bytes>()?;
+        for _ in 0..cnames.len() {
+            match cnames.get(&target) {
+                Some(next) if qtype != Rtype::Cname => target = next.clone(),
+                _ => break,
+            }
+        }
+
+        let negative = match rcode {
+            Rcode::NXDomain => true,
+            Rcode::NoError => qtype != Rtype::Any && !rrsets.contains_key(&(target.clone(), qtype)),
+            _ => return Ok(false),
+        };
+        if negative {
+            let owner = match qtype {
+                Rtype::Ds => parent_of(&target).ok_or(DnssecError::Bogus(Ede::Bogus))?,
+                _ => target.clone(),
+            };
+            if let (Zone::Secure(zone, keys), _) = self
+                .zone_signed(&owner, authority_sigs.values().flatten())
+                .await?
+            {
+                match (
+                    Proofs::new(resp, &zone, &keys)?.deny(&target, qtype)?,
+                    rcode,
+                ) {
+                    (Denial::Unsigned, _) => secure = false,
+                    (Denial::NxDomain, Rcode::NXDomain) | (Denial::NoData, Rcode::NoError) => {}
+                    _ => return Err(DnssecError::Bogus(Ede::NsecMissing)),
+                }
+            } else {
+                secure = false;
+            }
+        }
+        Ok(secure)
+    }
+}
+
+#[async_trait]
+impl QHandle for Dnssec {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut edns = Edns::from_message_or_new(msg).map_err(|_| DnssecError::InvalidQuery)?;
+        let client_do = msg.opt().is_some() && edns.dnssec_ok;
+        edns.dnssec_ok = true;
+        let query = set_edns(msg, Some(&edns)).map_err(|_| DnssecError::InvalidQuery)?;
+        let resp = self.inner.query(&query).await?;
+
+        match self.validate(&resp).await {
+            Ok(secure) => {
+                let resp = if client_do {
+                    resp
+                } else {
+                    strip_dnssec(&resp, msg.opt().is_some())?
+                };
+                if secure && (client_do || msg.header().ad()) {
+                    let mut resp = Message::from_octets(BytesMut::from(resp.as_slice()))?;
+                    resp.header_mut().set_ad(true);
+                    Ok(Message::from_octets(resp.into_octets().freeze())?)
+                } else {
+                    Ok(resp)
+                }
+            }
+            Err(DnssecError::Bogus(ede)) => {
+                log::warn!("bogus DNSSEC answer from upstream: {:?}", ede);
+                Ok(servfail(msg, ede)?)
+            }
+            Err(DnssecError::Upstream(e)) => Err(*e),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Group the records of a section into RRsets and the signatures covering them.
+// Names are only hashed by their octets, which are never mutated.
+#[allow(clippy::mutable_key_type)]
+fn rrsets(section: RecordSection<&Bytes>) -> DnssecResult<(Rrsets<'_>, Sigs)> {
+    let mut rrsets = Rrsets::new();
+    let mut sigs = Sigs::new();
+    for item in section {
+        if let Some(record) = item?.into_record::<ZoneRecordData<_, _>>()? {
+            let owner = record.owner().to_dname::<Bytes>()?;
+            match record.data() {
+                ZoneRecordData::Rrsig(sig) => sigs
+                    .entry((owner, sig.type_covered()))
+                    .or_default()
+                    .push(Rrsig::new(
+                        sig.type_covered(),
+                        sig.algorithm(),
+                        sig.labels(),
+                        sig.original_ttl(),
+                        sig.expiration(),
+                        sig.inception(),
+                        sig.key_tag(),
+                        sig.signer_name().to_dname::<Bytes>()?,
+                        sig.signature().clone(),
+                    )),
+                _ => rrsets
+                    .entry((owner, record.rtype()))
+                    .or_default()
+                    .push(record),
+            }
+        }
+    }
+    Ok((rrsets, sigs))
+}
+
+// Check that every signature over the RRset is made by the zone, and that one of them is valid and
+// made by one of the keys, returning the label count of the valid signature.
+fn verify(
+    rrset: &mut [Rec<'_>],
+    sigs: Option<&Vec<Sig>>,
+    zone: &Dname<Bytes>,
+    keys: &[Dnskey<Bytes>],
+) -> DnssecResult<u8> {
+    let sigs = sigs.ok_or(DnssecError::Bogus(Ede::RrsigsMissing))?;
+    if sigs.iter().any(|sig| sig.signer_name() != zone) {
+        return Err(DnssecError::Bogus(Ede::Bogus));
+    }
+    verify_rrset(rrset, sigs, keys)
+}
+
+// Check that one of the signatures over the RRset is valid and made by one of the keys.
+fn verify_rrset(rrset: &mut [Rec<'_>], sigs: &[Sig], keys: &[Dnskey<Bytes>]) -> DnssecResult<u8> {
+    let now = Serial::now();
+    let labels = rrset.first().map(|r| r.owner().label_count()).unwrap_or(1) - 1;
+    let mut ede = Ede::DnskeyMissing;
+    for sig in sigs {
+        if sig.expiration() < now {
+            ede = Ede::SignatureExpired;
+            continue;
+        }
+        if sig.inception() > now {
+            ede = Ede::SignatureNotYetValid;
+            continue;
+        }
+        if usize::from(sig.labels()) > labels {
+            ede = Ede::Bogus;
+            continue;
+        }
+        let mut signed = Vec::new();
+        sig.signed_data(&mut signed, rrset)?;
+        for key in keys
+            .iter()
+            .filter(|k| k.algorithm() == sig.algorithm() && key_tag(k) == sig.key_tag())
+        {
+            if sig.verify_signed_data(key, &signed).is_ok() {
+                return Ok(sig.labels());
+            }
+            ede = Ede::Bogus;
+        }
+    }
+    Err(DnssecError::Bogus(ede))
+}
+
+// The authenticated NSEC and NSEC3 records of a zone found in the authority section.
+struct Proofs {
+    zone: Dname<Bytes>,
+    nsec: Vec<(Dname<Bytes>, NsecRecord)>,
+    // NSEC3 records with the hash decoded from their owner names
+    nsec3: Vec<(Vec<u8>, Nsec3<Bytes>)>,
+}
+
+impl Proofs {
+    fn new(
+        resp: &Message<Bytes>,
+        zone: &Dname<Bytes>,
+        keys: &[Dnskey<Bytes>],
+    ) -> DnssecResult<Self> {
+        let (rrsets, sigs) = rrsets(resp.authority()?)?;
+        let mut proofs = Self {
+            zone: zone.clone(),
+            nsec: Vec::new(),
+            nsec3: Vec::new(),
+        };
+        for (key, mut rrset) in rrsets {
+            if !matches!(key.1, Rtype::Nsec | Rtype::Nsec3) || !key.0.ends_with(zone) {
+                continue;
+            }
+            // NSEC and NSEC3 records can't be expanded from wildcards.
+            if usize::from(verify(&mut rrset, sigs.get(&key), zone, keys)?) + 1
+                < key.0.label_count()
+            {
+                continue;
+            }
+            for record in &rrset {
+                match record.data() {
+                    ZoneRecordData::Nsec(nsec) => proofs.nsec.push((
+                        key.0.clone(),
+                        Nsec::new(nsec.next_name().to_dname()?, nsec.types().clone()),
+                    )),
+                    ZoneRecordData::Nsec3(nsec3)
+                        if key.0.label_count() == zone.label_count() + 1 =>
+                    {
+                        if let Some(hash) = base32hex(key.0.first().as_slice()) {
+                            proofs.nsec3.push((hash, nsec3.clone()));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(proofs)
+    }
+
+    // Find out what the records prove about the name not having records of the type.
+    fn deny(&self, name: &Dname<Bytes>, rtype: Rtype) -> DnssecResult<Denial> {
+        if self.nsec.is_empty() {
+            self.deny_nsec3(name, rtype)
+        } else {
+            self.deny_nsec(name, rtype)
+        }
+    }
+
+    // Denial of existence with NSEC records (RFC 4035 Section 5.4).
+    fn deny_nsec(&self, name: &Dname<Bytes>, rtype: Rtype) -> DnssecResult<Denial> {
+        if let Some((_, nsec)) = self.nsec.iter().find(|(owner, _)| owner == name) {
+            return nodata(nsec.types(), rtype);
+        }
+        let (owner, nsec) = self
+            .nsec
+            .iter()
+            .find(|(owner, nsec)| covers(owner, nsec.next_name(), name))
+            .ok_or(DnssecError::Bogus(Ede::NsecMissing))?;
+        // Records above a delegation or a DNAME come from another zone than the name's.
+        if name.ends_with(owner) && delegates(nsec.types()) {
+            return Err(DnssecError::Bogus(Ede::NsecMissing));
+        }
+        // An empty non-terminal exists but owns no record.
+        if nsec.next_name().ends_with(name) {
+            return Ok(Denial::NoData);
+        }
+
+        // No wildcard at the closest encloser may match the name either.
+        let encloser = suffix(
+            name,
+            common_labels(name, owner).max(common_labels(name, nsec.next_name())),
+        );
+        let wildcard = wildcard_of(&encloser)?;
+        if let Some((_, nsec)) = self.nsec.iter().find(|(owner, _)| *owner == wildcard) {
+            return nodata(nsec.types(), rtype);
+        }
+        if self
+            .nsec
+            .iter()
+            .any(|(owner, nsec)| covers(owner, nsec.next_name(), &wildcard))
+        {
+            Ok(Denial::NxDomain)
+        } else {
+            Err(DnssecError::Bogus(Ede::NsecMissing))
+        }
+    }
+
+    // Denial of existence with NSEC3 records (RFC 5155 Section 8).
+    fn deny_nsec3(&self, name: &Dname<Bytes>, rtype: Rtype) -> DnssecResult<Denial> {
+        let params = match self.nsec3.first() {
+            Some((_, params)) => params,
+            None => return Err(DnssecError::Bogus(Ede::NsecMissing)),
+        };
+        // Records with unknown hash algorithms must be ignored, leaving the answer insecure.
+        if params.hash_algorithm() != Nsec3HashAlg::Sha1 {
+            return Ok(Denial::Unsigned);
+        }
+        let hash =
+            |name: &Dname<Bytes>| nsec3_hash(name, params.iterations(), params.salt().as_slice());
+
+        if let Some(nsec3) = self.nsec3_match(&hash(name)) {
+            return nodata(nsec3.types(), rtype);
+        }
+
+        // The closest encloser proof: the closest ancestor that exists and the next closer name
+        // below it that doesn't.
+        let mut next_closer = name.clone();
+        let encloser = loop {
+            let encloser = parent_of(&next_closer)
+                .filter(|encloser| encloser.ends_with(&self.zone))
+                .ok_or(DnssecError::Bogus(Ede::NsecMissing))?;
+            if let Some(nsec3) = self.nsec3_match(&hash(&encloser)) {
+                if delegates(nsec3.types()) {
+                    return Err(DnssecError::Bogus(Ede::NsecMissing));
+                }
+                break encloser;
+            }
+            next_closer = encloser;
+        };
+        let cover = self
+            .nsec3_cover(&hash(&next_closer))
+            .ok_or(DnssecError::Bogus(Ede::NsecMissing))?;
+        // Opt-out spans may hide unsigned delegations.
+        if cover.opt_out() {
+            return Ok(Denial::Unsigned);
+        }
+
+        let wildcard = wildcard_of(&encloser)?;
+        if let Some(nsec3) = self.nsec3_match(&hash(&wildcard)) {
+            return nodata(nsec3.types(), rtype);
+        }
+        if self.nsec3_cover(&hash(&wildcard)).is_some() {
+            Ok(Denial::NxDomain)
+        } else {
+            Err(DnssecError::Bogus(Ede::NsecMissing))
+        }
+    }
+
+    // Whether the records prove that no name closer than the wildcard an RRset of the name was
+    // expanded from exists (RFC 4035 Section 5.3.4).
+    fn no_closer(&self, name: &Dname<Bytes>, labels: u8) -> bool {
+        if !self.nsec.is_empty() {
+            return self
+                .nsec
+                .iter()
+                .any(|(owner, nsec)| covers(owner, nsec.next_name(), name));
+        }
+        match self.nsec3.first() {
+            Some((_, params)) if params.hash_algorithm() == Nsec3HashAlg::Sha1 => {
+                let next_closer = suffix(name, usize::from(labels) + 2);
+                let hash = nsec3_hash(&next_closer, params.iterations(), params.salt().as_slice());
+                self.nsec3_cover(&hash).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn nsec3_match(&self, hash: &[u8]) -> Option<&Nsec3<Bytes>> {
+        self.nsec3
+            .iter()
+            .find(|(owner, _)| owner == hash)
+            .map(|(_, nsec3)| nsec3)
+    }
+
+    fn nsec3_cover(&self, hash: &[u8]) -> Option<&Nsec3<Bytes>> {
+        self.nsec3
+            .iter()
+            .find(|(owner, nsec3)| covers(owner.as_slice(), nsec3.next_owner().as_slice(), hash))
+            .map(|(_, nsec3)| nsec3)
+    }
+}
+
+// The denial a record proving the name exists gives, unless its types contradict it.
+fn nodata(types: &RtypeBitmap<Bytes>, rtype: Rtype) -> DnssecResult<Denial> {
+    if types.contains(rtype) || (rtype != Rtype::Cname && types.contains(Rtype::Cname)) {
+        return Err(DnssecError::Bogus(Ede::NsecMissing));
+    }
+    // Delegations have NS records without SOA. The parent only proves the absence of DS there.
+    if types.contains(Rtype::Ns) && !types.contains(Rtype::Soa) {
+        return match rtype {
+            Rtype::Ds => Ok(Denial::Unsigned),
+            _ => Err(DnssecError::Bogus(Ede::NsecMissing)),
+        };
+    }
+    Ok(Denial::NoData)
+}
+
+// Whether the types are those of a delegation or a DNAME, below which the zone has no names.
+fn delegates(types: &RtypeBitmap<Bytes>) -> bool {
+    (types.contains(Rtype::Ns) && !types.contains(Rtype::Soa)) || types.contains(Rtype::Dname)
+}
+
+// Whether the span from owner to next covers the value, wrapping around at the end of the zone.
+fn covers<T: PartialOrd + ?Sized>(owner: &T, next: &T, value: &T) -> bool {
+    if owner < next {
+        owner < value && value < next
+    } else {
+        owner < value || value < next
+    }
+}
+
+// The number of labels the names share from the root.
+fn common_labels(a: &Dname<Bytes>, b: &Dname<Bytes>) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+// The ancestor of the name with the given number of labels, counting the root label.
+fn suffix(name: &Dname<Bytes>, labels: usize) -> Dname<Bytes> {
+    let mut name = name.clone();
+    while name.label_count() > labels && name.parent() {}
+    name
+}
+
+fn parent_of(name: &Dname<Bytes>) -> Option<Dname<Bytes>> {
+    let mut parent = name.clone();
+    parent.parent().then_some(parent)
+}
+
+fn wildcard_of(name: &Dname<Bytes>) -> DnssecResult<Dname<Bytes>> {
+    RelativeDname::<Bytes>::wildcard()
+        .chain(name)
+        .map_err(|_| DnssecError::Bogus(Ede::Bogus))?
+        .to_dname()
+        .map_err(Into::into)
+}
+
+// Whether the CNAME from owner to target is the one the DNAME from dname to to synthesizes.
+fn synthesized(
+    owner: &Dname<Bytes>,
+    target: &Dname<Bytes>,
+    dname: &Dname<Bytes>,
+    to: &Dname<Bytes>,
+) -> bool {
+    owner != dname
+        && owner.ends_with(dname)
+        && owner
+            .iter()
+            .take(owner.label_count() - dname.label_count())
+            .chain(to.iter())
+            .eq(target.iter())
+}
+
+// The iterated hash of the name in canonical wire form (RFC 5155 Section 5).
+fn nsec3_hash(name: &Dname<Bytes>, iterations: u16, salt: &[u8]) -> Vec<u8> {
+    let mut hash = Vec::with_capacity(name.len());
+    for label in name.iter() {
+        hash.push(label.len() as u8);
+        hash.extend(label.as_slice().iter().map(u8::to_ascii_lowercase));
+    }
+    for _ in 0..=iterations {
+        let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        ctx.update(&hash);
+        ctx.update(salt);
+        hash = ctx.finish().as_ref().to_vec();
+    }
+    hash
+}
+
+// Decode the base32hex (RFC 4648 Section 7) label of an NSEC3 owner name.
+fn base32hex(label: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(label.len() * 5 / 8);
+    let (mut buf, mut bits) = (0u32, 0);
+    for c in label {
+        let v = match c.to_ascii_lowercase() {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'v' => c - b'a' + 10,
+            _ => return None,
+        };
+        buf = (buf << 5) | u32::from(v);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+fn matches_ds(key: &Dnskey<Bytes>, zone: &Dname<Bytes>, ds: &Ds<Bytes>) -> bool {
+    key.algorithm() == ds.algorithm()
+        && key_tag(key) == ds.key_tag()
+        && matches!(key.digest(zone, ds.digest_type()), Ok(digest) if digest.as_ref() == ds.digest().as_ref())
+}
+
+// Key tag as defined in RFC 4034 Appendix B.
+fn key_tag(key: &Dnskey<Bytes>) -> u16 {
+    let mut rdata = Vec::with_capacity(4 + key.public_key().len());
+    rdata.extend_from_slice(&key.flags().to_be_bytes());
+    rdata.push(key.protocol());
+    rdata.push(key.algorithm().to_int());
+    rdata.extend_from_slice(key.public_key().as_ref());
+    let mut ac = rdata.iter().enumerate().fold(0u32, |ac, (i, b)| {
+        if i & 1 == 0 {
+            ac + (u32::from(*b) << 8)
+        } else {
+            ac + u32::from(*b)
+        }
+    });
+    ac += (ac >> 16) & 0xffff;
+    (ac & 0xffff) as u16
+}
+
+fn root_anchors() -> Vec<Ds<Bytes>> {
+    ROOT_ANCHORS
+        .iter()
+        .map(|(tag, alg, digest)| {
+            Ds::new(
+                *tag,
+                SecAlg::from_int(*alg),
+                DigestAlg::Sha256,
+                Bytes::from(hex::decode(digest).unwrap()),
+            )
+        })
+        .collect()
+}
+
+// Remove the signatures and denial of existence records the client didn't ask for.
+fn strip_dnssec(msg: &Message<Bytes>, keep_opt: bool) -> DnssecResult<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+    *builder.header_mut() = msg.header();
+
+    let mut builder = builder.question();
+    for item in msg.question() {
+        builder.push(item?)?;
+    }
+
+    let mut builder = builder.answer();
+    for item in msg.answer()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            if !is_dnssec(record.rtype()) {
+                builder.push(record)?;
+            }
+        }
+    }
+
+    let mut builder = builder.authority();
+    for item in msg.authority()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            if !is_dnssec(record.rtype()) {
+                builder.push(record)?;
+            }
+        }
+    }
+
+    let mut builder = builder.additional();
+    for item in msg.additional()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            if !is_dnssec(record.rtype()) {
+                builder.push(record)?;
+            }
+        }
+    }
+    let resp = builder.into_message();
+
+    // Clear the DO bit we set, or drop the OPT record we introduced.
+    let edns = match Edns::from_message(&resp) {
+        Ok(Some(mut edns)) if keep_opt => {
+            edns.dnssec_ok = false;
+            Some(edns)
+        }
+        _ => None,
+    };
+    Ok(set_edns(&resp, edns.as_ref()).unwrap_or(resp))
+}
+
+fn is_dnssec(rtype: Rtype) -> bool {
+    matches!(
+        rtype,
+        Rtype::Rrsig | Rtype::Nsec | Rtype::Nsec3 | Rtype::Nsec3param
+    )
+}
+
+// SERVFAIL with the Extended DNS Error explaining the failure, if the client understands EDNS.
+fn servfail(query: &Message<Bytes>, ede: Ede) -> DnssecResult<Message<Bytes>> {
+    let resp = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+        .start_answer(query, Rcode::ServFail)?
+        .into_message();
+    if query.opt().is_none() {
+        return Ok(resp);
+    }
+    let mut edns = Edns::new();
+    edns.options
+        .push(AllOptData::Other(UnknownOptData::from_octets(
+            OptionCode::from_int(15),
+            Bytes::copy_from_slice(&(ede as u16).to_be_bytes()),
+        )));
+    Ok(set_edns(&resp, Some(&edns)).unwrap_or(resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        base32hex, covers, key_tag, nsec3_hash, synthesized, wildcard_of, Dnssec, DnssecError, Ede,
+        QHandle, Result, MAX_ZONE_CUTS,
+    };
+    use crate::MAX_LEN;
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use clru::CLruCache;
+    use domain::{
+        base::{
+            iana::{Class, DigestAlg, Nsec3HashAlg, Rcode, Rtype, SecAlg},
+            Dname, Message, MessageBuilder, Record, Serial, ToDname,
+        },
+        rdata::{
+            rfc4034::RtypeBitmap,
+            rfc5155::{Nsec3Salt, OwnerHash},
+            Dnskey, Ds, Nsec, Nsec3, Rrsig, ZoneRecordData, A,
+        },
+        validate::{DnskeyExt, RrsigExt},
+    };
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::{
+        collections::HashMap,
+        net::Ipv4Addr,
+        num::NonZeroUsize,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
+
+    type Rec = Record<Dname<Bytes>, ZoneRecordData<Bytes, Dname<Bytes>>>;
+
+    fn name(s: &str) -> Dname<Bytes> {
+        match s {
+            "." => Dname::root_bytes(),
+            _ => Dname::from_str(s).unwrap(),
+        }
+    }
+
+    fn record(owner: &str, data: ZoneRecordData<Bytes, Dname<Bytes>>) -> Rec {
+        Record::new(name(owner), Class::In, 3600, data)
+    }
+
+    fn a(owner: &str, ip: [u8; 4]) -> Rec {
+        record(owner, ZoneRecordData::A(A::new(Ipv4Addr::from(ip))))
+    }
+
+    fn types(rtypes: &[Rtype]) -> RtypeBitmap<Bytes> {
+        let mut builder = RtypeBitmap::<Bytes>::builder();
+        for rtype in rtypes {
+            builder.add(*rtype).unwrap();
+        }
+        builder.finalize()
+    }
+
+    fn nsec(owner: &str, next: &str, rtypes: &[Rtype]) -> Rec {
+        record(
+            owner,
+            ZoneRecordData::Nsec(Nsec::new(name(next), types(rtypes))),
+        )
+    }
+
+    // NSEC3 records without salt nor additional iterations, chaining the hashes of the names.
+    fn nsec3_chain(zone: &str, names: &[(&str, &[Rtype])]) -> Vec<Rec> {
+        let mut hashes: Vec<(Vec<u8>, &[Rtype])> = names
+            .iter()
+            .map(|(owner, rtypes)| (nsec3_hash(&name(owner), 0, &[]), *rtypes))
+            .collect();
+        hashes.sort();
+        (0..hashes.len())
+            .map(|i| {
+                let (hash, rtypes) = &hashes[i];
+                let next = &hashes[(i + 1) % hashes.len()].0;
+                record(
+                    &format!("{}.{}", base32hex_encode(hash), zone),
+                    ZoneRecordData::Nsec3(Nsec3::new(
+                        Nsec3HashAlg::Sha1,
+                        0,
+                        0,
+                        Nsec3Salt::from_octets(Bytes::new()).unwrap(),
+                        OwnerHash::from_octets(Bytes::from(next.clone())).unwrap(),
+                        types(rtypes),
+                    )),
+                )
+            })
+            .collect()
+    }
+
+    fn base32hex_encode(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+        let (mut out, mut buf, mut bits) = (String::new(), 0u32, 0);
+        for b in bytes {
+            buf = (buf << 8) | u32::from(*b);
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(ALPHABET[((buf >> bits) & 31) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            out.push(ALPHABET[((buf << (5 - bits)) & 31) as usize] as char);
+        }
+        out
+    }
+
+    fn message(
+        qname: &str,
+        qtype: Rtype,
+        rcode: Rcode,
+        answer: Vec<Rec>,
+        authority: Vec<Rec>,
+    ) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN)).unwrap();
+        builder.header_mut().set_rcode(rcode);
+        let mut builder = builder.question();
+        builder.push((name(qname), qtype)).unwrap();
+        let mut builder = builder.answer();
+        for record in answer {
+            builder.push(record).unwrap();
+        }
+        let mut builder = builder.authority();
+        for record in authority {
+            builder.push(record).unwrap();
+        }
+        builder.into_message()
+    }
+
+    // The signing key of a zone, used both as its KSK and ZSK.
+    struct Key {
+        zone: Dname<Bytes>,
+        pair: Ed25519KeyPair,
+        dnskey: Dnskey<Bytes>,
+    }
+
+    impl Key {
+        fn new(zone: &str, seed: u8) -> Self {
+            let pair = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+            let dnskey = Dnskey::new(
+                257,
+                3,
+                SecAlg::Ed25519,
+                Bytes::copy_from_slice(pair.public_key().as_ref()),
+            );
+            Self {
+                zone: name(zone),
+                pair,
+                dnskey,
+            }
+        }
+
+        fn ds(&self) -> Ds<Bytes> {
+            let digest = self.dnskey.digest(&self.zone, DigestAlg::Sha256).unwrap();
+            Ds::new(
+                key_tag(&self.dnskey),
+                SecAlg::Ed25519,
+                DigestAlg::Sha256,
+                Bytes::copy_from_slice(digest.as_ref()),
+            )
+        }
+
+        // The RRset followed by the signature of the key over it.
+        fn sign(&self, mut rrset: Vec<Rec>) -> Vec<Rec> {
+            let (owner, rtype, ttl) = (rrset[0].owner().clone(), rrset[0].rtype(), rrset[0].ttl());
+            let rrsig = |signature| {
+                Rrsig::new(
+                    rtype,
+                    SecAlg::Ed25519,
+                    (owner.label_count() - 1) as u8,
+                    ttl,
+                    Serial::now().add(3600),
+                    Serial(Serial::now().0 - 3600),
+                    key_tag(&self.dnskey),
+                    self.zone.clone(),
+                    signature,
+                )
+            };
+            let mut signed = Vec::new();
+            rrsig(Bytes::new())
+                .signed_data(&mut signed, &mut rrset)
+                .unwrap();
+            let signature = Bytes::copy_from_slice(self.pair.sign(&signed).as_ref());
+            rrset.push(Record::new(
+                owner.clone(),
+                Class::In,
+                ttl,
+                ZoneRecordData::Rrsig(rrsig(signature)),
+            ));
+            rrset
+        }
+    }
+
+    // An upstream answering the DNSKEY and DS queries of the validator, recording them.
+    struct Canned {
+        answers: HashMap<(Dname<Bytes>, Rtype), Message<Bytes>>,
+        queried: Mutex<Vec<(Dname<Bytes>, Rtype)>>,
+    }
+
+    #[async_trait]
+    impl QHandle for Canned {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+            let question = msg.first_question().unwrap();
+            let key = (
+                question.qname().to_dname::<Bytes>().unwrap(),
+                question.qtype(),
+            );
+            self.queried.lock().unwrap().push(key.clone());
+            Ok(self
+                .answers
+                .get(&key)
+                .unwrap_or_else(|| panic!("unexpected query for {:?}", key))
+                .clone())
+        }
+    }
+
+    // A root zone delegating to example. signed with NSEC, to test. signed with NSEC3, and from
+    // example. to the unsigned insecure.example.
+    #[allow(clippy::mutable_key_type)]
+    fn validator() -> (Dnssec, Arc<Canned>) {
+        let (root, example, test) = (
+            Key::new(".", 1),
+            Key::new("example.", 2),
+            Key::new("test.", 3),
+        );
+        let mut answers = HashMap::new();
+        for key in [&root, &example, &test] {
+            let dnskey = record(
+                &key.zone.to_string(),
+                ZoneRecordData::Dnskey(key.dnskey.clone()),
+            );
+            answers.insert(
+                (key.zone.clone(), Rtype::Dnskey),
+                message(
+                    &key.zone.to_string(),
+                    Rtype::Dnskey,
+                    Rcode::NoError,
+                    key.sign(vec![dnskey]),
+                    vec![],
+                ),
+            );
+        }
+        for key in [&example, &test] {
+            let ds = record(&key.zone.to_string(), ZoneRecordData::Ds(key.ds()));
+            answers.insert(
+                (key.zone.clone(), Rtype::Ds),
+                message(
+                    &key.zone.to_string(),
+                    Rtype::Ds,
+                    Rcode::NoError,
+                    root.sign(vec![ds]),
+                    vec![],
+                ),
+            );
+        }
+        for (owner, proof) in [
+            ("insecure.example.", example_nsec()[1].clone()),
+            ("www.example.", example_nsec()[2].clone()),
+        ] {
+            answers.insert(
+                (name(owner), Rtype::Ds),
+                message(
+                    owner,
+                    Rtype::Ds,
+                    Rcode::NoError,
+                    vec![],
+                    example.sign(vec![proof]),
+                ),
+            );
+        }
+
+        let canned = Arc::new(Canned {
+            answers,
+            queried: Mutex::new(Vec::new()),
+        });
+        let dnssec = Dnssec {
+            inner: canned.clone(),
+            anchors: vec![root.ds()],
+            zones: Mutex::new(CLruCache::new(NonZeroUsize::new(MAX_ZONE_CUTS).unwrap())),
+        };
+        (dnssec, canned)
+    }
+
+    // The NSEC chain of example., with a delegation at insecure.example.
+    fn example_nsec() -> Vec<Rec> {
+        vec![
+            nsec(
+                "example.",
+                "insecure.example.",
+                &[
+                    Rtype::Soa,
+                    Rtype::Ns,
+                    Rtype::Dnskey,
+                    Rtype::Nsec,
+                    Rtype::Rrsig,
+                ],
+            ),
+            nsec(
+                "insecure.example.",
+                "www.example.",
+                &[Rtype::Ns, Rtype::Nsec, Rtype::Rrsig],
+            ),
+            nsec(
+                "www.example.",
+                "example.",
+                &[Rtype::A, Rtype::Nsec, Rtype::Rrsig],
+            ),
+        ]
+    }
+
+    // The signed NSEC records of example. at the given positions of the chain.
+    fn example_proofs(at: &[usize]) -> Vec<Rec> {
+        let (example, chain) = (Key::new("example.", 2), example_nsec());
+        at.iter()
+            .flat_map(|i| example.sign(vec![chain[*i].clone()]))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn secure_answers() {
+        let (dnssec, _) = validator();
+        let example = Key::new("example.", 2);
+        let resp = message(
+            "www.example.",
+            Rtype::A,
+            Rcode::NoError,
+            example.sign(vec![a("www.example.", [192, 0, 2, 1])]),
+            vec![],
+        );
+        assert!(dnssec.validate(&resp).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn bogus_signatures() {
+        let (dnssec, _) = validator();
+        let example = Key::new("example.", 2);
+        let mut answer = example.sign(vec![a("www.example.", [192, 0, 2, 1])]);
+        answer[0] = a("www.example.", [192, 0, 2, 2]);
+        let resp = message("www.example.", Rtype::A, Rcode::NoError, answer, vec![]);
+        assert!(matches!(
+            dnssec.validate(&resp).await,
+            Err(DnssecError::Bogus(Ede::Bogus))
+        ));
+
+        // Signatures made by another zone than the one of the name are bogus as well.
+        let other = Key::new("test.", 3);
+        let resp = message(
+            "www.example.",
+            Rtype::A,
+            Rcode::NoError,
+            other.sign(vec![a("www.example.", [192, 0, 2, 1])]),
+            vec![],
+        );
+        assert!(dnssec.validate(&resp).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_signatures() {
+        let (dnssec, _) = validator();
+        let resp = message(
+            "www.example.",
+            Rtype::A,
+            Rcode::NoError,
+            vec![a("www.example.", [192, 0, 2, 1])],
+            vec![],
+        );
+        assert!(matches!(
+            dnssec.validate(&resp).await,
+            Err(DnssecError::Bogus(Ede::RrsigsMissing))
+        ));
+    }
+
+    #[tokio::test]
+    async fn nsec_denials() {
+        let (dnssec, _) = validator();
+        let nxdomain = message(
+            "nope.example.",
+            Rtype::A,
+            Rcode::NXDomain,
+            vec![],
+            example_proofs(&[0, 1]),
+        );
+        assert!(dnssec.validate(&nxdomain).await.unwrap());
+
+        let nodata = message(
+            "www.example.",
+            Rtype::Aaaa,
+            Rcode::NoError,
+            vec![],
+            example_proofs(&[2]),
+        );
+        assert!(dnssec.validate(&nodata).await.unwrap());
+
+        // The NSEC record of www.example. proves it exists.
+        let nxdomain = message(
+            "www.example.",
+            Rtype::A,
+            Rcode::NXDomain,
+            vec![],
+            example_proofs(&[2]),
+        );
+        assert!(matches!(
+            dnssec.validate(&nxdomain).await,
+            Err(DnssecError::Bogus(Ede::NsecMissing))
+        ));
+
+        // Without the record covering the wildcard, the name could have been expanded from it.
+        let nxdomain = message(
+            "nope.example.",
+            Rtype::A,
+            Rcode::NXDomain,
+            vec![],
+            example_proofs(&[1]),
+        );
+        assert!(matches!(
+            dnssec.validate(&nxdomain).await,
+            Err(DnssecError::Bogus(Ede::NsecMissing))
+        ));
+    }
+
+    #[tokio::test]
+    async fn nsec3_denials() {
+        let (dnssec, _) = validator();
+        let test = Key::new("test.", 3);
+        let chain = nsec3_chain(
+            "test.",
+            &[
+                (
+                    "test.",
+                    &[
+                        Rtype::Soa,
+                        Rtype::Ns,
+                        Rtype::Dnskey,
+                        Rtype::Nsec3param,
+                        Rtype::Rrsig,
+                    ],
+                ),
+                ("www.test.", &[Rtype::A, Rtype::Rrsig]),
+            ],
+        );
+        let proofs: Vec<Rec> = chain
+            .iter()
+            .flat_map(|r| test.sign(vec![r.clone()]))
+            .collect();
+
+        let nxdomain = message(
+            "nope.test.",
+            Rtype::A,
+            Rcode::NXDomain,
+            vec![],
+            proofs.clone(),
+        );
+        assert!(dnssec.validate(&nxdomain).await.unwrap());
+
+        let nodata = message(
+            "www.test.",
+            Rtype::Aaaa,
+            Rcode::NoError,
+            vec![],
+            proofs.clone(),
+        );
+        assert!(dnssec.validate(&nodata).await.unwrap());
+
+        let nxdomain = message("www.test.", Rtype::A, Rcode::NXDomain, vec![], proofs);
+        assert!(dnssec.validate(&nxdomain).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn insecure_delegations() {
+        let (dnssec, canned) = validator();
+        let resp = message(
+            "host.insecure.example.",
+            Rtype::A,
+            Rcode::NoError,
+            vec![a("host.insecure.example.", [192, 0, 2, 3])],
+            vec![],
+        );
+        assert!(!dnssec.validate(&resp).await.unwrap());
+
+        // The unsigned delegation is remembered, other names below it need no query.
+        let queried = canned.queried.lock().unwrap().len();
+        let resp = message(
+            "other.insecure.example.",
+            Rtype::A,
+            Rcode::NoError,
+            vec![a("other.insecure.example.", [192, 0, 2, 4])],
+            vec![],
+        );
+        assert!(!dnssec.validate(&resp).await.unwrap());
+        assert_eq!(canned.queried.lock().unwrap().len(), queried);
+
+        // The parent's NSEC record at the delegation can't deny names below it.
+        let nxdomain = message(
+            "nope.insecure.example.",
+            Rtype::A,
+            Rcode::NXDomain,
+            vec![],
+            example_proofs(&[1]),
+        );
+        assert!(matches!(
+            dnssec.validate(&nxdomain).await,
+            Err(DnssecError::Bogus(Ede::NsecMissing))
+        ));
+    }
+
+    #[tokio::test]
+    async fn random_subdomains() {
+        let (dnssec, canned) = validator();
+        for qname in ["a1.example.", "a2.example.", "a3.example."] {
+            let nxdomain = message(
+                qname,
+                Rtype::A,
+                Rcode::NXDomain,
+                vec![],
+                example_proofs(&[0, 1]),
+            );
+            assert!(dnssec.validate(&nxdomain).await.unwrap());
+        }
+        // Only the chain of trust down to example. is fetched, once.
+        assert_eq!(
+            *canned.queried.lock().unwrap(),
+            vec![
+                (Dname::root_bytes(), Rtype::Dnskey),
+                (name("example."), Rtype::Ds),
+                (name("example."), Rtype::Dnskey),
+            ]
+        );
+        assert_eq!(dnssec.zones.lock().unwrap().len(), 2);
+    }
+
+    // Hashes from RFC 5155 Appendix A, with salt aabbccdd and 12 additional iterations.
+    #[test]
+    fn hash_nsec3_owners() {
+        let salt = hex::decode("aabbccdd").unwrap();
+        for (owner, hash) in [
+            ("example.", "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            ("a.example.", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+            ("*.w.example.", "r53bq7cc2uvmubfu5ocmm6pers9tk9en"),
+            ("X.W.Example.", "b4um86eghhds6nea196smvmlo4ors995"),
+        ] {
+            assert_eq!(
+                nsec3_hash(&name(owner), 12, &salt),
+                base32hex(hash.as_bytes()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn cover_spans() {
+        assert!(covers(
+            &name("a.example."),
+            &name("c.example."),
+            &name("b.example.")
+        ));
+        assert!(!covers(
+            &name("a.example."),
+            &name("c.example."),
+            &name("c.example.")
+        ));
+        // The last span wraps around to the apex.
+        assert!(covers(
+            &name("z.example."),
+            &name("example."),
+            &name("zz.example.")
+        ));
+        assert!(!covers(
+            &name("z.example."),
+            &name("example."),
+            &name("b.example.")
+        ));
+    }
+
+    #[test]
+    fn wildcard_names() {
+        assert_eq!(wildcard_of(&name("example.")).unwrap(), name("*.example."));
+    }
+
+    #[test]
+    fn synthesized_cnames() {
+        let (dname, to) = (name("example.com."), name("example.net."));
+        assert!(synthesized(
+            &name("www.example.com."),
+            &name("www.example.net."),
+            &dname,
+            &to
+        ));
+        assert!(!synthesized(
+            &name("www.example.com."),
+            &name("ftp.example.net."),
+            &dname,
+            &to
+        ));
+        assert!(!synthesized(
+            &name("example.com."),
+            &name("example.net."),
+            &dname,
+            &to
+        ));
+    }
+}
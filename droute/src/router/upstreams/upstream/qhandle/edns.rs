@@ -0,0 +1,74 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{QHandle, Result};
+use crate::router::script::utils::edns::{set_edns, Edns};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use std::sync::Arc;
+
+// Control the OPT record of queries sent to the inner handle.
+pub struct EdnsControl {
+    inner: Arc<dyn QHandle>,
+    // Whether to send the OPT record at all
+    enabled: bool,
+    // UDP payload size advertised, or the one in the query if not set
+    udp_size: Option<u16>,
+}
+
+impl EdnsControl {
+    pub fn new(inner: Arc<dyn QHandle>, enabled: bool, udp_size: Option<u16>) -> Self {
+        Self {
+            inner,
+            enabled,
+            udp_size,
+        }
+    }
+
+    // Get the rewritten query, or `None` if the query is sent as is.
+    fn rewrite(&self, msg: &Message<Bytes>) -> Option<Message<Bytes>> {
+        if !self.enabled {
+            // Nothing to strip if there is no OPT record.
+            msg.opt()?;
+            return set_edns(msg, None).ok();
+        }
+        let udp_size = self.udp_size?;
+        let mut edns = Edns::from_message_or_new(msg).ok()?;
+        if edns.udp_payload_size == udp_size && msg.opt().is_some() {
+            return None;
+        }
+        edns.udp_payload_size = udp_size;
+        set_edns(msg, Some(&edns)).ok()
+    }
+}
+
+#[async_trait]
+impl QHandle for EdnsControl {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        match self.rewrite(msg) {
+            Some(query) => {
+                let resp = self.inner.query(&query).await?;
+                // Don't hand an OPT record to clients that didn't send one.
+                if msg.opt().is_none() && resp.opt().is_some() {
+                    Ok(set_edns(&resp, None).unwrap_or(resp))
+                } else {
+                    Ok(resp)
+                }
+            }
+            None => self.inner.query(msg).await,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{QHandleError, Result};
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{net::TcpStream, time::sleep};
+
+// Time to wait on a pending connection attempt before starting the next one (RFC 8305, Section 5)
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// TCP connector for a server with several addresses, e.g. both of the IPv4 and IPv6 ones.
+/// Connection attempts are raced in the Happy Eyeballs style, and the address that won is tried first next time.
+pub struct HappyEyeballs {
+    addrs: Vec<SocketAddr>,
+    // Index of the address last connected to
+    preferred: AtomicUsize,
+}
+
+impl HappyEyeballs {
+    /// Create a new connector with the addresses in the order of preference.
+    pub fn new(addrs: Vec<SocketAddr>) -> Result<Self> {
+        if addrs.is_empty() {
+            return Err(QHandleError::NoAddress);
+        }
+        Ok(Self {
+            addrs,
+            preferred: AtomicUsize::new(0),
+        })
+    }
+
+    // Indices of the addresses to try in order: the preferred one first, then alternating between the address families.
+    fn order(&self) -> Vec<usize> {
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let is_ipv4 = self.addrs[preferred].is_ipv4();
+        let (mut same, mut other): (VecDeque<usize>, VecDeque<usize>) = (0..self.addrs.len())
+            .filter(|i| *i != preferred)
+            .partition(|i| self.addrs[*i].is_ipv4() == is_ipv4);
+
+        let mut order = vec![preferred];
+        while !(same.is_empty() && other.is_empty()) {
+            order.extend(other.pop_front());
+            order.extend(same.pop_front());
+        }
+        order
+    }
+
+    async fn attempt(&self, i: usize) -> (usize, std::io::Result<TcpStream>) {
+        (i, TcpStream::connect(self.addrs[i]).await)
+    }
+
+    /// Connect to the server. A new attempt to the next address is started every 250 milliseconds, or as soon as the previous one fails, until one of them succeeds.
+    pub async fn connect(&self) -> std::io::Result<TcpStream> {
+        let mut pending = self.order().into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut error = None;
+
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    Some(i) => attempts.push(self.attempt(i)),
+                    None => break,
+                }
+            }
+            tokio::select! {
+                Some((i, r)) = attempts.next() => match r {
+                    Ok(stream) => {
+                        self.preferred.store(i, Ordering::Relaxed);
+                        return Ok(stream);
+                    }
+                    Err(e) => {
+                        log::debug!("failed to connect to {}: {}", self.addrs[i], e);
+                        error = Some(e);
+                        if let Some(i) = pending.next() {
+                            attempts.push(self.attempt(i));
+                        }
+                    }
+                },
+                _ = sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                    if let Some(i) = pending.next() {
+                        attempts.push(self.attempt(i));
+                    }
+                }
+            }
+        }
+
+        // There is at least one address, so at least one attempt has failed.
+        Err(error.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HappyEyeballs;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn remember_winner() {
+        // Nothing listens on the port once the listener is dropped, so connections to it are refused.
+        let refused: SocketAddr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connector = HappyEyeballs::new(vec![refused, addr]).unwrap();
+        let stream = connector.connect().await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(connector.order(), vec![1, 0]);
+    }
+
+    #[test]
+    fn alternate_families() {
+        let addrs = ["[::1]:53", "[::2]:53", "127.0.0.1:53", "127.0.0.2:53"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let connector = HappyEyeballs::new(addrs).unwrap();
+        assert_eq!(connector.order(), vec![0, 2, 1, 3]);
+    }
+}
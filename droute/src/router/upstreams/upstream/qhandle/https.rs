@@ -15,74 +15,186 @@
 
 #[cfg(feature = "doh-rustls")]
 mod rustls_cfgs {
+    use super::{ClientCert, QHandleError, Result, TlsTrust};
     use once_cell::sync::Lazy;
-    use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
-
-    pub static NO_SNI_CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&false));
-    pub static CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&true));
-
-    fn create_client_config(sni: &bool) -> ClientConfig {
-        let mut root_store = RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-
-        let mut client_config = ClientConfig::builder()
+    use rustls::ClientConfig;
+
+    pub type TlsConfig = ClientConfig;
+
+    static NO_SNI_CLIENT_CFG: Lazy<ClientConfig> =
+        Lazy::new(|| create_client_config(&false, None, &TlsTrust::default()).unwrap());
+    static CLIENT_CFG: Lazy<ClientConfig> =
+        Lazy::new(|| create_client_config(&true, None, &TlsTrust::default()).unwrap());
+
+    fn create_client_config(
+        sni: &bool,
+        client_cert: Option<&ClientCert>,
+        trust: &TlsTrust,
+    ) -> Result<ClientConfig> {
+        let builder = ClientConfig::builder()
             .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+            .with_custom_certificate_verifier(trust.to_rustls()?);
+        let mut client_config = match client_cert {
+            Some(client_cert) => {
+                let (certs, key) = client_cert.to_rustls()?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| QHandleError::InvalidClientCert(e.to_string()))?
+            }
+            None => builder.with_no_client_auth(),
+        };
 
         client_config.enable_sni = *sni; // Disable SNI on need.
 
-        client_config
+        Ok(client_config)
+    }
+
+    // Share the default configurations, only the ones with client certificates or custom trust are built per upstream.
+    pub fn tls_config(
+        sni: bool,
+        client_cert: Option<&ClientCert>,
+        trust: &TlsTrust,
+    ) -> Result<TlsConfig> {
+        match (client_cert.is_none() && trust.is_default(), sni) {
+            (true, true) => Ok(CLIENT_CFG.clone()),
+            (true, false) => Ok(NO_SNI_CLIENT_CFG.clone()),
+            (false, _) => create_client_config(&sni, client_cert, trust),
+        }
     }
 }
 
 #[cfg(feature = "doh-native-tls")]
 mod native_tls_cfgs {
+    use super::{ClientCert, Result, TlsTrust};
     use native_tls::TlsConnector;
     use once_cell::sync::Lazy;
 
-    pub static NO_SNI_CLIENT_CFG: Lazy<TlsConnector> =
+    pub type TlsConfig = TlsConnector;
+
+    static NO_SNI_CLIENT_CFG: Lazy<TlsConnector> =
         Lazy::new(|| TlsConnector::builder().use_sni(false).build().unwrap());
-    pub static CLIENT_CFG: Lazy<TlsConnector> = Lazy::new(|| TlsConnector::new().unwrap());
+    static CLIENT_CFG: Lazy<TlsConnector> = Lazy::new(|| TlsConnector::new().unwrap());
+
+    pub fn tls_config(
+        sni: bool,
+        client_cert: Option<&ClientCert>,
+        trust: &TlsTrust,
+    ) -> Result<TlsConfig> {
+        match (client_cert.is_none() && trust.is_default(), sni) {
+            (true, true) => Ok(CLIENT_CFG.clone()),
+            (true, false) => Ok(NO_SNI_CLIENT_CFG.clone()),
+            (false, _) => {
+                let mut builder = TlsConnector::builder();
+                builder.use_sni(sni);
+                if let Some(client_cert) = client_cert {
+                    builder.identity(client_cert.to_native_tls()?);
+                }
+                trust.apply_native_tls(&mut builder)?;
+                Ok(builder.build()?)
+            }
+        }
+    }
 }
 
 #[cfg(feature = "doh-rustls")]
-use rustls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+use rustls_cfgs::{tls_config, TlsConfig};
 
 #[cfg(feature = "doh-native-tls")]
-use native_tls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+use native_tls_cfgs::{tls_config, TlsConfig};
 
-use super::{ConnInitiator, QHandle, QHandleError, Result};
+use super::{
+    client_cert::ClientCert, trust::TlsTrust, ConnInitiator, QHandle, QHandleError, Result,
+};
 use async_trait::async_trait;
+use base64::Engine;
 use bytes::{Bytes, BytesMut};
 use domain::base::Message;
-use reqwest::{Client, Proxy, Url};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Resolve, Resolving},
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, ClientBuilder, Proxy, Url,
+};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
     str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
+// Resolver handing out the configured addresses of the server, the one that responded last coming first.
+// The connector of hyper races the addresses of different families Happy Eyeballs style, trying the first one first.
+struct ServerAddrs {
+    domain: String,
+    addrs: Vec<IpAddr>,
+    // Index of the address that responded last
+    preferred: AtomicUsize,
+}
+
+impl ServerAddrs {
+    // Remember the address that responded.
+    fn set_preferred(&self, addr: SocketAddr) {
+        if let Some(i) = self.addrs.iter().position(|ip| *ip == addr.ip()) {
+            self.preferred.store(i, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Resolve for ServerAddrs {
+    fn resolve(&self, name: Name) -> Resolving {
+        // Other domains, like the one of the proxy, are resolved by the system.
+        if name.as_str() != self.domain {
+            let host = format!("{}:0", name.as_str());
+            return Box::pin(async move {
+                let addrs: Box<dyn Iterator<Item = SocketAddr> + Send> =
+                    Box::new(tokio::net::lookup_host(host).await?);
+                Ok(addrs)
+            });
+        }
+
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let mut addrs = self.addrs.clone();
+        addrs.rotate_left(preferred);
+        // The port in socket addr doesn't take effect here, the one in the URL is used instead.
+        let addrs: Box<dyn Iterator<Item = SocketAddr> + Send> =
+            Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+        Box::pin(futures::future::ready(Ok(addrs)))
+    }
+}
+
 /// Client instance for HTTPS connections
 #[derive(Clone)]
 pub struct Https {
-    client: PostClient,
+    client: HttpsClient,
 }
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
 impl Https {
-    /// Create a new HTTPS client creator instance. with the given remote server address.
+    /// Create a new HTTPS client creator instance. with the given remote server addresses, which are raced on connecting.
+    /// If `http3` is true, queries are sent over HTTP/3 first, falling back to HTTP/2 on failure.
+    /// If `client_cert` is set, it is presented to the server for mutual TLS. The server certificate is verified against `trust`.
+    /// Queries are sent with `method`, along with the extra `headers`, e.g. for authentication.
     // We *CANNOT* reuse the client *WITH* connection pool because if the network changes, *connection* inside client pool of each client remains the same, and cloning them inevitably leads to no reconnection but using stale connections.
     // However, we are able to disable the connection pool and use the client.
     // We cannot store ClientBuilder because it is not Clone.
-    pub async fn new(uri: String, addr: IpAddr, proxy: Option<String>, sni: bool) -> Result<Self> {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        uri: String,
+        addrs: Vec<IpAddr>,
+        proxy: Option<String>,
+        sni: bool,
+        http3: bool,
+        client_cert: Option<ClientCert>,
+        trust: TlsTrust,
+        method: HttpMethod,
+        headers: HashMap<String, String>,
+    ) -> Result<Self> {
         let uri = Url::from_str(&uri).map_err(|_| QHandleError::InvalidUri(uri))?;
         // Check domain validness
         let _ = uri
@@ -91,44 +203,85 @@ impl Https {
 
         // This has already been checked and it is safe to unwrap
         let domain = uri.domain().unwrap();
-        let client = Client::builder()
-            // The port in socket addr doesn't take effect here per documentation
-            .resolve(domain, SocketAddr::new(addr, 0))
-            .use_preconfigured_tls(if sni {
-                CLIENT_CFG.clone()
-            } else {
-                NO_SNI_CLIENT_CFG.clone()
+        if addrs.is_empty() {
+            return Err(QHandleError::NoAddress);
+        }
+        let addrs = Arc::new(ServerAddrs {
+            domain: domain.to_string(),
+            addrs,
+            preferred: AtomicUsize::new(0),
+        });
+
+        let tls = tls_config(sni, client_cert.as_ref(), &trust)?;
+        let headers = headers
+            .into_iter()
+            .map(|(k, v)| {
+                Ok((
+                    HeaderName::from_str(&k).map_err(|_| QHandleError::InvalidHeader(k.clone()))?,
+                    HeaderValue::from_str(&v).map_err(|_| QHandleError::InvalidHeader(k))?,
+                ))
             })
-            .https_only(true)
-            .user_agent(APP_USER_AGENT)
-            .connect_timeout(Duration::from_secs(3))
-            // Disable the inner connection pool
-            .pool_max_idle_per_host(0);
-
-        // Add proxy
-        let client = if let Some(proxy) = proxy {
-            client.proxy(Proxy::all(proxy)?)
+            .collect::<Result<HeaderMap>>()?;
+
+        // HTTP/3 runs over QUIC, which cannot be tunneled through the proxies supported.
+        let h3 = match (http3, &proxy) {
+            (false, _) => None,
+            (true, Some(_)) => {
+                log::warn!("HTTP/3 is not available with proxies, falling back to HTTP/2");
+                None
+            }
+            #[cfg(feature = "doh3")]
+            (true, None) => Some(build_client(
+                client_builder(addrs.clone(), tls.clone(), headers.clone()).http3_prior_knowledge(),
+            )?),
+            #[cfg(not(feature = "doh3"))]
+            (true, None) => return Err(QHandleError::Http3Unsupported),
+        };
+
+        let client = client_builder(addrs.clone(), tls, headers);
+        // Add proxy. The peer is then the proxy, whose address is not remembered.
+        let (client, addrs) = if let Some(proxy) = proxy {
+            (client.proxy(Proxy::all(proxy)?), None)
         } else {
-            client
+            (client, Some(addrs))
         };
 
         Ok(Self {
-            client: PostClient(
-                client.build().map_err(|_| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "TLS backend failed to initialize",
-                    )
-                })?,
-                uri.clone(),
-            ),
+            client: HttpsClient {
+                client: build_client(client)?,
+                h3,
+                url: uri,
+                method,
+                addrs,
+            },
         })
     }
 }
 
+fn client_builder(addrs: Arc<ServerAddrs>, tls: TlsConfig, headers: HeaderMap) -> ClientBuilder {
+    Client::builder()
+        .default_headers(headers)
+        .dns_resolver(addrs)
+        .use_preconfigured_tls(tls)
+        .https_only(true)
+        .user_agent(APP_USER_AGENT)
+        .connect_timeout(Duration::from_secs(3))
+        // Disable the inner connection pool
+        .pool_max_idle_per_host(0)
+}
+
+fn build_client(builder: ClientBuilder) -> Result<Client> {
+    Ok(builder.build().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "TLS backend failed to initialize",
+        )
+    })?)
+}
+
 #[async_trait]
 impl ConnInitiator for Https {
-    type Connection = PostClient;
+    type Connection = HttpsClient;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
         Ok(self.client.clone())
@@ -139,25 +292,58 @@ impl ConnInitiator for Https {
     }
 }
 
-#[derive(Clone)]
-pub struct PostClient(Client, Url);
+/// HTTP method used to send DNS queries
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMethod {
+    /// Queries are encoded in the `dns` parameter of the URL, which is friendlier to HTTP caches
+    Get,
+    /// Queries are sent as the request body
+    Post,
+}
 
-#[async_trait]
-impl QHandle for PostClient {
-    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
-        // Per RFC, the message ID should be set to 0 to better facilitate HTTPS caching.
-        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
-        msg.header_mut().set_id(0);
+impl Default for HttpMethod {
+    fn default() -> Self {
+        Self::Post
+    }
+}
+
+// The client over HTTP/2 (or HTTP/1.1), and optionally the client over HTTP/3 preferred.
+#[derive(Clone)]
+pub struct HttpsClient {
+    client: Client,
+    h3: Option<Client>,
+    url: Url,
+    method: HttpMethod,
+    // Addresses of the server, unless connected through a proxy
+    addrs: Option<Arc<ServerAddrs>>,
+}
 
-        let body: reqwest::Body = msg.into_octets().freeze().into();
-        let res = self
-            .0
-            .post(self.1.clone())
-            .header("content-type", "application/dns-message")
-            .body(body)
+impl HttpsClient {
+    async fn send(&self, client: &Client, body: Bytes) -> Result<Message<Bytes>> {
+        let req = match self.method {
+            HttpMethod::Post => client
+                .post(self.url.clone())
+                .header("content-type", "application/dns-message")
+                .body(body),
+            HttpMethod::Get => {
+                let mut url = self.url.clone();
+                url.query_pairs_mut().append_pair(
+                    "dns",
+                    &base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(body),
+                );
+                client.get(url)
+            }
+        };
+        let res = req
+            .header("accept", "application/dns-message")
             .send()
             .await?;
 
+        if let (Some(addrs), Some(addr)) = (&self.addrs, res.remote_addr()) {
+            addrs.set_preferred(addr);
+        }
+
         if res.status().is_success() {
             let res = res.bytes().await?;
             let answer = Message::from_octets(res)?;
@@ -166,6 +352,25 @@ impl QHandle for PostClient {
             Err(QHandleError::FailedHttp(res.status()))
         }
     }
+}
+
+#[async_trait]
+impl QHandle for HttpsClient {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // Per RFC, the message ID should be set to 0 to better facilitate HTTPS caching.
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        msg.header_mut().set_id(0);
+        let body = msg.into_octets().freeze();
+
+        if let Some(h3) = &self.h3 {
+            match self.send(h3, body.clone()).await {
+                Ok(answer) => return Ok(answer),
+                Err(e) => log::debug!("HTTP/3 query failed: {}, falling back to HTTP/2", e),
+            }
+        }
+
+        self.send(&self.client, body).await
+    }
 
     async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
         Ok(())
@@ -0,0 +1,70 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// EDNS TCP keepalive (RFC 7828), with which servers tell us how long they keep idle connections open.
+
+use crate::router::script::utils::edns::{set_edns, Edns};
+use bytes::Bytes;
+use domain::base::{
+    iana::OptionCode,
+    opt::{AllOptData, UnknownOptData},
+    Message,
+};
+use std::time::Duration;
+
+// Add the keepalive option to the query. The query is left untouched if it fails.
+pub(super) fn add_keepalive(msg: &Message<Bytes>) -> Message<Bytes> {
+    let mut edns = match Edns::from_message_or_new(msg) {
+        Ok(edns) => edns,
+        Err(_) => return msg.clone(),
+    };
+    edns.options
+        .retain(|o| !matches!(o, AllOptData::TcpKeepalive(_)));
+    // Clients must send the option without the timeout.
+    edns.options
+        .push(AllOptData::Other(UnknownOptData::from_octets(
+            OptionCode::TcpKeepalive,
+            Bytes::new(),
+        )));
+    set_edns(msg, Some(&edns)).unwrap_or_else(|_| msg.clone())
+}
+
+// Get the idle timeout advertised by the server, and remove the keepalive option from the response as it is per connection.
+// If the original query had no OPT record, the OPT record we introduced is removed as well.
+pub(super) fn take_keepalive(
+    resp: Message<Bytes>,
+    query: &Message<Bytes>,
+) -> (Message<Bytes>, Option<Duration>) {
+    let mut edns = match Edns::from_message(&resp) {
+        Ok(Some(edns)) => edns,
+        _ => return (resp, None),
+    };
+    let timeout = edns.options.iter().find_map(|o| match o {
+        // The timeout is in units of 100 milliseconds.
+        AllOptData::TcpKeepalive(k) => Some(Duration::from_millis(u64::from(k.timeout()) * 100)),
+        _ => None,
+    });
+    let resp = if query.opt().is_none() {
+        set_edns(&resp, None)
+    } else if timeout.is_some() {
+        edns.options
+            .retain(|o| !matches!(o, AllOptData::TcpKeepalive(_)));
+        set_edns(&resp, Some(&edns))
+    } else {
+        return (resp, None);
+    }
+    .unwrap_or(resp);
+    (resp, timeout)
+}
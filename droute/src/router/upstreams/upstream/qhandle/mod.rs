@@ -13,14 +13,45 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-rustls",
+    feature = "dot-native-tls"
+))]
+pub mod client_cert;
+#[cfg(feature = "dnscrypt")]
+pub mod dnscrypt;
+#[cfg(feature = "dnssec")]
+pub mod dnssec;
+pub mod edns;
+#[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
+pub mod happy_eyeballs;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 pub mod https;
+mod keepalive;
 #[cfg_attr(target_pointer_width = "64", path = "qos_governor.rs")]
 #[cfg_attr(not(target_pointer_width = "64"), path = "qos_none.rs")]
-mod qos;
+pub mod qos;
+#[cfg(feature = "doq")]
+pub mod quic;
+pub mod retry;
+pub mod rotate;
+pub mod tcp;
 #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
 pub mod tls;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-rustls",
+    feature = "dot-native-tls",
+    feature = "doq"
+))]
+pub mod trust;
+#[cfg(feature = "tsig")]
+pub mod tsig;
 pub mod udp;
+pub mod zone;
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
@@ -139,15 +170,79 @@ pub enum QHandleError {
     #[error("unsuccessful HTTP code: {0}")]
     FailedHttp(StatusCode),
 
-    #[cfg(any(feature = "dot-native-tls"))]
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[error("the HTTP header '{0}' is invalid")]
+    InvalidHeader(String),
+
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[error("HTTP/3 is not supported in this build")]
+    Http3Unsupported,
+
+    #[cfg(any(
+        feature = "doh-rustls",
+        feature = "doh-native-tls",
+        feature = "dot-rustls",
+        feature = "dot-native-tls"
+    ))]
+    #[error("invalid client certificate: {0}")]
+    InvalidClientCert(String),
+
+    #[cfg(any(
+        feature = "doh-rustls",
+        feature = "doh-native-tls",
+        feature = "dot-rustls",
+        feature = "dot-native-tls",
+        feature = "doq"
+    ))]
+    #[error("invalid CA certificates or SPKI pins: {0}")]
+    InvalidTrust(String),
+
+    #[cfg(feature = "dnscrypt")]
+    #[error(transparent)]
+    DnsCryptError(#[from] dnscrypt::DnsCryptError),
+
+    #[cfg(feature = "dnssec")]
+    #[error(transparent)]
+    DnssecError(#[from] dnssec::DnssecError),
+
+    #[error("DNSSEC validation is not supported in this build")]
+    DnssecUnsupported,
+
+    #[cfg(feature = "tsig")]
+    #[error(transparent)]
+    TsigError(#[from] tsig::TsigError),
+
+    #[error("TSIG is not supported in this build")]
+    TsigUnsupported,
+
+    #[cfg(any(feature = "doh-native-tls", feature = "dot-native-tls"))]
     #[error(transparent)]
     NativeTlsError(#[from] native_tls::Error),
 
     #[error(transparent)]
     ShortBuf(#[from] domain::base::ShortBuf),
 
+    #[error(transparent)]
+    ZoneError(#[from] zone::ZoneError),
+
+    #[cfg(any(test, feature = "testing"))]
+    #[error(transparent)]
+    MockError(#[from] crate::router::testing::MockError),
+
+    #[error("no address of the server is configured")]
+    NoAddress,
+
+    #[error("the zone '{0}' is not a valid domain name")]
+    InvalidZone(String),
+
+    #[error("the dnsmasq directive '{0}' doesn't contain a valid server address")]
+    InvalidDnsmasq(String),
+
     #[error("ratelimiter throttled the upstream query")]
     Throttled,
+
+    #[error("the upstream responded with a message that doesn't answer the query")]
+    UnexpectedResponse,
 }
 
 // For HTTPS connections, ConnPool enables parallelism
@@ -174,12 +269,21 @@ impl<T: ConnInitiator> ConnPool<T> {
             ratelimiter,
         })
     }
+
+    /// Establish `n` connections in advance so that the first queries don't pay for the handshakes.
+    #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
+    pub async fn warm_up(&self, n: usize) {
+        let conns = futures::future::join_all((0..n).map(|_| self.pool.get())).await;
+        let established = conns.iter().filter(|c| c.is_ok()).count();
+        log::debug!("warmed up {} of {} connections", established, n);
+        // Connections are returned to the pool on drop.
+    }
 }
 
 #[async_trait]
 impl<T: ConnInitiator> QHandle for ConnPool<T> {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
-        if self.ratelimiter.check() {
+        if self.ratelimiter.acquire().await {
             let mut conn = self.pool.get().await?;
 
             log::debug!(
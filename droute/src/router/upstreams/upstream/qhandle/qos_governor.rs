@@ -19,29 +19,57 @@ use governor::{
     state::{InMemoryState, NotKeyed},
     Quota, RateLimiter,
 };
-use std::num::NonZeroU32;
+use std::{num::NonZeroU32, time::Duration};
 
 type QosPolicyInner =
     Option<RateLimiter<NotKeyed, InMemoryState, QuantaClock, NoOpMiddleware<QuantaInstant>>>;
 
 #[derive(Default)]
-pub struct QosPolicy(QosPolicyInner);
-
-impl From<Option<NonZeroU32>> for QosPolicy {
-    fn from(qps: Option<NonZeroU32>) -> Self {
-        if let Some(qps) = qps {
-            Self(Some(RateLimiter::direct(Quota::per_second(qps))))
-        } else {
-            Self(None)
-        }
-    }
+pub struct QosPolicy {
+    limiter: QosPolicyInner,
+    // Maximum time queries over the ratelimit wait for their turns, or they fail right away.
+    queue: Option<Duration>,
 }
 
 impl QosPolicy {
-    pub fn check(&self) -> bool {
-        match &self.0 {
-            Some(ratelimit) => ratelimit.check().is_ok(),
-            None => true,
+    pub fn new(qps: Option<NonZeroU32>, queue: Option<Duration>) -> Self {
+        Self {
+            limiter: qps.map(|qps| RateLimiter::direct(Quota::per_second(qps))),
+            queue,
+        }
+    }
+
+    // Whether the query is allowed to be sent.
+    pub async fn acquire(&self) -> bool {
+        match (&self.limiter, self.queue) {
+            (Some(ratelimit), Some(wait)) => tokio::time::timeout(wait, ratelimit.until_ready())
+                .await
+                .is_ok(),
+            (Some(ratelimit), None) => ratelimit.check().is_ok(),
+            (None, _) => true,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::QosPolicy;
+    use std::{num::NonZeroU32, time::Duration};
+
+    #[tokio::test]
+    async fn queue_over_ratelimit() {
+        let qps = NonZeroU32::new(1);
+
+        let fail = QosPolicy::new(qps, None);
+        assert!(fail.acquire().await);
+        assert!(!fail.acquire().await);
+
+        let queue = QosPolicy::new(qps, Some(Duration::from_secs(3)));
+        assert!(queue.acquire().await);
+        assert!(queue.acquire().await);
+
+        let short = QosPolicy::new(qps, Some(Duration::from_millis(100)));
+        assert!(short.acquire().await);
+        assert!(!short.acquire().await);
+    }
+}
@@ -13,19 +13,17 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::num::NonZeroU32;
+use std::{num::NonZeroU32, time::Duration};
 
 #[derive(Default)]
 pub struct QosPolicy;
 
-impl From<Option<NonZeroU32>> for QosPolicy {
-    fn from(_: Option<NonZeroU32>) -> Self {
+impl QosPolicy {
+    pub fn new(_: Option<NonZeroU32>, _: Option<Duration>) -> Self {
         Self
     }
-}
 
-impl QosPolicy {
-    pub fn check(&self) -> bool {
+    pub async fn acquire(&self) -> bool {
         true
     }
 }
@@ -0,0 +1,289 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{trust::TlsTrust, ConnInitiator, QHandle, QHandleError, Result};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use deadpool::managed::{self, RecycleError};
+use domain::base::Message;
+use once_cell::sync::Lazy;
+use quinn::{ClientConfig, Connection, Endpoint};
+use std::{net::SocketAddr, sync::Arc};
+
+// The ALPN token for DNS over QUIC per RFC 9250
+const DOQ_ALPN: &[u8] = b"doq";
+
+// TLS session tickets are cached in the config for 0-RTT resumption, so it should be shared.
+static CLIENT_CFG: Lazy<ClientConfig> =
+    Lazy::new(|| create_client_config(&TlsTrust::default()).unwrap());
+
+fn create_client_config(trust: &TlsTrust) -> Result<ClientConfig> {
+    let mut client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(trust.to_rustls()?)
+        .with_no_client_auth();
+    client_config.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+    client_config.enable_early_data = true;
+
+    Ok(ClientConfig::new(Arc::new(client_config)))
+}
+
+fn bind_addr(is_ipv4: bool) -> SocketAddr {
+    if is_ipv4 {
+        ([0u8; 4], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    }
+}
+
+fn other_err(e: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+/// Client instance for DNS over QUIC connections
+#[derive(Clone)]
+pub struct Quic {
+    endpoint: Endpoint,
+    domain: String,
+    addr: SocketAddr,
+}
+
+impl Quic {
+    /// Create a new QUIC client creator instance with the given TLS domain name and remote server address.
+    /// The server certificate is verified against `trust`.
+    pub fn new(domain: String, addr: SocketAddr, trust: TlsTrust) -> Result<Self> {
+        let mut endpoint = Endpoint::client(bind_addr(addr.is_ipv4()))?;
+        endpoint.set_default_client_config(if trust.is_default() {
+            CLIENT_CFG.clone()
+        } else {
+            create_client_config(&trust)?
+        });
+        Ok(Self {
+            endpoint,
+            domain,
+            addr,
+        })
+    }
+}
+
+#[async_trait]
+impl ConnInitiator for Quic {
+    type Connection = Connection;
+
+    async fn create(&self) -> std::io::Result<Self::Connection> {
+        let connecting = self
+            .endpoint
+            .connect(self.addr, &self.domain)
+            .map_err(other_err)?;
+        // Try to resume the session with 0-RTT, and fall back to the full handshake if no ticket is available.
+        Ok(match connecting.into_0rtt() {
+            Ok((conn, _)) => conn,
+            Err(connecting) => connecting.await.map_err(other_err)?,
+        })
+    }
+
+    fn conn_type(&self) -> &'static str {
+        "QUIC"
+    }
+}
+
+#[async_trait]
+impl QHandle for Connection {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // Per RFC 9250, the message ID must be set to 0.
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        msg.header_mut().set_id(0);
+        let msg = msg.for_slice();
+
+        // Each query is sent over its own stream.
+        let (mut send, mut recv) = self.open_bi().await.map_err(other_err)?;
+
+        // Prefix our payload with length per RFC.
+        let len = u16::try_from(msg.as_slice().len())
+            .expect("request too long")
+            .to_be_bytes();
+        send.write_all(&len).await.map_err(other_err)?;
+        send.write_all(msg.as_slice()).await.map_err(other_err)?;
+        // Indicate that no more data will be sent on this stream.
+        send.finish().await.map_err(other_err)?;
+
+        // Get the length of the response
+        let mut len = [0; 2];
+        recv.read_exact(&mut len).await.map_err(other_err)?;
+        let len: usize = u16::from_be_bytes(len).into();
+
+        // Read the response
+        let mut buf = BytesMut::with_capacity(len);
+        buf.resize(len, 0);
+        recv.read_exact(&mut buf).await.map_err(other_err)?;
+
+        let answer = Message::from_octets(buf.freeze())?;
+        if answer.is_answer(&msg) {
+            Ok(answer)
+        } else {
+            Err(QHandleError::UnexpectedResponse)
+        }
+    }
+
+    async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
+        match self.close_reason() {
+            None => Ok(()),
+            Some(_) => Err(RecycleError::StaticMessage("QUIC connection closed")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnInitiator, QHandle, QHandleError, Quic, TlsTrust, DOQ_ALPN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+    use quinn::{Endpoint, RecvStream, SendStream, ServerConfig};
+    use std::{net::SocketAddr, str::FromStr, sync::Arc};
+
+    // Starting from droute's crate root. The certificate of `localhost` is issued by the test CA.
+    const CA: &str = "../data/tls-test-ca.pem";
+    const CERT: &[u8] = include_bytes!("../../../../../../data/tls-test.pem");
+    const KEY: &[u8] = include_bytes!("../../../../../../data/tls-test.key");
+
+    fn query(name: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.header_mut().set_id(1234);
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    // Answer the query of the stream, or the query for `other.example.com` instead if `wrong` is set.
+    async fn answer(mut send: SendStream, mut recv: RecvStream, wrong: bool) {
+        let mut len = [0; 2];
+        recv.read_exact(&mut len).await.unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len).into()];
+        recv.read_exact(&mut buf).await.unwrap();
+        let msg = Message::from_octets(Bytes::from(buf)).unwrap();
+        // Per RFC 9250, the message ID must be 0.
+        assert_eq!(msg.header().id(), 0);
+
+        let msg = if wrong {
+            query("other.example.com")
+        } else {
+            msg
+        };
+        let resp = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&msg, Rcode::NoError)
+            .unwrap()
+            .finish();
+        send.write_all(&(resp.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        send.write_all(&resp).await.unwrap();
+        send.finish().await.unwrap();
+    }
+
+    // A DNS over QUIC server on a local port, answering every query.
+    fn serve(wrong: bool) -> (Endpoint, SocketAddr) {
+        let certs = rustls_pemfile::certs(&mut &CERT[..])
+            .unwrap()
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        let key = rustls::PrivateKey(
+            rustls_pemfile::pkcs8_private_keys(&mut &KEY[..])
+                .unwrap()
+                .remove(0),
+        );
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        crypto.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        let endpoint = Endpoint::server(
+            ServerConfig::with_crypto(Arc::new(crypto)),
+            ([127, 0, 0, 1], 0).into(),
+        )
+        .unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let server = endpoint.clone();
+        tokio::spawn(async move {
+            while let Some(connecting) = server.accept().await {
+                tokio::spawn(async move {
+                    let conn = match connecting.await {
+                        Ok(conn) => conn,
+                        Err(_) => return,
+                    };
+                    while let Ok((send, recv)) = conn.accept_bi().await {
+                        tokio::spawn(answer(send, recv, wrong));
+                    }
+                });
+            }
+        });
+        (endpoint, addr)
+    }
+
+    fn trust() -> TlsTrust {
+        TlsTrust {
+            ca_file: Some(CA.into()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn query_streams() {
+        let (_server, addr) = serve(false);
+        let quic = Quic::new("localhost".to_string(), addr, trust()).unwrap();
+        let conn = quic.create().await.unwrap();
+
+        // Queries are sent over streams of the same connection.
+        for name in ["a.example.com", "b.example.com"] {
+            let resp = conn.query(&query(name)).await.unwrap();
+            assert_eq!(resp.header().id(), 0);
+            assert!(resp.header().qr());
+            assert_eq!(resp.first_question().unwrap().qname().to_string(), name);
+        }
+        assert!(conn.reusable().await.is_ok());
+
+        conn.close(0u32.into(), b"");
+        assert!(conn.reusable().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unexpected_response() {
+        let (_server, addr) = serve(true);
+        let quic = Quic::new("localhost".to_string(), addr, trust()).unwrap();
+        let conn = quic.create().await.unwrap();
+        assert!(matches!(
+            conn.query(&query("example.com")).await,
+            Err(QHandleError::UnexpectedResponse)
+        ));
+    }
+
+    #[tokio::test]
+    async fn untrusted() {
+        let (_server, addr) = serve(false);
+        // The certificate is not issued for this name.
+        let quic = Quic::new("example.com".to_string(), addr, trust()).unwrap();
+        assert!(quic.create().await.is_err());
+
+        // Nor by the built-in roots
+        let quic = Quic::new("localhost".to_string(), addr, TlsTrust::default()).unwrap();
+        assert!(quic.create().await.is_err());
+    }
+}
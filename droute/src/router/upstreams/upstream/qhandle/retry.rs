@@ -0,0 +1,219 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{QHandle, QHandleError, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::{iana::Rcode, Message};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+/// Failures on which the query is retried
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryOn {
+    /// The upstream didn't respond in time
+    Timeout,
+    /// The upstream responded with SERVFAIL
+    ServFail,
+    /// Any other error, like connection failures
+    Error,
+}
+
+// Retry the query on the inner handle with exponential backoff.
+pub struct Retry {
+    inner: Arc<dyn QHandle>,
+    retries: usize,
+    backoff: Duration,
+    retry_on: Vec<RetryOn>,
+}
+
+impl Retry {
+    pub fn new(
+        inner: Arc<dyn QHandle>,
+        retries: usize,
+        backoff: Duration,
+        retry_on: Vec<RetryOn>,
+    ) -> Self {
+        Self {
+            inner,
+            retries,
+            backoff,
+            retry_on,
+        }
+    }
+
+    fn should_retry(&self, r: &Result<Message<Bytes>>) -> bool {
+        let kind = match r {
+            Ok(resp) if resp.header().rcode() == Rcode::ServFail => RetryOn::ServFail,
+            Ok(_) => return false,
+            Err(QHandleError::TimeError(_)) => RetryOn::Timeout,
+            // Retrying doesn't help if we are throttled.
+            Err(QHandleError::Throttled) => return false,
+            Err(_) => RetryOn::Error,
+        };
+        self.retry_on.contains(&kind)
+    }
+}
+
+#[async_trait]
+impl QHandle for Retry {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut backoff = self.backoff;
+        let mut r = self.inner.query(msg).await;
+        for attempt in 1..=self.retries {
+            if !self.should_retry(&r) {
+                break;
+            }
+            log::debug!("retrying the query ({}/{})", attempt, self.retries);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            r = self.inner.query(msg).await;
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QHandle, QHandleError, Result, Retry, RetryOn};
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Message, MessageBuilder};
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    #[derive(Clone, Copy)]
+    enum Outcome {
+        NoError,
+        ServFail,
+        Timeout,
+        Error,
+        Throttled,
+    }
+
+    // An upstream failing as scripted, and answering once the script runs out.
+    struct Scripted {
+        outcomes: Mutex<Vec<Outcome>>,
+        queries: Mutex<usize>,
+    }
+
+    impl Scripted {
+        fn new(outcomes: &[Outcome]) -> Arc<Self> {
+            Arc::new(Self {
+                outcomes: Mutex::new(outcomes.iter().rev().copied().collect()),
+                queries: Mutex::new(0),
+            })
+        }
+
+        fn queries(&self) -> usize {
+            *self.queries.lock().unwrap()
+        }
+    }
+
+    fn response(rcode: Rcode) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_rcode(rcode);
+        builder.into_message()
+    }
+
+    #[async_trait]
+    impl QHandle for Scripted {
+        async fn query(&self, _: &Message<Bytes>) -> Result<Message<Bytes>> {
+            *self.queries.lock().unwrap() += 1;
+            let outcome = self.outcomes.lock().unwrap().pop();
+            match outcome.unwrap_or(Outcome::NoError) {
+                Outcome::NoError => Ok(response(Rcode::NoError)),
+                Outcome::ServFail => Ok(response(Rcode::ServFail)),
+                Outcome::Timeout => Err(tokio::time::timeout(
+                    Duration::ZERO,
+                    std::future::pending::<()>(),
+                )
+                .await
+                .unwrap_err()
+                .into()),
+                Outcome::Error => {
+                    Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into())
+                }
+                Outcome::Throttled => Err(QHandleError::Throttled),
+            }
+        }
+    }
+
+    async fn retry(
+        outcomes: &[Outcome],
+        retries: usize,
+        retry_on: Vec<RetryOn>,
+    ) -> (Result<Message<Bytes>>, usize) {
+        let inner = Scripted::new(outcomes);
+        let retry = Retry::new(inner.clone(), retries, Duration::from_millis(1), retry_on);
+        (
+            retry.query(&response(Rcode::NoError)).await,
+            inner.queries(),
+        )
+    }
+
+    #[tokio::test]
+    async fn retry_until_answered() {
+        use Outcome::*;
+        let all = vec![RetryOn::Timeout, RetryOn::ServFail, RetryOn::Error];
+        let (r, queries) = retry(&[Timeout, ServFail, Error], 3, all.clone()).await;
+        assert_eq!(r.unwrap().header().rcode(), Rcode::NoError);
+        assert_eq!(queries, 4);
+
+        // The last failure is returned once the retries run out.
+        let (r, queries) = retry(&[Timeout, ServFail, Error], 2, all).await;
+        assert!(matches!(r, Err(QHandleError::IoError(_))));
+        assert_eq!(queries, 3);
+    }
+
+    #[tokio::test]
+    async fn retry_on() {
+        use Outcome::*;
+        let (r, queries) = retry(&[ServFail], 3, vec![RetryOn::Timeout]).await;
+        assert_eq!(r.unwrap().header().rcode(), Rcode::ServFail);
+        assert_eq!(queries, 1);
+
+        let (r, queries) = retry(&[Error], 3, vec![RetryOn::Timeout]).await;
+        assert!(r.is_err());
+        assert_eq!(queries, 1);
+
+        let (_, queries) = retry(&[Timeout, Error], 3, vec![RetryOn::Timeout]).await;
+        assert_eq!(queries, 2);
+
+        // Never retried when throttled
+        let (r, queries) = retry(&[Throttled], 3, vec![RetryOn::Error]).await;
+        assert!(matches!(r, Err(QHandleError::Throttled)));
+        assert_eq!(queries, 1);
+    }
+
+    #[tokio::test]
+    async fn backoff() {
+        let inner = Scripted::new(&[Outcome::ServFail; 3]);
+        let retry = Retry::new(
+            inner.clone(),
+            3,
+            Duration::from_millis(20),
+            vec![RetryOn::ServFail],
+        );
+        let start = Instant::now();
+        retry.query(&response(Rcode::NoError)).await.unwrap();
+        // Waiting 20, 40 and 80 milliseconds before the retries
+        assert!(start.elapsed() >= Duration::from_millis(140));
+        assert_eq!(inner.queries(), 4);
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{qos::QosPolicy, QHandle, QHandleError, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+// Send queries to the addresses of the same server in turns, failing over to the next address on errors.
+pub struct Rotate {
+    handles: Vec<Arc<dyn QHandle>>,
+    next: AtomicUsize,
+    // The ratelimit is shared by all the addresses.
+    ratelimiter: QosPolicy,
+}
+
+impl Rotate {
+    pub fn new(handles: Vec<Arc<dyn QHandle>>, ratelimiter: QosPolicy) -> Result<Self> {
+        if handles.is_empty() {
+            return Err(QHandleError::NoAddress);
+        }
+        Ok(Self {
+            handles,
+            next: AtomicUsize::new(0),
+            ratelimiter,
+        })
+    }
+}
+
+#[async_trait]
+impl QHandle for Rotate {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        if !self.ratelimiter.acquire().await {
+            return Err(QHandleError::Throttled);
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut error = None;
+        for i in 0..self.handles.len() {
+            match self.handles[(start + i) % self.handles.len()]
+                .query(msg)
+                .await
+            {
+                Ok(answer) => return Ok(answer),
+                Err(e) => {
+                    log::debug!("query failed: {}, failing over to the next address", e);
+                    error = Some(e);
+                }
+            }
+        }
+        // There is at least one handle, so at least one query has failed.
+        Err(error.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QHandle, QHandleError, QosPolicy, Result, Rotate};
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Message, MessageBuilder};
+    use std::sync::{Arc, Mutex};
+
+    // An address recording the queries sent to it, which fails them if it is down.
+    struct Addr {
+        id: u16,
+        up: bool,
+        log: Arc<Mutex<Vec<u16>>>,
+    }
+
+    #[async_trait]
+    impl QHandle for Addr {
+        async fn query(&self, _: &Message<Bytes>) -> Result<Message<Bytes>> {
+            self.log.lock().unwrap().push(self.id);
+            if self.up {
+                let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+                builder.header_mut().set_id(self.id);
+                Ok(builder.into_message())
+            } else {
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into())
+            }
+        }
+    }
+
+    fn addrs(up: &[bool]) -> (Rotate, Arc<Mutex<Vec<u16>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handles = up
+            .iter()
+            .enumerate()
+            .map(|(id, &up)| {
+                Arc::new(Addr {
+                    id: id as u16,
+                    up,
+                    log: log.clone(),
+                }) as Arc<dyn QHandle>
+            })
+            .collect();
+        (
+            Rotate::new(handles, QosPolicy::new(None, None)).unwrap(),
+            log,
+        )
+    }
+
+    fn query() -> Message<Bytes> {
+        MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .into_message()
+    }
+
+    #[tokio::test]
+    async fn in_turns() {
+        let (rotate, log) = addrs(&[true, true, true]);
+        for id in [0, 1, 2, 0] {
+            assert_eq!(rotate.query(&query()).await.unwrap().header().id(), id);
+        }
+        assert_eq!(*log.lock().unwrap(), [0, 1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let (rotate, log) = addrs(&[true, false, true]);
+        for id in [0, 2, 2, 0] {
+            assert_eq!(rotate.query(&query()).await.unwrap().header().id(), id);
+        }
+        assert_eq!(*log.lock().unwrap(), [0, 1, 2, 2, 0]);
+
+        // Every address is tried once before giving up.
+        let (rotate, log) = addrs(&[false, false]);
+        assert!(matches!(
+            rotate.query(&query()).await,
+            Err(QHandleError::IoError(_))
+        ));
+        assert_eq!(*log.lock().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn no_address() {
+        assert!(matches!(
+            Rotate::new(Vec::new(), QosPolicy::new(None, None)),
+            Err(QHandleError::NoAddress)
+        ));
+    }
+}
@@ -0,0 +1,615 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    keepalive::{add_keepalive, take_keepalive},
+    qos::QosPolicy,
+    QHandle, QHandleError, Result,
+};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::oneshot,
+    time::timeout,
+};
+
+// Read a length-prefixed message from the stream.
+async fn read_prefixed(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<BytesMut> {
+    // Get the length of the response
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let len: usize = u16::from_be_bytes(len).into();
+
+    // Read the response
+    let mut buf = BytesMut::with_capacity(len);
+    buf.resize(len, 0);
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Send the query over a fresh TCP connection to the given address and wait for its response.
+pub async fn query_once(addr: SocketAddr, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    let mut stream = TcpStream::connect(addr).await?;
+
+    // Prefix our payload with length per RFC.
+    let len = u16::try_from(msg.as_slice().len())
+        .expect("request too long")
+        .to_be_bytes();
+    stream.write_all(&len).await?;
+    stream.write_all(msg.as_slice()).await?;
+    stream.flush().await?;
+
+    loop {
+        let buf = read_prefixed(&mut stream).await?;
+
+        // We ignore garbage since there is a timer on this whole thing.
+        let answer = match Message::from_octets(buf.freeze()) {
+            Ok(answer) => answer,
+            Err(_) => continue,
+        };
+        if !answer.is_answer(msg) {
+            continue;
+        }
+        return Ok(answer);
+    }
+}
+
+// Queries in flight on a pipelined connection, keyed by their message IDs
+type Pending = Mutex<HashMap<u16, oneshot::Sender<Message<Bytes>>>>;
+
+// A query waiting for its response on a pipelined connection. It is removed from the pending ones when dropped, e.g. on timeout.
+struct Waiting<'a> {
+    pending: &'a Pending,
+    id: u16,
+    receiver: oneshot::Receiver<Message<Bytes>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.receiver.close();
+        let mut pending = self.pending.lock().unwrap();
+        // Once answered, the ID may have been taken by another query, whose receiver is still open.
+        if pending
+            .get(&self.id)
+            .map_or(false, |sender| sender.is_closed())
+        {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+// A persistent TCP connection carrying multiple outstanding queries at a time
+struct Pipeline {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Arc<Pending>,
+    closed: Arc<AtomicBool>,
+    // Time the connection was last used, and the idle timeout, which is lowered to the one advertised by the server.
+    idle: Mutex<(Instant, Duration)>,
+    // Whether to ask the server for its idle timeout, which requires an OPT record
+    keepalive: bool,
+}
+
+impl Pipeline {
+    async fn connect(
+        addr: SocketAddr,
+        idle_timeout: Duration,
+        keepalive: bool,
+    ) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (mut reader, writer) = stream.into_split();
+        let pending: Arc<Pending> = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        // Dispatch responses to the queries they answer. Once the connection breaks, all the pending senders are dropped so that the queries waiting on them fail immediately.
+        {
+            let (pending, closed) = (pending.clone(), closed.clone());
+            tokio::spawn(async move {
+                loop {
+                    let buf = match read_prefixed(&mut reader).await {
+                        Ok(buf) => buf,
+                        Err(e) => {
+                            log::debug!("pipelined TCP connection to {} closed: {}", addr, e);
+                            break;
+                        }
+                    };
+                    // We ignore garbage and responses to queries already gone.
+                    if let Ok(answer) = Message::from_octets(buf.freeze()) {
+                        let sender = pending.lock().unwrap().remove(&answer.header().id());
+                        if let Some(sender) = sender {
+                            let _ = sender.send(answer);
+                        }
+                    }
+                }
+                closed.store(true, Ordering::Release);
+                pending.lock().unwrap().clear();
+            });
+        }
+
+        Ok(Self {
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            closed,
+            idle: Mutex::new((Instant::now(), idle_timeout)),
+            keepalive,
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // Whether the connection is broken or likely to be closed by the server soon.
+    fn is_expired(&self) -> bool {
+        let (last_used, timeout) = *self.idle.lock().unwrap();
+        self.is_closed() || last_used.elapsed() >= timeout
+    }
+
+    async fn query(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // Pick an ID not used by other outstanding queries on this connection
+        let mut msg = if self.keepalive {
+            Message::from_octets(BytesMut::from(add_keepalive(query).as_slice()))?
+        } else {
+            Message::from_octets(BytesMut::from(query.as_slice()))?
+        };
+        let mut waiting = {
+            let mut pending = self.pending.lock().unwrap();
+            if self.is_closed() {
+                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into());
+            }
+            let mut id = rand::random();
+            while pending.contains_key(&id) {
+                id = rand::random();
+            }
+            msg.header_mut().set_id(id);
+            let (sender, receiver) = oneshot::channel();
+            pending.insert(id, sender);
+            Waiting {
+                pending: &self.pending,
+                id,
+                receiver,
+            }
+        };
+        let msg = msg.for_slice();
+
+        // Prefix our payload with length per RFC.
+        let len = u16::try_from(msg.as_slice().len())
+            .expect("request too long")
+            .to_be_bytes();
+        {
+            let mut writer = self.writer.lock().await;
+            let written = async {
+                writer.write_all(&len).await?;
+                writer.write_all(msg.as_slice()).await?;
+                writer.flush().await
+            }
+            .await;
+            if let Err(e) = written {
+                self.closed.store(true, Ordering::Release);
+                return Err(e.into());
+            }
+        }
+
+        let answer = (&mut waiting.receiver)
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::ConnectionReset))?;
+        if !answer.is_answer(&msg) {
+            return Err(QHandleError::UnexpectedResponse);
+        }
+
+        let (answer, server_timeout) = take_keepalive(answer, query);
+        {
+            let mut idle = self.idle.lock().unwrap();
+            idle.0 = Instant::now();
+            if let Some(t) = server_timeout {
+                idle.1 = std::cmp::min(idle.1, t);
+            }
+        }
+        Ok(answer)
+    }
+}
+
+/// Client for plain DNS over TCP, which pipelines queries on a persistent connection
+pub struct Tcp {
+    addr: SocketAddr,
+    timeout: Duration,
+    idle_timeout: Duration,
+    keepalive: bool,
+    ratelimiter: QosPolicy,
+    conn: tokio::sync::Mutex<Option<Arc<Pipeline>>>,
+}
+
+impl Tcp {
+    /// Create a new TCP client with the given remote server address. The connection is established lazily on the first query.
+    /// Connections idle for `idle_timeout`, or the shorter one advertised by the server via EDNS TCP keepalive, are replaced by new ones.
+    /// If `keepalive` is false, the EDNS TCP keepalive option is not sent, which keeps queries without OPT records untouched.
+    pub fn new(
+        addr: SocketAddr,
+        timeout: Duration,
+        idle_timeout: Duration,
+        keepalive: bool,
+        ratelimiter: QosPolicy,
+    ) -> Self {
+        Self {
+            addr,
+            timeout,
+            idle_timeout,
+            keepalive,
+            ratelimiter,
+            conn: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    // Get the current connection, reconnecting if it is broken or has been idle for too long.
+    async fn conn(&self) -> std::io::Result<Arc<Pipeline>> {
+        let mut conn = self.conn.lock().await;
+        match &*conn {
+            Some(c) if !c.is_expired() => Ok(c.clone()),
+            _ => {
+                let c = Arc::new(
+                    Pipeline::connect(self.addr, self.idle_timeout, self.keepalive).await?,
+                );
+                *conn = Some(c.clone());
+                Ok(c)
+            }
+        }
+    }
+
+    async fn query_inner(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let conn = self.conn().await?;
+        match conn.query(msg).await {
+            // The server may close idle connections at any time. Retry once on a fresh connection if the query didn't make it.
+            Err(QHandleError::IoError(e)) if conn.is_closed() => {
+                log::debug!("pipelined TCP connection broken ({}), reconnecting", e);
+                self.conn().await?.query(msg).await
+            }
+            r => r,
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Tcp {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        if self.ratelimiter.acquire().await {
+            timeout(self.timeout, self.query_inner(msg)).await?
+        } else {
+            Err(QHandleError::Throttled)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_prefixed, Pending, Pipeline, QHandle, QosPolicy, Tcp, Waiting};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{
+        iana::Rcode,
+        opt::{Opt, TcpKeepalive},
+        Dname, Message, MessageBuilder, Rtype, ToDname,
+    };
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+        time::{sleep, timeout},
+    };
+
+    fn query(name: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    fn qname(msg: &Message<Bytes>) -> String {
+        msg.first_question()
+            .unwrap()
+            .qname()
+            .to_dname::<Vec<u8>>()
+            .unwrap()
+            .to_string()
+    }
+
+    async fn read_query(stream: &mut TcpStream) -> Option<Message<Bytes>> {
+        let buf = read_prefixed(stream).await.ok()?;
+        Some(Message::from_octets(buf.freeze()).unwrap())
+    }
+
+    // Answer the query, advertising the idle timeout in units of 100 milliseconds if any.
+    async fn answer(stream: &mut TcpStream, query: &Message<Bytes>, keepalive: Option<u16>) {
+        let builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(query, Rcode::NoError)
+            .unwrap();
+        let msg = match keepalive {
+            Some(t) => {
+                let mut builder = builder.additional();
+                builder.opt(|opt| TcpKeepalive::push(opt, t)).unwrap();
+                builder.into_message()
+            }
+            None => builder.into_message(),
+        };
+        stream
+            .write_all(&(msg.as_slice().len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(msg.as_slice()).await.unwrap();
+    }
+
+    // A server answering every query on every connection, counting the connections accepted.
+    async fn serve(keepalive: Option<u16>) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    while let Some(query) = read_query(&mut stream).await {
+                        answer(&mut stream, &query, keepalive).await;
+                    }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    fn tcp(addr: SocketAddr, idle_timeout: Duration, keepalive: bool) -> Tcp {
+        Tcp::new(
+            addr,
+            Duration::from_secs(2),
+            idle_timeout,
+            keepalive,
+            QosPolicy::new(None, None),
+        )
+    }
+
+    #[tokio::test]
+    async fn unused_id() {
+        let (addr, _) = serve(None).await;
+        let pipeline = Pipeline::connect(addr, Duration::from_secs(60), false)
+            .await
+            .unwrap();
+        // All the IDs but one are taken by queries in flight.
+        let receivers: Vec<_> = {
+            let mut pending = pipeline.pending.lock().unwrap();
+            (0..=u16::MAX)
+                .filter(|&id| id != 42)
+                .map(|id| {
+                    let (sender, receiver) = oneshot::channel();
+                    pending.insert(id, sender);
+                    receiver
+                })
+                .collect()
+        };
+        let resp = pipeline.query(&query("example.com")).await.unwrap();
+        assert_eq!(resp.header().id(), 42);
+        assert_eq!(pipeline.pending.lock().unwrap().len(), receivers.len());
+    }
+
+    #[test]
+    fn waiting_drop() {
+        let pending: Pending = Mutex::new(HashMap::new());
+
+        let (sender, receiver) = oneshot::channel();
+        pending.lock().unwrap().insert(1, sender);
+        drop(Waiting {
+            pending: &pending,
+            id: 1,
+            receiver,
+        });
+        assert!(pending.lock().unwrap().is_empty());
+
+        // Answered, and the ID has been taken by another query since.
+        let (_, receiver) = oneshot::channel();
+        let (sender, _other) = oneshot::channel();
+        pending.lock().unwrap().insert(1, sender);
+        drop(Waiting {
+            pending: &pending,
+            id: 1,
+            receiver,
+        });
+        assert!(pending.lock().unwrap().contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn out_of_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let first = read_query(&mut stream).await.unwrap();
+            let second = read_query(&mut stream).await.unwrap();
+            assert_ne!(first.header().id(), second.header().id());
+            answer(&mut stream, &second, None).await;
+            answer(&mut stream, &first, None).await;
+        });
+
+        let tcp = tcp(addr, Duration::from_secs(60), false);
+        let (a, b) = (query("a.example.com"), query("b.example.com"));
+        let (a, b) = tokio::join!(tcp.query(&a), tcp.query(&b));
+        assert_eq!(qname(&a.unwrap()), "a.example.com");
+        assert_eq!(qname(&b.unwrap()), "b.example.com");
+    }
+
+    #[tokio::test]
+    async fn eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_query(&mut stream).await.unwrap();
+            read_query(&mut stream).await.unwrap();
+        });
+
+        let pipeline = Pipeline::connect(addr, Duration::from_secs(60), false)
+            .await
+            .unwrap();
+        // Both queries fail as soon as the connection is closed, rather than on timeout.
+        let (a, b) = (query("a.example.com"), query("b.example.com"));
+        let (a, b) = timeout(Duration::from_secs(2), async {
+            tokio::join!(pipeline.query(&a), pipeline.query(&b))
+        })
+        .await
+        .unwrap();
+        assert!(a.is_err() && b.is_err());
+        assert!(pipeline.is_closed());
+        assert!(pipeline.pending.lock().unwrap().is_empty());
+        assert!(pipeline.query(&query("example.com")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            // Connections are closed once a query arrives, except that the second one answers its first query.
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let query = read_query(&mut stream).await.unwrap();
+                    if n == 1 {
+                        answer(&mut stream, &query, None).await;
+                        read_query(&mut stream).await;
+                    }
+                });
+            }
+        });
+
+        let tcp = tcp(addr, Duration::from_secs(60), false);
+        let resp = tcp.query(&query("example.com")).await.unwrap();
+        assert_eq!(qname(&resp), "example.com");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // Only retried once
+        assert!(tcp.query(&query("example.com")).await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn idle_expiry() {
+        let (addr, accepted) = serve(None).await;
+        let tcp = tcp(addr, Duration::from_millis(100), false);
+        tcp.query(&query("example.com")).await.unwrap();
+        tcp.query(&query("example.com")).await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        sleep(Duration::from_millis(150)).await;
+        tcp.query(&query("example.com")).await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn advertised_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    while let Some(query) = read_query(&mut stream).await {
+                        // The option is sent without the timeout: code 11, length 0.
+                        assert_eq!(
+                            *query.opt().unwrap().as_opt(),
+                            Opt::from_octets([0u8, 11, 0, 0]).unwrap()
+                        );
+                        answer(&mut stream, &query, Some(1)).await;
+                    }
+                });
+            }
+        });
+
+        let tcp = tcp(addr, Duration::from_secs(60), true);
+        let resp = tcp.query(&query("example.com")).await.unwrap();
+        // The OPT record added for the option is removed, as the query had none.
+        assert!(resp.opt().is_none());
+        let idle = tcp
+            .conn
+            .lock()
+            .await
+            .as_ref()
+            .unwrap()
+            .idle
+            .lock()
+            .unwrap()
+            .1;
+        assert_eq!(idle, Duration::from_millis(100));
+
+        sleep(Duration::from_millis(150)).await;
+        tcp.query(&query("example.com")).await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn closing_mid_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let stale = read_query(&mut stream).await.unwrap();
+            let query = read_query(&mut stream).await.unwrap();
+            // Answered after the client gave up on it
+            answer(&mut stream, &stale, None).await;
+            answer(&mut stream, &query, None).await;
+        });
+
+        let tcp = Tcp::new(
+            addr,
+            Duration::from_millis(100),
+            Duration::from_secs(60),
+            false,
+            QosPolicy::new(None, None),
+        );
+        assert!(tcp.query(&query("stale.example.com")).await.is_err());
+        let conn = tcp.conn.lock().await.clone().unwrap();
+        assert!(conn.pending.lock().unwrap().is_empty());
+
+        // The connection is still used, and the late answer to the query given up is ignored.
+        let resp = tcp.query(&query("example.com")).await.unwrap();
+        assert_eq!(qname(&resp), "example.com");
+        assert!(Arc::ptr_eq(&conn, tcp.conn.lock().await.as_ref().unwrap()));
+    }
+}
@@ -18,7 +18,13 @@
 #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
 mod connector;
 
-use super::{ConnInitiator, QHandle, Result};
+use super::{
+    client_cert::ClientCert,
+    happy_eyeballs::HappyEyeballs,
+    keepalive::{add_keepalive, take_keepalive},
+    trust::TlsTrust,
+    ConnInitiator, QHandle, Result,
+};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 pub use connector::Tls;
@@ -26,30 +32,70 @@ use connector::TlsStream;
 use deadpool::managed::{self, RecycleError};
 use domain::base::Message;
 use log::debug;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     sync::Mutex,
 };
 
-// Instant: Time the connection established
-// usize: Number of query sent
+struct TlsConnState {
+    stream: TlsStream<TcpStream>,
+    // Time the connection established
+    established: Instant,
+    // Number of query sent
+    queries: usize,
+    // Time the last query was sent
+    last_used: Instant,
+    // Idle timeout, which is lowered to the one advertised by the server via EDNS TCP keepalive
+    idle_timeout: Duration,
+}
+
+/// A persistent TLS connection to the upstream
+pub struct TlsConn {
+    state: Mutex<TlsConnState>,
+    reuse_timeout: u64,
+    max_reuse: usize,
+}
+
+impl TlsConn {
+    fn new(
+        stream: TlsStream<TcpStream>,
+        reuse_timeout: u64,
+        max_reuse: usize,
+        idle_timeout: u64,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(TlsConnState {
+                stream,
+                established: now,
+                queries: 0,
+                last_used: now,
+                idle_timeout: Duration::from_millis(idle_timeout),
+            }),
+            reuse_timeout,
+            max_reuse,
+        }
+    }
+}
+
 #[async_trait]
-impl QHandle for (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize) {
-    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
-        let mut guard = self.0.lock().await;
+impl QHandle for TlsConn {
+    async fn query(&self, query: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut guard = self.state.lock().await;
 
         {
             // Sadly because of borrow checker issue we cannot increase our counter after we have sent all of our query.
             // We have sent our query once more
-            guard.2 += 1;
+            guard.queries += 1;
+            guard.last_used = Instant::now();
         }
 
-        let stream = &mut guard.0;
+        let stream = &mut guard.stream;
 
-        // Randomnize the message
-        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        // Randomnize the message, and ask the server for its idle timeout
+        let mut msg = Message::from_octets(BytesMut::from(add_keepalive(query).as_slice()))?;
         msg.header_mut().set_random_id();
         let msg = msg.for_slice();
 
@@ -89,6 +135,12 @@ impl QHandle for (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize) {
                 continue;
             }
 
+            let (answer, server_timeout) = take_keepalive(answer, query);
+            if let Some(t) = server_timeout {
+                debug!("TlsStream idle timeout advertised by the server: {:?}", t);
+                guard.idle_timeout = std::cmp::min(guard.idle_timeout, t);
+            }
+
             return Ok(answer);
         }
     }
@@ -97,17 +149,23 @@ impl QHandle for (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize) {
         // No matter when our last valid query was on, TCP connections all expire a certain amount of time after they were established.
         // This is because the server may have got a timeout timer set on our outgoing connections.
         // Moreover, most of the server has limit on the maximum number of query possible. We check it as well here
-        let mut guard = self.0.lock().await;
-        if guard.2 >= self.2 {
-            guard.0.shutdown().await?;
+        let mut guard = self.state.lock().await;
+        if guard.queries >= self.max_reuse {
+            guard.stream.shutdown().await?;
             log::debug!("TlsStream has reached maximum number of queries that can be sent on the underlying persistent TCP connection.");
             return Err(RecycleError::StaticMessage("max reuse TCP queries reached"));
         }
-        if guard.1.elapsed().as_millis() >= self.1.into() {
-            guard.0.shutdown().await?;
+        if guard.established.elapsed().as_millis() >= self.reuse_timeout.into() {
+            guard.stream.shutdown().await?;
             log::debug!("TlsStream has reached period dcompass will keep the underlying TCP persistent connections open.");
             return Err(RecycleError::StaticMessage("TCP reuse timeout reached"));
         }
+        // Servers close connections idle for long, on which our query would be lost.
+        if guard.last_used.elapsed() >= guard.idle_timeout {
+            guard.stream.shutdown().await?;
+            log::debug!("TlsStream has been idle for longer than the idle timeout.");
+            return Err(RecycleError::StaticMessage("TCP idle timeout reached"));
+        }
         Ok(())
     }
 }
@@ -13,12 +13,12 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{ConnInitiator, Result};
+use super::{ClientCert, ConnInitiator, HappyEyeballs, Result, TlsConn, TlsTrust};
 use async_trait::async_trait;
 use native_tls::{Protocol, TlsConnector as NativeTlsConnector};
 use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, time::Instant};
-use tokio::{net::TcpStream, sync::Mutex};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpStream;
 use tokio_native_tls::TlsConnector;
 pub use tokio_native_tls::TlsStream;
 
@@ -26,41 +26,58 @@ pub use tokio_native_tls::TlsStream;
 #[derive(Clone)]
 pub struct Tls {
     client: TlsConnector,
-    addr: SocketAddr,
+    connector: Arc<HappyEyeballs>,
     domain: String,
     tcp_reuse_timeout: u64,
     max_reuse_tcp_queries: usize,
+    idle_timeout: u64,
 }
 
 impl Tls {
-    /// Create a new TLS connection creator instance. with the given remote server address.
+    /// Create a new TLS connection creator instance. with the given remote server addresses, which are raced on connecting.
+    /// Connections idle for `idle_timeout` milliseconds, or the shorter one advertised by the server via EDNS TCP keepalive, are not reused.
+    /// Native TLS doesn't support TLS 1.3 early data, therefore `early_data` is ignored.
+    /// If `client_cert` is set, it is presented to the server for mutual TLS. The server certificate is verified against `trust`.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         domain: String,
-        addr: SocketAddr,
+        addrs: Vec<SocketAddr>,
         sni: bool,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
+        idle_timeout: u64,
+        early_data: bool,
+        client_cert: Option<ClientCert>,
+        trust: TlsTrust,
     ) -> Result<Self> {
+        if early_data {
+            log::warn!("TLS early data is not supported with native TLS, ignoring");
+        }
+        let mut builder = NativeTlsConnector::builder();
+        builder
+            .use_sni(sni)
+            .min_protocol_version(Some(Protocol::Tlsv12));
+        if let Some(client_cert) = client_cert {
+            builder.identity(client_cert.to_native_tls()?);
+        }
+        trust.apply_native_tls(&mut builder)?;
         Ok(Self {
-            client: NativeTlsConnector::builder()
-                .use_sni(sni)
-                .min_protocol_version(Some(Protocol::Tlsv12))
-                .build()?
-                .into(),
-            addr,
+            client: builder.build()?.into(),
+            connector: Arc::new(HappyEyeballs::new(addrs)?),
             domain,
             tcp_reuse_timeout,
             max_reuse_tcp_queries,
+            idle_timeout,
         })
     }
 }
 
 #[async_trait]
 impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+    type Connection = TlsConn;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
-        let mut stream = TcpStream::connect(self.addr).await?;
+        let mut stream = self.connector.connect().await?;
 
         // Good default as reqwest also sets this
         let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
@@ -68,17 +85,14 @@ impl ConnInitiator for Tls {
         socket.set_tcp_keepalive(&keepalive)?;
         stream = TcpStream::from_std(socket.into())?;
 
-        Ok((
-            Mutex::new((
-                self.client
-                    .connect(&self.domain, stream)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
-                Instant::now(),
-                0,
-            )),
+        Ok(TlsConn::new(
+            self.client
+                .connect(&self.domain, stream)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
             self.tcp_reuse_timeout,
             self.max_reuse_tcp_queries,
+            self.idle_timeout,
         ))
     }
 
@@ -13,70 +13,100 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{ConnInitiator, Result};
+use super::{
+    super::QHandleError, ClientCert, ConnInitiator, HappyEyeballs, Result, TlsConn, TlsTrust,
+};
 use async_trait::async_trait;
-use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use rustls::{client::Resumption, ClientConfig};
 use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, sync::Arc, time::Instant};
-use tokio::{net::TcpStream, sync::Mutex};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpStream;
 pub use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
 
-fn create_client_config(sni: &bool) -> ClientConfig {
-    let mut root_store = RootCertStore::empty();
-    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
+// Number of TLS sessions cached for resumption, which are shared by all the connections to the same upstream.
+const SESSION_CACHE_SIZE: usize = 32;
 
-    let mut client_config = ClientConfig::builder()
+fn create_client_config(
+    sni: &bool,
+    early_data: bool,
+    client_cert: Option<&ClientCert>,
+    trust: &TlsTrust,
+) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(trust.to_rustls()?);
+    let mut client_config = match client_cert {
+        Some(client_cert) => {
+            let (certs, key) = client_cert.to_rustls()?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| QHandleError::InvalidClientCert(e.to_string()))?
+        }
+        None => builder.with_no_client_auth(),
+    };
 
     client_config.enable_sni = *sni; // Disable SNI on need.
 
-    client_config
+    // Resume sessions with tickets so that reconnecting costs one round trip instead of a full handshake.
+    client_config.resumption = Resumption::in_memory_sessions(SESSION_CACHE_SIZE);
+    // Send the query along with the ClientHello on resumed sessions. DNS queries are idempotent, so replays are harmless.
+    client_config.enable_early_data = early_data;
+
+    Ok(client_config)
 }
 
 /// Client instance for TLS connections
 #[derive(Clone)]
 pub struct Tls {
     client: TlsConnector,
-    addr: SocketAddr,
+    connector: Arc<HappyEyeballs>,
     domain: String,
     tcp_reuse_timeout: u64,
     max_reuse_tcp_queries: usize,
+    idle_timeout: u64,
 }
 
 impl Tls {
-    /// Create a new TLS connection creator instance. with the given remote server address.
+    /// Create a new TLS connection creator instance. with the given remote server addresses, which are raced on connecting.
+    /// Connections idle for `idle_timeout` milliseconds, or the shorter one advertised by the server via EDNS TCP keepalive, are not reused.
+    /// TLS sessions are resumed on reconnecting. If `early_data` is true, queries are sent as TLS 1.3 early data (0-RTT) on resumed sessions.
+    /// If `client_cert` is set, it is presented to the server for mutual TLS. The server certificate is verified against `trust`.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         domain: String,
-        addr: SocketAddr,
+        addrs: Vec<SocketAddr>,
         sni: bool,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
+        idle_timeout: u64,
+        early_data: bool,
+        client_cert: Option<ClientCert>,
+        trust: TlsTrust,
     ) -> Result<Self> {
         Ok(Self {
-            client: TlsConnector::from(Arc::new(create_client_config(&sni))),
-            addr,
+            client: TlsConnector::from(Arc::new(create_client_config(
+                &sni,
+                early_data,
+                client_cert.as_ref(),
+                &trust,
+            )?))
+            .early_data(early_data),
+            connector: Arc::new(HappyEyeballs::new(addrs)?),
             domain,
             tcp_reuse_timeout,
             max_reuse_tcp_queries,
+            idle_timeout,
         })
     }
 }
 
 #[async_trait]
 impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+    type Connection = TlsConn;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
-        let mut stream = TcpStream::connect(self.addr).await?;
+        let mut stream = self.connector.connect().await?;
 
         // Good default as reqwest also sets this.
         let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
@@ -87,17 +117,14 @@ impl ConnInitiator for Tls {
         let domain = rustls::ServerName::try_from(self.domain.as_str()).map_err(|_| {
             std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid dnsname")
         })?;
-        Ok((
-            Mutex::new((
-                self.client
-                    .connect(domain, stream)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
-                Instant::now(),
-                0,
-            )),
+        Ok(TlsConn::new(
+            self.client
+                .connect(domain, stream)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
             self.tcp_reuse_timeout,
             self.max_reuse_tcp_queries,
+            self.idle_timeout,
         ))
     }
 
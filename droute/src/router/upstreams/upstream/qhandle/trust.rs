@@ -0,0 +1,251 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Custom CA bundles and SPKI pinning for encrypted upstreams
+
+use super::{QHandleError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Certificates trusted when verifying the server of an encrypted upstream
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub struct TlsTrust {
+    /// Path to a PEM file of CA certificates, which replaces the built-in roots, e.g. for self-hosted resolvers with a private CA
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Base64 encoded SHA-256 digests of the SubjectPublicKeyInfo, optionally prefixed with `sha256/`.
+    /// If set, one of the certificates in the chain presented by the server must match one of the pins.
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+}
+
+impl TlsTrust {
+    /// Whether the built-in roots are trusted without pinning
+    pub fn is_default(&self) -> bool {
+        self.ca_file.is_none() && self.spki_pins.is_empty()
+    }
+
+    #[cfg(any(feature = "doh-rustls", feature = "dot-rustls", feature = "doq"))]
+    fn root_store(&self) -> Result<rustls::RootCertStore> {
+        use rustls::{Certificate, OwnedTrustAnchor, RootCertStore};
+
+        let mut root_store = RootCertStore::empty();
+        match &self.ca_file {
+            Some(path) => {
+                let certs = rustls_pemfile::certs(&mut read_ca(path)?.as_slice())
+                    .map_err(|e| QHandleError::InvalidTrust(e.to_string()))?;
+                if certs.is_empty() {
+                    return Err(QHandleError::InvalidTrust(format!(
+                        "no certificate found in {}",
+                        path.display()
+                    )));
+                }
+                for cert in certs {
+                    root_store
+                        .add(&Certificate(cert))
+                        .map_err(|e| QHandleError::InvalidTrust(e.to_string()))?;
+                }
+            }
+            None => {
+                root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }));
+            }
+        }
+        Ok(root_store)
+    }
+
+    // The verifier checking the chain against the roots, and then the pins if any.
+    #[cfg(any(feature = "doh-rustls", feature = "dot-rustls", feature = "doq"))]
+    pub(super) fn to_rustls(
+        &self,
+    ) -> Result<std::sync::Arc<dyn rustls::client::ServerCertVerifier>> {
+        use base64::Engine;
+
+        let pins = self
+            .spki_pins
+            .iter()
+            .map(|pin| {
+                let digest = base64::engine::general_purpose::STANDARD
+                    .decode(pin.strip_prefix("sha256/").unwrap_or(pin))
+                    .map_err(|e| {
+                        QHandleError::InvalidTrust(format!("invalid pin `{}`: {}", pin, e))
+                    })?;
+                if digest.len() != 32 {
+                    return Err(QHandleError::InvalidTrust(format!(
+                        "invalid pin `{}`: not a SHA-256 digest",
+                        pin
+                    )));
+                }
+                Ok(digest)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(std::sync::Arc::new(rustls_verifier::PinnedVerifier::new(
+            self.root_store()?,
+            pins,
+        )))
+    }
+
+    #[cfg(any(feature = "doh-native-tls", feature = "dot-native-tls"))]
+    pub(super) fn apply_native_tls(
+        &self,
+        builder: &mut native_tls::TlsConnectorBuilder,
+    ) -> Result<()> {
+        if !self.spki_pins.is_empty() {
+            return Err(QHandleError::InvalidTrust(
+                "SPKI pinning is not supported with native TLS".to_string(),
+            ));
+        }
+        if let Some(path) = &self.ca_file {
+            const END: &str = "-----END CERTIFICATE-----";
+            let pem = String::from_utf8(read_ca(path)?)
+                .map_err(|e| QHandleError::InvalidTrust(e.to_string()))?;
+            let mut found = false;
+            for block in pem.split_inclusive(END).filter(|b| b.contains(END)) {
+                let cert = native_tls::Certificate::from_pem(block.trim().as_bytes())
+                    .map_err(|e| QHandleError::InvalidTrust(e.to_string()))?;
+                builder.add_root_certificate(cert);
+                found = true;
+            }
+            if !found {
+                return Err(QHandleError::InvalidTrust(format!(
+                    "no certificate found in {}",
+                    path.display()
+                )));
+            }
+            builder.disable_built_in_roots(true);
+        }
+        Ok(())
+    }
+}
+
+fn read_ca(path: &PathBuf) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| {
+        QHandleError::InvalidTrust(format!("failed to read {}: {}", path.display(), e))
+    })
+}
+
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls", feature = "doq"))]
+mod rustls_verifier {
+    use ring::digest::{digest, SHA256};
+    use rustls::{
+        client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+        Certificate, CertificateError, Error, RootCertStore, ServerName,
+    };
+    use std::time::SystemTime;
+
+    // Verify the chain as usual, and then require one of the certificates to match the pins.
+    pub struct PinnedVerifier {
+        inner: WebPkiVerifier,
+        pins: Vec<Vec<u8>>,
+    }
+
+    impl PinnedVerifier {
+        pub fn new(roots: RootCertStore, pins: Vec<Vec<u8>>) -> Self {
+            Self {
+                inner: WebPkiVerifier::new(roots, None),
+                pins,
+            }
+        }
+
+        fn pinned(&self, cert: &Certificate) -> bool {
+            match spki(&cert.0) {
+                Some(spki) => {
+                    let digest = digest(&SHA256, spki);
+                    self.pins
+                        .iter()
+                        .any(|pin| pin.as_slice() == digest.as_ref())
+                }
+                None => false,
+            }
+        }
+    }
+
+    impl ServerCertVerifier for PinnedVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &Certificate,
+            intermediates: &[Certificate],
+            server_name: &ServerName,
+            scts: &mut dyn Iterator<Item = &[u8]>,
+            ocsp_response: &[u8],
+            now: SystemTime,
+        ) -> Result<ServerCertVerified, Error> {
+            let verified = self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+            if self.pins.is_empty()
+                || std::iter::once(end_entity)
+                    .chain(intermediates)
+                    .any(|cert| self.pinned(cert))
+            {
+                Ok(verified)
+            } else {
+                // No certificate in the chain matches the SPKI pins.
+                Err(Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+        }
+    }
+
+    // Split the first DER element into its tag, the whole element, its content and the rest.
+    #[allow(clippy::type_complexity)]
+    fn der_next(der: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+        let tag = *der.first()?;
+        let first = *der.get(1)?;
+        let (len, header) = if first & 0x80 == 0 {
+            (first as usize, 2)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 {
+                return None;
+            }
+            let len = der
+                .get(2..2 + n)?
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            (len, 2 + n)
+        };
+        let whole = der.get(..header.checked_add(len)?)?;
+        Some((tag, whole, &whole[header..], &der[whole.len()..]))
+    }
+
+    // The DER encoded SubjectPublicKeyInfo of an X.509 certificate.
+    fn spki(cert: &[u8]) -> Option<&[u8]> {
+        // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signatureValue }
+        let (_, _, cert, _) = der_next(cert)?;
+        let (_, _, mut tbs, _) = der_next(cert)?;
+        // Skip the optional explicitly tagged version.
+        if tbs.first() == Some(&0xa0) {
+            tbs = der_next(tbs)?.3;
+        }
+        // Skip serialNumber, signature, issuer, validity and subject.
+        for _ in 0..5 {
+            tbs = der_next(tbs)?.3;
+        }
+        Some(der_next(tbs)?.1)
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// TSIG (RFC 8945) signed queries and verified responses
+
+use super::{QHandle, Result};
+use crate::{router::script::utils::rebuild::copy_records, MAX_LEN};
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        name::PushError,
+        octets::{OctetsVec, ParseError},
+        Dname, Message, MessageBuilder, ShortBuf,
+    },
+    tsig::{Algorithm, ClientTransaction, Key, ValidationError},
+};
+use std::{str::FromStr, sync::Arc};
+use thiserror::Error;
+
+/// Errors related to TSIG
+#[derive(Debug, Error)]
+pub enum TsigError {
+    /// The key configured is invalid
+    #[error("invalid TSIG key: {0}")]
+    InvalidKey(String),
+
+    /// The response is not signed or signed with a wrong key
+    #[error("failed to verify the TSIG signed response: {0}")]
+    Validation(#[from] ValidationError),
+
+    /// Failed to parse the query to sign
+    #[error(transparent)]
+    ParseError(#[from] ParseError),
+
+    /// Failed to build the signed query
+    #[error(transparent)]
+    PushError(#[from] PushError),
+
+    /// The buffer is too short
+    #[error(transparent)]
+    ShortBuf(#[from] ShortBuf),
+}
+
+// Sign queries sent to the inner handle and verify the responses.
+pub struct Tsig {
+    inner: Arc<dyn QHandle>,
+    key: Arc<Key>,
+}
+
+impl Tsig {
+    /// Create a TSIG signer with the key name, the algorithm (e.g. `hmac-sha256`) and the base64 encoded secret.
+    pub fn new(
+        inner: Arc<dyn QHandle>,
+        name: &str,
+        algorithm: &str,
+        secret: &str,
+    ) -> std::result::Result<Self, TsigError> {
+        let name =
+            Dname::<OctetsVec>::from_str(name).map_err(|e| TsigError::InvalidKey(e.to_string()))?;
+        let algorithm = Algorithm::from_str(algorithm)
+            .map_err(|_| TsigError::InvalidKey(format!("unknown algorithm `{}`", algorithm)))?;
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(secret)
+            .map_err(|e| TsigError::InvalidKey(e.to_string()))?;
+        let key = Key::new(algorithm, &secret, name, None, None)
+            .map_err(|e| TsigError::InvalidKey(e.to_string()))?;
+        Ok(Self {
+            inner,
+            key: Arc::new(key),
+        })
+    }
+
+    fn sign(
+        &self,
+        msg: &Message<Bytes>,
+    ) -> std::result::Result<(Message<Bytes>, ClientTransaction<Arc<Key>>), TsigError> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+        *builder.header_mut() = msg.header();
+
+        let mut builder = builder.question();
+        for item in msg.question() {
+            builder.push(item?)?;
+        }
+
+        let mut builder = builder.answer();
+        copy_records!(msg.answer()?, builder);
+
+        let mut builder = builder.authority();
+        copy_records!(msg.authority()?, builder);
+
+        // The TSIG record must be the last one in the additional section.
+        let mut builder = builder.additional();
+        copy_records!(msg.additional()?, builder);
+        let transaction = ClientTransaction::request(self.key.clone(), &mut builder)?;
+
+        Ok((builder.into_message(), transaction))
+    }
+}
+
+#[async_trait]
+impl QHandle for Tsig {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let (query, transaction) = self.sign(msg)?;
+        let resp = self.inner.query(&query).await?;
+
+        // Verify the response, which also removes the TSIG record from it.
+        let mut resp = Message::from_octets(resp.as_slice().to_vec())?;
+        transaction
+            .answer(&mut resp)
+            .map_err(TsigError::Validation)?;
+        Ok(Message::from_octets(Bytes::from(resp.into_octets()))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QHandle, Result, Tsig, TsigError};
+    use crate::router::upstreams::upstream::qhandle::QHandleError;
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, octets::OctetsVec, Dname, Message, MessageBuilder, Rtype},
+        tsig::{Algorithm, Key, ServerTransaction},
+    };
+    use std::{str::FromStr, sync::Arc};
+
+    const NAME: &str = "tsig.example.";
+    const SECRET: &str = "ZGNvbXBhc3MgdHNpZyB0ZXN0IHNlY3JldCwgMzIgQiE=";
+
+    #[derive(Clone, Copy)]
+    enum Mode {
+        Sign,
+        // Sign the response, then flip a bit of its MAC
+        Tamper,
+        Unsigned,
+    }
+
+    // A server verifying the signed queries with the same key, and answering them as told.
+    struct Server {
+        key: Arc<Key>,
+        mode: Mode,
+    }
+
+    impl Server {
+        fn new(mode: Mode) -> Arc<Self> {
+            let secret =
+                base64::Engine::decode(&base64::engine::general_purpose::STANDARD, SECRET).unwrap();
+            let key = Key::new(
+                Algorithm::Sha256,
+                &secret,
+                Dname::<OctetsVec>::from_str(NAME).unwrap(),
+                None,
+                None,
+            )
+            .unwrap();
+            Arc::new(Self {
+                key: Arc::new(key),
+                mode,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl QHandle for Server {
+        async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+            let mut query = Message::from_octets(msg.as_slice().to_vec()).unwrap();
+            let transaction = ServerTransaction::request(&self.key, &mut query)
+                .unwrap()
+                .expect("query not signed");
+            let mut builder = MessageBuilder::from_target(Vec::new())
+                .unwrap()
+                .start_answer(&query, Rcode::NoError)
+                .unwrap()
+                .additional();
+            if !matches!(self.mode, Mode::Unsigned) {
+                transaction.answer(&mut builder).unwrap();
+            }
+            let mut resp = builder.finish();
+            if matches!(self.mode, Mode::Tamper) {
+                // The MAC is followed by the original ID, the error and the other length, two bytes each.
+                let len = resp.len();
+                resp[len - 7] ^= 1;
+            }
+            Ok(Message::from_octets(Bytes::from(resp)).unwrap())
+        }
+    }
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let tsig = Tsig::new(Server::new(Mode::Sign), NAME, "hmac-sha256", SECRET).unwrap();
+        let resp = tsig.query(&query()).await.unwrap();
+        // The TSIG record is removed once verified.
+        assert_eq!(resp.header_counts().arcount(), 0);
+        assert!(resp.is_answer(&query()));
+    }
+
+    #[tokio::test]
+    async fn bad_mac() {
+        let tsig = Tsig::new(Server::new(Mode::Tamper), NAME, "hmac-sha256", SECRET).unwrap();
+        assert!(matches!(
+            tsig.query(&query()).await,
+            Err(QHandleError::TsigError(TsigError::Validation(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unsigned() {
+        let tsig = Tsig::new(Server::new(Mode::Unsigned), NAME, "hmac-sha256", SECRET).unwrap();
+        assert!(matches!(
+            tsig.query(&query()).await,
+            Err(QHandleError::TsigError(TsigError::Validation(_)))
+        ));
+    }
+
+    #[test]
+    fn invalid_key() {
+        let server = Server::new(Mode::Sign);
+        assert!(matches!(
+            Tsig::new(server.clone(), NAME, "hmac-md4", SECRET),
+            Err(TsigError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            Tsig::new(server, NAME, "hmac-sha256", "not base64!"),
+            Err(TsigError::InvalidKey(_))
+        ));
+    }
+}
@@ -15,34 +15,87 @@
 
 use crate::MAX_LEN;
 
-use super::{ConnInitiator, QHandle, Result};
+use super::{tcp, ConnInitiator, QHandle, Result};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::base::Message;
-use std::net::SocketAddr;
+use rand::Rng;
+use std::{net::SocketAddr, ops::Range};
 use tokio::net::UdpSocket;
 
+// The position of the question name in the wire format, which directly follows the 12-byte header.
+fn qname_range(msg: &[u8]) -> Option<Range<usize>> {
+    let mut pos = 12;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        // Compression pointers are not expected in the first question name.
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        pos += len + 1;
+        if len == 0 {
+            return Some(12..pos);
+        }
+    }
+}
+
+// Randomize the letter case of the question name (DNS 0x20 encoding) in place.
+fn randomize_case(msg: &mut [u8], range: Range<usize>) {
+    let mut rng = rand::thread_rng();
+    // Length bytes are never ASCII letters as labels are at most 63 bytes long.
+    for b in &mut msg[range] {
+        if b.is_ascii_alphabetic() && rng.gen::<bool>() {
+            *b ^= 0x20;
+        }
+    }
+}
+
 /// Client instance for UDP connections
 #[derive(Clone)]
 pub struct Udp {
     addr: SocketAddr,
+    case_randomization: bool,
+    tcp_fallback: bool,
 }
 
 impl Udp {
     /// Create a new UDP client creator instance. with the given remote server address.
-    pub async fn new(addr: SocketAddr) -> Result<Self> {
-        Ok(Self { addr })
+    /// If `case_randomization` is true, the letter case of question names are randomized and verified in responses to resist spoofing.
+    /// If `tcp_fallback` is true, queries getting truncated responses are sent again over TCP.
+    pub async fn new(
+        addr: SocketAddr,
+        case_randomization: bool,
+        tcp_fallback: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            addr,
+            case_randomization,
+            tcp_fallback,
+        })
     }
 }
 
+/// A UDP connection to the upstream
+pub struct UdpConn {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    case_randomization: bool,
+    tcp_fallback: bool,
+}
+
 #[async_trait]
 impl ConnInitiator for Udp {
-    type Connection = UdpSocket;
+    type Connection = UdpConn;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
         let socket = UdpSocket::bind(bind_addr(self.addr.is_ipv4())).await?;
         socket.connect(self.addr).await?;
-        Ok(socket)
+        Ok(UdpConn {
+            socket,
+            addr: self.addr,
+            case_randomization: self.case_randomization,
+            tcp_fallback: self.tcp_fallback,
+        })
     }
 
     fn conn_type(&self) -> &'static str {
@@ -59,36 +112,59 @@ fn bind_addr(is_ipv4: bool) -> SocketAddr {
 }
 
 #[async_trait]
-impl QHandle for UdpSocket {
+impl QHandle for UdpConn {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
         // Randomnize the message
-        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
-        msg.header_mut().set_random_id();
-        let msg = msg.for_slice();
+        let mut query = BytesMut::from(msg.as_slice());
+        let qname = if self.case_randomization {
+            qname_range(&query)
+        } else {
+            None
+        };
+        if let Some(range) = qname.clone() {
+            randomize_case(&mut query, range);
+        }
+        let mut query = Message::from_octets(query)?;
+        query.header_mut().set_random_id();
+        let query = query.for_slice();
 
-        self.send(msg.as_slice()).await?;
+        self.socket.send(query.as_slice()).await?;
 
         loop {
             let mut buf = BytesMut::with_capacity(MAX_LEN);
             buf.resize(MAX_LEN, 0);
-            let len = self.recv(&mut buf).await?;
+            let len = self.socket.recv(&mut buf).await?;
             buf.resize(len, 0);
 
+            if let Some(range) = qname.clone() {
+                // Responses not echoing the question name in the exact case are likely spoofed.
+                if buf.get(range.clone()) != query.as_slice().get(range.clone()) {
+                    continue;
+                }
+                // Restore the original case, which also applies to names compressed against the question name.
+                buf[range.clone()].copy_from_slice(&msg.as_slice()[range]);
+            }
+
             // We ignore garbage since there is a timer on this whole thing.
             let answer = match Message::from_octets(buf.freeze()) {
                 Ok(answer) => answer,
                 Err(_) => continue,
             };
-            if !answer.is_answer(&msg) {
+            if !answer.is_answer(&query) {
                 continue;
             }
+            if self.tcp_fallback && answer.header().tc() {
+                log::debug!("UDP response truncated, retrying over TCP");
+                return tcp::query_once(self.addr, msg).await;
+            }
             return Ok(answer);
         }
     }
 
     async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
         // We don't care about the response of our test query because we would ignore unrelated response that up in receive loop.
-        self.send(super::DUMMY_QUERY.as_slice())
+        self.socket
+            .send(super::DUMMY_QUERY.as_slice())
             .await
             .map(|_| ())
             .map_err(deadpool::managed::RecycleError::Backend)
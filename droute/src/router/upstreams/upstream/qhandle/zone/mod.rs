@@ -0,0 +1,398 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+mod parse;
+
+pub(crate) use parse::parse;
+
+use super::{QHandle, Result};
+use crate::MAX_LEN;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::base::{
+    iana::{Class, Rcode, Rtype},
+    name::PushError,
+    octets::ParseError,
+    rdata::UnknownRecordData,
+    Dname, Message, MessageBuilder, ShortBuf, ToDname,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+};
+use thiserror::Error;
+
+// Maximum number of CNAME records followed in the zone
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Errors related to zone files
+#[derive(Debug, Error)]
+pub enum ZoneError {
+    /// Failed to read the zone file
+    #[error("failed to read the zone file: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The zone file is malformed
+    #[error("syntax error in the zone file on line {0}: {1}")]
+    Syntax(usize, String),
+
+    /// The origin is not a valid domain name
+    #[error("the origin '{0}' is not a valid domain name")]
+    InvalidOrigin(String),
+
+    /// The record is not in the zone
+    #[error("the record of '{0}' is out of the zone")]
+    OutOfZone(String),
+
+    /// The zone has none or multiple SOA records at the apex
+    #[error("the zone must have exactly one SOA record at its apex")]
+    InvalidSoa,
+
+    /// The query has no question
+    #[error("the query has no question")]
+    InvalidQuery,
+
+    /// Failed to parse names in the message
+    #[error(transparent)]
+    ParseError(#[from] ParseError),
+
+    /// Failed to build names
+    #[error(transparent)]
+    PushError(#[from] PushError),
+
+    /// The buffer is too short
+    #[error(transparent)]
+    ShortBuf(#[from] ShortBuf),
+}
+
+type ZoneResult<T> = std::result::Result<T, ZoneError>;
+
+// A resource record in the zone, whose data is in wire format.
+pub(crate) struct Record {
+    pub(crate) owner: Dname<Bytes>,
+    pub(crate) rtype: Rtype,
+    pub(crate) ttl: u32,
+    pub(crate) data: Bytes,
+}
+
+impl Record {
+    // The target of CNAME and NS records, or the exchange of MX records.
+    pub(crate) fn target(&self) -> Option<Dname<Bytes>> {
+        let name = match self.rtype {
+            Rtype::Cname | Rtype::Ns => self.data.clone(),
+            Rtype::Mx => self.data.slice(2..),
+            _ => return None,
+        };
+        Dname::from_octets(name).ok()
+    }
+}
+
+/// Authoritative server of a zone loaded from a zone file in the RFC 1035 format
+pub struct Zone {
+    apex: Dname<Bytes>,
+    records: HashMap<Dname<Bytes>, Vec<Record>>,
+    // All the names in the zone, including empty non-terminals
+    names: HashSet<Dname<Bytes>>,
+    // Owners of NS records below the apex, where subzones are delegated
+    cuts: HashSet<Dname<Bytes>>,
+    // TTL of SOA records in negative answers, which is the minimum of the SOA TTL and its MINIMUM field per RFC 2308
+    negative_ttl: u32,
+}
+
+impl Zone {
+    /// Load the zone from the text of a zone file. Relative names are relative to the `origin` unless `$ORIGIN` is given.
+    pub fn new(origin: &str, text: &str) -> ZoneResult<Self> {
+        let apex = Dname::<Bytes>::from_str(origin.trim_end_matches('.'))
+            .map_err(|_| ZoneError::InvalidOrigin(origin.to_string()))?;
+
+        let mut zone = Self {
+            apex: apex.clone(),
+            records: HashMap::new(),
+            names: HashSet::new(),
+            cuts: HashSet::new(),
+            negative_ttl: 0,
+        };
+        for record in parse::parse(text, apex.clone())? {
+            if !record.owner.ends_with(&apex) {
+                return Err(ZoneError::OutOfZone(record.owner.to_string()));
+            }
+            // The owner and all of its ancestors in the zone exist.
+            for name in record.owner.iter_suffixes() {
+                if !zone.names.insert(name.clone()) || name == apex {
+                    break;
+                }
+            }
+            if record.rtype == Rtype::Ns && record.owner != apex {
+                zone.cuts.insert(record.owner.clone());
+            }
+            zone.records
+                .entry(record.owner.clone())
+                .or_default()
+                .push(record);
+        }
+
+        let soa = match zone.find(&apex, Rtype::Soa).as_slice() {
+            [soa] => (soa.ttl, soa.data.clone()),
+            _ => return Err(ZoneError::InvalidSoa),
+        };
+        let minimum = soa
+            .1
+            .get(soa.1.len().saturating_sub(4)..)
+            .and_then(|m| m.try_into().ok())
+            .map(u32::from_be_bytes)
+            .ok_or(ZoneError::InvalidSoa)?;
+        zone.negative_ttl = std::cmp::min(soa.0, minimum);
+        Ok(zone)
+    }
+
+    /// Load the zone from the zone file at the path.
+    pub async fn load(origin: &str, path: impl AsRef<Path>) -> ZoneResult<Self> {
+        Self::new(origin, &tokio::fs::read_to_string(path).await?)
+    }
+
+    // Records of the type at the name
+    fn find(&self, name: &Dname<Bytes>, rtype: Rtype) -> Vec<&Record> {
+        self.records
+            .get(name)
+            .map(|rs| rs.iter().filter(|r| r.rtype == rtype).collect())
+            .unwrap_or_default()
+    }
+
+    // The zone cut at or above the name, if the name is delegated.
+    fn cut(&self, name: &Dname<Bytes>) -> Option<Dname<Bytes>> {
+        name.iter_suffixes()
+            .take_while(|n| *n != self.apex)
+            .find(|n| self.cuts.contains(n))
+    }
+
+    // Records at the name, either owned by it or synthesized from the wildcard of its closest encloser. None if the name doesn't exist.
+    fn node(&self, name: &Dname<Bytes>) -> Option<&[Record]> {
+        if self.names.contains(name) {
+            return Some(
+                self.records
+                    .get(name)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+            );
+        }
+        let encloser = name
+            .iter_suffixes()
+            .skip(1)
+            .find(|n| self.names.contains(n))
+            .unwrap_or_else(|| self.apex.clone());
+        let wildcard = Dname::<Bytes>::from_str(&format!("*.{}", encloser)).ok()?;
+        self.records.get(&wildcard).map(Vec::as_slice)
+    }
+
+    fn answer(&self, query: &Message<Bytes>) -> ZoneResult<Message<Bytes>> {
+        let question = query.first_question().ok_or(ZoneError::InvalidQuery)?;
+        let (qtype, mut name) = (question.qtype(), question.qname().to_dname::<Bytes>()?);
+
+        let builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+        if question.qclass() != Class::In || !name.ends_with(&self.apex) {
+            return Ok(builder.start_answer(query, Rcode::Refused)?.into_message());
+        }
+
+        // Follow the CNAME records in the zone
+        let mut answers: Vec<(Dname<Bytes>, &Record)> = Vec::new();
+        let mut rcode = Rcode::NoError;
+        let mut negative = false;
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(cut) = self.cut(&name) {
+                if answers.is_empty() {
+                    return self.referral(query, &cut);
+                }
+                break;
+            }
+            let records = match self.node(&name) {
+                Some(records) => records,
+                None => {
+                    rcode = Rcode::NXDomain;
+                    negative = true;
+                    break;
+                }
+            };
+            let matched: Vec<_> = records
+                .iter()
+                .filter(|r| r.rtype == qtype || qtype == Rtype::Any)
+                .collect();
+            if !matched.is_empty() {
+                answers.extend(matched.into_iter().map(|r| (name.clone(), r)));
+                break;
+            }
+            match records.iter().find(|r| r.rtype == Rtype::Cname) {
+                Some(cname) => {
+                    answers.push((name.clone(), cname));
+                    match cname.target() {
+                        Some(target) if target.ends_with(&self.apex) => name = target,
+                        // Names out of the zone are left to the client to resolve.
+                        _ => break,
+                    }
+                }
+                None => {
+                    negative = true;
+                    break;
+                }
+            }
+        }
+
+        let mut builder = builder.start_answer(query, rcode)?;
+        builder.header_mut().set_aa(true);
+        for (owner, r) in answers {
+            builder.push(record(&owner, r.rtype, r.ttl, &r.data))?;
+        }
+        let mut builder = builder.authority();
+        if negative {
+            for soa in self.find(&self.apex, Rtype::Soa) {
+                builder.push(record(&self.apex, Rtype::Soa, self.negative_ttl, &soa.data))?;
+            }
+        }
+        Ok(builder.into_message())
+    }
+
+    // Refer the client to the name servers of the subzone, along with their addresses in the zone.
+    fn referral(&self, query: &Message<Bytes>, cut: &Dname<Bytes>) -> ZoneResult<Message<Bytes>> {
+        let ns = self.find(cut, Rtype::Ns);
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(query, Rcode::NoError)?
+            .authority();
+        for r in &ns {
+            builder.push(record(cut, r.rtype, r.ttl, &r.data))?;
+        }
+        let mut builder = builder.additional();
+        for target in ns.iter().filter_map(|r| r.target()) {
+            for rtype in [Rtype::A, Rtype::Aaaa] {
+                for glue in self.find(&target, rtype) {
+                    builder.push(record(&target, rtype, glue.ttl, &glue.data))?;
+                }
+            }
+        }
+        Ok(builder.into_message())
+    }
+}
+
+fn record<'a>(
+    owner: &'a Dname<Bytes>,
+    rtype: Rtype,
+    ttl: u32,
+    data: &Bytes,
+) -> (&'a Dname<Bytes>, Class, u32, UnknownRecordData<Bytes>) {
+    (
+        owner,
+        Class::In,
+        ttl,
+        UnknownRecordData::from_octets(rtype, data.clone()),
+    )
+}
+
+#[async_trait]
+impl QHandle for Zone {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        Ok(self.answer(msg)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Zone;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{
+        iana::{Rcode, Rtype},
+        Dname, Message, MessageBuilder,
+    };
+    use std::str::FromStr;
+
+    const ZONE: &str = r#"
+$TTL 3600
+@       SOA ns admin 1 7200 3600 1209600 300
+        NS  ns
+ns      A   192.168.1.1
+nas     A   192.168.1.10
+www     CNAME   nas
+*.dev   A   192.168.1.20
+a.b     TXT "empty non-terminal above"
+lab     NS  ns.lab
+ns.lab  A   10.0.0.1
+"#;
+
+    fn query(name: &str, rtype: Rtype) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), rtype))
+            .unwrap();
+        builder.into_message()
+    }
+
+    fn answer(name: &str, rtype: Rtype) -> Message<Bytes> {
+        Zone::new("home.arpa", ZONE)
+            .unwrap()
+            .answer(&query(name, rtype))
+            .unwrap()
+    }
+
+    #[test]
+    fn authoritative_answers() {
+        let resp = answer("NAS.home.arpa", Rtype::A);
+        assert!(resp.header().aa());
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 1);
+
+        // The CNAME is followed in the zone.
+        let resp = answer("www.home.arpa", Rtype::A);
+        assert_eq!(resp.header_counts().ancount(), 2);
+
+        // Wildcard
+        let resp = answer("foo.dev.home.arpa", Rtype::A);
+        assert_eq!(resp.header_counts().ancount(), 1);
+    }
+
+    #[test]
+    fn negative_answers() {
+        let resp = answer("missing.home.arpa", Rtype::A);
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert_eq!(resp.header_counts().nscount(), 1);
+
+        // NODATA, including empty non-terminals
+        for name in ["nas.home.arpa", "b.home.arpa"] {
+            let resp = answer(name, Rtype::Aaaa);
+            assert_eq!(resp.header().rcode(), Rcode::NoError);
+            assert_eq!(resp.header_counts().ancount(), 0);
+            assert_eq!(resp.header_counts().nscount(), 1);
+        }
+
+        assert_eq!(
+            answer("example.com", Rtype::A).header().rcode(),
+            Rcode::Refused
+        );
+    }
+
+    #[test]
+    fn delegation() {
+        let resp = answer("printer.lab.home.arpa", Rtype::A);
+        assert!(!resp.header().aa());
+        assert_eq!(resp.header_counts().ancount(), 0);
+        assert_eq!(resp.header_counts().nscount(), 1);
+        assert_eq!(resp.header_counts().arcount(), 1);
+    }
+
+    #[test]
+    fn missing_soa() {
+        assert!(Zone::new("home.arpa", "$TTL 60\nnas A 192.168.1.10").is_err());
+        assert!(Zone::new("home.arpa", "$TTL 60\nnas.example.com. A 192.168.1.10").is_err());
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Parser of zone files in the RFC 1035 master file format
+
+use super::{Record, ZoneError};
+use bytes::{BufMut, Bytes, BytesMut};
+use domain::base::{iana::Rtype, Dname};
+use std::{
+    collections::VecDeque,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+type Result<T> = std::result::Result<T, ZoneError>;
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+// A record or a directive, which may span multiple lines in parentheses
+struct Entry {
+    line: usize,
+    // Whether the entry starts with blanks, in which case the owner is the previous one.
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+fn error(line: usize, msg: impl Into<String>) -> ZoneError {
+    ZoneError::Syntax(line, msg.into())
+}
+
+// Split the text into entries of tokens, with comments and parentheses removed. Escapes are kept as is.
+fn tokenize(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut depth = 0;
+    let mut entry: Option<Entry> = None;
+    let mut line_start = true;
+
+    while let Some(c) = chars.next() {
+        let starts_line = std::mem::replace(&mut line_start, false);
+        match c {
+            '\n' => {
+                line += 1;
+                line_start = true;
+                if depth == 0 {
+                    entries.extend(entry.take().filter(|e| !e.tokens.is_empty()));
+                }
+            }
+            ';' => while chars.next_if(|c| *c != '\n').is_some() {},
+            c if c.is_whitespace() => {
+                if starts_line && depth == 0 {
+                    entry = Some(Entry {
+                        line,
+                        blank_owner: true,
+                        tokens: Vec::new(),
+                    });
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(error(line, "unbalanced parentheses"));
+                }
+                depth -= 1;
+            }
+            c => {
+                let mut text = String::new();
+                let quoted = c == '"';
+                if !quoted {
+                    text.push(c);
+                }
+                loop {
+                    match chars.peek() {
+                        None if quoted => return Err(error(line, "unterminated quoted string")),
+                        None => break,
+                        Some('\\') => {
+                            text.push(chars.next().unwrap());
+                            match chars.next() {
+                                Some('\n') => return Err(error(line, "escaped newline")),
+                                Some(c) => text.push(c),
+                                None => return Err(error(line, "dangling escape")),
+                            }
+                        }
+                        Some('"') if quoted => {
+                            chars.next();
+                            break;
+                        }
+                        Some('\n') if quoted => {
+                            return Err(error(line, "newline in quoted string"))
+                        }
+                        Some(c) if !quoted && (c.is_whitespace() || "();\"".contains(*c)) => break,
+                        Some(_) => text.push(chars.next().unwrap()),
+                    }
+                }
+                entry
+                    .get_or_insert(Entry {
+                        line,
+                        blank_owner: false,
+                        tokens: Vec::new(),
+                    })
+                    .tokens
+                    .push(Token { text, quoted });
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(error(line, "unbalanced parentheses"));
+    }
+    entries.extend(entry.filter(|e| !e.tokens.is_empty()));
+    Ok(entries)
+}
+
+// Parse a TTL in seconds, or with units like `1h30m` as BIND does.
+fn parse_ttl(s: &str) -> Option<u32> {
+    if let Ok(ttl) = s.parse() {
+        return Some(ttl);
+    }
+    let (mut ttl, mut value) = (0u32, None::<u32>);
+    for c in s.chars() {
+        match c.to_ascii_lowercase() {
+            c @ '0'..='9' => {
+                let digit = c.to_digit(10).unwrap();
+                value = Some(value.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            }
+            unit => {
+                let scale = match unit {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86400,
+                    'w' => 604800,
+                    _ => return None,
+                };
+                ttl = ttl.checked_add(value.take()?.checked_mul(scale)?)?;
+            }
+        }
+    }
+    // Trailing digits without a unit are not allowed.
+    if value.is_some() {
+        None
+    } else {
+        Some(ttl)
+    }
+}
+
+// Decode the escapes (`\X` and `\DDD`) of a character string.
+fn unescape(line: usize, s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let digits = [d, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                let value = std::str::from_utf8(&digits)
+                    .ok()
+                    .and_then(|d| d.parse::<u8>().ok())
+                    .ok_or_else(|| error(line, format!("invalid escape in '{}'", s)))?;
+                out.push(value);
+            }
+            Some(c) => out.push(c),
+            None => return Err(error(line, format!("dangling escape in '{}'", s))),
+        }
+    }
+    Ok(out)
+}
+
+// Parser state carried across entries
+struct Parser {
+    origin: Dname<Bytes>,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<Dname<Bytes>>,
+}
+
+impl Parser {
+    fn name(&self, line: usize, s: &str) -> Result<Dname<Bytes>> {
+        let full = if s == "@" {
+            return Ok(self.origin.clone());
+        } else if s == "." {
+            return Ok(Dname::root_bytes());
+        } else if s.ends_with('.') && !s.ends_with("\\.") {
+            // Absolute names
+            s[..s.len() - 1].to_string()
+        } else if self.origin.is_root() {
+            s.to_string()
+        } else {
+            format!("{}.{}", s, self.origin)
+        };
+        Dname::from_str(&full).map_err(|_| error(line, format!("invalid domain name '{}'", s)))
+    }
+
+    fn entry(&mut self, entry: Entry) -> Result<Option<Record>> {
+        let line = entry.line;
+        let mut tokens = entry.tokens.into_iter().peekable();
+
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or_else(|| error(line, "no previous owner name"))?
+        } else {
+            let first = tokens.next().unwrap();
+            if !first.quoted && first.text.starts_with('$') {
+                self.directive(line, &first.text, tokens.map(|t| t.text).collect())?;
+                return Ok(None);
+            }
+            self.name(line, &first.text)?
+        };
+
+        // The TTL and the class may appear in either order.
+        let mut ttl = None;
+        let rtype = loop {
+            let token = tokens
+                .next()
+                .ok_or_else(|| error(line, "missing record type"))?
+                .text;
+            if let Some(t) = parse_ttl(&token).filter(|_| ttl.is_none()) {
+                ttl = Some(t);
+                continue;
+            }
+            match token.to_ascii_uppercase().as_str() {
+                "IN" => continue,
+                "CH" | "HS" | "CS" => {
+                    return Err(error(line, format!("class {} is not supported", token)))
+                }
+                t => {
+                    break Rtype::from_str(t)
+                        .map_err(|_| error(line, format!("unknown record type '{}'", token)))?
+                }
+            }
+        };
+
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or_else(|| error(line, "no TTL is given and no $TTL is set"))?;
+        let data = self.rdata(line, rtype, tokens.collect())?;
+
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        Ok(Some(Record {
+            owner,
+            rtype,
+            ttl,
+            data,
+        }))
+    }
+
+    fn directive(&mut self, line: usize, name: &str, args: Vec<String>) -> Result<()> {
+        let arg = args
+            .first()
+            .ok_or_else(|| error(line, format!("missing argument of {}", name)))?;
+        match name.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => self.origin = self.name(line, arg)?,
+            "$TTL" => {
+                self.default_ttl = Some(
+                    parse_ttl(arg).ok_or_else(|| error(line, format!("invalid TTL '{}'", arg)))?,
+                )
+            }
+            _ => return Err(error(line, format!("directive {} is not supported", name))),
+        }
+        Ok(())
+    }
+
+    // Encode the record data into wire format. Names in it are never compressed.
+    fn rdata(&self, line: usize, rtype: Rtype, args: Vec<Token>) -> Result<Bytes> {
+        let mut args = Args {
+            tokens: args.into_iter().map(|t| t.text).collect(),
+            line,
+            rtype,
+        };
+        let invalid = |what: &str, s: &str| error(line, format!("invalid {} '{}'", what, s));
+        let mut buf = BytesMut::new();
+
+        // RFC 3597 generic form for any type: \# <length> <hex>...
+        if args.tokens.front().map(String::as_str) == Some("\\#") {
+            args.tokens.pop_front();
+            let len = args.next("length")?;
+            let len: usize = len.parse().map_err(|_| invalid("length", &len))?;
+            let hex: String = args.tokens.drain(..).collect();
+            if hex.len() != len * 2 {
+                return Err(error(line, "data length mismatch"));
+            }
+            for i in (0..hex.len()).step_by(2) {
+                let byte = hex
+                    .get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    .ok_or_else(|| invalid("hex data", &hex))?;
+                buf.put_u8(byte);
+            }
+            return Ok(buf.freeze());
+        }
+
+        match rtype {
+            Rtype::A => {
+                let s = args.next("address")?;
+                let addr: Ipv4Addr = s.parse().map_err(|_| invalid("IPv4 address", &s))?;
+                buf.put_slice(&addr.octets());
+            }
+            Rtype::Aaaa => {
+                let s = args.next("address")?;
+                let addr: Ipv6Addr = s.parse().map_err(|_| invalid("IPv6 address", &s))?;
+                buf.put_slice(&addr.octets());
+            }
+            Rtype::Ns | Rtype::Cname | Rtype::Ptr | Rtype::Dname => {
+                buf.put_slice(self.name(line, &args.next("target")?)?.as_slice());
+            }
+            Rtype::Mx => {
+                let s = args.next("preference")?;
+                buf.put_u16(s.parse().map_err(|_| invalid("preference", &s))?);
+                buf.put_slice(self.name(line, &args.next("exchange")?)?.as_slice());
+            }
+            Rtype::Srv => {
+                for what in ["priority", "weight", "port"] {
+                    let s = args.next(what)?;
+                    buf.put_u16(s.parse().map_err(|_| invalid(what, &s))?);
+                }
+                buf.put_slice(self.name(line, &args.next("target")?)?.as_slice());
+            }
+            Rtype::Soa => {
+                buf.put_slice(
+                    self.name(line, &args.next("primary name server")?)?
+                        .as_slice(),
+                );
+                buf.put_slice(self.name(line, &args.next("mailbox")?)?.as_slice());
+                let s = args.next("serial")?;
+                buf.put_u32(s.parse().map_err(|_| invalid("serial", &s))?);
+                for what in ["refresh", "retry", "expire", "minimum"] {
+                    let s = args.next(what)?;
+                    buf.put_u32(parse_ttl(&s).ok_or_else(|| invalid(what, &s))?);
+                }
+            }
+            Rtype::Txt | Rtype::Spf => {
+                buf.put_slice(&char_string(line, &args.next("text")?)?);
+                for s in args.tokens.drain(..) {
+                    buf.put_slice(&char_string(line, &s)?);
+                }
+            }
+            Rtype::Caa => {
+                let s = args.next("flags")?;
+                buf.put_u8(s.parse().map_err(|_| invalid("flags", &s))?);
+                let tag = args.next("tag")?;
+                if tag.is_empty()
+                    || tag.len() > 255
+                    || !tag.bytes().all(|b| b.is_ascii_alphanumeric())
+                {
+                    return Err(invalid("tag", &tag));
+                }
+                buf.put_u8(tag.len() as u8);
+                buf.put_slice(tag.as_bytes());
+                buf.put_slice(&unescape(line, &args.next("value")?)?);
+            }
+            _ => {
+                return Err(error(
+                    line,
+                    format!(
+                        "type {} is only supported in the generic form (\\# <length> <hex>)",
+                        rtype
+                    ),
+                ))
+            }
+        }
+
+        if !args.tokens.is_empty() {
+            return Err(error(line, format!("trailing data in {} record", rtype)));
+        }
+        Ok(buf.freeze())
+    }
+}
+
+// Fields of the record data
+struct Args {
+    tokens: VecDeque<String>,
+    line: usize,
+    rtype: Rtype,
+}
+
+impl Args {
+    fn next(&mut self, what: &str) -> Result<String> {
+        self.tokens.pop_front().ok_or_else(|| {
+            error(
+                self.line,
+                format!("missing {} of {} record", what, self.rtype),
+            )
+        })
+    }
+}
+
+// Encode a length-prefixed character string.
+fn char_string(line: usize, s: &str) -> Result<Vec<u8>> {
+    let data = unescape(line, s)?;
+    if data.len() > 255 {
+        return Err(error(line, "character string longer than 255 bytes"));
+    }
+    let mut out = vec![data.len() as u8];
+    out.extend(data);
+    Ok(out)
+}
+
+// Parse the records in the zone file, where relative names are relative to `origin` unless changed by `$ORIGIN`.
+pub(crate) fn parse(text: &str, origin: Dname<Bytes>) -> Result<Vec<Record>> {
+    let mut parser = Parser {
+        origin,
+        default_ttl: None,
+        last_ttl: None,
+        last_owner: None,
+    };
+    let mut records = Vec::new();
+    for entry in tokenize(text)? {
+        records.extend(parser.entry(entry)?);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_ttl};
+    use domain::base::{iana::Rtype, Dname};
+    use std::str::FromStr;
+
+    const ZONE: &str = r#"
+$TTL 1h
+@   IN  SOA ns.home.arpa. admin.home.arpa. (
+        2023010101 ; serial
+        1d 2h 4w 1h )
+    IN  NS  ns
+ns      A   192.168.1.1
+nas 300 IN  A   192.168.1.10
+        IN  AAAA    fd00::10
+www     CNAME   nas
+txt     TXT "hello \"world\"" "v=1\059"
+$ORIGIN lab.home.arpa.
+printer A 10.0.0.2
+"#;
+
+    #[test]
+    fn parse_zone() {
+        let records = parse(ZONE, Dname::from_str("home.arpa").unwrap()).unwrap();
+        assert_eq!(records.len(), 8);
+
+        let soa = &records[0];
+        assert_eq!(soa.owner, Dname::<Vec<u8>>::from_str("home.arpa").unwrap());
+        assert_eq!(soa.rtype, Rtype::Soa);
+        assert_eq!(soa.ttl, 3600);
+        // The minimum field follows the serial, refresh, retry and expire fields.
+        assert_eq!(soa.data[soa.data.len() - 4..], 3600u32.to_be_bytes());
+
+        // The owner is inherited from the previous record, while the TTL is the default one.
+        assert_eq!(records[3].ttl, 300);
+        let aaaa = &records[4];
+        assert_eq!(
+            aaaa.owner,
+            Dname::<Vec<u8>>::from_str("nas.home.arpa").unwrap()
+        );
+        assert_eq!(aaaa.ttl, 3600);
+        assert_eq!(aaaa.rtype, Rtype::Aaaa);
+        assert_eq!(aaaa.data.len(), 16);
+
+        let txt = &records[6];
+        assert_eq!(txt.data.as_ref(), b"\x0dhello \"world\"\x04v=1;");
+
+        let printer = &records[7];
+        assert_eq!(
+            printer.owner,
+            Dname::<Vec<u8>>::from_str("printer.lab.home.arpa").unwrap()
+        );
+        assert_eq!(printer.ttl, 3600);
+        assert_eq!(printer.data.as_ref(), [10, 0, 0, 2]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let origin = || Dname::from_str("home.arpa").unwrap();
+        // No TTL
+        assert!(parse("nas A 192.168.1.10", origin()).is_err());
+        // Unbalanced parentheses
+        assert!(parse("$TTL 60\n@ SOA ns admin ( 1 2 3 4 5", origin()).is_err());
+        // Invalid address
+        assert!(parse("$TTL 60\nnas A 192.168.1", origin()).is_err());
+        // Unsupported directive
+        assert!(parse("$INCLUDE other.zone", origin()).is_err());
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(parse_ttl("3600"), Some(3600));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W"), Some(604800));
+        assert_eq!(parse_ttl("1h30"), None);
+        assert_eq!(parse_ttl("A"), None);
+    }
+}
@@ -13,14 +13,19 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{Dname, Message, MessageBuilder, Rtype},
+    base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
     rdata::A,
 };
-use droute::{builders::*, errors::*, mock::Server, AsyncTryInto, QueryContext, Upstreams};
+use droute::{
+    builders::*, errors::*, mock::Server, AsyncTryInto, ErrorPolicy, QueryContext, Upstreams,
+};
 use once_cell::sync::Lazy;
 use tokio::net::UdpSocket;
 
@@ -63,6 +68,13 @@ async fn test_resolve() {
                 max_pool_size: 256,
                 timeout: 10,
                 ratelimit: None,
+                ratelimit_queue: false,
+                case_randomization: false,
+                tcp_fallback: false,
+                retry: Default::default(),
+                edns: Default::default(),
+                tsig: None,
+                dnssec: false,
             },
         ),
     )
@@ -80,6 +92,600 @@ async fn test_resolve() {
     );
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reload_upstreams() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53536").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+
+    let upstreams = |addr: &str| {
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: addr.parse().unwrap(),
+                max_pool_size: 256,
+                timeout: 1,
+                ratelimit: None,
+                ratelimit_queue: false,
+                case_randomization: false,
+                tcp_fallback: false,
+                retry: Default::default(),
+                edns: Default::default(),
+                tsig: None,
+                dnssec: false,
+            },
+        )
+    };
+
+    // Nothing is listening on the initial upstream, queries fail with SERVFAIL.
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        upstreams("127.0.0.1:53537"),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+    assert_eq!(
+        router
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .header()
+            .rcode(),
+        Rcode::ServFail
+    );
+
+    router
+        .reload_upstreams(upstreams("127.0.0.1:53536"))
+        .await
+        .unwrap();
+    assert_eq!(
+        router
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+
+    // The cached response is carried over, though nothing is listening on the upstream again.
+    router
+        .reload_upstreams(upstreams("127.0.0.1:53537"))
+        .await
+        .unwrap();
+    assert_eq!(
+        router
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+}
+
+#[cfg(feature = "rune-scripting")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reload_script() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53540").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+
+    let upstream = |addr: &str| UdpBuilder {
+        addr: addr.parse().unwrap(),
+        max_pool_size: 256,
+        timeout: 1,
+        ratelimit: None,
+        ratelimit_queue: false,
+        case_randomization: false,
+        tcp_fallback: false,
+        retry: Default::default(),
+        edns: Default::default(),
+        tsig: None,
+        dnssec: false,
+    };
+    let script = |tag: &str| {
+        RuneScriptBuilder::new(format!(
+            "pub async fn route(upstreams, inited, ctx, query) {{ upstreams.send_default(\"{}\", query).await }}",
+            tag
+        ))
+    };
+
+    // Nothing is listening on the upstream the initial script sends to, queries fail with SERVFAIL.
+    let router = RouterBuilder::new(
+        script("dead"),
+        UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream("dead", upstream("127.0.0.1:53541"))
+            .add_upstream("mock", upstream("127.0.0.1:53540")),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+    assert_eq!(
+        router
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .header()
+            .rcode(),
+        Rcode::ServFail
+    );
+
+    router.reload_script(script("mock")).await.unwrap();
+    assert_eq!(
+        router
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+
+    // The script in use is kept if the new one fails to compile.
+    assert!(router
+        .reload_script(RuneScriptBuilder::new("pub async fn route("))
+        .await
+        .is_err());
+    assert_eq!(
+        router
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+}
+
+#[cfg(feature = "rune-scripting")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_script_limits() {
+    let router = |script: &str, limits: RuneScriptLimits| {
+        RouterBuilder::new(
+            RuneScriptBuilder::new(script).limits(limits),
+            UpstreamsBuilder::new(1).unwrap().add_upstream(
+                "mock",
+                UdpBuilder {
+                    // Never queried by the scripts
+                    addr: "127.0.0.1:53544".parse().unwrap(),
+                    max_pool_size: 256,
+                    timeout: 1,
+                    ratelimit: None,
+                    ratelimit_queue: false,
+                    case_randomization: false,
+                    tcp_fallback: false,
+                    retry: Default::default(),
+                    edns: Default::default(),
+                    tsig: None,
+                    dnssec: false,
+                },
+            ),
+        )
+        .async_try_into()
+    };
+
+    // An endless loop runs out of instructions.
+    let looping = router(
+        "pub async fn route(upstreams, inited, ctx, query) { loop {} }",
+        RuneScriptLimits {
+            max_instructions: Some(100000),
+            timeout: None,
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        looping
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .header()
+            .rcode(),
+        Rcode::ServFail
+    );
+
+    // Waiting counts towards the timeout but not the instructions.
+    let script =
+        "pub async fn route(upstreams, inited, ctx, query) { delay(2000).await; Ok(query) }";
+    let waiting = router(
+        script,
+        RuneScriptLimits {
+            max_instructions: Some(100000),
+            timeout: Some(200),
+        },
+    )
+    .await
+    .unwrap();
+    let start = Instant::now();
+    assert_eq!(
+        waiting
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .header()
+            .rcode(),
+        Rcode::ServFail
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let patient = router(
+        script,
+        RuneScriptLimits {
+            max_instructions: Some(100000),
+            timeout: None,
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        patient
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .into_octets(),
+        QUERY.clone().into_octets()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_error_policy() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53545").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+
+    let router = |on_error: ErrorPolicy| {
+        RouterBuilder::new(
+            NativeScriptBuilder::new(failing_script),
+            UpstreamsBuilder::new(1).unwrap().add_upstream(
+                "mock",
+                UdpBuilder {
+                    addr: "127.0.0.1:53545".parse().unwrap(),
+                    max_pool_size: 256,
+                    timeout: 1,
+                    ratelimit: None,
+                    ratelimit_queue: false,
+                    case_randomization: false,
+                    tcp_fallback: false,
+                    retry: Default::default(),
+                    edns: Default::default(),
+                    tsig: None,
+                    dnssec: false,
+                },
+            ),
+        )
+        .on_error(on_error)
+        .async_try_into()
+    };
+
+    let servfail = router(ErrorPolicy::ServFail).await.unwrap();
+    assert_eq!(
+        servfail
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .header()
+            .rcode(),
+        Rcode::ServFail
+    );
+
+    let fallthrough = router(ErrorPolicy::Upstream("mock".into())).await.unwrap();
+    assert_eq!(
+        fallthrough
+            .resolve(QUERY.clone(), None)
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+
+    let drop = router(ErrorPolicy::Drop).await.unwrap();
+    assert!(matches!(
+        drop.resolve(QUERY.clone(), None).await,
+        Err(ScriptError::Dropped)
+    ));
+
+    // The upstream to fall through to must exist.
+    assert!(router(ErrorPolicy::Upstream("missing".into()))
+        .await
+        .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failover() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53538").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+
+    // Nothing is listening on the first address, queries fail over to the second one.
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: Addrs::Many(vec![
+                    "127.0.0.1:53539".parse().unwrap(),
+                    "127.0.0.1:53538".parse().unwrap(),
+                ]),
+                max_pool_size: 256,
+                timeout: 1,
+                ratelimit: None,
+                ratelimit_queue: false,
+                case_randomization: false,
+                tcp_fallback: false,
+                retry: Default::default(),
+                edns: Default::default(),
+                tsig: None,
+                dnssec: false,
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    for _ in 0..2 {
+        assert_eq!(
+            router
+                .resolve(QUERY.clone(), None)
+                .await
+                .unwrap()
+                .into_octets(),
+            DUMMY_MSG.clone().into_octets()
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_send_timeout() {
+    // The socket is bound but never responds.
+    let _socket = UdpSocket::bind(&"127.0.0.1:53542").await.unwrap();
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53542".parse().unwrap(),
+                max_pool_size: 256,
+                timeout: 10,
+                ratelimit: None,
+                ratelimit_queue: false,
+                case_randomization: false,
+                tcp_fallback: false,
+                retry: Default::default(),
+                edns: Default::default(),
+                tsig: None,
+                dnssec: false,
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    assert!(matches!(
+        upstreams
+            .send_timeout(
+                &"mock".into(),
+                &droute::CacheMode::Standard,
+                &QUERY,
+                Duration::from_millis(200)
+            )
+            .await,
+        Err(UpstreamError::Timeout(_))
+    ));
+    // Gives up long before the timeout of the upstream.
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[cfg(all(feature = "rune-scripting", feature = "testing"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_no_response() {
+    let router = RouterBuilder::new(
+        RuneScriptBuilder::new(
+            "pub async fn route(upstreams, inited, ctx, query) { if query.first_question?.qname.to_str() == \"junk.example.com\" { no_response() } else { blackhole(query) } }",
+        ),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                // Never queried
+                addr: "127.0.0.1:53546".parse().unwrap(),
+                max_pool_size: 256,
+                timeout: 1,
+                ratelimit: None,
+                ratelimit_queue: false,
+                case_randomization: false,
+                tcp_fallback: false,
+                retry: Default::default(),
+                edns: Default::default(),
+                tsig: None,
+                dnssec: false,
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    assert!(router.resolve(QUERY.clone(), None).await.is_ok());
+
+    let name = Dname::<Bytes>::from_str("junk.example.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232))
+        .unwrap()
+        .question();
+    builder.push((&name, Rtype::A)).unwrap();
+    // Not answered with SERVFAIL by the error policy
+    assert!(matches!(
+        router.resolve(builder.into_message(), None).await,
+        Err(ScriptError::Dropped)
+    ));
+}
+
+#[cfg(feature = "rune-scripting")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_fixture() {
+    use droute::testing::{Case, Expect, Fixture, Mock};
+    use std::collections::HashMap;
+
+    let upstream = UdpBuilder {
+        // Never queried as the upstreams are mocked
+        addr: "127.0.0.1:53543".parse().unwrap(),
+        max_pool_size: 256,
+        timeout: 1,
+        ratelimit: None,
+        ratelimit_queue: false,
+        case_randomization: false,
+        tcp_fallback: false,
+        retry: Default::default(),
+        edns: Default::default(),
+        tsig: None,
+        dnssec: false,
+    };
+    let router = RouterBuilder::new(
+        RuneScriptBuilder::new(
+            "pub async fn route(upstreams, inited, ctx, query) { if query.first_question?.qname.to_str().ends_with(\"cn\") { upstreams.send_default(\"domestic\", query).await } else { upstreams.send_default(\"secure\", query).await } }",
+        ),
+        UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream("domestic", upstream.clone())
+            .add_upstream("secure", upstream),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let mocks: HashMap<_, _> = [
+        (
+            "domestic".into(),
+            Mock {
+                answers: vec!["1.1.1.1".parse().unwrap()],
+                ..Default::default()
+            },
+        ),
+        (
+            "secure".into(),
+            Mock {
+                rcode: Some("NXDOMAIN".to_string()),
+                ..Default::default()
+            },
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let case = |name: &str, qname: &str, expect| Case {
+        name: name.to_string(),
+        qname: qname.to_string(),
+        qtype: "A".to_string(),
+        client: "127.0.0.1".parse().unwrap(),
+        mocks: mocks.clone(),
+        expect,
+    };
+
+    let outcomes = router
+        .test(&Fixture {
+            cases: vec![
+                case(
+                    "domestic",
+                    "example.cn",
+                    Expect {
+                        rcode: Some("NOERROR".to_string()),
+                        answers: Some(vec!["1.1.1.1".parse().unwrap()]),
+                        upstreams: Some(vec!["domestic".into()]),
+                    },
+                ),
+                case(
+                    "secure",
+                    "example.com",
+                    Expect {
+                        rcode: Some("NXDOMAIN".to_string()),
+                        answers: Some(vec![]),
+                        upstreams: Some(vec!["secure".into()]),
+                    },
+                ),
+                case(
+                    "wrong",
+                    "example.com",
+                    Expect {
+                        upstreams: Some(vec!["domestic".into()]),
+                        ..Default::default()
+                    },
+                ),
+            ],
+        })
+        .await;
+    assert!(outcomes[0].passed());
+    assert!(outcomes[1].passed());
+    assert_eq!(outcomes[2].failures.len(), 1);
+}
+
+#[cfg(all(feature = "rune-scripting", feature = "testing"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_fixture_forward() {
+    use droute::testing::{Case, Expect, Fixture, Mock};
+
+    let router = RouterBuilder::new(
+        RuneScriptBuilder::new(
+            "pub async fn route(upstreams, inited, ctx, query) { upstreams.send_default(\"forward\", query).await }",
+        ),
+        UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream(
+                "udp",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    // Never queried as the upstreams are mocked
+                    addr: "127.0.0.1:53544".parse().unwrap(),
+                    max_pool_size: 256,
+                    timeout: 1,
+                    ratelimit: None,
+                    ratelimit_queue: false,
+                    case_randomization: false,
+                    tcp_fallback: false,
+                    retry: Default::default(),
+                    edns: Default::default(),
+                    tsig: None,
+                    dnssec: false,
+                }),
+            )
+            .add_upstream(
+                "forward",
+                UpstreamBuilder::Forward(
+                    ForwardBuilder::new()
+                        // A dedicated upstream, which is never queried either
+                        .add_zone("lan", ForwardTarget::Addr("127.0.0.1:53545".parse().unwrap()))
+                        .default_tag("udp"),
+                ),
+            ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let outcomes = router
+        .test(&Fixture {
+            cases: vec![Case {
+                name: "dedicated".to_string(),
+                qname: "nas.lan".to_string(),
+                qtype: "A".to_string(),
+                client: "127.0.0.1".parse().unwrap(),
+                mocks: [(
+                    "forward".into(),
+                    Mock {
+                        answers: vec!["10.0.0.2".parse().unwrap()],
+                        ..Default::default()
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                expect: Expect {
+                    answers: Some(vec!["10.0.0.2".parse().unwrap()]),
+                    upstreams: Some(vec!["forward".into()]),
+                    ..Default::default()
+                },
+            }],
+        })
+        .await;
+    assert!(outcomes[0].passed());
+}
+
 async fn resolve_script(
     upstreams: Upstreams,
     query: Message<Bytes>,
@@ -89,3 +695,11 @@ async fn resolve_script(
         .send(&"mock".into(), &droute::CacheMode::Standard, &query)
         .await?)
 }
+
+async fn failing_script(
+    _upstreams: Upstreams,
+    _query: Message<Bytes>,
+    _ctx: Option<QueryContext>,
+) -> Result<Message<Bytes>, ScriptError> {
+    Err(MessageError::NoFirstQuestion.into())
+}